@@ -1,4 +1,68 @@
 CREATE TABLE threads (
     url varchar,
-    ts varchar
-);
\ No newline at end of file
+    ts varchar,
+    channel varchar
+);
+
+CREATE TABLE review_request_messages (
+    url varchar,
+    ts varchar,
+    channel varchar,
+    reviewers varchar[] NOT NULL DEFAULT '{}',
+    acknowledged varchar[] NOT NULL DEFAULT '{}'
+);
+
+CREATE TABLE review_request_pings (
+    url varchar,
+    reviewer varchar,
+    pinged_at timestamptz NOT NULL,
+    PRIMARY KEY (url, reviewer)
+);
+
+CREATE TABLE review_escalations (
+    url varchar,
+    reviewer varchar,
+    reviewer_email varchar NOT NULL,
+    repository varchar NOT NULL,
+    pull_request_link varchar NOT NULL,
+    step integer NOT NULL,
+    requested_at timestamptz NOT NULL,
+    due_at timestamptz NOT NULL,
+    PRIMARY KEY (url, reviewer)
+);
+
+CREATE TABLE comment_mentions (
+    comment_id bigint,
+    username varchar,
+    PRIMARY KEY (comment_id, username)
+);
+
+CREATE TABLE comment_summaries (
+    url varchar PRIMARY KEY,
+    last_comment_id bigint NOT NULL,
+    mentioned varchar[] NOT NULL
+);
+
+CREATE TABLE pull_requests (
+    url varchar PRIMARY KEY,
+    mergeable boolean,
+    status text,
+    metadata text,
+    head varchar,
+    head_sha varchar,
+    commits integer
+);
+
+CREATE TABLE deferred_webhooks (
+    id serial PRIMARY KEY,
+    reason varchar NOT NULL,
+    key varchar NOT NULL,
+    url varchar NOT NULL,
+    payload text NOT NULL,
+    failed boolean NOT NULL DEFAULT false
+);
+
+CREATE TABLE repositories (
+    id bigint PRIMARY KEY,
+    url varchar NOT NULL
+);
@@ -0,0 +1,83 @@
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+use std::time::SystemTime;
+
+const DEFAULT_ALIASES_FILE: &str = "./email_aliases.toml";
+
+/// When the aliases file was last modified as of reading it, and what it said
+type Aliases = Option<(SystemTime, HashMap<String, String>)>;
+
+static ALIASES: OnceLock<Mutex<Aliases>> = OnceLock::new();
+
+/// The Slack user id an email is an alias of, e.g. someone's personal or noreply address, from
+/// the `"email" = "slack user id"` table in `EMAIL_ALIASES_FILE`. The file is read again
+/// whenever it's modified, so aliases can be added without a restart.
+pub fn slack_user_id(email: &str) -> Option<String> {
+    let path = std::env::var("EMAIL_ALIASES_FILE")
+        .ok()
+        .filter(|path| !path.is_empty())
+        .unwrap_or(DEFAULT_ALIASES_FILE.to_string());
+
+    let mut aliases = ALIASES
+        .get_or_init(Default::default)
+        .lock()
+        .expect("Email aliases should not be poisoned");
+
+    /* No file means no aliases, which is the usual case */
+    let Ok(modified) = std::fs::metadata(&path).and_then(|x| x.modified()) else {
+        *aliases = None;
+        return None;
+    };
+
+    if aliases.as_ref().map(|(read, _)| *read) != Some(modified) {
+        let read = std::fs::read_to_string(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|contents| parse(&contents));
+        match read {
+            Ok(read) => {
+                tracing::info!(aliases = read.len(), "Read email aliases from \"{}\"", path);
+                *aliases = Some((modified, read));
+            }
+            /* Keeping the previous aliases beats a typo unmapping everyone */
+            Err(x) => tracing::error!("Error attempting to read email aliases: \"{}\"", x),
+        }
+    }
+
+    aliases
+        .as_ref()
+        .and_then(|(_, aliases)| aliases.get(&email.to_lowercase()))
+        .cloned()
+}
+
+/// Emails are matched case-insensitively
+fn parse(contents: &str) -> Result<HashMap<String, String>, anyhow::Error> {
+    let aliases = toml::from_str::<HashMap<String, String>>(contents)?;
+    Ok(aliases
+        .into_iter()
+        .map(|(email, id)| (email.to_lowercase(), id))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aliases_are_matched_case_insensitively() {
+        let aliases = parse(
+            r#"
+            "Alice@Personal.example.com" = "U0ALICE"
+            "alice@noreply.gitea.example.com" = "U0ALICE"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            aliases
+                .get("alice@personal.example.com")
+                .map(String::as_str),
+            Some("U0ALICE")
+        );
+        assert_eq!(aliases.len(), 2);
+    }
+}
@@ -0,0 +1,182 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+use tracing::Instrument;
+
+/// What sends a batch before its window passes
+#[derive(Debug, Clone, Copy)]
+pub struct Triggers {
+    /// From the first item arriving to the batch being sent
+    pub window: Duration,
+    /// Sends the batch as soon as it holds this many items, zero for no limit
+    pub max_items: usize,
+    /// Sends the batch, including this item, as soon as it arrives
+    pub urgent: bool,
+}
+
+type Pending<T> = Mutex<HashMap<String, (u64, Vec<T>)>>;
+
+/// Items waiting out a window, keyed on whatever they're batched by, e.g. a PR's url, with the
+/// generation of each batch so a stale timer can't send a newer one
+pub struct Batcher<T> {
+    pending: OnceLock<Pending<T>>,
+    generation: AtomicU64,
+}
+
+impl<T: Send + 'static> Default for Batcher<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Send + 'static> Batcher<T> {
+    pub const fn new() -> Self {
+        Self {
+            pending: OnceLock::new(),
+            generation: AtomicU64::new(0),
+        }
+    }
+
+    fn pending(&self) -> &Pending<T> {
+        self.pending.get_or_init(Default::default)
+    }
+
+    /// Adds an item to its key's batch, returning the batch, in arrival order, if a trigger
+    /// means it should be sent now. Otherwise an item starting a new batch has it handed to
+    /// `flush` once the window passes.
+    pub fn add<F, Fut>(
+        &'static self,
+        key: &str,
+        item: T,
+        triggers: Triggers,
+        flush: F,
+    ) -> Option<Vec<T>>
+    where
+        F: FnOnce(Vec<T>) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send,
+    {
+        let mut pending = self.pending().lock().unwrap();
+
+        if let Some((_, batch)) = pending.get_mut(key) {
+            batch.push(item);
+            let full = triggers.max_items > 0 && batch.len() >= triggers.max_items;
+            return if triggers.urgent || full {
+                pending.remove(key).map(|(_, batch)| batch)
+            } else {
+                None
+            };
+        }
+
+        if triggers.urgent || triggers.max_items == 1 {
+            return Some(vec![item]);
+        }
+
+        let generation = self.generation.fetch_add(1, Ordering::Relaxed);
+        pending.insert(key.to_string(), (generation, vec![item]));
+
+        let key = key.to_string();
+        tokio::spawn(
+            async move {
+                tokio::time::sleep(triggers.window).await;
+                if let Some(batch) = self.take_generation(&key, Some(generation)) {
+                    flush(batch).await;
+                }
+            }
+            .in_current_span(),
+        );
+
+        None
+    }
+
+    /// Takes the items in the key's batch matching `f`, if there are any, to send them early.
+    /// The rest wait out the batch's window as before.
+    pub fn take_matching(&self, key: &str, f: impl Fn(&T) -> bool) -> Option<Vec<T>> {
+        let mut pending = self.pending().lock().unwrap();
+        let (_, batch) = pending.get_mut(key)?;
+
+        let (taken, rest) = std::mem::take(batch).into_iter().partition::<Vec<_>, _>(f);
+        if rest.is_empty() {
+            pending.remove(key);
+        } else {
+            *batch = rest;
+        }
+
+        (!taken.is_empty()).then_some(taken)
+    }
+
+    /// Looks at the first item of the key's batch, e.g. to check a new item belongs with it
+    pub fn first<R>(&self, key: &str, f: impl FnOnce(&T) -> R) -> Option<R> {
+        self.pending()
+            .lock()
+            .unwrap()
+            .get(key)
+            .and_then(|(_, batch)| batch.first())
+            .map(f)
+    }
+
+    fn take_generation(&self, key: &str, generation: Option<u64>) -> Option<Vec<T>> {
+        let mut pending = self.pending().lock().unwrap();
+        match pending.get(key) {
+            Some((batch_generation, _)) if generation.is_some_and(|x| x != *batch_generation) => {
+                None
+            }
+            Some(_) => pending.remove(key).map(|(_, batch)| batch),
+            None => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static BATCHER: Batcher<u32> = Batcher::new();
+
+    #[tokio::test]
+    async fn batches_are_sent_when_full_or_urgent() {
+        let triggers = Triggers {
+            window: Duration::from_secs(60),
+            max_items: 3,
+            urgent: false,
+        };
+        let add = |key, item, urgent| {
+            BATCHER.add(key, item, Triggers { urgent, ..triggers }, |_| async {})
+        };
+
+        assert_eq!(add("full", 1, false), None);
+        assert_eq!(add("full", 2, false), None);
+        assert_eq!(add("full", 3, false), Some(vec![1, 2, 3]));
+
+        assert_eq!(add("urgent", 4, false), None);
+        assert_eq!(add("urgent", 5, true), Some(vec![4, 5]));
+        assert_eq!(add("urgent", 6, true), Some(vec![6]));
+
+        assert_eq!(add("taken", 7, false), None);
+        assert_eq!(BATCHER.first("taken", |x| *x), Some(7));
+        assert_eq!(BATCHER.take_matching("taken", |_| true), Some(vec![7]));
+        assert_eq!(BATCHER.take_matching("taken", |_| true), None);
+    }
+
+    #[tokio::test]
+    async fn taking_some_items_leaves_the_rest_waiting() {
+        let triggers = Triggers {
+            window: Duration::from_secs(60),
+            max_items: 0,
+            urgent: false,
+        };
+        for item in 1..=4 {
+            assert_eq!(BATCHER.add("partial", item, triggers, |_| async {}), None);
+        }
+
+        assert_eq!(
+            BATCHER.take_matching("partial", |x| x % 2 == 0),
+            Some(vec![2, 4])
+        );
+        assert_eq!(BATCHER.take_matching("partial", |x| x % 2 == 0), None);
+        assert_eq!(BATCHER.first("partial", |x| *x), Some(1));
+        assert_eq!(BATCHER.take_matching("partial", |_| true), Some(vec![1, 3]));
+        assert_eq!(BATCHER.first("partial", |x| *x), None);
+    }
+}
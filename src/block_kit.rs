@@ -0,0 +1,162 @@
+use serde_json::Value;
+use slack_morphism::prelude::SlackMessageContent;
+
+/* Limits from https://api.slack.com/reference/block-kit/blocks */
+const MAX_BLOCKS: usize = 50;
+const MAX_BLOCK_ID_LEN: usize = 255;
+pub const MAX_SECTION_TEXT_LEN: usize = 3000;
+const MAX_SECTION_FIELDS: usize = 10;
+const MAX_SECTION_FIELD_LEN: usize = 2000;
+const MAX_HEADER_TEXT_LEN: usize = 150;
+const MAX_CONTEXT_ELEMENTS: usize = 10;
+const MAX_ACTIONS_ELEMENTS: usize = 25;
+
+/// Checks a message against Slack's documented Block Kit limits, returning each problem found.
+/// Structural only, Slack may still reject a message for reasons beyond these.
+pub fn validate(content: &SlackMessageContent) -> Vec<String> {
+    let value = match serde_json::to_value(content) {
+        Ok(value) => value,
+        Err(x) => return vec![format!("message doesn't serialise: {}", x)],
+    };
+
+    let blocks = value
+        .get("blocks")
+        .and_then(Value::as_array)
+        .map(Vec::as_slice)
+        .unwrap_or_default();
+
+    let mut problems = Vec::new();
+    if blocks.len() > MAX_BLOCKS {
+        problems.push(format!(
+            "{} blocks, at most {} are allowed",
+            blocks.len(),
+            MAX_BLOCKS
+        ));
+    }
+
+    for (index, block) in blocks.iter().enumerate() {
+        let kind = block
+            .get("type")
+            .and_then(Value::as_str)
+            .unwrap_or_default();
+        let mut problem =
+            |problem: String| problems.push(format!("block {} ({}): {}", index, kind, problem));
+
+        if let Some(block_id) = block.get("block_id").and_then(Value::as_str) {
+            check_len(&mut problem, "block_id", block_id, MAX_BLOCK_ID_LEN);
+        }
+
+        match kind {
+            "section" => {
+                let fields = block.get("fields").and_then(Value::as_array);
+                match block.get("text") {
+                    Some(text) => check_text(&mut problem, "text", text, MAX_SECTION_TEXT_LEN),
+                    None if fields.is_none() => problem("needs text or fields".to_string()),
+                    None => {}
+                }
+
+                if let Some(fields) = fields {
+                    if fields.len() > MAX_SECTION_FIELDS {
+                        problem(format!(
+                            "{} fields, at most {} are allowed",
+                            fields.len(),
+                            MAX_SECTION_FIELDS
+                        ));
+                    }
+                    for field in fields {
+                        check_text(&mut problem, "field", field, MAX_SECTION_FIELD_LEN);
+                    }
+                }
+            }
+            "header" => match block.get("text") {
+                Some(text) if text.get("type").and_then(Value::as_str) == Some("plain_text") => {
+                    check_text(&mut problem, "text", text, MAX_HEADER_TEXT_LEN)
+                }
+                Some(_) => problem("text must be plain_text".to_string()),
+                None => problem("needs text".to_string()),
+            },
+            "context" => {
+                check_elements(&mut problem, block, MAX_CONTEXT_ELEMENTS);
+            }
+            "actions" => {
+                check_elements(&mut problem, block, MAX_ACTIONS_ELEMENTS);
+            }
+            "divider" | "image" | "rich_text" | "input" | "file" | "video" => {}
+            kind => problem(format!("unknown block type \"{}\"", kind)),
+        }
+    }
+
+    problems
+}
+
+fn check_len(problem: &mut impl FnMut(String), name: &str, text: &str, max_len: usize) {
+    let len = text.chars().count();
+    if len > max_len {
+        problem(format!(
+            "{} is {} characters, at most {} are allowed",
+            name, len, max_len
+        ));
+    }
+}
+
+fn check_text(problem: &mut impl FnMut(String), name: &str, text: &Value, max_len: usize) {
+    match text.get("text").and_then(Value::as_str) {
+        Some("") | None => problem(format!("{} is empty", name)),
+        Some(text) => check_len(problem, name, text, max_len),
+    }
+}
+
+fn check_elements(problem: &mut impl FnMut(String), block: &Value, max_elements: usize) {
+    let elements = block
+        .get("elements")
+        .and_then(Value::as_array)
+        .map(Vec::len)
+        .unwrap_or_default();
+
+    if elements == 0 {
+        problem("needs at least one element".to_string());
+    } else if elements > max_elements {
+        problem(format!(
+            "{} elements, at most {} are allowed",
+            elements, max_elements
+        ));
+    }
+}
+
+/// Cuts text down to at most `max_len` characters, marking that it was with an ellipsis
+pub fn truncate(text: &str, max_len: usize) -> String {
+    match text.char_indices().nth(max_len.saturating_sub(1)) {
+        Some((end, _)) if text.chars().count() > max_len => format!("{}…", &text[..end]),
+        _ => text.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use slack_morphism::prelude::*;
+
+    #[test]
+    fn oversized_and_empty_sections_are_invalid() {
+        let content = SlackMessageContent::new().with_blocks(slack_blocks![
+            some_into(SlackSectionBlock::new().with_text(md!("{}", "a".repeat(3001)))),
+            some_into(SlackSectionBlock::new().with_text(md!(""))),
+            some_into(SlackContextBlock::new(vec![]))
+        ]);
+
+        assert_eq!(
+            validate(&content),
+            vec![
+                "block 0 (section): text is 3001 characters, at most 3000 are allowed",
+                "block 1 (section): text is empty",
+                "block 2 (context): needs at least one element",
+            ]
+        );
+    }
+
+    #[test]
+    fn truncate_counts_characters() {
+        assert_eq!(truncate("🎉🎉🎉", 3), "🎉🎉🎉");
+        assert_eq!(truncate("🎉🎉🎉🎉", 3), "🎉🎉…");
+    }
+}
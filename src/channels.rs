@@ -0,0 +1,142 @@
+use crate::config::config;
+use crate::gitea_webhooks::Webhook;
+use crate::{paths, slack};
+use regex::Regex;
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+static PLACEHOLDER: OnceLock<Regex> = OnceLock::new();
+static CHANNEL_NAME: OnceLock<Regex> = OnceLock::new();
+
+/// The channel a PR is posted to by its repo's `channel_template`, or its fallback if the
+/// template can't be filled in, `None` for the usual channel
+pub async fn templated(webhook: &Webhook) -> Option<String> {
+    let channel_template = &config().repo(&webhook.repository).channel_template;
+    let template = channel_template.template.as_ref()?;
+
+    let name = match render(template, &fields(webhook, template).await) {
+        Ok(name) => name,
+        Err(x) => {
+            tracing::warn!(
+                "Unable to name a channel from \"{}\", falling back: \"{}\"",
+                template,
+                x
+            );
+            return channel_template.fallback.clone();
+        }
+    };
+
+    if !channel_template.auto_create {
+        return Some(name);
+    }
+
+    match slack::find_or_create_channel(&name).await {
+        Ok(id) => Some(id.0),
+        Err(x) => {
+            tracing::error!(
+                "Error attempting to find or create channel {}: \"{}\"",
+                name,
+                x
+            );
+            channel_template.fallback.clone()
+        }
+    }
+}
+
+/// What the placeholders are filled in with, `None` where the PR has nothing for it
+async fn fields(webhook: &Webhook, template: &str) -> HashMap<&'static str, Option<String>> {
+    let full_name = &webhook.repository.full_name;
+    let (owner, repo_name) = full_name
+        .split_once('/')
+        .unwrap_or(("", full_name.as_str()));
+
+    /* Only worth fetching the files for when the template uses them */
+    let first_path_segment = if template.contains("{first_path_segment}") {
+        match paths::changed_files(webhook).await {
+            Some(files) => first_path_segment(&files),
+            None => None,
+        }
+    } else {
+        None
+    };
+
+    HashMap::from([
+        ("owner", Some(owner.to_string())),
+        ("repo_name", Some(repo_name.to_string())),
+        (
+            "base_branch",
+            webhook.pull_request.base.as_ref().map(|x| x.name.clone()),
+        ),
+        ("first_path_segment", first_path_segment),
+    ])
+}
+
+/// The top-level directory every one of the files is in, if they share one
+fn first_path_segment(files: &[String]) -> Option<String> {
+    let mut segments = files
+        .iter()
+        .map(|file| file.split_once('/').map(|(segment, _)| segment));
+    let first = segments.next()??;
+    segments
+        .all(|segment| segment == Some(first))
+        .then(|| first.to_string())
+}
+
+/// Fills in the template, erroring if a placeholder has nothing to fill it in or the result
+/// isn't a name Slack allows: lowercase letters, numbers, hyphens and underscores, at most 80
+fn render(template: &str, fields: &HashMap<&str, Option<String>>) -> Result<String, anyhow::Error> {
+    let placeholder = PLACEHOLDER
+        .get_or_init(|| Regex::new(r"\{([a-z_]+)\}").expect("Placeholder regex should be valid"));
+    let channel_name = CHANNEL_NAME.get_or_init(|| {
+        Regex::new(r"^[a-z0-9_-]{1,80}$").expect("Channel name regex should be valid")
+    });
+
+    let mut missing = None;
+    let name = placeholder.replace_all(template, |captures: &regex::Captures| {
+        match fields.get(&captures[1]) {
+            Some(Some(value)) => value.to_lowercase(),
+            _ => {
+                missing.get_or_insert_with(|| captures[1].to_string());
+                String::new()
+            }
+        }
+    });
+    if let Some(field) = missing {
+        anyhow::bail!("Nothing to fill in {{{}}} with", field);
+    }
+
+    let name = name.trim_start_matches('#');
+    if !channel_name.is_match(name) {
+        anyhow::bail!("`{}` isn't a valid Slack channel name", name);
+    }
+
+    Ok(name.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn channel_names_are_filled_in_and_validated() {
+        let fields = HashMap::from([
+            ("owner", Some("Acme".to_string())),
+            ("repo_name", Some("Widgets".to_string())),
+            ("base_branch", Some("release/1.2".to_string())),
+            ("first_path_segment", None),
+        ]);
+
+        assert_eq!(
+            render("#pr-{owner}-{repo_name}", &fields).unwrap(),
+            "pr-acme-widgets"
+        );
+        assert!(render("team-{first_path_segment}", &fields).is_err());
+        assert!(render("pr-{unknown}", &fields).is_err());
+        assert!(render("pr-{base_branch}", &fields).is_err());
+
+        let files = ["billing/api.rs", "billing/db.rs"].map(String::from);
+        assert_eq!(first_path_segment(&files).as_deref(), Some("billing"));
+        let files = ["billing/api.rs", "README.md"].map(String::from);
+        assert_eq!(first_path_segment(&files), None);
+    }
+}
@@ -0,0 +1,37 @@
+use crate::config::config_env_var;
+use crate::{gitea_api, slack};
+use url::Url;
+
+/// Preflights the credentials and channel a deployment needs, printing a report. Returns
+/// whether every check passed.
+pub async fn run() -> bool {
+    let results = [
+        ("Gitea API token", check_gitea().await),
+        ("Slack API token", check_slack_token().await),
+        ("Slack channel", slack::check_channel().await),
+    ];
+
+    for (name, result) in &results {
+        match result {
+            Ok(detail) => println!("PASS {}: {}", name, detail),
+            Err(x) => println!("FAIL {}: {:#}", name, x),
+        }
+    }
+
+    results.iter().all(|(_, result)| result.is_ok())
+}
+
+async fn check_gitea() -> Result<String, anyhow::Error> {
+    let url: Url = config_env_var("GITEA_URL")
+        .map_err(|_| anyhow::anyhow!("GITEA_URL must be set to the Gitea instance to check"))?
+        .parse()?;
+
+    let user = gitea_api::fetch_token_user(&url).await?;
+    Ok(format!("authenticated as {}", user.username))
+}
+
+async fn check_slack_token() -> Result<String, anyhow::Error> {
+    let auth = slack::auth_test().await?;
+    let token_type = slack::check_token(&auth)?;
+    Ok(format!("{} token with the required scopes", token_type))
+}
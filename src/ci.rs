@@ -0,0 +1,402 @@
+use crate::config::config;
+use crate::gitea_webhooks::{
+    self, Action, CombinedStatus, CommitState, PullRequest, StatusEvent, Webhook,
+};
+use crate::recent::Outcome;
+use crate::{db, gitea_api, quiet_hours, slack};
+use axum::Extension;
+use sqlx::postgres::PgPool;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tracing::Instrument;
+
+const DEFERRED_REASON: &str = "ci";
+
+/// Upper bound on the open PRs searched for the one whose head a failing commit is
+const MAX_PULL_REQUESTS_SEARCHED: usize = 50;
+const MAX_TRACKED_HEADS: usize = 256;
+/// How long a head's CI status is shown as fetched, so a burst of notifications about a PR
+/// doesn't fetch it for each
+const STATUS_TTL: Duration = Duration::from_secs(30);
+
+/// Per failing head SHA, when it was first tracked, the generation of the latest failure seen
+/// and whether its author has already been told
+type Heads = Mutex<HashMap<String, (Instant, u64, bool)>>;
+
+static HEADS: OnceLock<Heads> = OnceLock::new();
+
+type Statuses = Mutex<HashMap<String, (Instant, Arc<CombinedStatus>)>>;
+
+static STATUSES: OnceLock<Statuses> = OnceLock::new();
+
+fn heads() -> &'static Heads {
+    HEADS.get_or_init(Default::default)
+}
+
+/// The CI status of the PR's head for notifications showing it, `None` if the repo doesn't or
+/// it can't be fetched
+pub async fn head_status(webhook: &Webhook) -> Option<Arc<CombinedStatus>> {
+    if !config().repo(&webhook.repository).show_ci_status {
+        return None;
+    }
+    if !matches!(
+        webhook.action,
+        Action::Opened
+            | Action::Edited { .. }
+            | Action::ReviewRequested { .. }
+            | Action::Reviewed { .. }
+    ) {
+        return None;
+    }
+
+    let head = webhook.pull_request.head.as_ref()?;
+    let key = format!("{}@{}", webhook.repository.full_name, head.sha);
+
+    let statuses = STATUSES.get_or_init(Default::default);
+    if let Some((fetched, status)) = statuses.lock().unwrap().get(&key) {
+        if fetched.elapsed() < STATUS_TTL {
+            return Some(status.clone());
+        }
+    }
+
+    let status = gitea_api::fetch_combined_status(
+        &webhook.repository.url,
+        &webhook.repository.full_name,
+        &head.sha,
+    )
+    .await
+    .ok()?;
+    let status = Arc::new(status);
+
+    let mut statuses = statuses.lock().unwrap();
+    if statuses.len() >= MAX_TRACKED_HEADS && !statuses.contains_key(&key) {
+        evict_oldest(&mut statuses, |(fetched, _)| *fetched);
+    }
+    statuses.insert(key, (Instant::now(), status.clone()));
+
+    Some(status)
+}
+
+/// Holds back opened and review-requested notifications for repos that wait on CI, keyed on
+/// the PR's head SHA. Returns whether the webhook was deferred.
+pub async fn gate(webhook: &Webhook, payload: &serde_json::Value, db: &PgPool) -> bool {
+    if !config().repo(&webhook.repository).wait_for_ci {
+        return false;
+    }
+
+    let Some(head) = webhook.pull_request.head.as_ref() else {
+        return false;
+    };
+    let url = webhook.pull_request.url.as_str();
+
+    if let Action::Synchronized = webhook.action {
+        /* Anything still waiting should now wait on the CI run for the new head */
+        if let Err(x) = db::rekey_deferred_webhooks(db, DEFERRED_REASON, url, &head.sha).await {
+            tracing::error!("Error attempting to re-key deferred webhooks: \"{}\"", x);
+        }
+        return false;
+    }
+
+    if !waits_for_ci(webhook).await {
+        return false;
+    }
+
+    match db::insert_deferred_webhook(db, DEFERRED_REASON, &head.sha, url, &payload.to_string())
+        .await
+    {
+        Ok(()) => {
+            tracing::info!("Deferred notification until CI passes");
+            true
+        }
+        Err(x) => {
+            tracing::error!("Error attempting to defer webhook: \"{}\"", x);
+            false
+        }
+    }
+}
+
+/// Whether [`gate`] would hold the webhook back until CI passes
+pub async fn waits_for_ci(webhook: &Webhook) -> bool {
+    if !config().repo(&webhook.repository).wait_for_ci
+        || !matches!(
+            webhook.action,
+            Action::Opened | Action::ReviewRequested { .. }
+        )
+    {
+        return false;
+    }
+
+    let Some(head) = webhook.pull_request.head.as_ref() else {
+        return false;
+    };
+
+    /* CI may well have finished before the PR was opened, and if it can't be checked it's
+     * better to notify early than not at all */
+    gitea_api::fetch_combined_status(
+        &webhook.repository.url,
+        &webhook.repository.full_name,
+        &head.sha,
+    )
+    .await
+    .is_ok_and(|status| status.state != CommitState::Success)
+}
+
+/// Sends the notifications waiting on a commit once its CI passes, or lets the author know
+/// when it fails
+pub async fn handle_status(event: StatusEvent, db: &PgPool) -> Outcome {
+    if let CommitState::Pending | CommitState::Unknown = event.status.state {
+        return Outcome::Skipped("still pending");
+    }
+
+    /* A single context finishing doesn't mean the rest have */
+    let combined = match gitea_api::fetch_combined_status(
+        &event.repository.url,
+        &event.repository.full_name,
+        &event.sha,
+    )
+    .await
+    {
+        Ok(combined) => combined,
+        Err(x) => return Outcome::Failed(x.to_string()),
+    };
+
+    match combined.state {
+        CommitState::Success => {
+            /* A later failure after a retry passes is a new failure worth hearing about */
+            heads().lock().unwrap().remove(&event.sha);
+
+            let deferred = match db::take_deferred_webhooks(db, DEFERRED_REASON, &event.sha).await {
+                Ok(deferred) => deferred,
+                Err(x) => {
+                    tracing::error!("Error attempting to fetch deferred webhooks: \"{}\"", x);
+                    return Outcome::Failed(x.to_string());
+                }
+            };
+
+            /* Each records its own outcome */
+            let mut released = 0;
+            for (webhook, x) in deferred.iter().filter_map(|x| Some((x.decode()?, x))) {
+                if !quiet_hours::hold(&webhook, &x.payload, db).await {
+                    crate::post_repo_payload(webhook, Extension(db.clone())).await;
+                }
+                released += 1;
+            }
+            match released {
+                0 => Outcome::Skipped("nothing to notify"),
+                released => Outcome::Released(released),
+            }
+        }
+        CommitState::Failure | CommitState::Error => {
+            let deferred = match db::fail_deferred_webhooks(db, DEFERRED_REASON, &event.sha).await {
+                Ok(deferred) => deferred,
+                Err(x) => {
+                    tracing::error!("Error attempting to fetch deferred webhooks: \"{}\"", x);
+                    return Outcome::Failed(x.to_string());
+                }
+            };
+
+            /* Only the first failure for a head is worth telling the author about */
+            if let Some(webhook) = deferred.first().and_then(|x| x.decode()) {
+                let outcome = notify_failure(&webhook.pull_request, &combined, true, db).await;
+                track(&mut heads().lock().unwrap(), &event.sha, Instant::now()).2 = true;
+                outcome
+            } else if config().repo(&event.repository).ci_failures.enabled {
+                debounce_failure(event, db);
+                Outcome::Held("waiting out other statuses")
+            } else {
+                Outcome::Skipped("not announced")
+            }
+        }
+        _ => Outcome::Skipped("still pending"),
+    }
+}
+
+/// Waits out a burst of statuses for a failing commit before telling its PR's author, so
+/// churn from several contexts finishing at once only notifies once
+fn debounce_failure(event: StatusEvent, db: &PgPool) {
+    let Some(generation) =
+        register_failure(&mut heads().lock().unwrap(), &event.sha, Instant::now())
+    else {
+        return;
+    };
+
+    let window = config().repo(&event.repository).ci_failures.debounce_secs;
+    let db = db.clone();
+    tokio::spawn(
+        async move {
+            tokio::time::sleep(Duration::from_secs(window)).await;
+            flush_failure(&event, generation, &db).await;
+        }
+        .in_current_span(),
+    );
+}
+
+/// Counts another failure for the head, returning its generation for the wait on it to check
+/// it's still the latest, or `None` if the author has already been told
+fn register_failure(
+    heads: &mut HashMap<String, (Instant, u64, bool)>,
+    sha: &str,
+    now: Instant,
+) -> Option<u64> {
+    let (_, generation, notified) = track(heads, sha, now);
+    if *notified {
+        return None;
+    }
+
+    *generation += 1;
+    Some(*generation)
+}
+
+/// Whether the failure is still the head's latest, and its author not yet told
+fn is_latest_failure(
+    heads: &HashMap<String, (Instant, u64, bool)>,
+    sha: &str,
+    generation: u64,
+) -> bool {
+    matches!(heads.get(sha), Some((_, latest, false)) if *latest == generation)
+}
+
+/// The head's entry, making room for it by forgetting the longest tracked head if need be,
+/// rather than every other head's progress
+fn track<'a>(
+    heads: &'a mut HashMap<String, (Instant, u64, bool)>,
+    sha: &str,
+    now: Instant,
+) -> &'a mut (Instant, u64, bool) {
+    if heads.len() >= MAX_TRACKED_HEADS && !heads.contains_key(sha) {
+        evict_oldest(heads, |(tracked, ..)| *tracked);
+    }
+
+    heads
+        .entry(sha.to_string())
+        .or_insert_with(|| (now, 0, false))
+}
+
+fn evict_oldest<V>(map: &mut HashMap<String, V>, at: impl Fn(&V) -> Instant) {
+    let oldest = map
+        .iter()
+        .min_by_key(|(_, value)| at(value))
+        .map(|(key, _)| key.clone());
+
+    if let Some(oldest) = oldest {
+        map.remove(&oldest);
+    }
+}
+
+async fn flush_failure(event: &StatusEvent, generation: u64, db: &PgPool) {
+    if !is_latest_failure(&heads().lock().unwrap(), &event.sha, generation) {
+        return;
+    }
+
+    let repository = &event.repository;
+
+    /* It may have been re-run and passed in the meantime */
+    let Ok(combined) =
+        gitea_api::fetch_combined_status(&repository.url, &repository.full_name, &event.sha).await
+    else {
+        return;
+    };
+    if !matches!(combined.state, CommitState::Failure | CommitState::Error) {
+        return;
+    }
+
+    let Ok(pull_requests) = gitea_api::fetch_open_pull_requests(
+        &repository.url,
+        &repository.full_name,
+        None,
+        MAX_PULL_REQUESTS_SEARCHED,
+    )
+    .await
+    else {
+        return;
+    };
+
+    if let Some(entry) = heads().lock().unwrap().get_mut(&event.sha) {
+        entry.2 = true;
+    }
+
+    /* A commit that isn't a PR's head, e.g. on main, has nobody in particular to tell */
+    for pull_request in pull_requests
+        .iter()
+        .filter(|x| x.head.as_ref().is_some_and(|head| head.sha == event.sha))
+    {
+        notify_failure(pull_request, &combined, false, db).await;
+    }
+}
+
+/// Tells the author CI failed on their PR, in its thread if there is one. `held_back` is
+/// whether notifications were waiting on it.
+async fn notify_failure(
+    pull_request: &PullRequest,
+    combined: &CombinedStatus,
+    held_back: bool,
+    db: &PgPool,
+) -> Outcome {
+    let failures = combined
+        .statuses
+        .iter()
+        .filter(|x| matches!(x.state, CommitState::Failure | CommitState::Error))
+        .collect::<Vec<_>>();
+
+    let author = slack::mention_gitea_user(&pull_request.url, &pull_request.user).await;
+
+    let thread = match db::fetch_thread(db, pull_request.url.as_str()).await {
+        Ok(thread) => thread,
+        Err(x) => {
+            tracing::error!(
+                "Error attempting to retrieve possible timestamp from DB: \"{}\"",
+                x
+            );
+            None
+        }
+    };
+
+    let message = gitea_webhooks::render_ci_failed(&author, pull_request, &failures, held_back);
+    match slack::post_reply(message, thread.as_ref()).await {
+        Ok(_) => {
+            tracing::info!("CI failure notification sent");
+            Outcome::Posted
+        }
+        Err(x) => Outcome::Failed(x.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_latest_of_a_burst_of_failures_notifies() {
+        let mut heads = HashMap::new();
+        let now = Instant::now();
+
+        let first = register_failure(&mut heads, "3f2a9c8", now).unwrap();
+        let second = register_failure(&mut heads, "3f2a9c8", now).unwrap();
+        assert!(!is_latest_failure(&heads, "3f2a9c8", first));
+        assert!(is_latest_failure(&heads, "3f2a9c8", second));
+
+        heads.get_mut("3f2a9c8").unwrap().2 = true;
+        assert!(!is_latest_failure(&heads, "3f2a9c8", second));
+        assert_eq!(register_failure(&mut heads, "3f2a9c8", now), None);
+    }
+
+    #[test]
+    fn making_room_only_forgets_the_longest_tracked_head() {
+        let mut heads = HashMap::new();
+        let start = Instant::now();
+        for i in 0..MAX_TRACKED_HEADS {
+            let tracked = start + Duration::from_secs(i as u64);
+            register_failure(&mut heads, &i.to_string(), tracked);
+        }
+        heads.get_mut("1").unwrap().2 = true;
+
+        let later = start + Duration::from_secs(MAX_TRACKED_HEADS as u64);
+        assert_eq!(register_failure(&mut heads, "new", later), Some(1));
+
+        assert_eq!(heads.len(), MAX_TRACKED_HEADS);
+        assert!(!heads.contains_key("0"));
+        assert_eq!(register_failure(&mut heads, "1", later), None);
+        assert_eq!(register_failure(&mut heads, "2", later), Some(2));
+    }
+}
@@ -0,0 +1,246 @@
+use crate::batch::{Batcher, Triggers};
+use crate::config::{config, BatchKey, RepoConfig};
+use crate::gitea_webhooks::{Action, Webhook};
+use crate::{db, slack};
+use axum::Extension;
+use sqlx::postgres::PgPool;
+use std::time::Duration;
+
+static BATCHER: Batcher<Webhook> = Batcher::new();
+
+/// How long the webhook's kind of action is batched for, if at all
+fn window(webhook: &Webhook, repo_config: &RepoConfig) -> u64 {
+    match webhook.action {
+        Action::ReviewRequested { .. } => repo_config.coalesce_review_requests_secs,
+        ref action if action.is_metadata() => repo_config.coalesce_metadata_secs,
+        _ => 0,
+    }
+}
+
+/// What the webhook is batched with
+async fn key(webhook: &Webhook, db: &PgPool) -> String {
+    match config().repo(&webhook.repository).batching.key {
+        BatchKey::PullRequest => webhook.pull_request.url.to_string(),
+        BatchKey::Repository => format!("repository:{}", webhook.repository.full_name),
+        BatchKey::Channel => format!("channel:{}", channel(webhook, db).await),
+    }
+}
+
+/// Where the webhook will be posted, as far as can be told before it's handled
+async fn channel(webhook: &Webhook, db: &PgPool) -> String {
+    let thread = match db::fetch_thread(db, webhook.pull_request.url.as_str()).await {
+        Ok(thread) => thread,
+        Err(x) => {
+            tracing::error!(
+                "Error attempting to retrieve possible timestamp from DB: \"{}\"",
+                x
+            );
+            None
+        }
+    };
+
+    crate::routed_channels(webhook, thread.as_ref())
+        .await
+        .into_iter()
+        .next()
+        .or_else(|| slack::channel().ok())
+        .unwrap_or_default()
+}
+
+/// Whether two webhooks can be sent as one
+fn same_kind(a: &Webhook, b: &Webhook) -> bool {
+    match (&a.action, &b.action) {
+        (Action::ReviewRequested { .. }, Action::ReviewRequested { .. }) => true,
+        (a, b) => a.is_metadata() && b.is_metadata(),
+    }
+}
+
+/// Buffers review requests, and separately label and milestone changes, arriving within the
+/// configured window, so e.g. requesting several reviewers at once produces a single message
+/// pinging them all. Any other action flushes whatever of its PR is waiting first, to keep
+/// events in order. Returns the webhook if it should be handled now.
+pub async fn coalesce(webhook: Webhook, db: &PgPool) -> Option<Webhook> {
+    let key = key(&webhook, db).await;
+    let same_pull_request = |x: &Webhook| x.pull_request.url == webhook.pull_request.url;
+
+    /* A batch shared between repositories follows whichever started it */
+    let repo_config = BATCHER
+        .first(&key, |batch| config().repo(&batch.repository))
+        .unwrap_or_else(|| config().repo(&webhook.repository));
+
+    let window = window(&webhook, repo_config);
+    if window == 0 {
+        flush(BATCHER.take_matching(&key, same_pull_request), db).await;
+        return Some(webhook);
+    }
+
+    /* Each PR's part of a batch is only ever one kind, so it can be merged into one */
+    flush(
+        BATCHER.take_matching(&key, |x| same_pull_request(x) && !same_kind(x, &webhook)),
+        db,
+    )
+    .await;
+
+    let batching = &repo_config.batching;
+    let triggers = Triggers {
+        window: Duration::from_secs(window),
+        max_items: batching.max_events,
+        urgent: batching
+            .urgent_actions
+            .contains(&webhook.action.to_string()),
+    };
+
+    let later = db.clone();
+    let batch = BATCHER.add(&key, webhook, triggers, |batch| async move {
+        flush(Some(batch), &later).await
+    });
+    flush(batch, db).await;
+
+    None
+}
+
+/// Sends a batch as one webhook per PR in it, in the order each PR first appeared
+async fn flush(batch: Option<Vec<Webhook>>, db: &PgPool) {
+    for webhook in merge_batch(batch.unwrap_or_default()) {
+        crate::post_repo_payload(webhook, Extension(db.clone())).await;
+    }
+}
+
+fn merge_batch(batch: Vec<Webhook>) -> Vec<Webhook> {
+    let mut merged = Vec::<Webhook>::new();
+    for webhook in batch {
+        match merged
+            .iter_mut()
+            .find(|x| x.pull_request.url == webhook.pull_request.url)
+        {
+            Some(pull_request) => merge(pull_request, webhook),
+            None => merged.push(webhook),
+        }
+    }
+    merged
+}
+
+fn merge(batch: &mut Webhook, webhook: Webhook) {
+    /* Label and milestone changes are summarised against the PR's latest state */
+    if webhook.action.is_metadata() {
+        *batch = webhook;
+        return;
+    }
+
+    let (
+        Action::ReviewRequested {
+            requested_reviewers,
+            ..
+        },
+        Action::ReviewRequested {
+            requested_reviewers: additional,
+            ..
+        },
+    ) = (&mut batch.action, webhook.action)
+    else {
+        return;
+    };
+
+    for reviewer in additional {
+        if !requested_reviewers
+            .iter()
+            .any(|x| x.username == reviewer.username)
+        {
+            requested_reviewers.push(reviewer);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(reviewer: &str) -> Webhook {
+        let mut payload: serde_json::Value =
+            serde_json::from_str(include_str!("../tests/fixtures/review_requested.json")).unwrap();
+        payload["requested_reviewer"]["username"] = reviewer.into();
+        serde_json::from_value::<Webhook>(payload).unwrap()
+    }
+
+    fn on_pull_request(mut webhook: Webhook, number: u64) -> Webhook {
+        webhook.pull_request.number = number;
+        webhook.pull_request.url =
+            format!("https://gitea.example.com/acme/widgets/pulls/{}", number)
+                .parse()
+                .unwrap();
+        webhook
+    }
+
+    fn reviewers(webhook: &Webhook) -> Vec<&str> {
+        match &webhook.action {
+            Action::ReviewRequested {
+                requested_reviewers,
+                ..
+            } => requested_reviewers
+                .iter()
+                .map(|x| x.username.as_str())
+                .collect(),
+            _ => vec![],
+        }
+    }
+
+    #[test]
+    fn only_review_requests_and_metadata_are_batched() {
+        let repo_config = RepoConfig {
+            coalesce_review_requests_secs: 30,
+            coalesce_metadata_secs: 10,
+            ..Default::default()
+        };
+        let mut labelled = request("bob");
+        labelled.action = Action::LabelUpdated;
+        let mut opened = request("bob");
+        opened.action = Action::Opened;
+
+        assert_eq!(window(&request("bob"), &repo_config), 30);
+        assert_eq!(window(&labelled, &repo_config), 10);
+        assert_eq!(window(&opened, &repo_config), 0);
+        assert_eq!(window(&request("bob"), &RepoConfig::default()), 0);
+
+        let mut milestoned = request("bob");
+        milestoned.action = Action::Milestoned;
+        assert!(same_kind(&request("bob"), &request("carol")));
+        assert!(same_kind(&labelled, &milestoned));
+        assert!(!same_kind(&request("bob"), &labelled));
+        assert!(!same_kind(&opened, &opened));
+    }
+
+    #[test]
+    fn batches_are_sent_per_pull_request_in_order_of_appearance() {
+        let mut labelled = on_pull_request(request("bob"), 7);
+        labelled.action = Action::LabelUpdated;
+        let mut milestoned = on_pull_request(request("bob"), 7);
+        milestoned.action = Action::Milestoned;
+
+        let merged = merge_batch(vec![
+            on_pull_request(request("bob"), 42),
+            labelled,
+            on_pull_request(request("carol"), 42),
+            milestoned,
+        ]);
+
+        assert_eq!(
+            merged
+                .iter()
+                .map(|x| x.pull_request.number)
+                .collect::<Vec<_>>(),
+            vec![42, 7]
+        );
+        assert_eq!(reviewers(&merged[0]), vec!["bob", "carol"]);
+        /* The latest metadata event stands for the rest, being summarised from the PR's state */
+        assert!(matches!(merged[1].action, Action::Milestoned));
+    }
+
+    #[test]
+    fn merged_review_requests_list_each_reviewer_once() {
+        let mut batch = request("bob");
+        merge(&mut batch, request("carol"));
+        merge(&mut batch, request("bob"));
+
+        assert_eq!(reviewers(&batch), vec!["bob", "carol"]);
+    }
+}
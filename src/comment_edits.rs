@@ -0,0 +1,73 @@
+use crate::db;
+use crate::gitea_webhooks::{self, Action, Comment, Webhook};
+use sqlx::postgres::PgPool;
+
+/// Remembers who a new comment mentions, and turns an edit to one into a comment pinging only
+/// whoever the edit newly mentions, e.g. someone forgotten the first time round. Mentions the
+/// edit removes are left be. Returns whether there's still anything to notify about.
+pub async fn track(webhook: &mut Webhook, db: &PgPool) -> bool {
+    let (comment, previous_body) = match &mut webhook.action {
+        Action::Created { comment } => {
+            let _ = claim_mentions(comment.id, &comment.body, db).await;
+            return true;
+        }
+        Action::Edited { comment, changes } => match comment.take() {
+            Some(comment) => (comment, changes.body.take()),
+            None => return true,
+        },
+        _ => return true,
+    };
+
+    /* Without an id there's no telling who was already pinged */
+    if comment.id == 0 {
+        return false;
+    }
+
+    /* For comments from before mentions were recorded, or whose creation was missed, the body
+     * before the edit says who was already pinged. Otherwise it's already recorded. */
+    if let Some(previous_body) = previous_body {
+        if claim_mentions(comment.id, &previous_body.from, db)
+            .await
+            .is_none()
+        {
+            return false;
+        }
+    }
+
+    match claim_mentions(comment.id, &comment.body, db).await {
+        Some(new_mentions) if !new_mentions.is_empty() => {
+            webhook.action = Action::Created {
+                comment: Comment {
+                    new_mentions: Some(new_mentions),
+                    ..comment
+                },
+            };
+            true
+        }
+        /* Better to miss a new mention than re-ping everyone already mentioned */
+        _ => false,
+    }
+}
+
+/// Records the mentions in a comment's body, returning those not already recorded
+async fn claim_mentions(comment_id: i64, body: &str, db: &PgPool) -> Option<Vec<String>> {
+    if comment_id == 0 {
+        return None;
+    }
+
+    let usernames = gitea_webhooks::mentioned_usernames(body)
+        .into_iter()
+        .map(str::to_lowercase)
+        .collect::<Vec<String>>();
+    if usernames.is_empty() {
+        return Some(usernames);
+    }
+
+    match db::claim_comment_mentions(db, comment_id, &usernames).await {
+        Ok(claimed) => Some(claimed),
+        Err(x) => {
+            tracing::error!("Error attempting to record comment mentions: \"{}\"", x);
+            None
+        }
+    }
+}
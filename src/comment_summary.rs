@@ -0,0 +1,169 @@
+use crate::batch::{Batcher, Triggers};
+use crate::config::config;
+use crate::gitea_webhooks::{self, Action, User, Webhook};
+use crate::recent::{self, Delivery, Outcome};
+use crate::{comment_edits, db, sequence, slack};
+use axum::Extension;
+use slack_morphism::prelude::*;
+use sqlx::postgres::PgPool;
+use std::time::Duration;
+
+static BATCHER: Batcher<Webhook> = Batcher::new();
+
+/// Holds comments on PRs in repos summarising them, so a busy discussion produces one
+/// summary per window rather than a ping per mention. Returns the webhook if it should be
+/// handled now.
+pub fn hold(webhook: Webhook, db: &PgPool) -> Option<Webhook> {
+    let repo_config = config().repo(&webhook.repository);
+    let window = repo_config.comment_summary_secs;
+    if window == 0
+        || !matches!(webhook.action, Action::Created { .. })
+        || repo_config.ignores_sender(&webhook.sender.username)
+    {
+        return Some(webhook);
+    }
+
+    let triggers = Triggers {
+        window: Duration::from_secs(window),
+        max_items: 0,
+        urgent: false,
+    };
+    let db = db.clone();
+    let url = webhook.pull_request.url.to_string();
+
+    /* Never full nor urgent, so it's only ever sent once the window passes */
+    let _ = BATCHER.add(&url, webhook, triggers, |batch| async move {
+        summarise(batch, &db).await
+    });
+
+    None
+}
+
+/// Posts the comments as they would've been had there only been the one, and otherwise a
+/// summary of them pinging whoever they mentioned that no earlier summary of the PR already had
+async fn summarise(mut batch: Vec<Webhook>, db: &PgPool) {
+    if batch.len() == 1 {
+        let webhook = batch.remove(0);
+        crate::post_repo_payload(webhook, Extension(db.clone())).await;
+        return;
+    }
+    let Some(url) = batch.last().map(|x| x.pull_request.url.to_string()) else {
+        return;
+    };
+
+    let _sequenced = sequence::lock(&url).await;
+
+    let (last_summarised, already_mentioned) = match db::fetch_comment_summary(db, &url).await {
+        Ok(summary) => summary.unwrap_or_default(),
+        Err(x) => {
+            tracing::error!(
+                "Error attempting to retrieve the last comment summary: \"{}\"",
+                x
+            );
+            Default::default()
+        }
+    };
+
+    /* e.g. redelivered after being summarised already */
+    batch.retain(|webhook| comment_id(webhook).is_none_or(|id| id == 0 || id > last_summarised));
+
+    /* So a later edit to any of them only pings whoever it newly mentions */
+    for webhook in &mut batch {
+        comment_edits::track(webhook, db).await;
+    }
+    let Some(latest) = batch.last() else {
+        return;
+    };
+
+    let mut mentioned = Vec::<User>::new();
+    let mut comments = 0;
+    for webhook in &batch {
+        /* e.g. a Slack reply mirrored back to the PR isn't a new comment to anyone */
+        let Some(recipients) = webhook.recipients().await else {
+            continue;
+        };
+        comments += 1;
+
+        for recipient in recipients {
+            if !mentioned.iter().any(|x| x.username == recipient.username) {
+                mentioned.push(recipient);
+            }
+        }
+    }
+
+    let repo_config = config().repo(&latest.repository);
+    let mut mentions = Vec::new();
+    let mut pinged = 0;
+    for user in &mentioned {
+        /* Named rather than pinged, they've already been told about the discussion */
+        if already_mentioned.contains(&user.username) {
+            mentions.push(user.username.clone());
+            continue;
+        }
+
+        match slack::resolve_user(user, repo_config.resolve_by_name).await {
+            Some(slack_user) => {
+                mentions.push(slack_user.id.to_slack_format());
+                pinged += 1;
+            }
+            None if repo_config.post_unresolved_comments => mentions.push(user.username.clone()),
+            None => {}
+        }
+    }
+
+    /* As with a single comment, one pinging nobody isn't worth posting */
+    if comments == 0 || pinged == 0 && !repo_config.post_unresolved_comments {
+        recent::record(Delivery::new(latest, Outcome::Skipped("nobody to notify")));
+        return;
+    }
+
+    let link = match &latest.action {
+        Action::Created { comment } => comment.url.clone(),
+        _ => None,
+    };
+    let message = gitea_webhooks::render_comment_summary(
+        &latest.pull_request,
+        comments,
+        &mentions,
+        link.as_ref(),
+    );
+
+    let thread = match db::fetch_thread(db, latest.pull_request.url.as_str()).await {
+        Ok(thread) => thread,
+        Err(x) => {
+            tracing::error!(
+                "Error attempting to retrieve possible timestamp from DB: \"{}\"",
+                x
+            );
+            None
+        }
+    };
+
+    let outcome = match slack::post_reply(message, thread.as_ref()).await {
+        Ok(_) => {
+            tracing::info!(comments, "Comments summarised");
+            let last_comment_id = batch
+                .iter()
+                .filter_map(comment_id)
+                .max()
+                .unwrap_or_default();
+            let usernames = mentioned
+                .iter()
+                .map(|user| user.username.clone())
+                .collect::<Vec<_>>();
+            if let Err(x) = db::store_comment_summary(db, &url, last_comment_id, &usernames).await {
+                tracing::error!("Error attempting to record the comment summary: \"{}\"", x);
+            }
+            Outcome::Posted
+        }
+        Err(x) => Outcome::Failed(x.to_string()),
+    };
+    recent::record(Delivery::new(latest, outcome));
+}
+
+fn comment_id(webhook: &Webhook) -> Option<i64> {
+    match &webhook.action {
+        Action::Created { comment } => Some(comment.id),
+        _ => None,
+    }
+}
@@ -0,0 +1,905 @@
+use crate::gitea_webhooks::{Repository, ReviewKind};
+use chrono::format::{Item, StrftimeItems};
+use chrono::{NaiveTime, Weekday};
+use chrono_tz::Tz;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::HashMap;
+use std::sync::OnceLock;
+use strum::Display;
+use toml::Table;
+
+const DEFAULT_CONFIG_FILE: &str = "./config.toml";
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+/// Options which may be overridden per repository.
+///
+/// Top level values in the config file act as the defaults, and any table under
+/// `[repos."owner/name"]` is deep-merged over them for that repository.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct RepoConfig {
+    pub review_reminder: ReviewReminderConfig,
+    /// Render notifications as a single line, e.g. "🔀 merged: acme/widgets#42 by alice", for
+    /// channels preferring density over detail
+    pub compact: bool,
+    /// Include the head/base branches and head SHA in opened notifications
+    pub show_branches: bool,
+    /// Globs of base branches whose PRs are flagged for extra care when opened, e.g.
+    /// `["main", "release/*"]`
+    pub sensitive_branches: Vec<String>,
+    /// Flag PRs from forks for extra care when opened, rather than only noting where they're
+    /// from
+    pub flag_forks: bool,
+    /// Labels which escalate opened/review-requested notifications with a broadcast
+    pub broadcast_labels: HashMap<String, Broadcast>,
+    /// Extra links rendered beneath every notification, see [`FooterLink`]
+    pub footer_links: Vec<FooterLink>,
+    /// What to do when a PR author is requested to review their own PR
+    pub self_review_request: SelfReviewRequest,
+    /// Reminders of what a review should cover, e.g. `["Tests", "Docs", "Security"]`, listed
+    /// beneath review requests. None by default.
+    pub review_checklist: Vec<String>,
+    /// Keep a live summary of the PR as the root of its thread, with events replying beneath it
+    pub live_status: bool,
+    /// Say who last did something to the PR, and when, in its live status
+    pub show_last_update: bool,
+    /// Review types which also nudge the author at the top level when replying in a thread
+    pub escalate_reviews: Vec<ReviewKind>,
+    pub merge_conflicts: MergeConflictConfig,
+    /// Slack emoji names (without colons) to react with, keyed on the Gitea action, e.g.
+    /// `merged = "rocket"`. Actions without an entry get no reaction.
+    pub reactions: HashMap<String, String>,
+    /// Hold opened and review-requested notifications until CI passes on the PR's head
+    pub wait_for_ci: bool,
+    /// Link the CI runs on the PR's head, and how they're doing, in opened and review
+    /// notifications
+    pub show_ci_status: bool,
+    /// Actions, e.g. `review_requested`, where a recipient that can't be resolved to a Slack
+    /// user fails the webhook with a 500 so Gitea redelivers it, rather than being dropped
+    pub critical_actions: Vec<String>,
+    /// As a last resort, email the recipients of a critical action's notification when it can't
+    /// be posted to Slack at all. Sent through the HTTP mail relay in `EMAIL_WEBHOOK_URL`,
+    /// see [`crate::email`].
+    pub email_escalation: bool,
+    /// When a recipient's email isn't found in Slack, try matching their Gitea full name
+    /// against Slack display names. Fuzzy, so only unambiguous matches are used.
+    pub resolve_by_name: bool,
+    pub quiet_hours: QuietHoursConfig,
+    /// Title prefixes, e.g. `"WIP:"`, marking a PR as a draft. Matched case-insensitively,
+    /// drafts aren't announced until an edit removes the prefix.
+    pub draft_title_prefixes: Vec<String>,
+    /// How long to collect comments on a PR for before posting them as one summary in its
+    /// thread, pinging everyone they mentioned, rather than a message per comment. Zero posts
+    /// each as it arrives.
+    pub comment_summary_secs: u64,
+    /// Still post comment notifications when none of the mentioned users resolve to a Slack
+    /// user, naming them by username as other actions do. Comments mentioning nobody are
+    /// always dropped.
+    pub post_unresolved_comments: bool,
+    /// Mention whoever did what's being notified about, rather than only naming them by
+    /// username. Off by default as it can notify them of their own actions.
+    pub mention_sender: bool,
+    /// Show how long ago the PR was opened and last updated in review-request and comment
+    /// notifications
+    pub show_age: bool,
+    pub timestamps: TimestampConfig,
+    /// Extra channels to also post to, keyed on the Gitea action, e.g.
+    /// `merged = ["#releases"]`. Always posted standalone rather than into a thread.
+    pub feed_channels: HashMap<String, Vec<String>>,
+    /// Say why a reviewer was requested, e.g. as a code owner, or which review round it is,
+    /// when the payload includes it
+    pub show_review_reason: bool,
+    /// DM review requests to the reviewers rather than posting in the channel, falling back to
+    /// the channel for any reviewer who can't be resolved to a Slack user or DMed
+    pub dm_review_requests: bool,
+    /// How long to wait for more review requests on a PR before sending them as one message,
+    /// e.g. the webhook per member Gitea sends when a team is requested. Zero sends each as it
+    /// arrives.
+    pub coalesce_review_requests_secs: u64,
+    /// How long after pinging a reviewer to review a PR that they aren't pinged about it again,
+    /// e.g. when re-requested after every push. Zero pings them every time.
+    pub review_request_cooldown_secs: u64,
+    /// What happens to a re-request within the cooldown instead
+    pub rerequest_within_cooldown: RerequestWithinCooldown,
+    /// Events about anything older than this, by the comment's or PR's last update, are
+    /// dropped, e.g. the historical flood after a repo is imported or migrated. Zero notifies
+    /// about events however old.
+    pub max_event_age_secs: u64,
+    /// How long to wait for more label and milestone changes on a PR before summarising them
+    /// in one message. Zero sends a summary per change.
+    pub coalesce_metadata_secs: u64,
+    /// How the two kinds of coalescing above group events, and what sends them early
+    pub batching: BatchingConfig,
+    /// Channels to post a PR's notifications to instead, keyed on globs of the files it
+    /// changes, e.g. `"services/billing/**" = "#billing"`, for monorepos. PRs matching none go
+    /// to the usual channel. Decided when the PR's thread starts, which later events reply in
+    /// wherever it was, and not applied with `live_status`.
+    pub path_channels: HashMap<String, String>,
+    pub path_routing: PathRouting,
+    /// Where PRs matching none of the `path_channels` are posted, named from the payload
+    pub channel_template: ChannelTemplate,
+    /// Globs, e.g. `"docs/**"`, for files whose changes alone aren't worth notifying about.
+    /// Opened and now-mergeable notifications are dropped if every changed file matches.
+    pub ignore_paths: Vec<String>,
+    /// How to tell the author their PR was closed without being merged
+    pub closed_without_merge: ClosedWithoutMerge,
+    /// Post replies in a PR's Slack thread back to the PR as comments, which needs the Slack
+    /// app's event subscriptions pointed at `/slack/events`
+    pub mirror_slack_replies: bool,
+    /// Emoji reviewers react to a review request with to acknowledge it, e.g. `"eyes"`, shown
+    /// in the live status. Needs `reaction_added` and `reaction_removed` event subscriptions.
+    pub acknowledge_reaction: Option<String>,
+    /// Also comment on the PR when a reviewer acknowledges its review request
+    pub comment_acknowledgements: bool,
+    /// Longest excerpt, in characters, quoted from comments and rejections. 200 if unset.
+    pub excerpt_len: Option<usize>,
+    /// How reviews are described, keyed on the review type, e.g.
+    /// `rejected = "requested changes on"`. Defaults to "approved", "rejected" and
+    /// "commented on".
+    pub review_wording: HashMap<ReviewKind, String>,
+    pub ci_failures: CiFailureConfig,
+    /// Also notify a PR's author, requested reviewers and earlier commenters of new comments,
+    /// not only those mentioned. Noisier, so off by default.
+    pub notify_participants: bool,
+    /// Reply in a PR's thread when its branch is deleted, e.g. automatically after merging
+    pub announce_branch_deletions: bool,
+    pub packages: PackagesConfig,
+    /// What to do about pushes rewriting a PR's history, e.g. a rebase
+    pub force_pushes: ForcePushes,
+    pub ready_to_merge: ReadyToMergeConfig,
+    /// Ask reviewers whose review was dismissed to review again, rather than only noting it
+    pub reping_dismissed_reviewers: bool,
+    /// Post e.g. "PR was frobnicated" for actions from a newer Gitea this doesn't know of yet,
+    /// rather than only logging them
+    pub notify_unknown_actions: bool,
+    /// Gitea usernames, e.g. bots like `"renovate"`, whose events are never notified about.
+    /// Like any list, setting it for a repository replaces the top level one, so a repository
+    /// can e.g. set `ignore_senders = []` to hear from every bot.
+    pub ignore_senders: Vec<String>,
+    /// Usernames ignored as well as `ignore_senders`, for a repository to add to the top level
+    /// list rather than replace it
+    pub also_ignore_senders: Vec<String>,
+}
+
+impl RepoConfig {
+    pub fn ignores_sender(&self, username: &str) -> bool {
+        self.ignore_senders
+            .iter()
+            .chain(&self.also_ignore_senders)
+            .any(|ignored| ignored.eq_ignore_ascii_case(username))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ReadyToMergeConfig {
+    /// Tell authors once the approval their PR was waiting on lands
+    pub enabled: bool,
+    /// Approvals needed when the base branch's protection doesn't require any, or isn't
+    /// protected at all
+    pub unprotected_approvals: usize,
+}
+
+impl Default for ReadyToMergeConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            unprotected_approvals: 1,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct CiFailureConfig {
+    /// Tell authors when CI fails on their PR's head, once per failing commit
+    pub enabled: bool,
+    /// How long to let statuses for a failing commit settle before notifying
+    pub debounce_secs: u64,
+}
+
+impl Default for CiFailureConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            debounce_secs: 60,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct MergeConflictConfig {
+    /// Notify authors of open PRs that a merge into their base branch left conflicting
+    pub enabled: bool,
+    /// How long to give Gitea to re-check mergeability after a merge
+    pub delay_secs: u64,
+    /// Upper bound on the open PRs targeting the merged branch checked after each merge
+    pub max_pull_requests: usize,
+}
+
+impl Default for MergeConflictConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            delay_secs: 30,
+            max_pull_requests: 20,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SelfReviewRequest {
+    /// Drop the author from the notification entirely
+    Suppress,
+    /// Point out the request was likely a mistake instead of pinging the author
+    #[default]
+    Note,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct BatchingConfig {
+    pub key: BatchKey,
+    /// Sends a batch as soon as it holds this many events, zero for no limit
+    pub max_events: usize,
+    /// Batched actions, e.g. `"review_requested"`, sent as soon as they arrive along with
+    /// anything already waiting in their batch
+    pub urgent_actions: Vec<String>,
+}
+
+/// What events are batched together by. Events for the same PR are still merged into one
+/// message, a wider key only holds them back together.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BatchKey {
+    #[default]
+    PullRequest,
+    Repository,
+    /// Everything posted to the same channel, whichever repository it's from, following the
+    /// config of the repository whose event started the batch
+    Channel,
+}
+
+/// Where a PR matching several of the `path_channels` is posted
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PathRouting {
+    /// The channel matching the most of its files
+    #[default]
+    Dominant,
+    /// Every channel it matches, threaded in the first by name and standalone in the rest
+    All,
+}
+
+/// Names the channel a PR's notifications go to from its payload, e.g. `"pr-{repo_name}"`, for
+/// channels named by convention rather than mapped one by one. `{owner}`, `{repo_name}`,
+/// `{base_branch}` and `{first_path_segment}`, the top-level directory every changed file is
+/// in, are filled in, lowercased. As with `path_channels`, decided when the PR's thread starts
+/// and not applied with `live_status`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct ChannelTemplate {
+    pub template: Option<String>,
+    /// Where to post when the template can't be filled in or doesn't make a valid channel
+    /// name, the usual channel if unset
+    pub fallback: Option<String>,
+    /// Join the channel if the bot isn't in it, or create it if there isn't one, which needs
+    /// `channels:read`, `channels:join` and `channels:manage`
+    pub auto_create: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Deanonymise {
+    /// For every user, in case Gitea hides their email
+    #[default]
+    Always,
+    /// Only for users whose email looks like a noreply placeholder
+    Anonymised,
+    /// Never, for instances that send real emails anyway
+    Never,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RerequestWithinCooldown {
+    /// Don't notify at all
+    #[default]
+    Suppress,
+    /// Note it in the PR's thread without pinging anyone
+    Note,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ClosedWithoutMerge {
+    /// Don't notify at all
+    Suppress,
+    /// Say it was closed without pinging the author
+    Note,
+    /// Ping the author that it was closed
+    #[default]
+    Ping,
+}
+
+/// A named link where `{repo}`, `{owner}` and `{name}` in the url are replaced with the
+/// repository's full name, owner and name respectively
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FooterLink {
+    pub name: String,
+    pub url: String,
+}
+
+/// Ordered by reach so the widest broadcast wins when several labels match
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Broadcast {
+    Here,
+    Channel,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct QuietHoursConfig {
+    /// Hold non-urgent notifications during quiet hours, sending them once they end
+    pub enabled: bool,
+    /// Local time quiet hours start, e.g. `"19:00"`
+    #[serde(
+        deserialize_with = "deserialize_time",
+        serialize_with = "serialize_time"
+    )]
+    pub start: NaiveTime,
+    /// Local time quiet hours end, which may be on the following day
+    #[serde(
+        deserialize_with = "deserialize_time",
+        serialize_with = "serialize_time"
+    )]
+    pub end: NaiveTime,
+    /// IANA timezone the times are in, e.g. `"Europe/London"`
+    pub timezone: Tz,
+    /// Days quiet hours start on, e.g. `["fri", "sat", "sun"]`. Every day if empty.
+    pub days: Vec<Weekday>,
+    /// Labels marking a PR as urgent, whose notifications are sent regardless
+    pub urgent_labels: Vec<String>,
+}
+
+impl Default for QuietHoursConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            start: NaiveTime::from_hms_opt(19, 0, 0).expect("19:00 should be a valid time"),
+            end: NaiveTime::from_hms_opt(8, 0, 0).expect("08:00 should be a valid time"),
+            timezone: Tz::UTC,
+            days: Vec::new(),
+            urgent_labels: vec!["urgent".to_string()],
+        }
+    }
+}
+
+fn serialize_time<S>(time: &NaiveTime, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.serialize_str(&time.format("%H:%M").to_string())
+}
+
+fn deserialize_time<'de, D>(deserializer: D) -> Result<NaiveTime, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let time = String::deserialize(deserializer)?;
+    NaiveTime::parse_from_str(&time, "%H:%M").map_err(serde::de::Error::custom)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ForcePushes {
+    /// Treat them like any other push
+    #[default]
+    Ignore,
+    /// Drop notifications for those only rebasing, without adding any commits, unless it left
+    /// the PR mergeable again
+    SuppressRebases,
+    /// Warn whoever's already reviewed that their review may be out of date
+    Highlight,
+}
+
+/// Notifications of package versions published to Gitea's registry. Packages not linked to a
+/// repository follow the top level config.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct PackagesConfig {
+    pub enabled: bool,
+    /// Channel to post them to instead of the usual one, e.g. `"#releases"`
+    pub channel: Option<String>,
+}
+
+/// How times are written in notifications, e.g. how long ago a PR was opened
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct TimestampConfig {
+    pub style: TimestampStyle,
+    /// IANA timezone absolute times are given in, e.g. `"Europe/London"`
+    pub timezone: Tz,
+    /// `strftime` format of absolute times, to suit the local conventions, e.g.
+    /// `"%m/%d/%Y %-I:%M %p %Z"` for US style dates
+    #[serde(deserialize_with = "deserialize_time_format")]
+    pub format: String,
+}
+
+impl Default for TimestampConfig {
+    fn default() -> Self {
+        Self {
+            style: TimestampStyle::Relative,
+            timezone: Tz::UTC,
+            format: "%-d %b %Y %H:%M %Z".to_string(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TimestampStyle {
+    /// e.g. "3 hours ago"
+    #[default]
+    Relative,
+    /// e.g. "on 12 Mar 2026 07:12 UTC"
+    Absolute,
+}
+
+/// Rejects formats chrono can't render up front, rather than panicking once it first tries to
+fn deserialize_time_format<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let format = String::deserialize(deserializer)?;
+    if StrftimeItems::new(&format).any(|item| item == Item::Error) {
+        return Err(serde::de::Error::custom(format!(
+            "\"{}\" isn't a valid strftime format",
+            format
+        )));
+    }
+    Ok(format)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ReviewReminderConfig {
+    pub enabled: bool,
+    /// How long a requested reviewer has to review before being nudged
+    pub delay_secs: u64,
+    /// Upper bound on the nudges sent for a single review request
+    pub max_reminders: u32,
+    /// Steps taken while a review request goes unanswered, in order, replacing the reminders
+    /// above when set. They're stored in the DB so survive restarts.
+    pub ladder: Vec<EscalationStep>,
+    /// Emails of the leads pinged by a `lead` step, keyed on the reviewer's Gitea username
+    pub leads: HashMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct EscalationStep {
+    /// From the review being requested, not the previous step
+    pub after_secs: u64,
+    pub action: Escalation,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Escalation {
+    /// Ping the reviewer again
+    Remind,
+    /// Ping the reviewer's lead, or the channel if they don't have one
+    Lead,
+    /// Ping the channel with `@here`
+    Channel,
+    /// Note in the thread that the PR has gone stale, ending the ladder
+    Stale,
+}
+
+impl Default for ReviewReminderConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            delay_secs: 24 * 60 * 60,
+            max_reminders: 1,
+            ladder: vec![],
+            leads: HashMap::new(),
+        }
+    }
+}
+
+/// The kind of Slack token in `SLACK_API_TOKEN`, which changes who messages are posted as
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Display)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum SlackTokenType {
+    Bot,
+    User,
+}
+
+/// Where notifications are sent
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Notifier {
+    #[default]
+    Slack,
+    /// An incoming webhook in `TEAMS_WEBHOOK_URL`, as Adaptive Cards. Only notifications are
+    /// sent, threads, live status and reminders are Slack only.
+    Teams,
+}
+
+/// Who messages appear to be posted by, needing a bot token with `chat:write.customize`.
+/// Slack's defaults for the app are used for anything unset.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct BotIdentity {
+    pub username: Option<String>,
+    /// e.g. `":gitea:"`, which takes precedence over `icon_url`
+    pub icon_emoji: Option<String>,
+    pub icon_url: Option<String>,
+}
+
+impl BotIdentity {
+    pub fn is_set(&self) -> bool {
+        self.username.is_some() || self.icon_emoji.is_some() || self.icon_url.is_some()
+    }
+}
+
+/// Extra headers sent with outbound requests, e.g. `X-API-Gateway-Key` for a gateway in front
+/// of the API. Checked at startup. Only Gitea's requests can carry them, as the Slack client
+/// has no way to add headers.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct OutboundHeaders {
+    /// Sent with every request to Gitea's API, alongside its token
+    pub gitea: HashMap<String, String>,
+}
+
+/// What to do when a channel can't be posted to, e.g. because it was archived, rather than
+/// losing the notification
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct ChannelFallback {
+    /// Join public channels the bot isn't in, which needs `channels:join`
+    pub auto_join: bool,
+    /// Where to post instead, noting where the notification was meant for
+    pub channel: Option<String>,
+}
+
+/// Threads everything posted to the channel under one root message a day, so the channel
+/// itself only gets that one message. Needs `channels:history` to find the day's root again
+/// after a restart.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct DailyThreadConfig {
+    pub enabled: bool,
+    /// IANA timezone whose midnight starts a new day, e.g. `"Europe/London"`
+    pub timezone: Tz,
+    /// Post a PR's later events under the current day's root, rather than in the thread of
+    /// the day it was opened
+    pub flatten: bool,
+}
+
+impl Default for DailyThreadConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            timezone: Tz::UTC,
+            flatten: false,
+        }
+    }
+}
+
+/// When to raise the alarm about Gitea rejecting `GITEA_API_TOKEN`, e.g. once it's expired
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct GiteaAuthAlert {
+    /// Rejections in a row before alerting, once per run of them
+    pub threshold: usize,
+    /// Also posts the alert to this Slack channel, only logged by default
+    pub channel: Option<String>,
+}
+
+impl Default for GiteaAuthAlert {
+    fn default() -> Self {
+        Self {
+            threshold: 3,
+            channel: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct QueueConfig {
+    /// Webhooks received but not yet handled, beyond which Gitea is answered with a 503 so it
+    /// retries later
+    pub capacity: usize,
+    /// How many webhooks are handled at once
+    pub workers: usize,
+    /// How many of the workers a single repository's webhooks may take up at once, so a burst
+    /// from one doesn't hold up the rest. Half the workers if unset.
+    pub per_repository: Option<usize>,
+}
+
+impl QueueConfig {
+    pub fn per_repository(&self) -> usize {
+        self.per_repository.unwrap_or(self.workers / 2).max(1)
+    }
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 256,
+            workers: 4,
+            per_repository: None,
+        }
+    }
+}
+
+/// e.g. a compliance footer like "Automated notification, do not reply", in Slack mrkdwn
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct MessageNotes {
+    pub prefix: String,
+    pub suffix: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct Config {
+    pub notifier: Notifier,
+    /// Checked against the token at startup, inferred from the token if unset
+    pub slack_token_type: Option<SlackTokenType>,
+    /// Entra ID (formerly AAD) object ids to mention Teams users by, keyed on Gitea email or
+    /// username
+    pub teams_users: HashMap<String, String>,
+    /// Text added before and after every message sent, nothing by default
+    pub message_notes: MessageNotes,
+    pub queue: QueueConfig,
+    /// Overrides the name and icon messages are posted under, the app's own by default
+    pub bot_identity: BotIdentity,
+    pub gitea_auth_alert: GiteaAuthAlert,
+    pub daily_thread: DailyThreadConfig,
+    pub channel_fallback: ChannelFallback,
+    pub outbound_headers: OutboundHeaders,
+    /// Log every webhook as deserialised and who its recipients resolved to, at debug level,
+    /// with emails, users' names and credential-like fields redacted. For troubleshooting, too
+    /// noisy for production.
+    pub verbose_logging: bool,
+    /// When to look up users' real emails from Gitea rather than trusting the ones in payloads
+    pub deanonymise: Deanonymise,
+    #[serde(flatten)]
+    pub defaults: RepoConfig,
+    #[serde(skip)]
+    repos: HashMap<String, RepoConfig>,
+    /// Repository ids given by `id = ...` under `[repos."owner/name"]`, so overrides survive
+    /// the repository being renamed or transferred
+    #[serde(skip)]
+    repo_ids: HashMap<i64, String>,
+}
+
+impl Config {
+    /// Whether the defaults or any repository's overrides satisfy `predicate`
+    pub fn any_repo(&self, predicate: impl Fn(&RepoConfig) -> bool) -> bool {
+        predicate(&self.defaults) || self.repos.values().any(predicate)
+    }
+
+    pub fn repo(&self, repository: &Repository) -> &RepoConfig {
+        self.repo_ids
+            .get(&repository.id)
+            .and_then(|name| self.repos.get(name))
+            .unwrap_or_else(|| self.repo_named(&repository.full_name))
+    }
+
+    /// The top level config, for events that aren't about any repository
+    pub fn defaults(&self) -> &RepoConfig {
+        &self.defaults
+    }
+
+    /// Looks up a repository by name alone, for when there's no payload to take its id from
+    pub fn repo_named(&self, full_name: &str) -> &RepoConfig {
+        self.repos.get(full_name).unwrap_or(&self.defaults)
+    }
+
+    /// The config in effect, with each repository's overrides merged in and outbound header
+    /// values blanked out, as they're usually credentials
+    pub fn export(&self) -> Result<serde_json::Value, serde_json::Error> {
+        let mut exported = serde_json::to_value(self)?;
+
+        let mut repos = serde_json::Map::new();
+        for (name, repo_config) in &self.repos {
+            let mut repo = serde_json::to_value(repo_config)?;
+            if let Some(id) = self.repo_ids.iter().find(|(_, x)| *x == name) {
+                repo["id"] = (*id.0).into();
+            }
+            repos.insert(name.clone(), repo);
+        }
+        exported["repos"] = repos.into();
+
+        if let Some(headers) = exported["outbound_headers"]["gitea"].as_object_mut() {
+            for value in headers.values_mut() {
+                *value = "***".into();
+            }
+        }
+
+        Ok(exported)
+    }
+
+    fn from_table(mut table: Table) -> Result<Self, anyhow::Error> {
+        let repo_tables = match table.remove("repos") {
+            Some(toml::Value::Table(repos)) => repos,
+            Some(_) => anyhow::bail!("`repos` must be a table keyed by repository full name"),
+            None => Table::new(),
+        };
+
+        let mut config: Config = table.clone().try_into()?;
+
+        for (name, overrides) in repo_tables {
+            let toml::Value::Table(mut overrides) = overrides else {
+                anyhow::bail!("Overrides for repository \"{}\" must be a table", name);
+            };
+
+            match overrides.remove("id") {
+                Some(toml::Value::Integer(id)) => {
+                    config.repo_ids.insert(id, name.clone());
+                }
+                Some(_) => anyhow::bail!("`id` for repository \"{}\" must be an integer", name),
+                None => {}
+            }
+
+            let mut merged = table.clone();
+            merge_tables(&mut merged, overrides);
+            config.repos.insert(name, merged.try_into()?);
+        }
+
+        Ok(config)
+    }
+}
+
+fn merge_tables(base: &mut Table, overlay: Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(overlay)) => {
+                merge_tables(base, overlay)
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+pub fn load() -> Result<Config, anyhow::Error> {
+    let path = std::env::var("CONFIG_FILE")
+        .ok()
+        .filter(|path| !path.is_empty())
+        .unwrap_or(DEFAULT_CONFIG_FILE.to_string());
+
+    match std::fs::read_to_string(&path) {
+        Ok(contents) => Config::from_table(contents.parse()?),
+        Err(x) if x.kind() == std::io::ErrorKind::NotFound => {
+            tracing::info!("No config file found at \"{}\", using defaults", path);
+            Ok(Config::default())
+        }
+        Err(x) => Err(x.into()),
+    }
+}
+
+pub fn config() -> &'static Config {
+    CONFIG.get_or_init(|| {
+        /* Keep tests independent of whatever config file is lying around */
+        if cfg!(test) {
+            return Config::default();
+        }
+
+        load().expect("Failed to load config file")
+    })
+}
+
+pub fn config_env_var(name: &str) -> Result<String, anyhow::Error> {
+    Ok(std::env::var(name)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ignored_senders_are_replaced_or_added_to() {
+        let config = Config::from_table(
+            r#"
+            ignore_senders = ["renovate", "ci-bot"]
+
+            [repos."acme/deps"]
+            ignore_senders = ["ci-bot"]
+
+            [repos."acme/widgets"]
+            also_ignore_senders = ["translations-bot"]
+            "#
+            .parse()
+            .unwrap(),
+        )
+        .unwrap();
+
+        assert!(config.repo_named("acme/other").ignores_sender("Renovate"));
+        assert!(!config.repo_named("acme/deps").ignores_sender("renovate"));
+        assert!(config.repo_named("acme/deps").ignores_sender("ci-bot"));
+        assert!(config.repo_named("acme/widgets").ignores_sender("renovate"));
+        assert!(config
+            .repo_named("acme/widgets")
+            .ignores_sender("translations-bot"));
+        assert!(!config.repo_named("acme/widgets").ignores_sender("alice"));
+    }
+
+    #[test]
+    fn exported_config_merges_repos_and_blanks_headers() {
+        let config = Config::from_table(
+            r#"
+            show_age = true
+
+            [outbound_headers.gitea]
+            X-API-Gateway-Key = "hunter2"
+
+            [repos."acme/widgets"]
+            id = 7
+            compact = true
+            "#
+            .parse()
+            .unwrap(),
+        )
+        .unwrap();
+
+        let exported = config.export().unwrap();
+        assert_eq!(exported["show_age"], true);
+        assert_eq!(
+            exported["outbound_headers"]["gitea"]["X-API-Gateway-Key"],
+            "***"
+        );
+
+        let widgets = &exported["repos"]["acme/widgets"];
+        assert_eq!(widgets["id"], 7);
+        assert_eq!(widgets["compact"], true);
+        assert_eq!(widgets["show_age"], true);
+        assert_eq!(widgets["quiet_hours"]["start"], "19:00");
+    }
+
+    #[test]
+    fn escalation_ladders_are_kept_by_repos_overriding_reminders() {
+        let config = Config::from_table(
+            r#"
+            [review_reminder]
+            enabled = true
+            ladder = [
+                { after_secs = 86400, action = "remind" },
+                { after_secs = 172800, action = "lead" },
+                { after_secs = 604800, action = "stale" },
+            ]
+            leads = { alice = "lead@example.com" }
+
+            [repos."acme/widgets".review_reminder]
+            enabled = false
+            "#
+            .parse()
+            .unwrap(),
+        )
+        .unwrap();
+
+        let widgets = &config.repo_named("acme/widgets").review_reminder;
+        assert!(!widgets.enabled);
+        assert_eq!(
+            widgets.ladder.iter().map(|x| x.action).collect::<Vec<_>>(),
+            vec![Escalation::Remind, Escalation::Lead, Escalation::Stale]
+        );
+        assert_eq!(widgets.ladder[1].after_secs, 172800);
+        assert_eq!(
+            widgets.leads.get("alice").map(String::as_str),
+            Some("lead@example.com")
+        );
+    }
+}
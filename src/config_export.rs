@@ -0,0 +1,82 @@
+use crate::config::config;
+use crate::resend::is_admin;
+use crate::secret;
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde_json::json;
+
+/// Environment variables shown as they're set
+const SETTINGS: [&str; 20] = [
+    "CONFIG_FILE",
+    "LISTEN_ADDR",
+    "BIND_ADDRESS",
+    "WEBHOOK_PATH",
+    "MAX_BODY_BYTES",
+    "SLACK_CHANNEL",
+    "ENVIRONMENT",
+    "ENVIRONMENT_SLACK_CHANNEL",
+    "GITEA_URL",
+    "GITEA_CA_CERT",
+    "DANGER_ACCEPT_INVALID_CERTS",
+    "EMAIL_ALIASES_FILE",
+    "OTEL_EXPORTER_OTLP_ENDPOINT",
+    "SECRETS_BACKEND",
+    "VAULT_ADDR",
+    "VAULT_SECRET_PATH",
+    "VAULT_REFRESH_SECS",
+    "POSTGRES_DB",
+    "LOG_DIR",
+    "LOG_SUFFIX",
+];
+
+/// Credentials, only shown as whether they're set, from whichever secrets backend
+const SECRETS: [&str; 8] = [
+    "SLACK_API_TOKEN",
+    "SLACK_SIGNING_SECRET",
+    "GITEA_API_TOKEN",
+    "ADMIN_TOKEN",
+    "TEAMS_WEBHOOK_URL",
+    "EMAIL_WEBHOOK_URL",
+    "POSTGRES_PASSWORD",
+    "VAULT_TOKEN",
+];
+
+/// The config in effect, both the file's merged over the defaults and the environment, as JSON
+/// with credentials blanked out. Requires `ADMIN_TOKEN`, as for the recent deliveries page.
+pub async fn config_handler(headers: HeaderMap) -> Response {
+    if !is_admin(&headers) {
+        return (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, "Basic realm=\"gitea-notif\"")],
+        )
+            .into_response();
+    }
+
+    let exported = match config().export() {
+        Ok(exported) => exported,
+        Err(x) => {
+            tracing::error!("Error attempting to export config: \"{}\"", x);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    Json(json!({
+        "config": exported,
+        "environment": environment(),
+    }))
+    .into_response()
+}
+
+fn environment() -> serde_json::Value {
+    let mut environment = serde_json::Map::new();
+    for name in SETTINGS {
+        environment.insert(name.to_string(), std::env::var(name).ok().into());
+    }
+    for name in SECRETS {
+        let set = secret::read(name).is_ok_and(|x| !x.expose().is_empty());
+        environment.insert(name.to_string(), set.then_some("***").into());
+    }
+
+    environment.into()
+}
@@ -0,0 +1,93 @@
+use crate::config::config;
+use crate::gitea_webhooks::{self, PullRequest, Webhook};
+use crate::{db, gitea_api, slack};
+use sqlx::postgres::PgPool;
+use std::time::Duration;
+use tracing::Instrument;
+
+/// After a merge, checks the open PRs targeting the same base branch and notifies the authors
+/// of any that the merge left conflicting
+pub fn check_after_merge(webhook: &Webhook, db: &PgPool) {
+    let conflict_config = &config().repo(&webhook.repository).merge_conflicts;
+
+    let Some(base) = webhook.pull_request.base.as_ref() else {
+        return;
+    };
+
+    if !conflict_config.enabled {
+        return;
+    }
+
+    let repository_url = webhook.repository.url.clone();
+    let repository = webhook.repository.full_name.clone();
+    let base = base.name.clone();
+    let merged_link = gitea_webhooks::format_pull_request_url(&webhook.pull_request);
+    let delay = Duration::from_secs(conflict_config.delay_secs);
+    let limit = conflict_config.max_pull_requests;
+    let db = db.clone();
+
+    tokio::spawn(
+        async move {
+            /* Gitea re-checks mergeability in the background, so give it a chance to catch up */
+            tokio::time::sleep(delay).await;
+
+            let Ok(pull_requests) = gitea_api::fetch_open_pull_requests(
+                &repository_url,
+                &repository,
+                Some(&base),
+                limit,
+            )
+            .await
+            else {
+                return;
+            };
+
+            for pull_request in &pull_requests {
+                if became_conflicting(pull_request, &db).await {
+                    notify_author(pull_request, &merged_link, &base, &db).await;
+                }
+            }
+        }
+        .in_current_span(),
+    );
+}
+
+async fn became_conflicting(pull_request: &PullRequest, db: &PgPool) -> bool {
+    let Some(mergeable) = pull_request.mergeable else {
+        return false;
+    };
+
+    match db::swap_mergeable(db, pull_request.url.as_str(), mergeable).await {
+        /* A PR never seen before was presumably fine until this merge */
+        Ok(previous) => previous != Some(false) && !mergeable,
+        Err(x) => {
+            tracing::error!(
+                "Error attempting to update mergeable state in DB: \"{}\"",
+                x
+            );
+            false
+        }
+    }
+}
+
+async fn notify_author(pull_request: &PullRequest, merged_link: &str, base: &str, db: &PgPool) {
+    let url = &pull_request.url;
+    let user = slack::mention_gitea_user(url, &pull_request.user).await;
+
+    let thread = match db::fetch_thread(db, url.as_str()).await {
+        Ok(thread) => thread,
+        Err(x) => {
+            tracing::error!(
+                "Error attempting to retrieve possible timestamp from DB: \"{}\"",
+                x
+            );
+            None
+        }
+    };
+
+    let message = gitea_webhooks::render_merge_conflict(&user, pull_request, merged_link, base);
+
+    if slack::post_reply(message, thread.as_ref()).await.is_ok() {
+        tracing::info!("Merge conflict notification sent");
+    }
+}
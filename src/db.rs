@@ -0,0 +1,857 @@
+use crate::gitea_webhooks::{Metadata, Webhook};
+use crate::status::PullRequestStatus;
+use slack_morphism::prelude::*;
+use sqlx::postgres::PgPool;
+
+pub struct Thread {
+    pub ts: SlackTs,
+    /* Threads created before the channel was recorded won't have one */
+    pub channel: Option<SlackChannelId>,
+}
+
+/// Brings a database created by an older `init.sql` up to date, which only runs when the
+/// database is first created
+pub async fn upgrade(db: &PgPool) -> Result<(), sqlx::Error> {
+    sqlx::query("ALTER TABLE threads ADD COLUMN IF NOT EXISTS channel varchar")
+        .execute(db)
+        .await?;
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS review_request_messages (
+            url varchar,
+            ts varchar,
+            channel varchar,
+            reviewers varchar[] NOT NULL DEFAULT '{}',
+            acknowledged varchar[] NOT NULL DEFAULT '{}'
+        )",
+    )
+    .execute(db)
+    .await?;
+    sqlx::query(
+        "ALTER TABLE review_request_messages
+            ADD COLUMN IF NOT EXISTS reviewers varchar[] NOT NULL DEFAULT '{}',
+            ADD COLUMN IF NOT EXISTS acknowledged varchar[] NOT NULL DEFAULT '{}'",
+    )
+    .execute(db)
+    .await?;
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS comment_summaries (
+            url varchar PRIMARY KEY,
+            last_comment_id bigint NOT NULL,
+            mentioned varchar[] NOT NULL
+        )",
+    )
+    .execute(db)
+    .await?;
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS pull_requests (
+            url varchar PRIMARY KEY,
+            mergeable boolean,
+            status text,
+            metadata text,
+            head varchar,
+            head_sha varchar,
+            commits integer
+        )",
+    )
+    .execute(db)
+    .await?;
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS deferred_webhooks (
+            id serial PRIMARY KEY,
+            reason varchar NOT NULL,
+            key varchar NOT NULL,
+            url varchar NOT NULL,
+            payload text NOT NULL,
+            failed boolean NOT NULL DEFAULT false
+        )",
+    )
+    .execute(db)
+    .await?;
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS repositories (
+            id bigint PRIMARY KEY,
+            url varchar NOT NULL
+        )",
+    )
+    .execute(db)
+    .await?;
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS review_request_pings (
+            url varchar,
+            reviewer varchar,
+            pinged_at timestamptz NOT NULL,
+            PRIMARY KEY (url, reviewer)
+        )",
+    )
+    .execute(db)
+    .await?;
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS review_escalations (
+            url varchar,
+            reviewer varchar,
+            reviewer_email varchar NOT NULL,
+            repository varchar NOT NULL,
+            pull_request_link varchar NOT NULL,
+            step integer NOT NULL,
+            requested_at timestamptz NOT NULL,
+            due_at timestamptz NOT NULL,
+            PRIMARY KEY (url, reviewer)
+        )",
+    )
+    .execute(db)
+    .await?;
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS comment_mentions (
+            comment_id bigint,
+            username varchar,
+            PRIMARY KEY (comment_id, username)
+        )",
+    )
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn fetch_thread(db: &PgPool, url: &str) -> Result<Option<Thread>, sqlx::Error> {
+    let row: Option<(String, Option<String>)> =
+        sqlx::query_as("SELECT ts, channel FROM threads WHERE url = $1")
+            .bind(url)
+            .fetch_optional(db)
+            .await?;
+
+    Ok(row.map(|row| Thread {
+        ts: SlackTs::new(row.0),
+        channel: row.1.map(SlackChannelId::new),
+    }))
+}
+
+/// Finds the PR a Slack thread was started for
+pub async fn fetch_thread_url(
+    db: &PgPool,
+    ts: &SlackTs,
+    channel: &SlackChannelId,
+) -> Result<Option<String>, sqlx::Error> {
+    let row: Option<(String,)> = sqlx::query_as(
+        "SELECT url FROM threads WHERE ts = $1 AND (channel = $2 OR channel IS NULL)",
+    )
+    .bind(&ts.0)
+    .bind(&channel.0)
+    .fetch_optional(db)
+    .await?;
+
+    Ok(row.map(|row| row.0))
+}
+
+pub async fn insert_thread(
+    db: &PgPool,
+    url: &str,
+    ts: &SlackTs,
+    channel: &SlackChannelId,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("INSERT INTO threads (url, ts, channel) VALUES ($1, $2, $3)")
+        .bind(url)
+        .bind(&ts.0)
+        .bind(&channel.0)
+        .execute(db)
+        .await?;
+
+    Ok(())
+}
+
+/// Remembers a posted review request, and the Slack users it asked, so reactions to it can be
+/// traced back to its PR
+pub async fn insert_review_request_message(
+    db: &PgPool,
+    url: &str,
+    ts: &SlackTs,
+    channel: &SlackChannelId,
+    reviewers: &[SlackUserId],
+) -> Result<(), sqlx::Error> {
+    let reviewers = reviewers.iter().map(|x| x.0.clone()).collect::<Vec<_>>();
+    sqlx::query(
+        "INSERT INTO review_request_messages (url, ts, channel, reviewers) VALUES ($1, $2, $3, $4)",
+    )
+    .bind(url)
+    .bind(&ts.0)
+    .bind(&channel.0)
+    .bind(reviewers)
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+/// Finds the PR a review request was posted for, and the Slack users it asked
+pub async fn fetch_review_request(
+    db: &PgPool,
+    ts: &SlackTs,
+    channel: &SlackChannelId,
+) -> Result<Option<(String, Vec<SlackUserId>)>, sqlx::Error> {
+    let row: Option<(String, Vec<String>)> = sqlx::query_as(
+        "SELECT url, reviewers FROM review_request_messages WHERE ts = $1 AND channel = $2",
+    )
+    .bind(&ts.0)
+    .bind(&channel.0)
+    .fetch_optional(db)
+    .await?;
+
+    Ok(row.map(|(url, reviewers)| (url, reviewers.into_iter().map(SlackUserId).collect())))
+}
+
+/// Records a reviewer acknowledging a review request, returning whether it's the first time
+/// they have
+pub async fn claim_acknowledgement(
+    db: &PgPool,
+    ts: &SlackTs,
+    channel: &SlackChannelId,
+    reviewer: &SlackUserId,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query(
+        "UPDATE review_request_messages SET acknowledged = array_append(acknowledged, $3)
+            WHERE ts = $1 AND channel = $2 AND NOT $3 = ANY(acknowledged)",
+    )
+    .bind(&ts.0)
+    .bind(&channel.0)
+    .bind(&reviewer.0)
+    .execute(db)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Points a PR at a new thread root, for when the old one can no longer be used
+pub async fn replace_thread(
+    db: &PgPool,
+    url: &str,
+    ts: &SlackTs,
+    channel: &SlackChannelId,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE threads SET ts = $2, channel = $3 WHERE url = $1")
+        .bind(url)
+        .bind(&ts.0)
+        .bind(&channel.0)
+        .execute(db)
+        .await?;
+
+    Ok(())
+}
+
+/// Which of the reviewers were pinged about the PR within the cooldown
+pub async fn fetch_review_request_pings(
+    db: &PgPool,
+    url: &str,
+    reviewers: &[String],
+    cooldown_secs: u64,
+) -> Result<Vec<String>, sqlx::Error> {
+    let pinged: Vec<(String,)> = sqlx::query_as(
+        "SELECT reviewer FROM review_request_pings
+            WHERE url = $1 AND reviewer = ANY($2) AND pinged_at >= now() - $3 * interval '1 second'",
+    )
+    .bind(url)
+    .bind(reviewers)
+    .bind(cooldown_secs as i64)
+    .fetch_all(db)
+    .await?;
+
+    Ok(pinged.into_iter().map(|row| row.0).collect())
+}
+
+/// Records the reviewers as just pinged about the PR, once it's been delivered
+pub async fn record_review_request_pings(
+    db: &PgPool,
+    url: &str,
+    reviewers: &[String],
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO review_request_pings (url, reviewer, pinged_at)
+            SELECT $1, unnest($2::varchar[]), now()
+            ON CONFLICT (url, reviewer) DO UPDATE SET pinged_at = EXCLUDED.pinged_at",
+    )
+    .bind(url)
+    .bind(reviewers)
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+/// Records the usernames a comment mentions, returning those it hadn't already
+pub async fn claim_comment_mentions(
+    db: &PgPool,
+    comment_id: i64,
+    usernames: &[String],
+) -> Result<Vec<String>, sqlx::Error> {
+    let claimed: Vec<(String,)> = sqlx::query_as(
+        "INSERT INTO comment_mentions (comment_id, username) SELECT $1, unnest($2::varchar[])
+            ON CONFLICT DO NOTHING
+            RETURNING username",
+    )
+    .bind(comment_id)
+    .bind(usernames)
+    .fetch_all(db)
+    .await?;
+
+    Ok(claimed.into_iter().map(|(username,)| username).collect())
+}
+
+/// The last comment summarised on a PR, and the usernames of everyone its summaries mentioned
+pub async fn fetch_comment_summary(
+    db: &PgPool,
+    url: &str,
+) -> Result<Option<(i64, Vec<String>)>, sqlx::Error> {
+    sqlx::query_as("SELECT last_comment_id, mentioned FROM comment_summaries WHERE url = $1")
+        .bind(url)
+        .fetch_optional(db)
+        .await
+}
+
+/// Records a summary having been posted, adding whoever it mentioned to the earlier ones'
+pub async fn store_comment_summary(
+    db: &PgPool,
+    url: &str,
+    last_comment_id: i64,
+    mentioned: &[String],
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO comment_summaries (url, last_comment_id, mentioned) VALUES ($1, $2, $3)
+            ON CONFLICT (url) DO UPDATE SET
+                last_comment_id = GREATEST(comment_summaries.last_comment_id, EXCLUDED.last_comment_id),
+                mentioned = ARRAY(
+                    SELECT DISTINCT unnest(comment_summaries.mentioned || EXCLUDED.mentioned)
+                )",
+    )
+    .bind(url)
+    .bind(last_comment_id)
+    .bind(mentioned)
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+/// Starts, or restarts, escalating a review request, with the first step due after
+/// `after_secs`
+pub async fn schedule_escalation(
+    db: &PgPool,
+    escalation: &PendingEscalation,
+    after_secs: u64,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO review_escalations
+            (url, reviewer, reviewer_email, repository, pull_request_link, step, requested_at, due_at)
+            VALUES ($1, $2, $3, $4, $5, 0, now(), now() + $6 * interval '1 second')
+            ON CONFLICT (url, reviewer) DO UPDATE SET
+                reviewer_email = EXCLUDED.reviewer_email,
+                repository = EXCLUDED.repository,
+                pull_request_link = EXCLUDED.pull_request_link,
+                step = 0,
+                requested_at = EXCLUDED.requested_at,
+                due_at = EXCLUDED.due_at",
+    )
+    .bind(&escalation.url)
+    .bind(&escalation.reviewer)
+    .bind(&escalation.reviewer_email)
+    .bind(&escalation.repository)
+    .bind(&escalation.pull_request_link)
+    .bind(after_secs as i64)
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+/// Stops escalating a review request, or every one on the PR if `reviewer` is `None`
+pub async fn cancel_escalations(
+    db: &PgPool,
+    url: &str,
+    reviewer: Option<&str>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "DELETE FROM review_escalations WHERE url = $1 AND ($2::varchar IS NULL OR reviewer = $2)",
+    )
+    .bind(url)
+    .bind(reviewer)
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+/// Returns the escalations whose next step is due, leasing them for `lease_secs` so another
+/// instance won't take them too, and so they're retried should this one die mid-step
+pub async fn claim_due_escalations(
+    db: &PgPool,
+    lease_secs: u64,
+) -> Result<Vec<PendingEscalation>, sqlx::Error> {
+    let rows: Vec<(String, String, String, String, String, i32)> = sqlx::query_as(
+        "UPDATE review_escalations SET due_at = now() + $1 * interval '1 second'
+            WHERE due_at <= now()
+            RETURNING url, reviewer, reviewer_email, repository, pull_request_link, step",
+    )
+    .bind(lease_secs as i64)
+    .fetch_all(db)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(
+            |(url, reviewer, reviewer_email, repository, pull_request_link, step)| {
+                PendingEscalation {
+                    url,
+                    reviewer,
+                    reviewer_email,
+                    repository,
+                    pull_request_link,
+                    step: step as usize,
+                }
+            },
+        )
+        .collect())
+}
+
+/// Moves an escalation on from the step it was claimed at, to the next step due `after_secs`
+/// from the review being requested, or ends it if there are no more steps. Does nothing if
+/// the request has since been re-requested or cancelled.
+pub async fn advance_escalation(
+    db: &PgPool,
+    escalation: &PendingEscalation,
+    after_secs: Option<u64>,
+) -> Result<(), sqlx::Error> {
+    let query = match after_secs {
+        Some(after_secs) => sqlx::query(
+            "UPDATE review_escalations
+                SET step = step + 1, due_at = requested_at + $4 * interval '1 second'
+                WHERE url = $1 AND reviewer = $2 AND step = $3",
+        )
+        .bind(&escalation.url)
+        .bind(&escalation.reviewer)
+        .bind(escalation.step as i32)
+        .bind(after_secs as i64),
+        None => sqlx::query(
+            "DELETE FROM review_escalations WHERE url = $1 AND reviewer = $2 AND step = $3",
+        )
+        .bind(&escalation.url)
+        .bind(&escalation.reviewer)
+        .bind(escalation.step as i32),
+    };
+    query.execute(db).await?;
+
+    Ok(())
+}
+
+pub async fn fetch_mergeable(db: &PgPool, url: &str) -> Result<Option<bool>, sqlx::Error> {
+    let row: Option<(Option<bool>,)> =
+        sqlx::query_as("SELECT mergeable FROM pull_requests WHERE url = $1")
+            .bind(url)
+            .fetch_optional(db)
+            .await?;
+
+    Ok(row.and_then(|row| row.0))
+}
+
+/// Stores the latest `mergeable` state for a PR, returning the previously stored state
+pub async fn swap_mergeable(
+    db: &PgPool,
+    url: &str,
+    mergeable: bool,
+) -> Result<Option<bool>, sqlx::Error> {
+    ensure_pull_request(db, url).await?;
+
+    let previous: Option<(Option<bool>,)> = sqlx::query_as(
+        "WITH previous AS (SELECT url, mergeable FROM pull_requests WHERE url = $1 FOR UPDATE)
+            UPDATE pull_requests SET mergeable = $2 FROM previous
+            WHERE pull_requests.url = previous.url
+            RETURNING previous.mergeable",
+    )
+    .bind(url)
+    .bind(mergeable)
+    .fetch_optional(db)
+    .await?;
+
+    Ok(previous.and_then(|row| row.0))
+}
+
+/// Stores the SHA of a PR's head and how many commits it has, returning those previously
+/// stored
+pub async fn swap_head_commits(
+    db: &PgPool,
+    url: &str,
+    sha: &str,
+    commits: usize,
+) -> Result<Option<(String, usize)>, sqlx::Error> {
+    ensure_pull_request(db, url).await?;
+
+    let previous: Option<(Option<String>, Option<i32>)> = sqlx::query_as(
+        "WITH previous AS (SELECT url, head_sha, commits FROM pull_requests WHERE url = $1 FOR UPDATE)
+            UPDATE pull_requests SET head_sha = $2, commits = $3 FROM previous
+            WHERE pull_requests.url = previous.url
+            RETURNING previous.head_sha, previous.commits",
+    )
+    .bind(url)
+    .bind(sha)
+    .bind(commits as i32)
+    .fetch_optional(db)
+    .await?;
+
+    Ok(previous.and_then(|row| match row {
+        (Some(sha), Some(commits)) => Some((sha, commits as usize)),
+        _ => None,
+    }))
+}
+
+/// Adds a row for the PR if it hasn't one, so swapping its values only has to update it. The
+/// updates lock the row, so concurrent swaps each see the value the other left.
+async fn ensure_pull_request(db: &PgPool, url: &str) -> Result<(), sqlx::Error> {
+    sqlx::query("INSERT INTO pull_requests (url) VALUES ($1) ON CONFLICT (url) DO NOTHING")
+        .bind(url)
+        .execute(db)
+        .await?;
+
+    Ok(())
+}
+
+/// Records the branch a PR is from, taking it off any earlier PR in the repository from the
+/// same branch so a deletion is only ever matched to the latest
+pub async fn store_head(
+    db: &PgPool,
+    repository_url: &str,
+    url: &str,
+    head: &str,
+) -> Result<(), sqlx::Error> {
+    let mut transaction = db.begin().await?;
+
+    sqlx::query(
+        "UPDATE pull_requests SET head = NULL
+            WHERE head = $3 AND url <> $2 AND starts_with(url, $1 || '/')",
+    )
+    .bind(repository_url)
+    .bind(url)
+    .bind(head)
+    .execute(&mut *transaction)
+    .await?;
+
+    sqlx::query(
+        "INSERT INTO pull_requests (url, head) VALUES ($1, $2)
+            ON CONFLICT (url) DO UPDATE SET head = EXCLUDED.head",
+    )
+    .bind(url)
+    .bind(head)
+    .execute(&mut *transaction)
+    .await?;
+
+    transaction.commit().await
+}
+
+/// Finds the latest PR in a repository from the given branch
+pub async fn fetch_pull_request_url_by_head(
+    db: &PgPool,
+    repository_url: &str,
+    head: &str,
+) -> Result<Option<String>, sqlx::Error> {
+    let row: Option<(String,)> = sqlx::query_as(
+        "SELECT url FROM pull_requests WHERE head = $2 AND starts_with(url, $1 || '/')",
+    )
+    .bind(repository_url)
+    .bind(head)
+    .fetch_optional(db)
+    .await?;
+
+    Ok(row.map(|row| row.0))
+}
+
+/// Stores a PR's labels and milestone, returning what was previously stored
+pub async fn swap_metadata(
+    db: &PgPool,
+    url: &str,
+    metadata: &Metadata,
+) -> Result<Option<Metadata>, anyhow::Error> {
+    ensure_pull_request(db, url).await?;
+
+    let previous: Option<(Option<String>,)> = sqlx::query_as(
+        "WITH previous AS (SELECT url, metadata FROM pull_requests WHERE url = $1 FOR UPDATE)
+            UPDATE pull_requests SET metadata = $2 FROM previous
+            WHERE pull_requests.url = previous.url
+            RETURNING previous.metadata",
+    )
+    .bind(url)
+    .bind(serde_json::to_string(metadata)?)
+    .fetch_optional(db)
+    .await?;
+
+    match previous.and_then(|row| row.0) {
+        Some(previous) => Ok(Some(serde_json::from_str(&previous)?)),
+        None => Ok(None),
+    }
+}
+
+pub async fn fetch_status(
+    db: &PgPool,
+    url: &str,
+) -> Result<Option<PullRequestStatus>, anyhow::Error> {
+    let row: Option<(Option<String>,)> =
+        sqlx::query_as("SELECT status FROM pull_requests WHERE url = $1")
+            .bind(url)
+            .fetch_optional(db)
+            .await?;
+
+    match row.and_then(|row| row.0) {
+        Some(status) => Ok(Some(serde_json::from_str(&status)?)),
+        None => Ok(None),
+    }
+}
+
+pub async fn store_status(
+    db: &PgPool,
+    url: &str,
+    status: &PullRequestStatus,
+) -> Result<(), anyhow::Error> {
+    sqlx::query(
+        "INSERT INTO pull_requests (url, status) VALUES ($1, $2)
+            ON CONFLICT (url) DO UPDATE SET status = EXCLUDED.status",
+    )
+    .bind(url)
+    .bind(serde_json::to_string(status)?)
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+/// Stores the latest URL for a repository, returning the previously stored URL
+pub async fn swap_repository_url(
+    db: &PgPool,
+    id: i64,
+    url: &str,
+) -> Result<Option<String>, sqlx::Error> {
+    let previous: Option<(String,)> = sqlx::query_as("SELECT url FROM repositories WHERE id = $1")
+        .bind(id)
+        .fetch_optional(db)
+        .await?;
+
+    sqlx::query(
+        "INSERT INTO repositories (id, url) VALUES ($1, $2)
+            ON CONFLICT (id) DO UPDATE SET url = EXCLUDED.url",
+    )
+    .bind(id)
+    .bind(url)
+    .execute(db)
+    .await?;
+
+    Ok(previous.map(|row| row.0))
+}
+
+/// Moves everything stored against PRs under `old_url` to `new_url`, after a rename or transfer
+pub async fn move_repository(db: &PgPool, old_url: &str, new_url: &str) -> Result<(), sqlx::Error> {
+    let mut transaction = db.begin().await?;
+
+    for table in [
+        "threads",
+        "pull_requests",
+        "deferred_webhooks",
+        "comment_summaries",
+    ] {
+        sqlx::query(&format!(
+            "UPDATE {table} SET url = $2 || substr(url, length($1) + 1)
+                WHERE starts_with(url, $1 || '/')"
+        ))
+        .bind(old_url)
+        .bind(new_url)
+        .execute(&mut *transaction)
+        .await?;
+    }
+
+    transaction.commit().await
+}
+
+/// A review request being escalated, and the step it's on
+#[derive(Debug, Clone)]
+pub struct PendingEscalation {
+    pub url: String,
+    pub reviewer: String,
+    pub reviewer_email: String,
+    pub repository: String,
+    pub pull_request_link: String,
+    pub step: usize,
+}
+
+pub struct DeferredWebhook {
+    pub id: i32,
+    pub payload: String,
+}
+
+impl DeferredWebhook {
+    pub fn decode(&self) -> Option<Webhook> {
+        match serde_json::from_str(&self.payload) {
+            Ok(webhook) => Some(webhook),
+            Err(x) => {
+                tracing::error!("Error decoding deferred webhook \"{}\"", x);
+                None
+            }
+        }
+    }
+}
+
+pub async fn insert_deferred_webhook(
+    db: &PgPool,
+    reason: &str,
+    key: &str,
+    url: &str,
+    payload: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO deferred_webhooks (reason, key, url, payload) VALUES ($1, $2, $3, $4)",
+    )
+    .bind(reason)
+    .bind(key)
+    .bind(url)
+    .bind(payload)
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+/// Removes and returns the webhooks deferred under `key`, oldest first
+pub async fn take_deferred_webhooks(
+    db: &PgPool,
+    reason: &str,
+    key: &str,
+) -> Result<Vec<DeferredWebhook>, sqlx::Error> {
+    let mut rows: Vec<(i32, String)> = sqlx::query_as(
+        "DELETE FROM deferred_webhooks WHERE reason = $1 AND key = $2 RETURNING id, payload",
+    )
+    .bind(reason)
+    .bind(key)
+    .fetch_all(db)
+    .await?;
+
+    rows.sort_by_key(|row| row.0);
+
+    Ok(rows
+        .into_iter()
+        .map(|(id, payload)| DeferredWebhook { id, payload })
+        .collect())
+}
+
+/// Flags the webhooks deferred under `key` as failed, returning only those not already flagged
+pub async fn fail_deferred_webhooks(
+    db: &PgPool,
+    reason: &str,
+    key: &str,
+) -> Result<Vec<DeferredWebhook>, sqlx::Error> {
+    let rows: Vec<(i32, String)> = sqlx::query_as(
+        "UPDATE deferred_webhooks SET failed = true
+            WHERE reason = $1 AND key = $2 AND NOT failed RETURNING id, payload",
+    )
+    .bind(reason)
+    .bind(key)
+    .fetch_all(db)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|(id, payload)| DeferredWebhook { id, payload })
+        .collect())
+}
+
+/// Every webhook deferred for `reason`, oldest first, left in place
+pub async fn fetch_deferred_webhooks(
+    db: &PgPool,
+    reason: &str,
+) -> Result<Vec<DeferredWebhook>, sqlx::Error> {
+    let rows: Vec<(i32, String)> =
+        sqlx::query_as("SELECT id, payload FROM deferred_webhooks WHERE reason = $1 ORDER BY id")
+            .bind(reason)
+            .fetch_all(db)
+            .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|(id, payload)| DeferredWebhook { id, payload })
+        .collect())
+}
+
+/// Removes a single deferred webhook, returning whether it was still there to remove
+pub async fn delete_deferred_webhook(db: &PgPool, id: i32) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query("DELETE FROM deferred_webhooks WHERE id = $1")
+        .bind(id)
+        .execute(db)
+        .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Moves the webhooks deferred for a PR over to a new key, clearing any failure
+pub async fn rekey_deferred_webhooks(
+    db: &PgPool,
+    reason: &str,
+    url: &str,
+    key: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "UPDATE deferred_webhooks SET key = $3, failed = false WHERE reason = $1 AND url = $2",
+    )
+    .bind(reason)
+    .bind(url)
+    .bind(key)
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::postgres::PgConnectOptions;
+    use sqlx::Executor;
+
+    /// The schema as of the first release, before there was anything to upgrade
+    const BASELINE: &str = "CREATE TABLE threads (url varchar, ts varchar)";
+
+    /// A pool on its own freshly made schema of the database at `DATABASE_URL`
+    async fn schema(name: &str) -> PgPool {
+        let url = std::env::var("DATABASE_URL").expect("DATABASE_URL should be set");
+        let db = PgPool::connect(&url).await.unwrap();
+        db.execute(format!("DROP SCHEMA IF EXISTS {name} CASCADE; CREATE SCHEMA {name}").as_str())
+            .await
+            .unwrap();
+
+        let options = url
+            .parse::<PgConnectOptions>()
+            .unwrap()
+            .options([("search_path", name)]);
+        PgPool::connect_with(options).await.unwrap()
+    }
+
+    async fn columns(db: &PgPool, schema: &str) -> Vec<(String, String, String, String, String)> {
+        sqlx::query_as(
+            "SELECT table_name::text, column_name::text, data_type::text, is_nullable::text,
+                 coalesce(replace(column_default, $1 || '.', ''), '')
+             FROM information_schema.columns WHERE table_schema = $1
+             ORDER BY table_name, column_name",
+        )
+        .bind(schema)
+        .fetch_all(db)
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Postgres database at DATABASE_URL"]
+    async fn upgrading_the_first_release_matches_a_new_database() {
+        let fresh = schema("fresh_schema").await;
+        fresh.execute(include_str!("../init.sql")).await.unwrap();
+
+        let upgraded = schema("upgraded_schema").await;
+        upgraded.execute(BASELINE).await.unwrap();
+        upgrade(&upgraded).await.unwrap();
+        /* Every startup upgrades, whether or not there's anything left to do */
+        upgrade(&upgraded).await.unwrap();
+
+        let expected = columns(&fresh, "fresh_schema").await;
+        assert!(!expected.is_empty());
+        assert_eq!(columns(&upgraded, "upgraded_schema").await, expected);
+    }
+}
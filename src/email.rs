@@ -0,0 +1,105 @@
+use crate::config::config;
+use crate::gitea_webhooks::Webhook;
+use crate::http;
+use crate::notification::Notification;
+use crate::secret;
+use serde::Serialize;
+use tracing::instrument;
+
+/// What's posted to the mail relay, which is expected to send `text` as a plain text email
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct Email {
+    pub to: Vec<String>,
+    pub subject: String,
+    pub text: String,
+}
+
+/// Emails the recipients of a notification that couldn't be posted to Slack, if it's critical
+/// and the repo escalates to email. Failures are only logged, there's nothing left to fall
+/// back to.
+#[instrument(skip_all)]
+pub async fn escalate(webhook: &Webhook) {
+    if !config().repo(&webhook.repository).email_escalation || !webhook.is_critical() {
+        return;
+    }
+
+    let Some(recipients) = webhook.recipients().await else {
+        return;
+    };
+    let Some(email) = render_email(&webhook.notification(recipients)) else {
+        tracing::debug!("Nobody to email");
+        return;
+    };
+
+    match post_email(&email).await {
+        Ok(()) => tracing::info!(recipients = email.to.len(), "Escalated to email"),
+        Err(x) => tracing::error!("Error attempting to escalate to email: \"{}\"", x),
+    }
+}
+
+/// `None` if none of the recipients have an email address
+pub fn render_email(notification: &Notification) -> Option<Email> {
+    let mut to = Vec::<String>::new();
+    for user in &notification.mentions {
+        if !user.email.is_empty() && !to.contains(&user.email) {
+            to.push(user.email.clone());
+        }
+    }
+    if to.is_empty() {
+        return None;
+    }
+
+    let mut text = format!("{}\n\n", notification.text);
+    if let Some(excerpt) = &notification.excerpt {
+        for line in excerpt.lines() {
+            text += &format!("> {}\n", line);
+        }
+        text += "\n";
+    }
+    text += notification.link.as_str();
+
+    Some(Email {
+        to,
+        subject: notification.title.clone(),
+        text,
+    })
+}
+
+async fn post_email(email: &Email) -> Result<(), anyhow::Error> {
+    /* Likely to carry the relay's credentials */
+    let url = secret::read("EMAIL_WEBHOOK_URL")?;
+
+    /* Verified as usual, Gitea's TLS settings only being meant for Gitea */
+    http::client()
+        .post(url.expose())
+        .json(email)
+        .send()
+        .await?
+        .error_for_status()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gitea_webhooks::Action;
+
+    #[test]
+    fn render_review_requested_email() {
+        let webhook: Webhook =
+            serde_json::from_str(include_str!("../tests/fixtures/review_requested.json"))
+                .expect("Fixture should deserialise into a Webhook");
+        let Action::ReviewRequested {
+            requested_reviewers,
+            ..
+        } = &webhook.action
+        else {
+            panic!("Fixture should be a review request");
+        };
+
+        insta::assert_json_snapshot!(render_email(
+            &webhook.notification(requested_reviewers.clone())
+        ));
+    }
+}
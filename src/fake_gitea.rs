@@ -0,0 +1,20 @@
+//! A stand-in for the Gitea API, so tests can exercise the code that calls it without a real
+//! instance. Each test builds a `Router` with just the endpoints it needs.
+
+use axum::Router;
+use url::Url;
+
+/// Serves `router` on a free local port, returning a PR URL on it for the API calls to use as
+/// their base
+pub async fn serve(router: Router) -> Url {
+    /* Every test uses the same made up token, so there's no harm in them racing to set it */
+    std::env::set_var("GITEA_API_TOKEN", "fake-gitea-token");
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("Should bind to a free port");
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, router).await });
+
+    Url::parse(&format!("http://{}/acme/widgets/pulls/42", address)).unwrap()
+}
@@ -0,0 +1,135 @@
+use crate::config::{config, ForcePushes};
+use crate::db;
+use crate::gitea_api;
+use crate::gitea_webhooks::{Action, User, Webhook};
+use sqlx::postgres::PgPool;
+
+/// Works out whether a synchronisation rewrote the PR's history, from whether the head it
+/// last saw is still among its commits, for repos which treat that differently. Returns
+/// whether there's still anything to notify about, having turned one worth highlighting into
+/// [`Action::ForcePushed`], which keeps whether the push also made the PR mergeable.
+pub async fn check(webhook: &mut Webhook, became_mergeable: bool, db: &PgPool) -> bool {
+    let force_pushes = config().repo(&webhook.repository).force_pushes;
+    if force_pushes == ForcePushes::Ignore || !matches!(webhook.action, Action::Synchronized) {
+        return true;
+    }
+    let Some(head) = &webhook.pull_request.head else {
+        return true;
+    };
+
+    let (url, repository, number) = (
+        &webhook.repository.url,
+        webhook.repository.full_name.as_str(),
+        webhook.pull_request.number,
+    );
+    let commits = match gitea_api::fetch_commits(url, repository, number).await {
+        Ok(commits) => commits,
+        Err(x) => {
+            tracing::warn!("Unable to fetch PR commits from Gitea: \"{}\"", x);
+            return true;
+        }
+    };
+
+    let previous = db::swap_head_commits(
+        db,
+        webhook.pull_request.url.as_str(),
+        &head.sha,
+        commits.len(),
+    )
+    .await;
+    let previous = match previous {
+        Ok(Some(previous)) => previous,
+        /* Nothing to compare the first push seen with */
+        Ok(None) => return true,
+        Err(x) => {
+            tracing::error!("Error attempting to store PR head in DB: \"{}\"", x);
+            return true;
+        }
+    };
+
+    let Some(rebase_only) = rewritten(&previous, &head.sha, &commits) else {
+        return true;
+    };
+
+    match force_pushes {
+        ForcePushes::SuppressRebases => !rebase_only,
+        ForcePushes::Highlight => {
+            webhook.action = Action::ForcePushed {
+                rebase_only,
+                reviewers: reviewers(webhook).await,
+                now_mergeable: became_mergeable,
+            };
+            true
+        }
+        ForcePushes::Ignore => true,
+    }
+}
+
+/// Whether going from the previous head and commit count to these rewrote history, and if so
+/// whether it only rebased, not adding any commits
+fn rewritten(
+    (previous_head, previous_commits): &(String, usize),
+    head: &str,
+    commits: &[String],
+) -> Option<bool> {
+    if previous_head == head || commits.contains(previous_head) {
+        return None;
+    }
+    Some(commits.len() <= *previous_commits)
+}
+
+/// Whoever approved or requested changes, and hasn't had it dismissed since
+async fn reviewers(webhook: &Webhook) -> Vec<User> {
+    let reviews = match gitea_api::fetch_reviews(
+        &webhook.repository.url,
+        &webhook.repository.full_name,
+        webhook.pull_request.number,
+    )
+    .await
+    {
+        Ok(reviews) => reviews,
+        Err(x) => {
+            tracing::warn!("Unable to fetch reviews from Gitea: \"{}\"", x);
+            return Vec::new();
+        }
+    };
+
+    let mut reviewers = Vec::<User>::new();
+    for review in reviews {
+        let Some(user) = review.user else {
+            continue;
+        };
+        let verdict = matches!(review.state.as_str(), "APPROVED" | "REQUEST_CHANGES");
+        if verdict
+            && !review.dismissed
+            && !webhook.is_author(&user)
+            && !reviewers.iter().any(|x| x.username == user.username)
+        {
+            reviewers.push(user);
+        }
+    }
+
+    reviewers
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rewritten_history_is_told_apart_from_new_commits() {
+        let commits = |shas: &[&str]| shas.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+        let previous = ("b".to_string(), 2);
+
+        assert_eq!(rewritten(&previous, "c", &commits(&["a", "b", "c"])), None);
+        assert_eq!(rewritten(&previous, "b", &commits(&["a", "b"])), None);
+        assert_eq!(
+            rewritten(&previous, "b2", &commits(&["a2", "b2"])),
+            Some(true)
+        );
+        assert_eq!(
+            rewritten(&previous, "c2", &commits(&["a2", "b2", "c2"])),
+            Some(false)
+        );
+    }
+}
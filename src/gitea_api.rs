@@ -0,0 +1,455 @@
+use crate::config::config;
+use crate::gitea_webhooks::{CombinedStatus, PullRequest, Repository, User};
+use crate::{http, secret, slack};
+use reqwest::header::{HeaderValue, AUTHORIZATION};
+use reqwest::{Method, RequestBuilder, Response, StatusCode};
+use slack_morphism::prelude::*;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use tracing::{instrument, Instrument};
+use url::Url;
+
+/// Consecutive responses from Gitea rejecting the API token, which would otherwise only show
+/// up as notifications quietly falling back to usernames
+static AUTH_FAILURES: AuthFailures = AuthFailures::new();
+
+struct AuthFailures {
+    consecutive: AtomicUsize,
+    /// Set once the current run of failures has been alerted on, so it's only alerted on once
+    alerted: AtomicBool,
+}
+
+impl AuthFailures {
+    const fn new() -> Self {
+        Self {
+            consecutive: AtomicUsize::new(0),
+            alerted: AtomicBool::new(false),
+        }
+    }
+
+    /// Counts a response to a request for `path`, returning how many auth failures there have
+    /// been in a row if this is the one to alert on. A 403 is only a rejection from `/user`,
+    /// anywhere else it's the token's user lacking permission, e.g. not being a repo admin.
+    fn observe(&self, status: StatusCode, path: &str, threshold: usize) -> Option<usize> {
+        let rejected = status == StatusCode::UNAUTHORIZED
+            || (status == StatusCode::FORBIDDEN && path.ends_with("/api/v1/user"));
+        if !rejected {
+            self.consecutive.store(0, Ordering::Relaxed);
+            if self.alerted.swap(false, Ordering::Relaxed) {
+                tracing::info!("Gitea is accepting the API token again");
+            }
+            return None;
+        }
+
+        let failures = self.consecutive.fetch_add(1, Ordering::Relaxed) + 1;
+        tracing::warn!(
+            gitea_auth_failures = failures,
+            "Gitea rejected the API token"
+        );
+
+        (failures >= threshold && !self.alerted.swap(true, Ordering::Relaxed)).then_some(failures)
+    }
+}
+
+/// Sends a request built by [`get`] or [`post`], watching for the token having stopped working
+async fn send(request: RequestBuilder) -> Result<Response, reqwest::Error> {
+    let res = request.send().await?;
+
+    let alert = &config().gitea_auth_alert;
+    if let Some(failures) = AUTH_FAILURES.observe(res.status(), res.url().path(), alert.threshold) {
+        tracing::error!(
+            gitea_auth_failures = failures,
+            "Gitea has rejected the API token {} times in a row, GITEA_API_TOKEN most likely \
+             needs replacing",
+            failures
+        );
+
+        if let Some(channel) = alert.channel.clone() {
+            tokio::spawn(
+                async move {
+                    let message = SlackMessageContent::new().with_text(format!(
+                        ":rotating_light: Gitea has rejected the API token {} times in a row, \
+                         so notifications can't mention people until `GITEA_API_TOKEN` is \
+                         replaced",
+                        failures
+                    ));
+                    if let Err(x) = slack::post_message_to(&channel, message, &None).await {
+                        tracing::error!("Error attempting to alert about Gitea auth \"{}\"", x);
+                    }
+                }
+                .in_current_span(),
+            );
+        }
+    }
+
+    Ok(res)
+}
+
+/// Builds an authenticated GET for `path` under the API of the Gitea instance hosting `url`
+fn get(url: &Url, path: &str) -> Result<RequestBuilder, anyhow::Error> {
+    request(Method::GET, url, path)
+}
+
+/// Builds an authenticated POST for `path`, as for [`get`]
+fn post(url: &Url, path: &str) -> Result<RequestBuilder, anyhow::Error> {
+    request(Method::POST, url, path)
+}
+
+fn request(method: Method, url: &Url, path: &str) -> Result<RequestBuilder, anyhow::Error> {
+    let token = secret::read("GITEA_API_TOKEN")?;
+
+    /* Setting the path is the easiest way to keep the scheme and host together but remove the path */
+    let mut url = url.clone();
+    url.set_path(format!("api/v1/{}", path).as_str());
+
+    /* Keeps the token out of the request's Debug output, should it ever be logged */
+    let mut authorization = HeaderValue::from_str(&format!("token {}", token.expose()))?;
+    authorization.set_sensitive(true);
+
+    Ok(http::gitea_client()
+        .request(method, url.as_str())
+        .headers(http::gitea_headers().clone())
+        .header(AUTHORIZATION, authorization))
+}
+
+/// The user the API token belongs to, which fails if the token isn't valid
+#[instrument(err)]
+pub async fn fetch_token_user(url: &Url) -> Result<User, anyhow::Error> {
+    let res = send(get(url, "user")?)
+        .await?
+        .error_for_status()?
+        .json::<User>()
+        .await?;
+
+    Ok(res)
+}
+
+#[instrument(err)]
+pub async fn fetch_user(url: &Url, username: &str) -> Result<User, anyhow::Error> {
+    let res = send(get(url, &format!("users/{}", username))?)
+        .await?
+        .json::<User>()
+        .await?;
+
+    Ok(res)
+}
+
+pub async fn fetch_user_email(url: &Url, username: &str) -> Result<String, anyhow::Error> {
+    Ok(fetch_user(url, username).await?.email)
+}
+
+/// Comments on a PR (or issue) as the API token's user
+#[instrument(err, skip(body))]
+pub async fn post_comment(
+    url: &Url,
+    repository: &str,
+    number: u64,
+    body: &str,
+) -> Result<(), anyhow::Error> {
+    send(
+        post(
+            url,
+            &format!("repos/{}/issues/{}/comments", repository, number),
+        )?
+        .json(&serde_json::json!({ "body": body })),
+    )
+    .await?
+    .error_for_status()?;
+
+    Ok(())
+}
+
+#[instrument(err)]
+pub async fn fetch_repository(url: &Url, repository: &str) -> Result<Repository, anyhow::Error> {
+    let res = send(get(url, &format!("repos/{}", repository))?)
+        .await?
+        .error_for_status()?
+        .json::<Repository>()
+        .await?;
+
+    Ok(res)
+}
+
+#[derive(serde::Deserialize)]
+struct PullRequestWithReviewers {
+    #[serde(flatten)]
+    pull_request: PullRequest,
+    #[serde(default)]
+    requested_reviewers: Vec<User>,
+}
+
+/// Fetches a PR along with the reviewers whose review is still requested
+#[instrument(err)]
+pub async fn fetch_pull_request(
+    url: &Url,
+    repository: &str,
+    number: u64,
+) -> Result<(PullRequest, Vec<User>), anyhow::Error> {
+    let res = send(get(url, &format!("repos/{}/pulls/{}", repository, number))?)
+        .await?
+        .error_for_status()?
+        .json::<PullRequestWithReviewers>()
+        .await?;
+
+    Ok((res.pull_request, res.requested_reviewers))
+}
+
+/// Gitea caps how many items a page can hold
+const MAX_PAGE_LIMIT: usize = 50;
+
+#[derive(serde::Deserialize)]
+struct IssueComment {
+    user: User,
+}
+
+/// Fetches everyone who has commented on a PR (or issue), in the order they first did
+#[instrument(err)]
+pub async fn fetch_commenters(
+    url: &Url,
+    repository: &str,
+    number: u64,
+) -> Result<Vec<User>, anyhow::Error> {
+    let res = send(get(
+        url,
+        &format!("repos/{}/issues/{}/comments", repository, number),
+    )?)
+    .await?
+    .error_for_status()?
+    .json::<Vec<IssueComment>>()
+    .await?;
+
+    let mut seen = std::collections::HashSet::new();
+    Ok(res
+        .into_iter()
+        .map(|comment| comment.user)
+        .filter(|user| seen.insert(user.username.clone()))
+        .collect())
+}
+
+/// A review as listed by the API, where `state` is e.g. `APPROVED` or `REQUEST_REVIEW`
+#[derive(serde::Deserialize, Debug)]
+pub struct PullReview {
+    /* Unset for reviews requested from a team */
+    pub user: Option<User>,
+    pub state: String,
+    /// Dismissed reviews no longer count towards the PR's approvals
+    #[serde(default)]
+    pub dismissed: bool,
+}
+
+/// Fetches a PR's reviews, oldest first, including the requests for them
+#[instrument(err)]
+pub async fn fetch_reviews(
+    url: &Url,
+    repository: &str,
+    number: u64,
+) -> Result<Vec<PullReview>, anyhow::Error> {
+    let res = send(
+        get(
+            url,
+            &format!("repos/{}/pulls/{}/reviews", repository, number),
+        )?
+        .query(&[("limit", MAX_PAGE_LIMIT)]),
+    )
+    .await?
+    .error_for_status()?
+    .json::<Vec<PullReview>>()
+    .await?;
+
+    Ok(res)
+}
+
+#[derive(serde::Deserialize)]
+struct Branch {
+    #[serde(default)]
+    protected: bool,
+    /* From whichever protection rule applies, named or glob */
+    #[serde(default)]
+    required_approvals: usize,
+}
+
+/// How many approvals the protection rule for `branch` requires, `None` if it isn't protected.
+/// Read from the branch rather than its repository's protection rules, which only admins can.
+#[instrument(err)]
+pub async fn fetch_required_approvals(
+    url: &Url,
+    repository: &str,
+    branch: &str,
+) -> Result<Option<usize>, anyhow::Error> {
+    let res = send(get(
+        url,
+        &format!("repos/{}/branches/{}", repository, branch),
+    )?)
+    .await?
+    .error_for_status()?
+    .json::<Branch>()
+    .await?;
+
+    Ok(res.protected.then_some(res.required_approvals))
+}
+
+#[derive(serde::Deserialize)]
+struct ChangedFile {
+    filename: String,
+}
+
+/// Fetches the paths of every file a PR changes
+#[instrument(err)]
+pub async fn fetch_changed_files(
+    url: &Url,
+    repository: &str,
+    number: u64,
+) -> Result<Vec<String>, anyhow::Error> {
+    let mut files = Vec::new();
+    for page in 1.. {
+        let res = send(
+            get(url, &format!("repos/{}/pulls/{}/files", repository, number))?
+                .query(&[("page", page), ("limit", MAX_PAGE_LIMIT)]),
+        )
+        .await?
+        .error_for_status()?
+        .json::<Vec<ChangedFile>>()
+        .await?;
+
+        let last_page = res.len() < MAX_PAGE_LIMIT;
+        files.extend(res.into_iter().map(|file| file.filename));
+        if last_page {
+            break;
+        }
+    }
+
+    Ok(files)
+}
+
+#[derive(serde::Deserialize)]
+struct PullCommit {
+    sha: String,
+}
+
+/// Fetches the SHAs of every commit on a PR, oldest first
+#[instrument(err)]
+pub async fn fetch_commits(
+    url: &Url,
+    repository: &str,
+    number: u64,
+) -> Result<Vec<String>, anyhow::Error> {
+    let mut commits = Vec::new();
+    for page in 1.. {
+        let res = send(
+            get(
+                url,
+                &format!("repos/{}/pulls/{}/commits", repository, number),
+            )?
+            .query(&[("page", page), ("limit", MAX_PAGE_LIMIT)]),
+        )
+        .await?
+        .error_for_status()?
+        .json::<Vec<PullCommit>>()
+        .await?;
+
+        let last_page = res.len() < MAX_PAGE_LIMIT;
+        commits.extend(res.into_iter().map(|commit| commit.sha));
+        if last_page {
+            break;
+        }
+    }
+
+    Ok(commits)
+}
+
+/// Fetches up to `limit` open PRs, only counting those targeting `base` if given
+#[instrument(err)]
+pub async fn fetch_open_pull_requests(
+    url: &Url,
+    repository: &str,
+    base: Option<&str>,
+    limit: usize,
+) -> Result<Vec<PullRequest>, anyhow::Error> {
+    let mut pull_requests = Vec::new();
+    for page in 1.. {
+        let res = send(get(url, &format!("repos/{}/pulls", repository))?.query(&[
+            ("state", "open".to_string()),
+            ("page", page.to_string()),
+            ("limit", MAX_PAGE_LIMIT.to_string()),
+        ]))
+        .await?
+        .error_for_status()?
+        .json::<Vec<PullRequest>>()
+        .await?;
+
+        let last_page = res.len() < MAX_PAGE_LIMIT;
+        pull_requests.extend(
+            res.into_iter().filter(|x| {
+                base.is_none_or(|base| x.base.as_ref().is_some_and(|x| x.name == base))
+            }),
+        );
+        if last_page || pull_requests.len() >= limit {
+            break;
+        }
+    }
+
+    pull_requests.truncate(limit);
+    Ok(pull_requests)
+}
+
+#[instrument(err)]
+pub async fn fetch_combined_status(
+    url: &Url,
+    repository: &str,
+    sha: &str,
+) -> Result<CombinedStatus, anyhow::Error> {
+    let res = send(get(
+        url,
+        &format!("repos/{}/commits/{}/status", repository, sha),
+    )?)
+    .await?
+    .error_for_status()?
+    .json::<CombinedStatus>()
+    .await?;
+
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn auth_failures_alert_once_per_run() {
+        let failures = AuthFailures::new();
+
+        let observe = |status| failures.observe(status, "/api/v1/repos/acme/widgets", 2);
+
+        assert_eq!(observe(StatusCode::UNAUTHORIZED), None);
+        assert_eq!(observe(StatusCode::UNAUTHORIZED), Some(2));
+        assert_eq!(observe(StatusCode::UNAUTHORIZED), None);
+
+        /* Working again starts a new run, which gets its own alert */
+        assert_eq!(observe(StatusCode::OK), None);
+        assert_eq!(observe(StatusCode::UNAUTHORIZED), None);
+        assert_eq!(observe(StatusCode::UNAUTHORIZED), Some(2));
+    }
+
+    #[test]
+    fn permission_errors_arent_token_rejections() {
+        let failures = AuthFailures::new();
+
+        assert_eq!(
+            failures.observe(StatusCode::UNAUTHORIZED, "/api/v1/user", 2),
+            None
+        );
+        /* The token worked, its user just isn't a repo admin */
+        let protections = "/api/v1/repos/acme/widgets/branch_protections";
+        assert_eq!(
+            failures.observe(StatusCode::FORBIDDEN, protections, 2),
+            None
+        );
+        assert_eq!(
+            failures.observe(StatusCode::UNAUTHORIZED, "/api/v1/user", 2),
+            None
+        );
+        assert_eq!(
+            failures.observe(StatusCode::FORBIDDEN, "/api/v1/user", 2),
+            Some(2)
+        );
+    }
+}
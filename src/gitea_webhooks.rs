@@ -1,47 +1,128 @@
+use crate::config::{
+    config, Broadcast, ClosedWithoutMerge, Deanonymise, FooterLink, SelfReviewRequest,
+    TimestampConfig,
+};
+use crate::timestamps::format_time;
+use crate::{block_kit, gitea_api, paths, slack};
 use anyhow::Context;
-use reqwest::Client;
-use serde::{Deserialize, Serialize};
+use chrono::{DateTime, NaiveDate, TimeDelta, Utc};
+use futures::future::join_all;
+use regex::{Captures, Regex};
+use serde::{Deserialize, Deserializer, Serialize};
 use slack_morphism::prelude::*;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, OnceLock};
 use strum::Display;
 use tracing::instrument;
 use url::Url;
 
-#[derive(Deserialize, Debug)]
+/// Appended to comments mirrored from Slack, so they aren't notified about back into Slack
+pub const MIRRORED_COMMENT_MARKER: &str = "<!-- mirrored from slack -->";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct User {
     pub email: String,
     pub username: String,
+    #[serde(default)]
+    pub full_name: String,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "lowercase")]
 pub enum PullRequestState {
     Open,
     Closed,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct Repository {
+    /// Stable across renames and transfers, unlike `full_name`
+    #[serde(default)]
+    pub id: i64,
     pub full_name: String,
+    #[serde(rename = "html_url")]
+    pub url: Url,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Branch {
+    #[serde(rename = "ref")]
+    pub name: String,
+    pub sha: String,
+    /// Where the branch is, which for a PR's head is a fork's repository if it's from one
+    #[serde(default)]
+    pub repo: Option<Repository>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Label {
+    pub name: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Milestone {
+    pub title: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
 pub struct Comment {
+    /// Missing from older payloads
+    #[serde(default)]
+    pub id: i64,
     pub body: String,
+    /// Anchored to the comment itself, missing from older payloads
+    #[serde(rename = "html_url", default)]
+    pub url: Option<Url>,
+    /// The diff leading up to the line a code comment is on, ending with it
+    #[serde(default)]
+    pub diff_hunk: Option<String>,
+    /// The file a code comment is on
+    #[serde(default)]
+    pub path: Option<String>,
+    #[serde(default)]
+    pub created_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub updated_at: Option<DateTime<Utc>>,
+    /// Set on an edited comment to the usernames, lowercased, it newly mentions, who are the
+    /// only ones pinged about it
+    #[serde(skip)]
+    pub new_mentions: Option<Vec<String>>,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct PullRequest {
     pub body: String,
     pub comments: u64,
     pub id: u64,
+    /// The PR's index within its repository, as used in its URL
+    #[serde(default)]
+    pub number: u64,
     pub user: User,
     pub title: String,
     #[serde(rename = "html_url")]
     pub url: Url,
     pub state: PullRequestState,
+    /* Issue payloads (e.g. comments) don't carry merge information */
+    #[serde(default)]
+    pub mergeable: Option<bool>,
+    #[serde(default)]
+    pub head: Option<Branch>,
+    #[serde(default)]
+    pub base: Option<Branch>,
+    #[serde(default)]
+    pub labels: Vec<Label>,
+    #[serde(default)]
+    pub milestone: Option<Milestone>,
+    /// Whether a closed PR was closed by merging it
+    #[serde(default)]
+    pub merged: bool,
+    #[serde(default)]
+    pub created_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub updated_at: Option<DateTime<Utc>>,
 }
 
-#[derive(Deserialize, Debug, Display)]
+#[derive(Serialize, Deserialize, Debug, Display)]
 #[serde(tag = "type")]
 #[strum(serialize_all = "snake_case")]
 pub enum Review {
@@ -54,7 +135,33 @@ pub enum Review {
     Comment { content: String },
 }
 
-#[derive(Deserialize, Debug, Display)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum ReviewKind {
+    Approved,
+    Rejected,
+    Comment,
+}
+
+impl Review {
+    pub fn kind(&self) -> ReviewKind {
+        match self {
+            Review::Approved { content: _ } => ReviewKind::Approved,
+            Review::Rejected { content: _ } => ReviewKind::Rejected,
+            Review::Comment { content: _ } => ReviewKind::Comment,
+        }
+    }
+
+    /// What the reviewer did, e.g. "approved", in the repo's configured wording if it has any
+    pub fn phrase(&self, repository: &Repository) -> String {
+        match config().repo(repository).review_wording.get(&self.kind()) {
+            Some(phrase) => phrase.clone(),
+            None => self.to_string(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Display)]
 #[serde(rename_all = "snake_case", tag = "action")]
 #[strum(serialize_all = "snake_case")]
 pub enum Action {
@@ -62,19 +169,283 @@ pub enum Action {
     Closed,
     Reopened,
     Merged,
-    Created { comment: Comment },
-    Reviewed { review: Review },
-    ReviewRequested { requested_reviewer: User },
+    Synchronized,
+    Created {
+        comment: Comment,
+    },
+    Reviewed {
+        review: Review,
+    },
+    /// A review no longer counting, e.g. dismissed as stale once new commits landed. Whose it
+    /// was isn't always in the payload, in which case it's only picked up from Gitea's reviews.
+    ReviewDismissed {
+        #[serde(rename = "requested_reviewer", default)]
+        reviewer: Option<User>,
+    },
+    /// The PR being edited, or one of its comments when `comment` is set
+    Edited {
+        #[serde(default)]
+        changes: Changes,
+        #[serde(default)]
+        comment: Option<Comment>,
+    },
+    LabelUpdated,
+    LabelCleared,
+    Milestoned,
+    Demilestoned,
+    /// A push rewriting the PR's history, which Gitea sends as any other synchronisation
+    #[serde(skip_deserializing)]
+    ForcePushed {
+        /// Whether it only rebased, rather than also adding commits
+        rebase_only: bool,
+        /// Whoever approved or requested changes before the push
+        reviewers: Vec<User>,
+        /// Whether the push also left the PR mergeable again, e.g. by resolving conflicts
+        now_mergeable: bool,
+    },
+    /// Label and milestone changes since they were last notified about, which Gitea doesn't
+    /// send itself
+    #[serde(skip_deserializing)]
+    MetadataUpdated {
+        changes: MetadataChanges,
+    },
+    /* Gitea currently sends a webhook per reviewer, but accept a batch in case that changes */
+    ReviewRequested {
+        #[serde(rename = "requested_reviewer", deserialize_with = "one_or_many")]
+        requested_reviewers: Vec<User>,
+        /* Only sent by setups assigning reviewers automatically, e.g. "code owner of src/auth/" */
+        #[serde(default)]
+        review_reason: Option<String>,
+        #[serde(default)]
+        review_round: Option<u32>,
+    },
+    /// Reviewers taken off the PR's review requests, which isn't worth notifying anyone about
+    ReviewRequestRemoved {
+        #[serde(rename = "requested_reviewer", deserialize_with = "one_or_many")]
+        requested_reviewers: Vec<User>,
+    },
+    /// Anything this doesn't know of yet, e.g. from a newer Gitea, keeping what it was
+    #[serde(skip_deserializing)]
+    #[strum(to_string = "{action}")]
+    Unknown {
+        /* Logged as its own field, the tag being taken */
+        #[serde(rename = "unknown_action")]
+        action: String,
+    },
 }
 
-#[derive(Deserialize, Debug)]
+/// The labels and milestone of a PR, as last seen
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+pub struct Metadata {
+    pub labels: Vec<String>,
+    pub milestone: Option<String>,
+}
+
+#[derive(Serialize, Debug, Default, PartialEq, Eq)]
+pub struct MetadataChanges {
+    pub added_labels: Vec<String>,
+    pub removed_labels: Vec<String>,
+    /// The new milestone, if it changed, where `Some(None)` means it was removed
+    pub milestone: Option<Option<String>>,
+}
+
+impl Metadata {
+    pub fn of(pull_request: &PullRequest) -> Self {
+        Self {
+            labels: pull_request.labels.iter().map(|x| x.name.clone()).collect(),
+            milestone: pull_request.milestone.as_ref().map(|x| x.title.clone()),
+        }
+    }
+
+    /// What changed since `previous`, or `None` if nothing did
+    pub fn changes_since(&self, previous: &Metadata) -> Option<MetadataChanges> {
+        let changes = MetadataChanges {
+            added_labels: self
+                .labels
+                .iter()
+                .filter(|x| !previous.labels.contains(x))
+                .cloned()
+                .collect(),
+            removed_labels: previous
+                .labels
+                .iter()
+                .filter(|x| !self.labels.contains(x))
+                .cloned()
+                .collect(),
+            milestone: (self.milestone != previous.milestone).then(|| self.milestone.clone()),
+        };
+
+        (changes != MetadataChanges::default()).then_some(changes)
+    }
+}
+
+impl Action {
+    /// Whether this changes a PR's labels or milestone
+    pub fn is_metadata(&self) -> bool {
+        matches!(
+            self,
+            Action::LabelUpdated | Action::LabelCleared | Action::Milestoned | Action::Demilestoned
+        )
+    }
+}
+
+/// The previous values of whatever an edit changed
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Changes {
+    pub title: Option<ChangedFrom>,
+    pub body: Option<ChangedFrom>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ChangedFrom {
+    pub from: String,
+}
+
+fn one_or_many<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany<T> {
+        One(T),
+        Many(Vec<T>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(x) => vec![x],
+        OneOrMany::Many(x) => x,
+    })
+}
+
+/// Every action tag [`Action`] deserialises from
+const KNOWN_ACTIONS: [&str; 15] = [
+    "opened",
+    "closed",
+    "reopened",
+    "merged",
+    "synchronized",
+    "created",
+    "reviewed",
+    "review_dismissed",
+    "edited",
+    "label_updated",
+    "label_cleared",
+    "milestoned",
+    "demilestoned",
+    "review_requested",
+    "review_request_removed",
+];
+
+/// Deserialises an action, keeping an unrecognised one as [`Action::Unknown`] rather than
+/// failing the whole webhook
+fn known_or_unknown<'de, D>(deserializer: D) -> Result<Action, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = serde_json::Value::deserialize(deserializer)?;
+
+    /* Only the tag not matching is unknown, a known action missing its fields is an error */
+    match value.get("action").and_then(|x| x.as_str()) {
+        Some(action) if !KNOWN_ACTIONS.contains(&action) => Ok(Action::Unknown {
+            action: action.to_string(),
+        }),
+        _ => Action::deserialize(&value).map_err(serde::de::Error::custom),
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
 pub struct Webhook {
-    #[serde(flatten)]
+    #[serde(flatten, deserialize_with = "known_or_unknown")]
     pub action: Action,
     #[serde(alias = "issue")]
     pub pull_request: PullRequest,
     pub sender: User,
     pub repository: Repository,
+    /// The sender as a Slack mention, for repos mentioning senders rather than naming them
+    #[serde(skip)]
+    pub sender_mention: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CommitState {
+    Pending,
+    Success,
+    Error,
+    Failure,
+    Warning,
+    /* Gitea reports an empty state when a commit has no statuses at all */
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct CommitStatus {
+    /* The status API calls this `status`, while the webhook calls it `state` */
+    #[serde(alias = "status")]
+    pub state: CommitState,
+    pub context: String,
+    #[serde(default)]
+    pub target_url: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct CombinedStatus {
+    pub state: CommitState,
+    #[serde(default)]
+    pub statuses: Vec<CommitStatus>,
+}
+
+/// A branch or tag being deleted, sent under the `delete` event
+#[derive(Deserialize, Debug)]
+pub struct DeleteEvent {
+    #[serde(rename = "ref")]
+    pub name: String,
+    /// `branch` or `tag`
+    pub ref_type: String,
+    pub repository: Repository,
+    pub sender: User,
+}
+
+/// A package version being published to, or deleted from, Gitea's registry, sent under the
+/// `package` event
+#[derive(Deserialize, Debug)]
+pub struct PackageEvent {
+    /// `created` or `deleted`
+    pub action: String,
+    pub package: Package,
+    pub sender: User,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Package {
+    /// Only set once the package is linked to a repository
+    #[serde(default)]
+    pub repository: Option<Repository>,
+    pub creator: User,
+    /// e.g. `container` or `npm`
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub name: String,
+    pub version: String,
+    pub html_url: Url,
+}
+
+/// A commit status update, sent under the `status` event
+#[derive(Deserialize, Debug)]
+pub struct StatusEvent {
+    pub sha: String,
+    #[serde(flatten)]
+    pub status: CommitStatus,
+    pub repository: Repository,
+}
+
+/// A change to the repository itself, sent under the `repository` event
+#[derive(Deserialize, Debug)]
+pub struct RepositoryEvent {
+    pub repository: Repository,
 }
 
 #[derive(Serialize, Debug)]
@@ -87,243 +458,1534 @@ pub struct OutgoingWebhook {
 pub struct MySlackMessage<'a> {
     pub webhook: &'a Webhook,
     pub slack_user: Vec<SlackUser>,
+    /// Recipients that couldn't be found in Slack
+    pub unresolved: Vec<User>,
+    /// CI on the PR's head, for the notifications showing it
+    pub ci_status: Option<Arc<CombinedStatus>>,
+}
+
+/// The user's real email from Gitea, unless configured to trust the one they came with.
+/// `None` if it wasn't looked up or that failed.
+pub async fn deanonymised_email(url: &Url, user: &User) -> Option<String> {
+    let lookup = match config().deanonymise {
+        Deanonymise::Always => true,
+        Deanonymise::Anonymised => looks_anonymised(&user.email),
+        Deanonymise::Never => false,
+    };
+    if !lookup {
+        return None;
+    }
+
+    gitea_api::fetch_user_email(url, &user.username).await.ok()
+}
+
+/// Whether an email is one of the placeholders Gitea, or a mirror of GitHub, hands out in its
+/// place, e.g. `alice@noreply.gitea.example.com` or `12345+alice@users.noreply.github.com`
+fn looks_anonymised(email: &str) -> bool {
+    let Some((local, domain)) = email.rsplit_once('@') else {
+        return true;
+    };
+    let numbered = local
+        .split_once('+')
+        .is_some_and(|(id, _)| !id.is_empty() && id.bytes().all(|x| x.is_ascii_digit()));
+
+    numbered
+        || domain
+            .split('.')
+            .any(|label| label.eq_ignore_ascii_case("noreply"))
 }
 
 impl Webhook {
     pub async fn try_deanonymise_emails(mut self) -> Self {
-        /* Setting the path is the easiest way to keep the scheme and host together but remove the path */
-        let mut url = self.pull_request.url.clone();
+        let url = &self.pull_request.url;
+
+        let reviewers = match &self.action {
+            Action::ReviewRequested {
+                requested_reviewers,
+                ..
+            } => requested_reviewers.as_slice(),
+            _ => &[],
+        };
+
+        /* The lookups are independent, so there's no need to wait on each in turn */
+        let (sender_email, author_email, reviewer_emails) = tokio::join!(
+            deanonymised_email(url, &self.sender),
+            deanonymised_email(url, &self.pull_request.user),
+            join_all(
+                reviewers
+                    .iter()
+                    .map(|reviewer| deanonymised_email(url, reviewer))
+            ),
+        );
 
         /* If the email can't be de-anonymised for some reason, keep the anon email */
-        if let Ok(email) = Webhook::fetch_gitea_user_email(&mut url, &self.sender.username).await {
+        if let Some(email) = sender_email {
             self.sender.email = email;
         }
 
-        if let Ok(email) =
-            Webhook::fetch_gitea_user_email(&mut url, &self.pull_request.user.username).await
-        {
+        if let Some(email) = author_email {
             self.pull_request.user.email = email;
         }
 
         if let Action::ReviewRequested {
-            ref mut requested_reviewer,
+            ref mut requested_reviewers,
+            ..
         } = self.action
         {
-            if let Ok(email) =
-                Webhook::fetch_gitea_user_email(&mut url, &requested_reviewer.username).await
-            {
-                requested_reviewer.email = email;
+            for (requested_reviewer, email) in requested_reviewers.iter_mut().zip(reviewer_emails) {
+                if let Some(email) = email {
+                    requested_reviewer.email = email;
+                }
             }
         }
 
         self
     }
 
-    #[instrument(err)]
-    async fn fetch_gitea_user_email(
-        url: &mut Url,
-        username: &str,
-    ) -> Result<String, anyhow::Error> {
-        let token = config_env_var("GITEA_API_TOKEN")?;
-
-        url.set_path(format!("api/v1/users/{}", username).as_str());
+    /// Who the event is for, independently of the chat platform. `None` if it shouldn't be
+    /// notified about at all, e.g. a draft or a comment mirrored from Slack.
+    pub async fn recipients(&self) -> Option<Vec<User>> {
+        match self.action {
+            Action::Opened | Action::ReviewRequested { .. } if self.is_draft() => return None,
+            Action::Edited { .. } if !self.left_draft() => return None,
+            Action::ReviewRequestRemoved { .. } => return None,
+            _ => {}
+        }
 
-        let res = Client::new()
-            .get(url.as_str())
-            .header("Authorization", "token ".to_string() + &token.to_owned())
-            .send()
-            .await?
-            .json::<User>()
-            .await?;
+        if let Action::ReviewRequested {
+            ref requested_reviewers,
+            ..
+        } = self.action
+        {
+            let self_review_request = config().repo(&self.repository).self_review_request;
 
-        Ok(res.email)
-    }
+            if self_review_request == SelfReviewRequest::Suppress
+                && requested_reviewers.iter().all(|x| self.is_author(x))
+            {
+                return None;
+            }
+        }
 
-    async fn into_my_slack(&self) -> Option<MySlackMessage> {
-        let emails = match self.action {
+        let recipients = match self.action {
             Action::ReviewRequested {
-                ref requested_reviewer,
-            } => vec![requested_reviewer.email.clone()],
-            Action::Reviewed { review: _ } => vec![self.pull_request.user.email.clone()],
+                ref requested_reviewers,
+                ..
+            } => requested_reviewers
+                .iter()
+                .filter(|reviewer| !self.is_author(reviewer))
+                .cloned()
+                .collect(),
+            Action::Reviewed { review: _ } | Action::Synchronized => {
+                vec![self.pull_request.user.clone()]
+            }
+            Action::ForcePushed { ref reviewers, .. } => reviewers
+                .iter()
+                .filter(|x| x.username != self.sender.username)
+                .cloned()
+                .collect(),
+            Action::ReviewDismissed { ref reviewer }
+                if config().repo(&self.repository).reping_dismissed_reviewers =>
+            {
+                reviewer.iter().cloned().collect()
+            }
+            Action::Closed if !self.is_merged() => {
+                match config().repo(&self.repository).closed_without_merge {
+                    ClosedWithoutMerge::Suppress => return None,
+                    ClosedWithoutMerge::Note => Vec::new(),
+                    ClosedWithoutMerge::Ping => vec![self.pull_request.user.clone()],
+                }
+            }
+            Action::Created { ref comment } if comment.body.contains(MIRRORED_COMMENT_MARKER) => {
+                return None;
+            }
             Action::Created { ref comment } => {
-                Webhook::parse_comment_for_mention(&self.pull_request.url, comment).await
+                let mut mentioned =
+                    Webhook::parse_comment_for_mention(&self.pull_request.url, comment).await;
+
+                if let Some(new_mentions) = &comment.new_mentions {
+                    mentioned.retain(|x| new_mentions.contains(&x.username.to_lowercase()));
+                } else if config().repo(&self.repository).notify_participants {
+                    for participant in self.participants().await {
+                        if !mentioned.iter().any(|x| x.username == participant.username) {
+                            mentioned.push(participant);
+                        }
+                    }
+                }
+
+                mentioned
             }
             _ => Vec::new(),
         };
 
-        let mut slack_users = Vec::<Option<SlackUser>>::new();
-        for email in emails {
-            slack_users.push(Webhook::fetch_slack_user_from_email(&email).await.ok());
+        Some(recipients)
+    }
+
+    pub async fn to_my_slack(&self) -> Option<MySlackMessage<'_>> {
+        let recipients = self.recipients().await?;
+
+        let resolve_by_name = config().repo(&self.repository).resolve_by_name;
+
+        let mut resolved = Vec::new();
+        for recipient in recipients {
+            let slack_user = slack::resolve_user(&recipient, resolve_by_name).await;
+            resolved.push((recipient, slack_user));
         }
 
-        let slack_user: Vec<SlackUser> = slack_users.into_iter().filter_map(|x| x).collect();
+        self.to_my_slack_resolved(resolved).await
+    }
 
-        if let Action::Created { comment: _ } = self.action {
-            if slack_user.len() == 0 {
-                return None;
+    /// As [`Webhook::to_my_slack`], for recipients that have already been looked up in Slack
+    pub async fn to_my_slack_resolved(
+        &self,
+        resolved: Vec<(User, Option<SlackUser>)>,
+    ) -> Option<MySlackMessage<'_>> {
+        let mut slack_users = Vec::<Option<SlackUser>>::new();
+        let mut unresolved = Vec::<User>::new();
+        for (recipient, slack_user) in resolved {
+            if slack_user.is_none() {
+                unresolved.push(recipient);
             }
+            slack_users.push(slack_user);
+        }
+
+        /* Someone mentioned twice, or both mentioned and requested, should only be pinged once */
+        let slack_user = dedup_slack_users(slack_users.into_iter().flatten().collect());
+
+        let post_unresolved = config().repo(&self.repository).post_unresolved_comments;
+        if !self.has_audience(&slack_user, &unresolved, post_unresolved) {
+            return None;
         }
 
         Some(MySlackMessage {
             webhook: self,
             slack_user,
+            unresolved,
+            ci_status: crate::ci::head_status(self).await,
         })
     }
 
-    async fn parse_comment_for_mention(url: &Url, comment: &Comment) -> Vec<String> {
-        let users = comment
-            .body
-            .lines()
-            .filter_map(|line| {
-                let line = line.trim_start();
-                if line.starts_with(">") {
-                    None
-                } else {
-                    Some(line)
-                }
-            })
-            .flat_map(|x| x.split_whitespace())
-            .filter_map(|x| {
-                if x.starts_with("@") {
-                    Some(x.trim_start_matches("@"))
-                } else {
-                    None
-                }
-            });
+    /// Whether there's anyone to notify. Unlike other actions a comment only has recipients by
+    /// mentioning them, so it's dropped unless one of them can actually be pinged, or
+    /// `post_unresolved` names them instead.
+    fn has_audience(
+        &self,
+        slack_users: &[SlackUser],
+        unresolved: &[User],
+        post_unresolved: bool,
+    ) -> bool {
+        match self.action {
+            Action::Created { .. } => {
+                !slack_users.is_empty() || (post_unresolved && !unresolved.is_empty())
+            }
+            _ => true,
+        }
+    }
 
-        let mut mention_emails = Vec::<String>::new();
-        for user in users {
-            if let Some(email) = Webhook::fetch_gitea_user_email(&mut url.clone(), user)
-                .await
-                .ok()
-            {
-                mention_emails.push(email);
+    /// Looks the sender up in Slack, for repos mentioning senders rather than naming them
+    pub async fn resolve_sender(&mut self) {
+        let repo_config = config().repo(&self.repository);
+        if repo_config.mention_sender {
+            self.sender_mention =
+                slack::cached_mention(&self.sender, repo_config.resolve_by_name).await;
+        }
+    }
+
+    /// The sender's Slack mention if they were resolved to one, otherwise their username
+    pub fn sender_name(&self) -> &str {
+        self.sender_mention
+            .as_deref()
+            .unwrap_or(&self.sender.username)
+    }
+
+    /// The fork the PR is from, if it's not from a branch of the repository itself
+    pub fn fork(&self) -> Option<&Repository> {
+        self.pull_request
+            .head
+            .as_ref()?
+            .repo
+            .as_ref()
+            .filter(|repo| repo.full_name != self.repository.full_name)
+    }
+
+    pub fn is_author(&self, user: &User) -> bool {
+        user.username == self.pull_request.user.username
+    }
+
+    /// Whether the PR was merged, which Gitea may send as either a merge or a close
+    pub fn is_merged(&self) -> bool {
+        match self.action {
+            Action::Merged => true,
+            Action::Closed => self.pull_request.merged,
+            _ => false,
+        }
+    }
+
+    /// The emoji to react to the notification with, out of those keyed on the action. A merge
+    /// sent as a close is reacted to as a merge.
+    pub fn reaction<'a>(&self, reactions: &'a HashMap<String, String>) -> Option<&'a String> {
+        let action = match self.is_merged() {
+            true => Action::Merged.to_string(),
+            false => self.action.to_string(),
+        };
+        reactions.get(&action)
+    }
+
+    /// When what the event is about last changed, if the payload says, e.g. a comment's last
+    /// edit rather than the PR's
+    pub fn happened_at(&self) -> Option<DateTime<Utc>> {
+        let comment_at = match &self.action {
+            Action::Created { comment }
+            | Action::Edited {
+                comment: Some(comment),
+                ..
+            } => comment.updated_at.or(comment.created_at),
+            _ => None,
+        };
+        comment_at.or(self.pull_request.updated_at)
+    }
+
+    /// Whether the event is about something that happened longer than `max_age` ago, e.g. one
+    /// of the historical events a freshly imported repo floods in. Events without a timestamp
+    /// never are.
+    pub fn older_than(&self, max_age: TimeDelta, now: DateTime<Utc>) -> bool {
+        self.happened_at()
+            .is_some_and(|happened_at| now - happened_at > max_age)
+    }
+
+    /// Whether the PR is marked as a work in progress by one of the configured title prefixes
+    pub fn is_draft(&self) -> bool {
+        has_draft_prefix(&self.repository, &self.pull_request.title)
+    }
+
+    /// Whether this is an edit removing a draft prefix from the title, which is when the PR
+    /// is announced instead
+    fn left_draft(&self) -> bool {
+        match &self.action {
+            Action::Edited {
+                changes: Changes {
+                    title: Some(title), ..
+                },
+                comment: None,
+            } => has_draft_prefix(&self.repository, &title.from) && !self.is_draft(),
+            _ => false,
+        }
+    }
+
+    /// The PR's author, requested reviewers and earlier commenters, besides whoever sent the
+    /// event. Anyone that can't be looked up is left out, it's only a courtesy.
+    async fn participants(&self) -> Vec<User> {
+        let (url, repository, number) = (
+            &self.repository.url,
+            self.repository.full_name.as_str(),
+            self.pull_request.number,
+        );
+
+        let (pull_request, commenters) = tokio::join!(
+            gitea_api::fetch_pull_request(url, repository, number),
+            gitea_api::fetch_commenters(url, repository, number),
+        );
+        let requested_reviewers = pull_request.map(|x| x.1).unwrap_or_default();
+
+        let mut seen = HashSet::new();
+        let usernames = std::iter::once(&self.pull_request.user)
+            .chain(requested_reviewers.iter())
+            .chain(commenters.iter().flatten())
+            .map(|user| user.username.as_str())
+            .filter(|username| *username != self.sender.username && seen.insert(*username))
+            .collect::<Vec<_>>();
+
+        /* As with mentions, only the user's own profile has their real email */
+        join_all(
+            usernames
+                .iter()
+                .map(|username| gitea_api::fetch_user(&self.pull_request.url, username)),
+        )
+        .await
+        .into_iter()
+        .flatten()
+        .collect()
+    }
+
+    async fn parse_comment_for_mention(url: &Url, comment: &Comment) -> Vec<User> {
+        let mut mentioned = Vec::<User>::new();
+        for user in mentioned_usernames(&comment.body) {
+            if let Ok(user) = gitea_api::fetch_user(url, user).await {
+                mentioned.push(user);
+            }
+        }
+
+        mentioned
+    }
+
+    /// Whether failing to resolve a recipient should fail the webhook so it's redelivered
+    pub fn is_critical(&self) -> bool {
+        config()
+            .repo(&self.repository)
+            .critical_actions
+            .contains(&self.action.to_string())
+    }
+
+    /// An emoji and a few words for what happened, e.g. `("🔀", "merged")`, wording it the same
+    /// for compact messages and the other backends
+    pub fn summary(&self) -> (&'static str, String) {
+        match &self.action {
+            _ if self.is_merged() => ("🔀", Action::Merged.to_string()),
+            Action::Opened | Action::Edited { .. } => ("🆕", "opened".to_string()),
+            Action::Reviewed { review } => {
+                let emoji = match review.kind() {
+                    ReviewKind::Approved => "✅",
+                    ReviewKind::Rejected => "❌",
+                    ReviewKind::Comment => "💬",
+                };
+                (emoji, review.phrase(&self.repository))
             }
+            Action::ReviewRequested { .. } => ("👀", "review requested".to_string()),
+            Action::ReviewDismissed { .. } => ("🔁", "review dismissed".to_string()),
+            Action::Created { .. } => ("💬", "commented".to_string()),
+            Action::Synchronized => ("✅", "now mergeable".to_string()),
+            Action::ForcePushed {
+                now_mergeable: true,
+                ..
+            } => ("⚠️", "force-pushed, now mergeable".to_string()),
+            Action::ForcePushed { .. } => ("⚠️", "force-pushed".to_string()),
+            Action::Closed => ("🚫", "closed without merging".to_string()),
+            Action::MetadataUpdated { .. } => ("🏷️", "labels or milestone updated".to_string()),
+            action => ("🔔", action.to_string().replace('_', " ")),
         }
+    }
 
-        mention_emails
+    /// Whether this is a review the repo wants surfaced outside the PR's thread as well
+    pub fn escalates(&self) -> bool {
+        match &self.action {
+            Action::Reviewed { review } => config()
+                .repo(&self.repository)
+                .escalate_reviews
+                .contains(&review.kind()),
+            _ => false,
+        }
     }
 
-    #[instrument(err)]
-    async fn fetch_slack_user_from_email(email: &str) -> Result<SlackUser, anyhow::Error> {
-        let client = SlackClient::new(SlackClientHyperConnector::new()?);
-        let token_value: SlackApiTokenValue = config_env_var("SLACK_API_TOKEN")?.into();
-        let token = SlackApiToken::new(token_value);
-        let session = client.open_session(&token);
+    /// Posts a brief top-level nudge to the author pointing them at the review in the thread,
+    /// to `channel`, or the configured channel if not given
+    #[instrument(err, skip(self))]
+    pub async fn post_review_escalation(
+        &self,
+        channel: Option<&String>,
+    ) -> Result<SlackApiChatPostMessageResponse, anyhow::Error> {
+        let Action::Reviewed { review } = &self.action else {
+            anyhow::bail!("Only reviews can be escalated");
+        };
 
-        let request = SlackApiUsersLookupByEmailRequest::new(EmailAddress(email.to_string()));
-        let slack_user = session.users_lookup_by_email(&request).await?;
+        let message = render_review_escalation(
+            &self.to_my_slack().await.context("Unable to convert")?,
+            review,
+        );
 
-        Ok(slack_user.user)
+        slack::post_message_in(channel.map(String::as_str), message, &None).await
     }
+}
 
-    #[instrument(err)]
-    pub async fn post_slack_message(
+impl MySlackMessage<'_> {
+    /// Posts to `channel`, or the configured channel if not given
+    #[instrument(err, skip(self))]
+    pub async fn post(
         &self,
+        channel: Option<&str>,
         parent: &Option<SlackTs>,
-    ) -> Result<SlackTs, anyhow::Error> {
-        let client = SlackClient::new(SlackClientHyperConnector::new()?);
-        let token_value: SlackApiTokenValue = config_env_var("SLACK_API_TOKEN")?.into();
-        let token = SlackApiToken::new(token_value);
-        let session = client.open_session(&token);
-
-        let message = self
-            .into_my_slack()
-            .await
-            .context("Unable to convert")?
-            .render_template();
-
-        let channel = config_env_var("SLACK_CHANNEL")?;
+    ) -> Result<SlackApiChatPostMessageResponse, anyhow::Error> {
+        slack::post_message_in(channel, self.render_template(), parent).await
+    }
 
-        let post_chat_req = if let Some(thread_ts) = parent {
-            SlackApiChatPostMessageRequest::new(channel.into(), message)
-                .with_thread_ts(thread_ts.clone())
-        } else {
-            SlackApiChatPostMessageRequest::new(channel.into(), message)
+    /// Delivers a review request as a DM to each reviewer resolved to a Slack user instead of
+    /// to the channel, where enabled. Returns the username, Slack user and DM of each reviewer
+    /// it was delivered to, leaving the rest for the channel.
+    pub async fn post_as_dms(&self) -> Vec<(String, SlackUserId, SlackApiChatPostMessageResponse)> {
+        let Action::ReviewRequested {
+            requested_reviewers,
+            ..
+        } = &self.webhook.action
+        else {
+            return Vec::new();
         };
+        if !config().repo(&self.webhook.repository).dm_review_requests {
+            return Vec::new();
+        }
+
+        let content = self.render_template();
+        let mut sent = Vec::new();
+        for reviewer in requested_reviewers {
+            let Some(user) = self
+                .slack_user
+                .iter()
+                .find(|user| slack_user_is(user, reviewer))
+            else {
+                continue;
+            };
+
+            /* Failures are logged by the calls themselves */
+            let Ok(channel) = slack::open_dm(&user.id).await else {
+                continue;
+            };
+            if let Ok(response) = slack::post_message_to(&channel.0, content.clone(), &None).await {
+                sent.push((reviewer.username.clone(), user.id.clone(), response));
+            }
+        }
 
-        let post_chat_resp = session.chat_post_message(&post_chat_req).await?;
+        sent
+    }
+
+    /// Posts standalone copies to the feed channels configured for the action, e.g. every
+    /// merge to a release feed, with failures only logged. Only once the main post succeeded.
+    pub async fn post_to_feeds(&self) {
+        let feed_channels = &config().repo(&self.webhook.repository).feed_channels;
+        let Some(channels) = feed_channels.get(&self.webhook.action.to_string()) else {
+            return;
+        };
 
-        Ok(post_chat_resp.ts)
+        for channel in channels {
+            /* Failures are logged by the post itself */
+            let _ = slack::post_message_to(channel, self.render_template(), &None).await;
+        }
     }
 }
 
 impl SlackMessageTemplate for MySlackMessage<'_> {
     fn render_template(&self) -> SlackMessageContent {
-        match &self.webhook.action {
-            Action::Opened => render_pr_opened(&self.webhook),
+        if config().repo(&self.webhook.repository).compact {
+            return self.render_compact();
+        }
+
+        let content = match &self.webhook.action {
+            Action::Opened | Action::Edited { .. } => render_pr_opened(self.webhook),
             Action::Reviewed { review } => render_reviewed(self, review),
-            Action::ReviewRequested { requested_reviewer } => {
-                render_review_requested(self, &requested_reviewer)
+            Action::ReviewRequested {
+                requested_reviewers,
+                review_reason,
+                review_round,
+            } => with_review_reason(
+                render_review_requested(self, requested_reviewers),
+                &self.webhook.repository,
+                review_reason.as_deref(),
+                *review_round,
+            ),
+            Action::Created { comment } => render_comment(self, comment),
+            Action::Synchronized => render_now_mergeable(self),
+            Action::ForcePushed {
+                rebase_only,
+                now_mergeable,
+                ..
+            } => render_force_pushed(self, *rebase_only, *now_mergeable),
+            Action::Closed if !self.webhook.is_merged() => render_closed_without_merge(self),
+            Action::ReviewDismissed { reviewer } => {
+                render_review_dismissed(self, reviewer.as_ref())
+            }
+            Action::MetadataUpdated { changes } => render_metadata_updated(self.webhook, changes),
+            _ => render_basic_action(self.webhook),
+        };
+
+        let content = match &self.webhook.action {
+            Action::ReviewRequested { .. } | Action::Created { .. }
+                if config().repo(&self.webhook.repository).show_age =>
+            {
+                with_age(
+                    content,
+                    &self.webhook.pull_request,
+                    &config().repo(&self.webhook.repository).timestamps,
+                    Utc::now(),
+                )
             }
-            Action::Created { comment: _ } => render_comment(self),
-            _ => render_basic_action(&self.webhook),
+            _ => content,
+        };
+
+        let content = match &self.ci_status {
+            Some(ci_status) => with_ci_status(content, ci_status),
+            None => content,
+        };
+
+        let content = match self.webhook.broadcast() {
+            Some(broadcast) => with_broadcast(content, broadcast),
+            None => content,
+        };
+
+        with_footer_links(
+            content,
+            &self.webhook.repository,
+            &config().repo(&self.webhook.repository).footer_links,
+        )
+    }
+}
+
+impl MySlackMessage<'_> {
+    /// e.g. "🔀 merged: acme/widgets#42 by alice", followed by whoever it's for
+    fn render_compact(&self) -> SlackMessageContent {
+        let webhook = self.webhook;
+        let (emoji, what) = webhook.summary();
+
+        let pull_request = &webhook.pull_request;
+        /* Older payloads don't carry the number, so there's only the title to link */
+        let link = if pull_request.number == 0 {
+            format_pull_request_url(pull_request)
+        } else {
+            format!(
+                "<{}|{}#{}>",
+                pull_request.url, webhook.repository.full_name, pull_request.number
+            )
+        };
+
+        let mut line = format!("{} {}: {} by {}", emoji, what, link, webhook.sender_name());
+
+        let recipients = self
+            .slack_user
+            .iter()
+            .map(|user| user.id.to_slack_format())
+            .chain(self.unresolved.iter().map(|user| user.username.clone()))
+            .collect::<Vec<String>>();
+        if !recipients.is_empty() {
+            line += &format!(" · {}", recipients.join(", "));
+        }
+
+        if let Some(broadcast) = webhook.broadcast() {
+            line = format!("{} 🚨 {}", broadcast_mention(broadcast), line);
+        }
+
+        SlackMessageContent::new().with_blocks(slack_blocks![some_into(
+            SlackSectionBlock::new().with_text(md!("{}", line))
+        )])
+    }
+}
+
+impl Webhook {
+    /// The broadcast to escalate with, only ever for new PRs and review requests carrying a
+    /// configured label
+    fn broadcast(&self) -> Option<Broadcast> {
+        self.broadcast_for(&config().repo(&self.repository).broadcast_labels)
+    }
+
+    fn broadcast_for(&self, broadcast_labels: &HashMap<String, Broadcast>) -> Option<Broadcast> {
+        if !matches!(self.action, Action::Opened | Action::ReviewRequested { .. })
+            && !self.left_draft()
+        {
+            return None;
         }
+
+        self.pull_request
+            .labels
+            .iter()
+            .filter_map(|label| {
+                broadcast_labels
+                    .iter()
+                    .find(|(name, _)| name.eq_ignore_ascii_case(&label.name))
+                    .map(|(_, broadcast)| *broadcast)
+            })
+            .max()
     }
 }
 
-fn format_pull_request_url(pull_request: &PullRequest) -> String {
-    format!("<{}|{}>", pull_request.url, pull_request.title)
+fn broadcast_mention(broadcast: Broadcast) -> &'static str {
+    match broadcast {
+        Broadcast::Here => "<!here>",
+        Broadcast::Channel => "<!channel>",
+    }
 }
 
-fn render_basic_action(webhook: &Webhook) -> SlackMessageContent {
-    SlackMessageContent::new().with_blocks(slack_blocks![some_into(
-        SlackSectionBlock::new().with_text(md!(
-            "{} was {}",
-            format_pull_request_url(&webhook.pull_request),
-            webhook.action
-        ))
-    )])
+fn with_broadcast(content: SlackMessageContent, broadcast: Broadcast) -> SlackMessageContent {
+    let mut blocks = content.blocks.unwrap_or_default();
+    blocks.insert(
+        0,
+        SlackSectionBlock::new()
+            .with_text(md!(
+                "{} 🚨 This PR is labelled as urgent",
+                broadcast_mention(broadcast)
+            ))
+            .into(),
+    );
+
+    SlackMessageContent::new().with_blocks(blocks)
 }
 
-fn render_comment(slack_message: &MySlackMessage) -> SlackMessageContent {
-    let mentions = slack_message
-        .slack_user
-        .clone()
-        .into_iter()
-        .map(|x| x.id.to_slack_format())
+fn with_footer_links(
+    content: SlackMessageContent,
+    repository: &Repository,
+    footer_links: &[FooterLink],
+) -> SlackMessageContent {
+    if footer_links.is_empty() {
+        return content;
+    }
+
+    let (owner, name) = repository
+        .full_name
+        .split_once('/')
+        .unwrap_or(("", &repository.full_name));
+
+    let links = footer_links
+        .iter()
+        .map(|link| {
+            let url = link
+                .url
+                .replace("{repo}", &repository.full_name)
+                .replace("{owner}", owner)
+                .replace("{name}", name);
+            format!("<{}|{}>", url, link.name)
+        })
         .collect::<Vec<String>>()
-        .join(" ");
+        .join(" | ");
 
-    SlackMessageContent::new().with_blocks(slack_blocks![some_into(
-        SlackSectionBlock::new().with_text(md!("{}, you were mentioned in a comment", mentions))
-    )])
+    let mut blocks = content.blocks.unwrap_or_default();
+    blocks.push(SlackContextBlock::new(vec![md!("{}", links)]).into());
+
+    SlackMessageContent::new().with_blocks(blocks)
 }
 
-fn render_reviewed(slack_message: &MySlackMessage, review: &Review) -> SlackMessageContent {
-    let user = if let Some(user) = slack_message.slack_user.first() {
-        user.id.to_slack_format()
-    } else {
-        slack_message.webhook.pull_request.user.username.to_string()
+fn with_age(
+    content: SlackMessageContent,
+    pull_request: &PullRequest,
+    timestamps: &TimestampConfig,
+    now: DateTime<Utc>,
+) -> SlackMessageContent {
+    let Some(created_at) = pull_request.created_at else {
+        return content;
+    };
+
+    let mut age = format!("Opened {}", format_time(created_at, now, timestamps));
+
+    /* An update straight after opening isn't worth mentioning */
+    if let Some(updated_at) = pull_request.updated_at {
+        if updated_at - created_at >= TimeDelta::hours(1) {
+            age += &format!(
+                ", last updated {}",
+                format_time(updated_at, now, timestamps)
+            );
+        }
+    }
+
+    let mut blocks = content.blocks.unwrap_or_default();
+    blocks.push(SlackContextBlock::new(vec![md!("🕰️ {}", age)]).into());
+
+    SlackMessageContent::new().with_blocks(blocks)
+}
+
+/// CI runs listed in a notification before the rest are summarised as a count
+const MAX_LISTED_CI_RUNS: usize = 5;
+
+/// Lists the CI runs on the PR's head, each linked to its results where it has a link
+fn with_ci_status(content: SlackMessageContent, ci_status: &CombinedStatus) -> SlackMessageContent {
+    /* Nothing has reported on the head, or at least nothing that says so */
+    if ci_status.statuses.is_empty() {
+        return content;
+    }
+
+    let mut runs = ci_status
+        .statuses
+        .iter()
+        .take(MAX_LISTED_CI_RUNS)
+        .map(|status| {
+            let context = match &status.target_url {
+                Some(target_url) if !target_url.is_empty() => {
+                    format!("<{}|{}>", target_url, status.context)
+                }
+                _ => status.context.clone(),
+            };
+            format!("{} {}", ci_indicator(status.state), context)
+        })
+        .collect::<Vec<String>>()
+        .join(" · ");
+    if ci_status.statuses.len() > MAX_LISTED_CI_RUNS {
+        runs += &format!(
+            " · and {} more",
+            ci_status.statuses.len() - MAX_LISTED_CI_RUNS
+        );
+    }
+
+    let mut blocks = content.blocks.unwrap_or_default();
+    blocks.push(
+        SlackContextBlock::new(vec![md!("{} CI: {}", ci_indicator(ci_status.state), runs)]).into(),
+    );
+
+    SlackMessageContent::new().with_blocks(blocks)
+}
+
+fn ci_indicator(state: CommitState) -> &'static str {
+    match state {
+        CommitState::Success => "✅",
+        CommitState::Failure | CommitState::Error => "❌",
+        CommitState::Pending | CommitState::Warning | CommitState::Unknown => "🟡",
+    }
+}
+
+pub fn format_pull_request_url(pull_request: &PullRequest) -> String {
+    format!(
+        "<{}|{}>",
+        pull_request.url,
+        slack_emoji(&pull_request.title)
+    )
+}
+
+fn render_basic_action(webhook: &Webhook) -> SlackMessageContent {
+    /* A close by merging is a merge, whichever way Gitea sends it */
+    let action = if webhook.is_merged() {
+        Action::Merged.to_string()
+    } else {
+        webhook.action.to_string()
+    };
+
+    SlackMessageContent::new().with_blocks(slack_blocks![some_into(
+        SlackSectionBlock::new().with_text(md!(
+            "{} was {}",
+            format_pull_request_url(&webhook.pull_request),
+            action
+        ))
+    )])
+}
+
+fn render_closed_without_merge(slack_message: &MySlackMessage) -> SlackMessageContent {
+    let webhook = slack_message.webhook;
+    let author = match slack_message.slack_user.first() {
+        Some(user) => format!("{}, your PR", user.id.to_slack_format()),
+        None => "PR".to_string(),
     };
 
     SlackMessageContent::new().with_blocks(slack_blocks![some_into(
         SlackSectionBlock::new().with_text(md!(
+            "{} {} was closed without merging by {}",
+            author,
+            format_pull_request_url(&webhook.pull_request),
+            webhook.sender_name()
+        ))
+    )])
+}
+
+fn render_review_dismissed(
+    slack_message: &MySlackMessage,
+    reviewer: Option<&User>,
+) -> SlackMessageContent {
+    let webhook = slack_message.webhook;
+    let mention = match (slack_message.slack_user.first(), reviewer) {
+        (Some(user), _) => format!("{}'s review", user.id.to_slack_format()),
+        (None, Some(reviewer)) => format!("{}'s review", reviewer.username),
+        (None, None) => "a review".to_string(),
+    };
+
+    let text = format!(
+        "{} dismissed {} on {}",
+        webhook.sender_name(),
+        mention,
+        format_pull_request_url(&webhook.pull_request)
+    );
+    let text = if reviewer.is_some()
+        && config()
+            .repo(&webhook.repository)
+            .reping_dismissed_reviewers
+    {
+        format!("{}, could you take another look? 🔁", text)
+    } else {
+        text
+    };
+
+    SlackMessageContent::new().with_blocks(slack_blocks![some_into(
+        SlackSectionBlock::new().with_text(md!("{}", text))
+    )])
+}
+
+fn render_metadata_updated(webhook: &Webhook, changes: &MetadataChanges) -> SlackMessageContent {
+    let labels = |labels: &[String]| {
+        labels
+            .iter()
+            .map(|x| format!("`{}`", x))
+            .collect::<Vec<String>>()
+            .join(", ")
+    };
+
+    let mut summary = Vec::new();
+    if !changes.added_labels.is_empty() {
+        summary.push(format!("🏷️ Added {}", labels(&changes.added_labels)));
+    }
+    if !changes.removed_labels.is_empty() {
+        summary.push(format!("🏷️ Removed {}", labels(&changes.removed_labels)));
+    }
+    match &changes.milestone {
+        Some(Some(milestone)) => summary.push(format!("🎯 Milestone set to *{}*", milestone)),
+        Some(None) => summary.push("🎯 Milestone removed".to_string()),
+        None => {}
+    }
+
+    SlackMessageContent::new().with_blocks(slack_blocks![
+        some_into(SlackSectionBlock::new().with_text(md!(
+            "{} updated {}",
+            webhook.sender_name(),
+            format_pull_request_url(&webhook.pull_request)
+        ))),
+        some_into(SlackContextBlock::new(vec![md!("{}", summary.join(" · "))]))
+    ])
+}
+
+/// Everyone @-mentioned in a comment, once each and in order, besides in quoted lines
+pub fn mentioned_usernames(body: &str) -> Vec<&str> {
+    let users = body
+        .lines()
+        .filter_map(|line| {
+            let line = line.trim_start();
+            if line.starts_with(">") {
+                None
+            } else {
+                Some(line)
+            }
+        })
+        .flat_map(|x| x.split_whitespace())
+        .filter_map(|x| {
+            if x.starts_with("@") {
+                Some(x.trim_start_matches("@"))
+            } else {
+                None
+            }
+        });
+
+    let mut seen = HashSet::new();
+    users.filter(|user| seen.insert(*user)).collect()
+}
+
+fn dedup_slack_users(mut users: Vec<SlackUser>) -> Vec<SlackUser> {
+    let mut seen = HashSet::new();
+    users.retain(|user| seen.insert(user.id.clone()));
+    users
+}
+
+fn render_comment(slack_message: &MySlackMessage, comment: &Comment) -> SlackMessageContent {
+    /* Anyone who couldn't be resolved is still named by their username */
+    let mentions = dedup_slack_users(slack_message.slack_user.clone())
+        .into_iter()
+        .map(|x| x.id.to_slack_format())
+        .chain(
+            slack_message
+                .unresolved
+                .iter()
+                .map(|user| user.username.clone()),
+        )
+        .collect::<Vec<String>>()
+        .join(" ");
+
+    let edited = comment.new_mentions.is_some();
+    let what = if edited {
+        "an edited comment"
+    } else {
+        "a comment"
+    };
+
+    /* Without a link to the comment itself, the PR is the next best thing */
+    let pull_request = &slack_message.webhook.pull_request;
+    let link = match &comment.url {
+        Some(url) => format!(
+            "<{}|{}> on {}",
+            url,
+            what,
+            format_pull_request_url(pull_request)
+        ),
+        None => format!("{} on {}", what, format_pull_request_url(pull_request)),
+    };
+
+    let excerpt = quote_excerpt(&comment.body, &slack_message.webhook.repository);
+    let diff_hunk = comment
+        .diff_hunk
+        .as_deref()
+        .map(|hunk| format_diff_hunk(comment.path.as_deref(), hunk))
+        .filter(|hunk| !hunk.is_empty());
+
+    SlackMessageContent::new().with_blocks(slack_blocks![
+        some_into(SlackSectionBlock::new().with_text(
+            /* Participants weren't necessarily mentioned, just kept in the loop, though an edit
+             * only pings whoever it newly mentions */
+            if config().repo(&slack_message.webhook.repository).notify_participants && !edited {
+                md!("{}, {} left {}", mentions, slack_message.webhook.sender_name(), link)
+            } else {
+                md!("{}, you were mentioned in {}", mentions, link)
+            }
+        )),
+        optionally_into(diff_hunk.is_some() => SlackSectionBlock::new().with_text(md!("{}", diff_hunk.unwrap_or_default()))),
+        optionally_into(excerpt.is_some() => SlackSectionBlock::new().with_text(md!("{}", excerpt.unwrap_or_default())))
+    ])
+}
+
+/// How many lines of a diff hunk are shown, counting back from the commented line
+const DIFF_HUNK_LINES: usize = 6;
+/// Keeps minified code and the like from blowing up the message
+const MAX_DIFF_LINE_LEN: usize = 120;
+
+/// The end of a comment's diff hunk as a Slack code block, headed by the file it's in
+fn format_diff_hunk(path: Option<&str>, hunk: &str) -> String {
+    let lines = hunk
+        .lines()
+        .filter(|line| !line.starts_with("@@"))
+        .collect::<Vec<_>>();
+    if lines.is_empty() {
+        return String::new();
+    }
+
+    let skipped = lines.len().saturating_sub(DIFF_HUNK_LINES);
+    let code = lines[skipped..]
+        .iter()
+        .map(|line| block_kit::truncate(line, MAX_DIFF_LINE_LEN))
+        .collect::<Vec<_>>()
+        .join("\n")
+        /* Slack treats these as control characters, even in code blocks */
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        /* A zero-width space keeps the code from closing its own block */
+        .replace("```", "`\u{200b}``");
+
+    let code = if skipped > 0 {
+        format!("…\n{}", code)
+    } else {
+        code
+    };
+
+    match path {
+        Some(path) => format!("`{}`\n```{}```", path.replace('`', "'"), code),
+        None => format!("```{}```", code),
+    }
+}
+
+/// Excerpt length used when a repo doesn't configure one
+const DEFAULT_EXCERPT_LEN: usize = 200;
+
+pub fn excerpt_len(repository: &Repository) -> usize {
+    config()
+        .repo(repository)
+        .excerpt_len
+        .unwrap_or(DEFAULT_EXCERPT_LEN)
+}
+
+static MARKDOWN_LINK: OnceLock<Regex> = OnceLock::new();
+static MARKDOWN_EMPHASIS: OnceLock<Regex> = OnceLock::new();
+
+/// Flattens a markdown body into a single line of at most `max_len` characters, cut at a word
+/// boundary. Quotes and fenced code blocks are skipped, links and emphasis are reduced to their
+/// text, and inline code is kept as `code`.
+pub fn plain_excerpt(body: &str, max_len: usize) -> String {
+    let markdown_link = MARKDOWN_LINK.get_or_init(|| {
+        Regex::new(r"!?\[([^\]]*)\]\([^)]*\)").expect("Markdown link regex should be valid")
+    });
+    let markdown_emphasis = MARKDOWN_EMPHASIS.get_or_init(|| {
+        Regex::new(
+            r"\*\*(\S(?:.*?\S)?)\*\*|__(\S(?:.*?\S)?)__|~~(\S(?:.*?\S)?)~~|\*(\S(?:.*?\S)?)\*",
+        )
+        .expect("Markdown emphasis regex should be valid")
+    });
+
+    let mut in_code_block = false;
+    let mut lines = Vec::new();
+    for line in body.lines().map(str::trim) {
+        if line.starts_with("```") || line.starts_with("~~~") {
+            in_code_block = !in_code_block;
+            continue;
+        }
+        if in_code_block || line.starts_with('>') {
+            continue;
+        }
+        lines.push(line.trim_start_matches('#'));
+    }
+
+    let text = lines.join(" ");
+    let text = markdown_link.replace_all(&text, "$1");
+    let text = markdown_emphasis.replace_all(&text, |captures: &regex::Captures| {
+        /* Exactly one alternative matched, so exactly one group is set */
+        captures
+            .iter()
+            .skip(1)
+            .flatten()
+            .map(|inner| inner.as_str().to_owned())
+            .collect::<String>()
+    });
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+
+    match text.char_indices().nth(max_len) {
+        None => text,
+        Some((end, next)) => {
+            let cut = &text[..end];
+            /* Only back up to a word boundary if that doesn't lose most of the excerpt */
+            let cut = match cut.rfind(' ') {
+                Some(space) if next != ' ' && space > end / 2 => &cut[..space],
+                _ => cut,
+            };
+            let cut = cut.trim_end();
+
+            /* Don't leave inline code open, or the rest of the message renders as code */
+            if cut.matches('`').count() % 2 == 1 {
+                format!("{}`…", cut)
+            } else {
+                format!("{}…", cut)
+            }
+        }
+    }
+}
+
+/// A [`plain_excerpt`] ready for quoting in Slack
+pub fn format_excerpt(body: &str, max_len: usize) -> String {
+    /* Slack treats these as control characters, even in mrkdwn */
+    slack_emoji(&plain_excerpt(body, max_len))
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// An excerpt of a body as a Slack quote, or nothing if there's nothing to quote
+fn quote_excerpt(body: &str, repository: &Repository) -> Option<String> {
+    let excerpt = format_excerpt(body, excerpt_len(repository));
+    (!excerpt.is_empty()).then(|| format!(">{}", excerpt))
+}
+
+fn render_reviewed(slack_message: &MySlackMessage, review: &Review) -> SlackMessageContent {
+    let user = if let Some(user) = slack_message.slack_user.first() {
+        user.id.to_slack_format()
+    } else {
+        slack_message.webhook.pull_request.user.username.to_string()
+    };
+
+    let excerpt = match review {
+        Review::Rejected { content } => quote_excerpt(content, &slack_message.webhook.repository),
+        _ => None,
+    };
+
+    SlackMessageContent::new().with_blocks(slack_blocks![
+        some_into(SlackSectionBlock::new().with_text(md!(
             "{}, {} has {} your PR",
             user,
-            slack_message.webhook.sender.username,
-            review
+            slack_message.webhook.sender_name(),
+            review.phrase(&slack_message.webhook.repository)
+        ))),
+        optionally_into(excerpt.is_some() => SlackSectionBlock::new().with_text(md!("{}", excerpt.unwrap_or_default())))
+    ])
+}
+
+fn render_review_escalation(
+    slack_message: &MySlackMessage,
+    review: &Review,
+) -> SlackMessageContent {
+    let user = if let Some(user) = slack_message.slack_user.first() {
+        user.id.to_slack_format()
+    } else {
+        slack_message.webhook.pull_request.user.username.to_string()
+    };
+
+    SlackMessageContent::new().with_blocks(slack_blocks![some_into(
+        SlackSectionBlock::new().with_text(md!(
+            "{}, {} has {} {}, see the PR's thread for details",
+            user,
+            slack_message.webhook.sender_name(),
+            review.phrase(&slack_message.webhook.repository),
+            format_pull_request_url(&slack_message.webhook.pull_request)
         ))
     )])
 }
 
-fn render_review_requested(slack_message: &MySlackMessage, reviewer: &User) -> SlackMessageContent {
+fn render_review_requested(
+    slack_message: &MySlackMessage,
+    reviewers: &[User],
+) -> SlackMessageContent {
+    let webhook = slack_message.webhook;
+    let (authors, reviewers): (Vec<&User>, Vec<&User>) =
+        reviewers.iter().partition(|x| webhook.is_author(x));
+
+    /* Reviewers that couldn't be resolved to a Slack user are still named by their username */
+    let users = reviewers
+        .iter()
+        .map(|reviewer| {
+            slack_message
+                .slack_user
+                .iter()
+                .find(|user| slack_user_is(user, reviewer))
+                .map(|user| user.id.to_slack_format())
+                .unwrap_or(reviewer.username.to_string())
+        })
+        .collect::<Vec<String>>()
+        .join(", ");
+
+    let repo_config = config().repo(&webhook.repository);
+    let self_review_request = repo_config.self_review_request;
+    let mut blocks = slack_blocks![
+        optionally_into(!reviewers.is_empty() => SlackSectionBlock::new().with_text(md!(
+            "{}, {} has requested you to review {}",
+            users,
+            webhook.sender_name(),
+            format_pull_request_url(&webhook.pull_request)
+        ))),
+        optionally_into(
+            !authors.is_empty() && self_review_request == SelfReviewRequest::Note =>
+                SlackSectionBlock::new().with_text(md!(
+                    "A review of {} was requested from its author, {}, which is likely a mistake",
+                    format_pull_request_url(&webhook.pull_request),
+                    webhook.pull_request.user.username
+                ))
+        )
+    ];
+
+    if !reviewers.is_empty() {
+        if let Some(checklist) = render_review_checklist(&repo_config.review_checklist) {
+            blocks.push(checklist.into());
+        }
+    }
+
+    SlackMessageContent::new().with_blocks(blocks)
+}
+
+/// The repo's review checklist as one line of unticked boxes, `None` if it hasn't one
+fn render_review_checklist(items: &[String]) -> Option<SlackContextBlock> {
+    if items.is_empty() {
+        return None;
+    }
+
+    let checklist = items
+        .iter()
+        .map(|item| format!("☐ {}", item))
+        .collect::<Vec<String>>()
+        .join("   ");
+    Some(SlackContextBlock::new(vec![md!("{}", checklist)]))
+}
+
+/// Explains why the reviewer was picked, where the payload says and it's enabled
+fn with_review_reason(
+    content: SlackMessageContent,
+    repository: &Repository,
+    reason: Option<&str>,
+    round: Option<u32>,
+) -> SlackMessageContent {
+    if !config().repo(repository).show_review_reason {
+        return content;
+    }
+
+    let explanation = match (reason, round) {
+        (Some(reason), Some(round)) => format!("Assigned as {} for review round {}", reason, round),
+        (Some(reason), None) => format!("Assigned as {}", reason),
+        (None, Some(round)) => format!("Review round {}", round),
+        (None, None) => return content,
+    };
+
+    let mut blocks = content.blocks.unwrap_or_default();
+    blocks.push(SlackContextBlock::new(vec![md!("{}", explanation)]).into());
+
+    SlackMessageContent::new().with_blocks(blocks)
+}
+
+/// Whether a resolved Slack user is the Gitea user, by email or, where they were resolved by
+/// name, their full name
+fn slack_user_is(user: &SlackUser, gitea_user: &User) -> bool {
+    slack_user_has_email(user, &gitea_user.email) || slack::has_name(user, &gitea_user.full_name)
+}
+
+fn has_draft_prefix(repository: &Repository, title: &str) -> bool {
+    let title = title.trim_start().to_lowercase();
+    config()
+        .repo(repository)
+        .draft_title_prefixes
+        .iter()
+        .any(|prefix| title.starts_with(&prefix.to_lowercase()))
+}
+
+fn slack_user_has_email(user: &SlackUser, email: &str) -> bool {
+    user.profile
+        .as_ref()
+        .and_then(|profile| profile.email.as_ref())
+        .is_some_and(|x| x.0.eq_ignore_ascii_case(email))
+}
+
+fn render_now_mergeable(slack_message: &MySlackMessage) -> SlackMessageContent {
     let user = if let Some(user) = slack_message.slack_user.first() {
         user.id.to_slack_format()
     } else {
-        reviewer.username.to_string()
+        slack_message.webhook.pull_request.user.username.to_string()
     };
 
     SlackMessageContent::new().with_blocks(slack_blocks![some_into(
         SlackSectionBlock::new().with_text(md!(
-            "{}, {} has requested you to review {}",
+            "{}, {} is now mergeable ✅",
             user,
-            slack_message.webhook.sender.username,
             format_pull_request_url(&slack_message.webhook.pull_request)
         ))
     )])
 }
 
+fn render_force_pushed(
+    slack_message: &MySlackMessage,
+    rebase_only: bool,
+    now_mergeable: bool,
+) -> SlackMessageContent {
+    let webhook = slack_message.webhook;
+    let reviewers = slack_message
+        .slack_user
+        .iter()
+        .map(|x| x.id.to_slack_format())
+        .chain(slack_message.unresolved.iter().map(|x| x.username.clone()))
+        .collect::<Vec<String>>();
+
+    let what = if rebase_only {
+        "rebasing it without adding commits"
+    } else {
+        "rewriting its history"
+    };
+    let mut text = format!(
+        "⚠️ {} force-pushed {}, {}",
+        webhook.sender_name(),
+        format_pull_request_url(&webhook.pull_request),
+        what
+    );
+    if !reviewers.is_empty() {
+        text += &format!(". {}, your review may be out of date", reviewers.join(", "));
+    }
+    if now_mergeable {
+        text += ". It's now mergeable ✅";
+    }
+
+    SlackMessageContent::new().with_blocks(slack_blocks![some_into(
+        SlackSectionBlock::new().with_text(md!("{}", text))
+    )])
+}
+
+/// A re-request within the cooldown, naming reviewers without pinging them
+pub fn render_quiet_rerequest(reviewers: &[User]) -> SlackMessageContent {
+    let reviewers = reviewers
+        .iter()
+        .map(|x| x.username.clone())
+        .collect::<Vec<String>>()
+        .join(", ");
+
+    SlackMessageContent::new().with_blocks(slack_blocks![some_into(SlackContextBlock::new(vec![
+        md!(
+            "🔁 Review re-requested from {}, not pinged again so soon",
+            reviewers
+        )
+    ]))])
+}
+
+/// The root of a day's thread, e.g. "📅 PR activity for Wednesday 14 October"
+pub fn render_daily_thread(day: NaiveDate) -> SlackMessageContent {
+    SlackMessageContent::new().with_blocks(slack_blocks![some_into(
+        SlackSectionBlock::new().with_text(md!("📅 *PR activity for {}*", day.format("%A %-d %B")))
+    )])
+}
+
+pub fn render_branch_deleted(branch: &str, sender: &str) -> SlackMessageContent {
+    SlackMessageContent::new().with_blocks(slack_blocks![some_into(SlackContextBlock::new(vec![
+        md!("🧹 Branch `{}` was deleted by {}", branch, sender)
+    ]))])
+}
+
+/// e.g. "📦 alice published widgets@1.4.0"
+pub fn render_package_published(package: &Package) -> SlackMessageContent {
+    let repository = match &package.repository {
+        Some(repository) => format!(" in {}", repository.full_name),
+        None => String::new(),
+    };
+
+    SlackMessageContent::new().with_blocks(slack_blocks![
+        some_into(SlackSectionBlock::new().with_text(md!(
+            "📦 {} published <{}|{}@{}>",
+            package.creator.username,
+            package.html_url,
+            package.name,
+            package.version
+        ))),
+        some_into(SlackContextBlock::new(vec![md!(
+            "{} package{}",
+            package.kind,
+            repository
+        )]))
+    ])
+}
+
+/// e.g. "💬 5 new comments on PR, mentioning @alice, @bob"
+pub fn render_comment_summary(
+    pull_request: &PullRequest,
+    comments: usize,
+    mentions: &[String],
+    latest: Option<&Url>,
+) -> SlackMessageContent {
+    let latest = match latest {
+        Some(latest) => format!(" · <{}|Latest comment>", latest),
+        None => String::new(),
+    };
+
+    SlackMessageContent::new().with_blocks(slack_blocks![some_into(
+        SlackSectionBlock::new().with_text(md!(
+            "💬 {} new comments on {}, mentioning {}{}",
+            comments,
+            format_pull_request_url(pull_request),
+            mentions.join(", "),
+            latest
+        ))
+    )])
+}
+
+pub fn render_ready_to_merge(author: &str, pull_request: &PullRequest) -> SlackMessageContent {
+    SlackMessageContent::new().with_blocks(slack_blocks![some_into(
+        SlackSectionBlock::new().with_text(md!(
+            "{}, {} is approved and ready to merge 🎉",
+            author,
+            format_pull_request_url(pull_request)
+        ))
+    )])
+}
+
+pub fn render_merge_conflict(
+    author: &str,
+    pull_request: &PullRequest,
+    merged_link: &str,
+    base: &str,
+) -> SlackMessageContent {
+    SlackMessageContent::new().with_blocks(slack_blocks![some_into(
+        SlackSectionBlock::new().with_text(md!(
+            "{}, {} now has conflicts with `{}` after {} was merged ⚠️",
+            author,
+            format_pull_request_url(pull_request),
+            base,
+            merged_link
+        ))
+    )])
+}
+
+pub fn render_ci_failed(
+    author: &str,
+    pull_request: &PullRequest,
+    failures: &[&CommitStatus],
+    held_back: bool,
+) -> SlackMessageContent {
+    let failures = failures
+        .iter()
+        .map(|status| match &status.target_url {
+            Some(target_url) if !target_url.is_empty() => {
+                format!("<{}|{}>", target_url, status.context)
+            }
+            _ => status.context.clone(),
+        })
+        .collect::<Vec<String>>()
+        .join(", ");
+
+    let consequence = if held_back {
+        ", so its reviewers haven't been notified yet"
+    } else {
+        ""
+    };
+
+    SlackMessageContent::new().with_blocks(slack_blocks![some_into(
+        SlackSectionBlock::new().with_text(md!(
+            "{}, CI failed on {} ({}) ❌{}",
+            author,
+            format_pull_request_url(pull_request),
+            failures,
+            consequence
+        ))
+    )])
+}
+
+pub fn render_review_reminder(reviewer: &str, pull_request_link: &str) -> SlackMessageContent {
+    SlackMessageContent::new().with_blocks(slack_blocks![some_into(
+        SlackSectionBlock::new().with_text(md!(
+            "{}, friendly reminder that your review is still requested on {} ⏰",
+            reviewer,
+            pull_request_link
+        ))
+    )])
+}
+
+/// Pings whoever's been asked to chase a review that's still outstanding, e.g. the reviewer's
+/// lead or `<!here>`
+pub fn render_review_chase(
+    chaser: &str,
+    reviewer: &str,
+    pull_request_link: &str,
+) -> SlackMessageContent {
+    SlackMessageContent::new().with_blocks(slack_blocks![some_into(
+        SlackSectionBlock::new().with_text(md!(
+            "{}, {} is still waiting on a review from {}, could someone pick it up? 📣",
+            chaser,
+            pull_request_link,
+            reviewer
+        ))
+    )])
+}
+
+pub fn render_review_stale(reviewer: &str, pull_request_link: &str) -> SlackMessageContent {
+    SlackMessageContent::new().with_blocks(slack_blocks![some_into(
+        SlackSectionBlock::new().with_text(md!(
+            "🕸️ {} has gone stale waiting on a review from {}",
+            pull_request_link,
+            reviewer
+        ))
+    )])
+}
+
+/// Gitea (gemoji) shortcodes which Slack knows by another name. Any other shortcode is either
+/// shared by both or unknown to Slack, and is left alone.
+const EMOJI_ALIASES: [(&str, &str); 15] = [
+    ("shipit", "squirrel"),
+    ("robot", "robot_face"),
+    ("hugs", "hugging_face"),
+    ("thinking", "thinking_face"),
+    ("rofl", "rolling_on_the_floor_laughing"),
+    ("man_technologist", "male-technologist"),
+    ("woman_technologist", "female-technologist"),
+    ("construction_worker_man", "male-construction-worker"),
+    ("construction_worker_woman", "female-construction-worker"),
+    ("detective", "sleuth_or_spy"),
+    ("man_shrugging", "man-shrugging"),
+    ("woman_shrugging", "woman-shrugging"),
+    ("man_facepalming", "man-facepalming"),
+    ("woman_facepalming", "woman-facepalming"),
+    ("medal_sports", "sports_medal"),
+];
+
+static EMOJI_SHORTCODE: OnceLock<Regex> = OnceLock::new();
+
+/// Translates Gitea emoji shortcodes into the names Slack renders them by
+pub fn slack_emoji(text: &str) -> String {
+    let emoji_shortcode = EMOJI_SHORTCODE.get_or_init(|| {
+        Regex::new(r":([a-z0-9_+-]+):").expect("Emoji shortcode regex should be valid")
+    });
+
+    emoji_shortcode
+        .replace_all(text, |captures: &Captures| {
+            match EMOJI_ALIASES
+                .iter()
+                .find(|(gitea, _)| *gitea == &captures[1])
+            {
+                Some((_, slack)) => format!(":{}:", slack),
+                None => captures[0].to_string(),
+            }
+        })
+        .into_owned()
+}
+
+static ISSUE_REFERENCE: OnceLock<Regex> = OnceLock::new();
+
+/// Turns `#42`, `owner/repo#42` and `Closes #42` style references into links to the issue
+fn link_issue_references(body: &str, repository: &Repository) -> String {
+    let issue_reference = ISSUE_REFERENCE.get_or_init(|| {
+        Regex::new(
+            r"(?i)(^|[\s(\[])(?:(close[sd]?|fix(?:e[sd])?|resolve[sd]?):?\s+)?([\w.-]+/[\w.-]+)?#(\d+)\b",
+        )
+        .expect("Issue reference regex should be valid")
+    });
+
+    issue_reference
+        .replace_all(body, |captures: &Captures| {
+            let repo = captures
+                .get(3)
+                .map_or(repository.full_name.as_str(), |x| x.as_str());
+
+            let mut url = repository.url.clone();
+            url.set_path(&format!("{}/issues/{}", repo, &captures[4]));
+
+            let reference = match captures.get(3) {
+                Some(repo) => format!("<{}|{}#{}>", url, repo.as_str(), &captures[4]),
+                None => format!("<{}|#{}>", url, &captures[4]),
+            };
+
+            match captures.get(2) {
+                Some(keyword) => format!("{}🔗 {} {}", &captures[1], keyword.as_str(), reference),
+                None => format!("{}{}", &captures[1], reference),
+            }
+        })
+        .into_owned()
+}
+
 fn render_pr_opened(webhook: &Webhook) -> SlackMessageContent {
     let repo_name = webhook
         .repository
@@ -331,15 +1993,16 @@ fn render_pr_opened(webhook: &Webhook) -> SlackMessageContent {
         .split_once("/")
         .expect("Invalid full_name field!");
 
-    let body = webhook
-        .pull_request
-        .body
+    let body = link_issue_references(&webhook.pull_request.body, &webhook.repository);
+    let body = slack_emoji(&body)
         .split_inclusive("\n")
         .map(|line| ">".to_string() + line)
         .collect::<Vec<String>>()
         .join("");
+    /* A long description would otherwise get the whole message rejected */
+    let body = block_kit::truncate(&body, block_kit::MAX_SECTION_TEXT_LEN);
 
-    SlackMessageContent::new().with_blocks(slack_blocks![
+    let mut blocks = slack_blocks![
         some_into(SlackHeaderBlock::new(pt!(
             "{} | {}",
             repo_name.0,
@@ -348,12 +2011,760 @@ fn render_pr_opened(webhook: &Webhook) -> SlackMessageContent {
         some_into(SlackSectionBlock::new().with_text(md!(
             "Pull request {} opened by {}",
             format_pull_request_url(&webhook.pull_request),
-            webhook.sender.username
+            webhook.sender_name()
         ))),
         some_into(SlackSectionBlock::new().with_text(md!("{}", body)))
-    ])
+    ];
+
+    let repo_config = config().repo(&webhook.repository);
+    if repo_config.show_branches {
+        if let Some(context) = render_branch_context(webhook) {
+            blocks.insert(2, context.into());
+        }
+    }
+    if let Some(fork) = webhook.fork() {
+        let fork = format!("<{}|{}>", fork.url, fork.full_name);
+        let block = if repo_config.flag_forks {
+            SlackSectionBlock::new()
+                .with_text(md!(
+                    "⚠️ *From fork {}*, which needs extra care when reviewing",
+                    fork
+                ))
+                .into()
+        } else {
+            SlackContextBlock::new(vec![md!("🍴 From fork {}", fork)]).into()
+        };
+        blocks.insert(2, block);
+    }
+    if let Some(warning) = render_sensitive_base(webhook, &repo_config.sensitive_branches) {
+        blocks.insert(2, warning.into());
+    }
+
+    SlackMessageContent::new().with_blocks(blocks)
+}
+
+/// A warning that the PR targets one of `sensitive_branches`, e.g. a release branch
+fn render_sensitive_base(
+    webhook: &Webhook,
+    sensitive_branches: &[String],
+) -> Option<SlackSectionBlock> {
+    let base = webhook.pull_request.base.as_ref()?;
+    if sensitive_branches.is_empty() {
+        return None;
+    }
+
+    let sensitive = match paths::glob_set(sensitive_branches) {
+        Ok(sensitive) => sensitive,
+        Err(x) => {
+            tracing::error!("Invalid sensitive_branches glob: \"{}\"", x);
+            return None;
+        }
+    };
+
+    sensitive.is_match(&base.name).then(|| {
+        SlackSectionBlock::new().with_text(md!(
+            "⚠️ *Targets `{}`*, which needs extra care when reviewing",
+            base.name
+        ))
+    })
+}
+
+/// Renders e.g. "feature/x → main (abc1234)" with the SHA linking to the head commit
+fn render_branch_context(webhook: &Webhook) -> Option<SlackContextBlock> {
+    let (head, base) = (
+        webhook.pull_request.head.as_ref()?,
+        webhook.pull_request.base.as_ref()?,
+    );
+
+    let short_sha = head.sha.get(..7).unwrap_or(&head.sha);
+    /* A fork's branch isn't in the repository itself, so is named and linked in the fork */
+    let (head_name, mut commit_url) = match webhook.fork() {
+        Some(fork) => (
+            format!("{}:{}", fork.full_name, head.name),
+            fork.url.clone(),
+        ),
+        None => (head.name.clone(), webhook.repository.url.clone()),
+    };
+    commit_url
+        .path_segments_mut()
+        .ok()?
+        .extend(["commit", head.sha.as_str()]);
+
+    Some(SlackContextBlock::new(vec![md!(
+        "`{}` → `{}` (<{}|{}>)",
+        head_name,
+        base.name,
+        commit_url,
+        short_sha
+    )]))
 }
 
-fn config_env_var(name: &str) -> Result<String, anyhow::Error> {
-    Ok(std::env::var(name)?)
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn webhook(fixture: &str) -> Webhook {
+        serde_json::from_str(fixture).expect("Fixture should deserialise into a Webhook")
+    }
+
+    fn slack_user(id: &str, email: &str) -> SlackUser {
+        serde_json::from_value(serde_json::json!({
+            "id": id,
+            "profile": { "email": email },
+        }))
+        .unwrap()
+    }
+
+    fn render(webhook: &Webhook, slack_user: Vec<SlackUser>) -> SlackMessageContent {
+        MySlackMessage {
+            webhook,
+            slack_user,
+            unresolved: vec![],
+            ci_status: None,
+        }
+        .render_template()
+    }
+
+    #[test]
+    fn old_events_are_detected() {
+        let webhook = webhook(include_str!("../tests/fixtures/review_requested.json"));
+        let updated_at = "2026-03-12T07:12:40Z".parse::<DateTime<Utc>>().unwrap();
+
+        assert!(!webhook.older_than(TimeDelta::hours(1), updated_at + TimeDelta::minutes(59)));
+        assert!(webhook.older_than(TimeDelta::hours(1), updated_at + TimeDelta::minutes(61)));
+
+        let undated = self::webhook(include_str!("../tests/fixtures/opened.json"));
+        assert!(!undated.older_than(TimeDelta::hours(1), updated_at + TimeDelta::days(365)));
+    }
+
+    #[test]
+    fn anonymised_emails_are_recognised() {
+        assert!(looks_anonymised("alice@noreply.gitea.example.com"));
+        assert!(looks_anonymised("12345+alice@users.noreply.github.com"));
+        assert!(looks_anonymised(""));
+        assert!(!looks_anonymised("alice@example.com"));
+        assert!(!looks_anonymised("alice+gitea@example.com"));
+    }
+
+    #[test]
+    fn render_opened() {
+        let webhook = webhook(include_str!("../tests/fixtures/opened.json"));
+        insta::assert_json_snapshot!(render(&webhook, vec![]));
+    }
+
+    #[test]
+    fn render_merged() {
+        let webhook = webhook(include_str!("../tests/fixtures/merged.json"));
+        insta::assert_json_snapshot!(render(&webhook, vec![]));
+    }
+
+    #[test]
+    fn render_closed_without_merge() {
+        let webhook = webhook(include_str!("../tests/fixtures/closed.json"));
+        let alice = slack_user("U0ALICE", "alice@noreply.gitea.example.com");
+        insta::assert_json_snapshot!(render(&webhook, vec![alice]));
+    }
+
+    #[test]
+    fn render_approved() {
+        let webhook = webhook(include_str!("../tests/fixtures/review_approved.json"));
+        let alice = slack_user("U0ALICE", "alice@noreply.gitea.example.com");
+        insta::assert_json_snapshot!(render(&webhook, vec![alice]));
+    }
+
+    #[test]
+    fn render_rejected() {
+        let webhook = webhook(include_str!("../tests/fixtures/review_rejected.json"));
+        let alice = slack_user("U0ALICE", "alice@noreply.gitea.example.com");
+        insta::assert_json_snapshot!(render(&webhook, vec![alice]));
+    }
+
+    #[test]
+    fn render_review_dismissed() {
+        let webhook = webhook(include_str!("../tests/fixtures/review_dismissed.json"));
+        let bob = slack_user("U0BOB", "bob@noreply.gitea.example.com");
+        assert!(matches!(webhook.action, Action::ReviewDismissed { .. }));
+        insta::assert_json_snapshot!(render(&webhook, vec![bob]));
+    }
+
+    #[tokio::test]
+    async fn review_dismissed_without_reviewer_pings_nobody() {
+        let mut payload: serde_json::Value =
+            serde_json::from_str(include_str!("../tests/fixtures/review_dismissed.json")).unwrap();
+        payload
+            .as_object_mut()
+            .unwrap()
+            .remove("requested_reviewer");
+
+        let webhook = serde_json::from_value::<Webhook>(payload).unwrap();
+        assert!(matches!(
+            webhook.action,
+            Action::ReviewDismissed { reviewer: None }
+        ));
+        assert!(webhook.recipients().await.unwrap_or_default().is_empty());
+    }
+
+    #[test]
+    fn render_force_pushed() {
+        let mut webhook = webhook(include_str!("../tests/fixtures/opened.json"));
+        let bob = slack_user("U0BOB", "bob@noreply.gitea.example.com");
+        webhook.action = Action::ForcePushed {
+            rebase_only: true,
+            reviewers: vec![],
+            now_mergeable: false,
+        };
+        insta::assert_json_snapshot!(render(&webhook, vec![bob]));
+    }
+
+    #[test]
+    fn force_pushes_also_making_the_pr_mergeable_say_so() {
+        let mut webhook = webhook(include_str!("../tests/fixtures/opened.json"));
+        let text = |webhook: &Webhook| serde_json::to_string(&render(webhook, vec![])).unwrap();
+
+        webhook.action = Action::ForcePushed {
+            rebase_only: true,
+            reviewers: vec![],
+            now_mergeable: false,
+        };
+        assert!(!text(&webhook).contains("now mergeable"));
+
+        webhook.action = Action::ForcePushed {
+            rebase_only: true,
+            reviewers: vec![],
+            now_mergeable: true,
+        };
+        assert!(text(&webhook).contains("It's now mergeable ✅"));
+        assert_eq!(
+            webhook.summary(),
+            ("⚠️", "force-pushed, now mergeable".to_string())
+        );
+    }
+
+    #[test]
+    fn render_summarised_comments() {
+        let webhook = webhook(include_str!("../tests/fixtures/comment_mention.json"));
+        let latest = "https://gitea.example.com/acme/widgets/pulls/42#issuecomment-7"
+            .parse::<Url>()
+            .unwrap();
+
+        insta::assert_json_snapshot!(render_comment_summary(
+            &webhook.pull_request,
+            5,
+            &["<@U0BOB>".to_string(), "carol".to_string()],
+            Some(&latest)
+        ));
+    }
+
+    #[test]
+    fn sensitive_base_is_flagged() {
+        let webhook = webhook(include_str!("../tests/fixtures/opened.json"));
+        let sensitive = |globs: &[&str]| {
+            let globs = globs.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+            render_sensitive_base(&webhook, &globs).is_some()
+        };
+
+        assert!(sensitive(&["main"]));
+        assert!(sensitive(&["release/*", "ma*"]));
+        assert!(!sensitive(&["release/*"]));
+        assert!(!sensitive(&[]));
+    }
+
+    #[test]
+    fn render_opened_from_fork() {
+        let mut payload: serde_json::Value =
+            serde_json::from_str(include_str!("../tests/fixtures/opened.json")).unwrap();
+        payload["pull_request"]["head"]["repo"] = serde_json::json!({
+            "id": 9,
+            "full_name": "carol/widgets",
+            "html_url": "https://gitea.example.com/carol/widgets",
+        });
+        payload["pull_request"]["base"]["repo"] = payload["repository"].clone();
+
+        let webhook = serde_json::from_value::<Webhook>(payload).unwrap();
+        assert_eq!(
+            webhook.fork().map(|x| x.full_name.as_str()),
+            Some("carol/widgets")
+        );
+        insta::assert_json_snapshot!(render(&webhook, vec![]));
+        insta::assert_json_snapshot!("branch_context_from_fork", render_branch_context(&webhook));
+    }
+
+    #[test]
+    fn only_new_prs_and_review_requests_with_a_broadcast_label_broadcast() {
+        let broadcast_labels = HashMap::from([
+            ("urgent".to_string(), Broadcast::Here),
+            ("production-incident".to_string(), Broadcast::Channel),
+        ]);
+        let label = |name: &str| Label {
+            name: name.to_string(),
+        };
+
+        let mut opened = webhook(include_str!("../tests/fixtures/opened.json"));
+        opened.pull_request.labels = vec![label("backend")];
+        assert_eq!(opened.broadcast_for(&broadcast_labels), None);
+
+        /* Labels match case-insensitively, and the widest broadcast wins */
+        opened.pull_request.labels = vec![label("URGENT")];
+        assert_eq!(
+            opened.broadcast_for(&broadcast_labels),
+            Some(Broadcast::Here)
+        );
+        opened
+            .pull_request
+            .labels
+            .push(label("production-incident"));
+        assert_eq!(
+            opened.broadcast_for(&broadcast_labels),
+            Some(Broadcast::Channel)
+        );
+
+        let mut requested = webhook(include_str!("../tests/fixtures/review_requested.json"));
+        requested.pull_request.labels = vec![label("urgent")];
+        assert_eq!(
+            requested.broadcast_for(&broadcast_labels),
+            Some(Broadcast::Here)
+        );
+
+        let mut merged = webhook(include_str!("../tests/fixtures/merged.json"));
+        merged.pull_request.labels = vec![label("urgent")];
+        assert_eq!(merged.broadcast_for(&broadcast_labels), None);
+    }
+
+    #[test]
+    fn unknown_actions_still_deserialise() {
+        let mut payload: serde_json::Value =
+            serde_json::from_str(include_str!("../tests/fixtures/opened.json")).unwrap();
+        payload["action"] = "frobnicated".into();
+
+        let webhook = serde_json::from_value::<Webhook>(payload.clone())
+            .expect("Unknown action should still deserialise");
+        assert!(matches!(
+            &webhook.action,
+            Action::Unknown { action } if action == "frobnicated"
+        ));
+        assert_eq!(webhook.action.to_string(), "frobnicated");
+
+        /* A known action missing what it needs is still an error */
+        payload["action"] = "reviewed".into();
+        assert!(serde_json::from_value::<Webhook>(payload).is_err());
+    }
+
+    #[test]
+    fn known_actions_are_all_actions() {
+        for known in KNOWN_ACTIONS {
+            let action = serde_json::json!({ "action": known });
+            if let Err(x) = Action::deserialize(&action) {
+                assert!(!x.to_string().starts_with("unknown variant"), "{}", x);
+            }
+        }
+    }
+
+    #[test]
+    fn render_comment_with_mentions() {
+        let webhook = webhook(include_str!("../tests/fixtures/comment_mention.json"));
+        let alice = slack_user("U0ALICE", "alice@noreply.gitea.example.com");
+        let bob = slack_user("U0BOB", "bob@noreply.gitea.example.com");
+        insta::assert_json_snapshot!(render(&webhook, vec![alice, bob]));
+    }
+
+    #[test]
+    fn render_edited_comment_with_new_mentions() {
+        let mut webhook = webhook(include_str!("../tests/fixtures/comment_mention.json"));
+        let Action::Created { comment } = &mut webhook.action else {
+            panic!("Fixture should be a comment");
+        };
+        comment.new_mentions = Some(vec!["bob".to_string()]);
+        let bob = slack_user("U0BOB", "bob@noreply.gitea.example.com");
+        insta::assert_json_snapshot!(render(&webhook, vec![bob]));
+    }
+
+    #[test]
+    fn render_package() {
+        let event: PackageEvent =
+            serde_json::from_str(include_str!("../tests/fixtures/package_published.json"))
+                .expect("Fixture should deserialise into a PackageEvent");
+        insta::assert_json_snapshot!(render_package_published(&event.package));
+    }
+
+    #[test]
+    fn render_review_requested() {
+        let webhook = webhook(include_str!("../tests/fixtures/review_requested.json"));
+        let bob = slack_user("U0BOB", "bob@noreply.gitea.example.com");
+        insta::assert_json_snapshot!(render(&webhook, vec![bob]));
+    }
+
+    #[test]
+    fn render_review_requested_with_ci_status() {
+        let webhook = webhook(include_str!("../tests/fixtures/review_requested.json"));
+        let bob = slack_user("U0BOB", "bob@noreply.gitea.example.com");
+        let ci_status = serde_json::from_value(serde_json::json!({
+            "state": "failure",
+            "statuses": [
+                {
+                    "status": "success",
+                    "context": "ci/build",
+                    "target_url": "https://gitea.example.com/acme/widgets/actions/runs/41",
+                },
+                { "status": "failure", "context": "ci/lint", "target_url": "" },
+                { "status": "pending", "context": "ci/e2e" },
+            ],
+        }))
+        .unwrap();
+
+        insta::assert_json_snapshot!(MySlackMessage {
+            webhook: &webhook,
+            slack_user: vec![bob],
+            unresolved: vec![],
+            ci_status: Some(Arc::new(ci_status)),
+        }
+        .render_template());
+    }
+
+    #[test]
+    fn render_compact_review_requested() {
+        let webhook = webhook(include_str!("../tests/fixtures/review_requested.json"));
+        let bob = slack_user("U0BOB", "bob@noreply.gitea.example.com");
+        insta::assert_json_snapshot!(MySlackMessage {
+            webhook: &webhook,
+            slack_user: vec![bob],
+            unresolved: vec![],
+            ci_status: None,
+        }
+        .render_compact());
+    }
+
+    #[test]
+    fn resolved_senders_are_mentioned() {
+        let mut webhook = webhook(include_str!("../tests/fixtures/review_approved.json"));
+        webhook.sender_mention = Some("<@U0BOB>".to_string());
+
+        let rendered = serde_json::to_string(&render(&webhook, vec![])).unwrap();
+        assert!(rendered.contains("<@U0BOB> has approved your PR"));
+    }
+
+    #[test]
+    fn render_review_checklist_as_one_line() {
+        let items = ["Tests", "Docs", "Security"].map(String::from);
+        insta::assert_json_snapshot!(render_review_checklist(&items));
+        assert!(render_review_checklist(&[]).is_none());
+    }
+
+    #[test]
+    fn render_review_requested_from_author() {
+        let webhook = webhook(include_str!(
+            "../tests/fixtures/review_requested_author.json"
+        ));
+        insta::assert_json_snapshot!(render(&webhook, vec![]));
+    }
+
+    #[test]
+    fn render_comment_with_diff_hunk() {
+        let webhook = webhook(include_str!("../tests/fixtures/comment_diff_hunk.json"));
+        let bob = slack_user("U0BOB", "bob@noreply.gitea.example.com");
+        insta::assert_json_snapshot!(render(&webhook, vec![bob]));
+    }
+
+    #[test]
+    fn render_comment_mentioning_same_user_twice() {
+        let webhook = webhook(include_str!(
+            "../tests/fixtures/comment_duplicate_mention.json"
+        ));
+        let bob = slack_user("U0BOB", "bob@noreply.gitea.example.com");
+        let content = render(&webhook, vec![bob.clone(), bob]);
+
+        insta::assert_json_snapshot!(content);
+    }
+
+    #[test]
+    fn render_ci_failed_links_failing_contexts() {
+        let opened = webhook(include_str!("../tests/fixtures/opened.json"));
+        let status: StatusEvent =
+            serde_json::from_str(include_str!("../tests/fixtures/status_failure.json")).unwrap();
+
+        assert_eq!(status.status.state, CommitState::Failure);
+        insta::assert_json_snapshot!(render_ci_failed(
+            "<@U0ALICE>",
+            &opened.pull_request,
+            &[&status.status],
+            true
+        ));
+    }
+
+    #[test]
+    fn link_issue_references_across_repos() {
+        let webhook = webhook(include_str!("../tests/fixtures/opened.json"));
+        let body = "Fixes #12, relates to acme/gadgets#7 but not a#b or https://x.example.com/#3";
+
+        assert_eq!(
+            link_issue_references(body, &webhook.repository),
+            "🔗 Fixes <https://gitea.example.com/acme/widgets/issues/12|#12>, relates to \
+             <https://gitea.example.com/acme/gadgets/issues/7|acme/gadgets#7> but not a#b or \
+             https://x.example.com/#3"
+        );
+    }
+
+    #[tokio::test]
+    async fn comments_need_someone_to_notify() {
+        let comment = webhook(include_str!("../tests/fixtures/comment_mention.json"));
+        let bob = User {
+            email: "bob@noreply.gitea.example.com".to_string(),
+            username: "bob".to_string(),
+            full_name: String::new(),
+        };
+        let slack_bob = slack_user("U0BOB", "bob@noreply.gitea.example.com");
+
+        assert!(comment.has_audience(std::slice::from_ref(&slack_bob), &[], false));
+        assert!(!comment.has_audience(&[], std::slice::from_ref(&bob), false));
+        assert!(comment.has_audience(&[], std::slice::from_ref(&bob), true));
+        assert!(!comment.has_audience(&[], &[], true));
+
+        let opened = webhook(include_str!("../tests/fixtures/opened.json"));
+        assert!(opened.has_audience(&[], &[], false));
+
+        /* Unresolved recipients are kept to be named, and only resolved ones pinged */
+        let message = comment
+            .to_my_slack_resolved(vec![(bob.clone(), Some(slack_bob)), (bob.clone(), None)])
+            .await
+            .expect("A resolved mention should be notified");
+        assert_eq!(message.slack_user.len(), 1);
+        assert_eq!(message.unresolved.len(), 1);
+        assert!(comment
+            .to_my_slack_resolved(vec![(bob, None)])
+            .await
+            .is_none());
+    }
+
+    #[test]
+    fn merges_sent_as_closes_get_the_merged_reaction() {
+        let reactions = HashMap::from([
+            ("merged".to_string(), "rocket".to_string()),
+            ("closed".to_string(), "wastebasket".to_string()),
+        ]);
+
+        let merged = webhook(include_str!("../tests/fixtures/merged.json"));
+        assert_eq!(
+            merged.reaction(&reactions).map(String::as_str),
+            Some("rocket")
+        );
+
+        let mut closed = webhook(include_str!("../tests/fixtures/closed.json"));
+        assert_eq!(
+            closed.reaction(&reactions).map(String::as_str),
+            Some("wastebasket")
+        );
+        closed.pull_request.merged = true;
+        assert_eq!(
+            closed.reaction(&reactions).map(String::as_str),
+            Some("rocket")
+        );
+
+        let opened = webhook(include_str!("../tests/fixtures/opened.json"));
+        assert_eq!(opened.reaction(&reactions), None);
+    }
+
+    #[test]
+    fn footer_links_are_templated_per_repository_and_joined() {
+        let webhook = webhook(include_str!("../tests/fixtures/opened.json"));
+        let link = |name: &str, url: &str| FooterLink {
+            name: name.to_string(),
+            url: url.to_string(),
+        };
+        let links = [
+            link("Runbook", "https://wiki.example.com/{repo}"),
+            link("Board", "https://board.example.com/{owner}?project={name}"),
+        ];
+
+        let content = with_footer_links(
+            SlackMessageContent::new().with_text("PR".to_string()),
+            &webhook.repository,
+            &links,
+        );
+        assert_eq!(
+            content.blocks.unwrap().last(),
+            Some(
+                &SlackContextBlock::new(vec![md!(
+                    "<https://wiki.example.com/acme/widgets|Runbook> | \
+                 <https://board.example.com/acme?project=widgets|Board>"
+                )])
+                .into()
+            )
+        );
+
+        let content = with_footer_links(
+            SlackMessageContent::new().with_text("PR".to_string()),
+            &webhook.repository,
+            &[],
+        );
+        assert_eq!(content.text.as_deref(), Some("PR"));
+        assert_eq!(content.blocks, None);
+    }
+
+    #[test]
+    fn render_age_of_review_request() {
+        let webhook = webhook(include_str!("../tests/fixtures/review_requested.json"));
+        let now = "2026-03-12T09:30:00Z".parse().unwrap();
+        insta::assert_json_snapshot!(with_age(
+            SlackMessageContent::new(),
+            &webhook.pull_request,
+            &TimestampConfig::default(),
+            now
+        ));
+    }
+
+    #[test]
+    fn slack_emoji_translates_only_known_aliases() {
+        assert_eq!(
+            slack_emoji(":shipit: :rocket: at 12:30:45 :not_an_emoji: :robot:"),
+            ":squirrel: :rocket: at 12:30:45 :not_an_emoji: :robot_face:"
+        );
+    }
+
+    #[test]
+    fn format_excerpt_cuts_long_comments_at_a_word() {
+        let body = "This retry loop looks fine overall, but I think the backoff should be capped.";
+        assert_eq!(
+            format_excerpt(body, 40),
+            "This retry loop looks fine overall, but…"
+        );
+    }
+
+    #[test]
+    fn format_excerpt_handles_emoji() {
+        let body = "🎉🎉 **Great** work :shipit: — just one nit 🙂 below";
+        assert_eq!(format_excerpt(body, 8), "🎉🎉 Great…");
+        assert_eq!(
+            format_excerpt(body, 100),
+            "🎉🎉 Great work :squirrel: — just one nit 🙂 below"
+        );
+    }
+
+    #[test]
+    fn format_excerpt_skips_code_blocks() {
+        let body = "## Suggestion\n\nUse `Duration::from_secs` here:\n\n```rust\nlet delay = 1 << attempt;\n```\n\nSee [the docs](https://docs.rs) & <Vec<T>>";
+        assert_eq!(
+            format_excerpt(body, 200),
+            "Suggestion Use `Duration::from_secs` here: See the docs &amp; &lt;Vec&lt;T&gt;&gt;"
+        );
+        assert_eq!(format_excerpt(body, 27), "Suggestion Use…");
+        assert_eq!(
+            format_excerpt("`SlackClientHyperConnector::new` is deprecated", 20),
+            "`SlackClientHyperCon`…"
+        );
+    }
+
+    #[test]
+    fn rendered_fixtures_are_valid_block_kit() {
+        let alice = slack_user("U0ALICE", "alice@noreply.gitea.example.com");
+        let bob = slack_user("U0BOB", "bob@noreply.gitea.example.com");
+
+        let mut fixtures =
+            std::fs::read_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures"))
+                .expect("Fixtures should be readable")
+                .map(|x| x.expect("Fixture should be readable").path())
+                .collect::<Vec<_>>();
+        fixtures.sort();
+
+        for path in fixtures {
+            let fixture = std::fs::read_to_string(&path).expect("Fixture should be readable");
+            /* Fixtures for other events have renderers of their own */
+            let Ok(webhook) = serde_json::from_str::<Webhook>(&fixture) else {
+                assert!(
+                    serde_json::from_str::<PackageEvent>(&fixture).is_ok()
+                        || serde_json::from_str::<StatusEvent>(&fixture).is_ok(),
+                    "{}: should deserialise into an event",
+                    path.display()
+                );
+                continue;
+            };
+
+            for slack_users in [vec![], vec![alice.clone(), bob.clone()]] {
+                let problems = block_kit::validate(&render(&webhook, slack_users));
+                assert!(problems.is_empty(), "{}: {:?}", path.display(), problems);
+            }
+        }
+    }
+
+    #[test]
+    fn render_opened_with_a_long_body_is_valid_block_kit() {
+        let mut webhook = webhook(include_str!("../tests/fixtures/opened.json"));
+        webhook.pull_request.body = "A very long description. ".repeat(200);
+        assert_eq!(
+            block_kit::validate(&render(&webhook, vec![])),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn render_metadata_updated() {
+        let mut webhook = webhook(include_str!("../tests/fixtures/opened.json"));
+        let previous = Metadata {
+            labels: vec!["wip".to_string()],
+            milestone: Some("v1.1".to_string()),
+        };
+        let current = Metadata {
+            labels: vec!["bug".to_string(), "backend".to_string()],
+            milestone: Some("v1.2".to_string()),
+        };
+
+        assert_eq!(current.changes_since(&current), None);
+        webhook.action = Action::MetadataUpdated {
+            changes: current.changes_since(&previous).unwrap(),
+        };
+        insta::assert_json_snapshot!(render(&webhook, vec![]));
+    }
+
+    #[tokio::test]
+    async fn deanonymising_looks_everyone_up_at_once_and_keeps_emails_it_cant_find() {
+        use axum::{extract::Path, http::StatusCode, response::IntoResponse, routing::get, Json};
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::time::Duration;
+        use tokio::sync::Barrier;
+
+        /* The sender, author and reviewer are each looked up, and none is answered until all
+         * three are in flight, so looking them up in turn would time out instead */
+        let lookups = Arc::new(Barrier::new(3));
+        let all_at_once = Arc::new(AtomicBool::new(true));
+        let in_flight = (lookups, all_at_once.clone());
+        let router = axum::Router::new().route(
+            "/api/v1/users/:username",
+            get(|Path(username): Path<String>| async move {
+                let (lookups, all_at_once) = in_flight;
+                if tokio::time::timeout(Duration::from_secs(10), lookups.wait())
+                    .await
+                    .is_err()
+                {
+                    all_at_once.store(false, Ordering::SeqCst);
+                }
+                match username.as_str() {
+                    "bob" => Json(serde_json::json!({
+                        "email": "bob@example.com",
+                        "username": "bob",
+                        "full_name": "Bob",
+                    }))
+                    .into_response(),
+                    _ => StatusCode::NOT_FOUND.into_response(),
+                }
+            }),
+        );
+
+        let mut webhook = webhook(include_str!("../tests/fixtures/review_requested.json"));
+        webhook.pull_request.url = crate::fake_gitea::serve(router).await;
+
+        let webhook = webhook.try_deanonymise_emails().await;
+        assert!(all_at_once.load(Ordering::SeqCst));
+
+        assert_eq!(webhook.sender.email, "alice@noreply.gitea.example.com");
+        assert_eq!(
+            webhook.pull_request.user.email,
+            "alice@noreply.gitea.example.com"
+        );
+        let Action::ReviewRequested {
+            requested_reviewers,
+            ..
+        } = &webhook.action
+        else {
+            panic!("Fixture should be a review request");
+        };
+        assert_eq!(requested_reviewers[0].email, "bob@example.com");
+    }
 }
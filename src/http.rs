@@ -0,0 +1,76 @@
+use crate::config::config;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::{Certificate, Client};
+use std::collections::HashMap;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+/// How long any one request through the general client may take, so a hung webhook or API
+/// can't hold up whatever's waiting on it
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+static CLIENT: OnceLock<Client> = OnceLock::new();
+static GITEA_CLIENT: OnceLock<Client> = OnceLock::new();
+static GITEA_HEADERS: OnceLock<HeaderMap> = OnceLock::new();
+
+/// The reqwest client shared by outbound requests other than Gitea's and those made through
+/// the Slack client, verifying TLS as usual
+pub fn client() -> &'static Client {
+    CLIENT.get_or_init(|| {
+        Client::builder()
+            .timeout(TIMEOUT)
+            .build()
+            .expect("Failed to build HTTP client")
+    })
+}
+
+/// The reqwest client shared by all outbound Gitea requests
+pub fn gitea_client() -> &'static Client {
+    GITEA_CLIENT.get_or_init(|| build_gitea_client().expect("Failed to build Gitea HTTP client"))
+}
+
+fn build_gitea_client() -> Result<Client, anyhow::Error> {
+    let mut builder = Client::builder();
+
+    /* Self-hosted instances are often behind internal PKI */
+    if let Ok(path) = std::env::var("GITEA_CA_CERT") {
+        let bundle = std::fs::read(&path)?;
+        for cert in Certificate::from_pem_bundle(&bundle)? {
+            builder = builder.add_root_certificate(cert);
+        }
+        tracing::info!("Trusting custom CA certificates from \"{}\"", path);
+    }
+
+    if std::env::var("DANGER_ACCEPT_INVALID_CERTS").is_ok_and(|x| x == "true" || x == "1") {
+        tracing::warn!(
+            "DANGER_ACCEPT_INVALID_CERTS is set, TLS certificates will NOT be verified for Gitea requests"
+        );
+        builder = builder.danger_accept_invalid_certs(true);
+    }
+
+    Ok(builder.build()?)
+}
+
+/// The extra headers configured for requests to Gitea, e.g. for a gateway in front of it
+pub fn gitea_headers() -> &'static HeaderMap {
+    GITEA_HEADERS.get_or_init(|| {
+        parse_headers(&config().outbound_headers.gitea).expect("Invalid outbound_headers.gitea")
+    })
+}
+
+fn parse_headers(headers: &HashMap<String, String>) -> Result<HeaderMap, anyhow::Error> {
+    let mut parsed = HeaderMap::new();
+    for (name, value) in headers {
+        let name = HeaderName::try_from(name.as_str())
+            .map_err(|x| anyhow::anyhow!("\"{}\" isn't a valid header name: {}", name, x))?;
+        let mut value = HeaderValue::from_str(value).map_err(|x| {
+            anyhow::anyhow!("The value of {} isn't a valid header value: {}", name, x)
+        })?;
+
+        /* They're likely to be credentials */
+        value.set_sensitive(true);
+        parsed.insert(name, value);
+    }
+
+    Ok(parsed)
+}
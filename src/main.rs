@@ -1,15 +1,68 @@
+use anyhow::Context;
+use axum::extract::Request;
+use axum::http::{HeaderMap, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
 use axum::Extension;
-use axum::{extract::Json, routing::post, Router};
-use gitea_webhooks::Webhook;
-use serde_json;
+use axum::{
+    extract::{DefaultBodyLimit, Json},
+    routing::{get, post},
+    Router,
+};
+use chrono::{TimeDelta, Utc};
+use gitea_webhooks::{
+    Action, DeleteEvent, Metadata, MetadataChanges, PackageEvent, Repository, RepositoryEvent,
+    StatusEvent, User, Webhook,
+};
+use recent::{Delivery, Outcome, Received};
+use serde::de::DeserializeOwned;
 use slack_morphism::prelude::*;
 use sqlx::postgres::PgPool;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
 use tower_http::trace::TraceLayer;
-use tracing;
-use tracing_appender;
+use tracing::instrument;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
+pub mod aliases;
+pub mod batch;
+pub mod block_kit;
+pub mod channels;
+pub mod check;
+pub mod ci;
+pub mod coalesce;
+pub mod comment_edits;
+pub mod comment_summary;
+pub mod config;
+pub mod config_export;
+pub mod conflicts;
+pub mod db;
+pub mod email;
+#[cfg(test)]
+mod fake_gitea;
+pub mod force_push;
+pub mod gitea_api;
 pub mod gitea_webhooks;
+pub mod http;
+pub mod notification;
+pub mod paths;
+pub mod preview;
+pub mod queue;
+pub mod quiet_hours;
+pub mod ready;
+pub mod recent;
+pub mod reminders;
+pub mod resend;
+pub mod secret;
+pub mod sequence;
+pub mod slack;
+pub mod slack_events;
+pub mod status;
+pub mod teams;
+pub mod telemetry;
+pub mod timestamps;
+pub mod verbose;
 
 const MAX_LOG_FILES: usize = 48;
 
@@ -31,78 +84,1097 @@ async fn main() {
         .with(tracing_subscriber::EnvFilter::from_default_env())
         .with(tracing_subscriber::fmt::layer())
         .with(tracing_subscriber::fmt::layer().with_writer(non_blocking))
+        .with(telemetry::layer())
         .init();
 
+    /* Load the config up front so a malformed file is caught at startup */
+    config::config();
+    http::client();
+    http::gitea_client();
+    http::gitea_headers();
+    secret::load().await.expect("Failed to load secrets");
+
+    if std::env::args().nth(1).as_deref() == Some("check") {
+        let passed = check::run().await;
+        std::process::exit(if passed { 0 } else { 1 });
+    }
+
+    let (listen_addr, webhook_path) = listen_config().expect("Invalid listen configuration");
+    let max_body_bytes = max_body_bytes().expect("Invalid MAX_BODY_BYTES");
+
     let db_pool = PgPool::connect(&construct_db_connection_string())
         .await
         .unwrap();
+    db::upgrade(&db_pool)
+        .await
+        .expect("Failed to upgrade the database");
+
+    queue::spawn_workers(db_pool.clone());
 
     let app = Router::new()
-        .route("/", post(post_handler))
+        .route(&webhook_path, post(post_handler))
+        .route("/resend/:owner/:name/:number", post(resend::resend_handler))
+        .route("/preview", post(preview::preview_handler))
+        .route("/recent", get(recent::recent_handler))
+        .route("/config", get(config_export::config_handler))
+        .route("/slack/events", post(slack_events::events_handler))
+        .route_layer(middleware::from_fn(require_ready))
+        /* Over-long bodies are turned away with a 413 as soon as they pass the limit, rather
+         * than once they've been read in full */
+        .layer(DefaultBodyLimit::max(max_body_bytes))
         .layer(TraceLayer::new_for_http())
-        .layer(Extension(db_pool));
+        .layer(Extension(db_pool.clone()));
+
+    let listener = tokio::net::TcpListener::bind(listen_addr).await.unwrap();
 
-    let bind_addr = std::env::var("BIND_ADDRESS").expect("A binding address is required");
-    let listener = tokio::net::TcpListener::bind(bind_addr).await.unwrap();
+    /* Listen straight away so anything arriving during startup is turned away with a 503 and
+     * retried, rather than the connection being refused */
+    tokio::spawn(async move {
+        initialise(db_pool).await;
+        READY.store(true, Ordering::Release);
+        tracing::info!("Ready to accept webhooks");
+    });
+
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown_signal())
+        .await
+        .unwrap();
 
-    axum::serve(listener, app).await.unwrap();
+    tracing::info!("Shutting down");
+    let _ = tokio::task::spawn_blocking(telemetry::shutdown).await;
 }
 
-async fn post_handler(db: Extension<PgPool>, Json(payload): Json<serde_json::Value>) {
-    tracing::debug!(%payload);
+/// Resolves on Ctrl+C, or the SIGTERM a container is stopped with
+async fn shutdown_signal() {
+    let interrupt = async {
+        let _ = tokio::signal::ctrl_c().await;
+    };
 
-    match serde_json::from_value::<Webhook>(payload) {
-        Ok(webhook) => post_repo_payload(webhook, db).await,
-        Err(x) => tracing::error!("Error decoding JSON payload into Webhook \"{}\"", x),
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(x) => {
+                tracing::warn!("Unable to listen for SIGTERM: \"{}\"", x);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = interrupt => {},
+        _ = terminate => {},
     }
 }
 
-async fn post_repo_payload(payload: Webhook, db: Extension<PgPool>) {
-    let payload = payload.try_deanonymise_emails().await;
+/// The address to listen on from `LISTEN_ADDR`, or `BIND_ADDRESS` as it used to be called, and
+/// the path Gitea posts to from `WEBHOOK_PATH`, `/` by default
+fn listen_config() -> Result<(SocketAddr, String), anyhow::Error> {
+    let listen_addr = config::config_env_var("LISTEN_ADDR")
+        .ok()
+        .filter(|addr| !addr.is_empty())
+        .or_else(|| config::config_env_var("BIND_ADDRESS").ok())
+        .context("A listen address is required in LISTEN_ADDR")?;
+    let listen_addr = listen_addr
+        .to_socket_addrs()
+        .with_context(|| format!("\"{}\" isn't a host:port address", listen_addr))?
+        .next()
+        .with_context(|| format!("\"{}\" doesn't resolve to an address", listen_addr))?;
 
-    let ts = {
-        let rows: Result<Option<(String,)>, sqlx::Error> =
-            sqlx::query_as("SELECT ts FROM threads WHERE url = $1")
-                .bind(payload.pull_request.url.to_string())
-                .fetch_optional(&*db)
-                .await;
+    let webhook_path = config::config_env_var("WEBHOOK_PATH")
+        .ok()
+        .filter(|path| !path.is_empty())
+        .unwrap_or("/".to_string());
+    if !webhook_path.starts_with('/') {
+        anyhow::bail!("WEBHOOK_PATH \"{}\" must start with a /", webhook_path);
+    }
 
-        match rows {
-            Ok(rows) => rows.map(|row| SlackTs::new(row.0)),
-            Err(x) => {
-                tracing::error!(
-                    "Error attempting to retrieve possible timestamp from DB: \"{}\"",
-                    x
-                );
-                None
+    Ok((listen_addr, webhook_path))
+}
+
+/// Generous for Gitea's payloads, which are largest for pushes of many commits
+const DEFAULT_MAX_BODY_BYTES: usize = 4 * 1024 * 1024;
+
+/// The largest request body accepted, from `MAX_BODY_BYTES`
+fn max_body_bytes() -> Result<usize, anyhow::Error> {
+    let Some(max_body_bytes) = config::config_env_var("MAX_BODY_BYTES")
+        .ok()
+        .filter(|bytes| !bytes.is_empty())
+    else {
+        return Ok(DEFAULT_MAX_BODY_BYTES);
+    };
+
+    match max_body_bytes.parse() {
+        Ok(0) | Err(_) => anyhow::bail!(
+            "MAX_BODY_BYTES \"{}\" must be a positive number of bytes",
+            max_body_bytes
+        ),
+        Ok(max_body_bytes) => Ok(max_body_bytes),
+    }
+}
+
+/// Set once startup validation has passed and webhooks can be handled
+static READY: AtomicBool = AtomicBool::new(false);
+
+async fn initialise(db_pool: PgPool) {
+    if config::config().notifier == config::Notifier::Slack {
+        if let Err(x) = slack::validate_token().await {
+            tracing::error!("Slack token is unusable: \"{}\"", x);
+            std::process::exit(1);
+        }
+        slack::warm_users_cache().await;
+    }
+
+    reminders::spawn_escalations(db_pool.clone());
+    quiet_hours::spawn_flusher(db_pool);
+}
+
+async fn require_ready(request: Request, next: Next) -> Response {
+    if !READY.load(Ordering::Acquire) {
+        return StatusCode::SERVICE_UNAVAILABLE.into_response();
+    }
+
+    next.run(request).await
+}
+
+const MAX_PAYLOAD_SNIPPET_LEN: usize = 512;
+const REDACTED_KEYS: [&str; 4] = ["email", "password", "secret", "token"];
+
+const GITEA_EVENT_HEADER: &str = "X-Gitea-Event";
+const GITEA_DELIVERY_HEADER: &str = "X-Gitea-Delivery";
+
+/* The delivery id correlates a trace with the delivery in Gitea's webhook history */
+#[instrument(skip_all, fields(delivery = headers.get(GITEA_DELIVERY_HEADER).and_then(|x| x.to_str().ok())))]
+async fn post_handler(
+    db: Extension<PgPool>,
+    headers: HeaderMap,
+    Json(payload): Json<serde_json::Value>,
+) -> StatusCode {
+    tracing::debug!(payload = %redact(payload.clone()));
+
+    let event = headers
+        .get(GITEA_EVENT_HEADER)
+        .and_then(|x| x.to_str().ok())
+        .unwrap_or_default();
+
+    let received = Received {
+        delivery: headers
+            .get(GITEA_DELIVERY_HEADER)
+            .and_then(|x| x.to_str().ok())
+            .map(str::to_string),
+        at: Instant::now(),
+        time: Utc::now(),
+    };
+
+    /* A critical action may need failing for redelivery, which can't be done once it's queued */
+    if event_is_critical(event, &payload) {
+        return recent::scope(received, handle_event(event, payload, &db)).await;
+    }
+
+    if !queue::enqueue(event, payload, received) {
+        tracing::warn!("Webhook queue is full, asking Gitea to redeliver later");
+        return StatusCode::SERVICE_UNAVAILABLE;
+    }
+
+    StatusCode::OK
+}
+
+fn event_is_critical(event: &str, payload: &serde_json::Value) -> bool {
+    if matches!(event, "repository" | "status" | "delete" | "package") {
+        return false;
+    }
+
+    /* Decoding errors are logged once the event is handled */
+    serde_json::from_value::<Webhook>(payload.clone()).is_ok_and(|webhook| webhook.is_critical())
+}
+
+async fn handle_event(event: &str, payload: serde_json::Value, db: &PgPool) -> StatusCode {
+    let outcome = match event {
+        "repository" => match decode::<RepositoryEvent>(&payload) {
+            Some(event) => {
+                track_repository(&event.repository, db).await;
+                Outcome::Skipped("nothing to notify")
+            }
+            None => undecodable(),
+        },
+        "delete" => match decode::<DeleteEvent>(&payload) {
+            Some(event) => {
+                track_repository(&event.repository, db).await;
+                announce_branch_deletion(&event, db).await
+            }
+            None => undecodable(),
+        },
+        "package" => match decode::<PackageEvent>(&payload) {
+            Some(event) => {
+                if let Some(repository) = &event.package.repository {
+                    track_repository(repository, db).await;
+                }
+                announce_package(&event).await
             }
+            None => undecodable(),
+        },
+        "status" => match decode::<StatusEvent>(&payload) {
+            Some(status) => {
+                track_repository(&status.repository, db).await;
+                ci::handle_status(status, db).await
+            }
+            None => undecodable(),
+        },
+        _ => match decode::<Webhook>(&payload) {
+            Some(webhook) => return handle_webhook(webhook, &payload, db).await,
+            None => undecodable(),
+        },
+    };
+
+    recent::record(Delivery::of_event(event, &payload, outcome));
+    StatusCode::OK
+}
+
+/* The error itself is logged as it's decoded */
+fn undecodable() -> Outcome {
+    Outcome::Failed("couldn't decode the payload".to_string())
+}
+
+async fn handle_webhook(webhook: Webhook, payload: &serde_json::Value, db: &PgPool) -> StatusCode {
+    track_repository(&webhook.repository, db).await;
+    /* Checked on receipt only, as anything held or resent is older by design */
+    let max_event_age = config::config()
+        .repo(&webhook.repository)
+        .max_event_age_secs;
+    let too_old = max_event_age > 0
+        && webhook.older_than(TimeDelta::seconds(max_event_age as i64), Utc::now());
+    if too_old {
+        reminders::cancel_finished(&webhook, db).await;
+        recent::record(Delivery::new(&webhook, Outcome::Skipped("too old")));
+    } else if ci::gate(&webhook, payload, db).await {
+        recent::record(Delivery::new(&webhook, Outcome::Held("waiting on CI")));
+    } else if quiet_hours::hold(&webhook, &payload.to_string(), db).await {
+        recent::record(Delivery::new(&webhook, Outcome::Held("quiet hours")));
+    } else {
+        let held = Delivery::new(&webhook, Outcome::Held("batched with others"));
+        let webhook = match coalesce::coalesce(webhook, db).await {
+            Some(webhook) => comment_summary::hold(webhook, db),
+            None => None,
+        };
+        match webhook {
+            Some(webhook) => return post_repo_payload(webhook, Extension(db.clone())).await,
+            None => recent::record(held),
         }
+    }
+
+    StatusCode::OK
+}
+
+/// Notices a repository being renamed or transferred by its stable id, and moves everything
+/// stored under its old URL across so existing threads keep being replied to
+async fn track_repository(repository: &Repository, db: &PgPool) {
+    /* Payloads from older Gitea versions might not carry an id */
+    if repository.id == 0 {
+        return;
+    }
+
+    let url = repository.url.as_str();
+    match db::swap_repository_url(db, repository.id, url).await {
+        Ok(Some(old_url)) if old_url != url => {
+            tracing::info!("Repository moved from {} to {}", old_url, url);
+            if let Err(x) = db::move_repository(db, &old_url, url).await {
+                tracing::error!("Error attempting to move repository state: \"{}\"", x);
+            }
+        }
+        Ok(_) => {}
+        Err(x) => tracing::error!("Error attempting to record repository URL: \"{}\"", x),
+    }
+}
+
+fn decode<T: DeserializeOwned>(payload: &serde_json::Value) -> Option<T> {
+    match serde_path_to_error::deserialize(payload) {
+        Ok(x) => Some(x),
+        /* Still respond with a 200, otherwise Gitea keeps redelivering an event we can't handle */
+        Err(x) => {
+            tracing::error!(
+                path = %x.path(),
+                snippet = payload_snippet(payload, x.path()),
+                "Error decoding JSON payload into {} \"{}\"",
+                std::any::type_name::<T>().rsplit("::").next().unwrap_or_default(),
+                x.inner()
+            );
+            None
+        }
+    }
+}
+
+/// Renders the deepest part of the payload along `path` with any sensitive values redacted
+fn payload_snippet(payload: &serde_json::Value, path: &serde_path_to_error::Path) -> String {
+    let mut node = payload;
+    for segment in path.iter() {
+        let next = match segment {
+            serde_path_to_error::Segment::Seq { index } => node.get(index),
+            serde_path_to_error::Segment::Map { key } => node.get(key),
+            _ => None,
+        };
+
+        match next {
+            Some(next) => node = next,
+            None => break,
+        }
+    }
+
+    let mut snippet = redact(node.clone()).to_string();
+    if snippet.len() > MAX_PAYLOAD_SNIPPET_LEN {
+        let mut end = MAX_PAYLOAD_SNIPPET_LEN;
+        while !snippet.is_char_boundary(end) {
+            end -= 1;
+        }
+        snippet.truncate(end);
+        snippet.push('…');
+    }
+
+    snippet
+}
+
+fn redact(mut value: serde_json::Value) -> serde_json::Value {
+    match &mut value {
+        serde_json::Value::Object(map) => {
+            /* A user's full name is as personal as their email, unlike a repository's */
+            let is_user = map.contains_key("username");
+
+            for (key, value) in map.iter_mut() {
+                if REDACTED_KEYS.iter().any(|redacted| key.contains(redacted))
+                    || is_user && key == "full_name"
+                {
+                    *value = serde_json::Value::String("***".to_string());
+                } else {
+                    *value = redact(value.take());
+                }
+            }
+        }
+        serde_json::Value::Array(values) => {
+            for value in values.iter_mut() {
+                *value = redact(value.take());
+            }
+        }
+        _ => {}
+    }
+
+    value
+}
+
+/* Only non-identifying fields are recorded so every nested log line can be filtered on them */
+#[instrument(skip_all, fields(repo = %payload.repository.full_name, action = %payload.action))]
+async fn post_repo_payload(mut payload: Webhook, db: Extension<PgPool>) -> StatusCode {
+    verbose::log_webhook(&payload);
+
+    if let Action::Unknown { action } = &payload.action {
+        tracing::warn!(action, "Received an action this doesn't know of yet");
+        if !config::config()
+            .repo(&payload.repository)
+            .notify_unknown_actions
+        {
+            recent::record(Delivery::new(&payload, Outcome::Skipped("unknown action")));
+            return StatusCode::OK;
+        }
+    }
+
+    if config::config()
+        .repo(&payload.repository)
+        .ignores_sender(&payload.sender.username)
+    {
+        reminders::cancel_finished(&payload, &db).await;
+        recent::record(Delivery::new(&payload, Outcome::Skipped("ignored sender")));
+        return StatusCode::OK;
+    }
+
+    /* e.g. a close needs the thread its open creates, so a PR's events are handled in turn */
+    let _sequenced = sequence::lock(payload.pull_request.url.as_str()).await;
+
+    if payload.action.is_metadata() || matches!(payload.action, Action::Opened) {
+        let changes = update_metadata(&payload, &db).await;
+        if payload.action.is_metadata() {
+            let Some(changes) = changes else {
+                recent::record(Delivery::new(&payload, Outcome::Skipped("nothing changed")));
+                return StatusCode::OK;
+            };
+            payload.action = Action::MetadataUpdated { changes };
+        }
+    }
+
+    if !comment_edits::track(&mut payload, &db).await {
+        recent::record(Delivery::new(&payload, Outcome::Skipped("no new mentions")));
+        return StatusCode::OK;
+    }
+
+    /* Still carried on with, so the live status and reminders see the request */
+    let cooling_down = !review_request_cooldown(&mut payload, &db).await;
+
+    let became_mergeable = became_mergeable(&payload, &db).await;
+    /* A rebase resolving conflicts is still worth saying the PR is mergeable again */
+    let rebase_only =
+        !force_push::check(&mut payload, became_mergeable, &db).await && !became_mergeable;
+    let skipped = if cooling_down {
+        Some("reviewers pinged recently")
+    } else if rebase_only {
+        Some("rebase only")
+    } else {
+        None
+    };
+    /* Only the transition back to mergeable is worth a notification */
+    let notify = skipped.is_none()
+        && (!matches!(payload.action, Action::Synchronized) || became_mergeable)
+        && !paths::only_touches_ignored(&payload).await;
+    let live_status = config::config().repo(&payload.repository).live_status;
+
+    if !notify && !live_status {
+        reminders::cancel_finished(&payload, &db).await;
+        store_mergeable_state(&payload, &db).await;
+        let reason = skipped.unwrap_or("not worth notifying");
+        recent::record(Delivery::new(&payload, Outcome::Skipped(reason)));
+        return StatusCode::OK;
+    }
+
+    let mut payload = payload.try_deanonymise_emails().await;
+    payload.resolve_sender().await;
+
+    if config::config().notifier == config::Notifier::Teams {
+        return notify_teams(&payload, notify, skipped, &db).await;
+    }
+
+    let message = if notify {
+        payload.to_my_slack().await
+    } else {
+        None
+    };
+
+    if let Some(message) = &message {
+        verbose::log_resolution(message);
+        if payload.is_critical() && !message.unresolved.is_empty() {
+            tracing::warn!(
+                unresolved = message.unresolved.len(),
+                "Unable to resolve every recipient to a Slack user, failing webhook for redelivery"
+            );
+            recent::record(
+                Delivery::new(
+                    &payload,
+                    Outcome::Failed("unresolved, redelivery".to_string()),
+                )
+                .with_message(message),
+            );
+            return StatusCode::INTERNAL_SERVER_ERROR;
+        }
+    }
+
+    track_progress(&payload, &db).await;
+
+    let thread = match db::fetch_thread(&db, payload.pull_request.url.as_str()).await {
+        Ok(thread) => thread,
+        Err(x) => {
+            tracing::error!(
+                "Error attempting to retrieve possible timestamp from DB: \"{}\"",
+                x
+            );
+            None
+        }
+    };
+
+    let channels = if live_status {
+        Vec::new()
+    } else {
+        routed_channels(&payload, thread.as_ref()).await
+    };
+
+    let ts = if live_status {
+        update_live_status(&payload, &db, thread).await
+    } else {
+        thread.map(|thread| thread.ts)
+    };
+
+    let Some(message) = message else {
+        if notify {
+            tracing::debug!("Nobody to notify");
+        }
+        store_mergeable_state(&payload, &db).await;
+        recent::record(Delivery::new(
+            &payload,
+            Outcome::Skipped(skipped.unwrap_or("nobody to notify")),
+        ));
+        return StatusCode::OK;
+    };
+
+    let sent = message.post_as_dms().await;
+    let message = if sent.is_empty() {
+        message
+    } else {
+        tracing::info!(dms = sent.len(), "Review request sent as DMs");
+        for (_, slack_user, response) in &sent {
+            track_review_request(&payload, &db, response, std::slice::from_ref(slack_user)).await;
+        }
+        let delivered = split_off_reviewers(&mut payload, &sent);
+        record_pings(&payload, &delivered, &db).await;
+
+        let rest = if matches!(
+            &payload.action,
+            Action::ReviewRequested { requested_reviewers, .. } if !requested_reviewers.is_empty()
+        ) {
+            payload.to_my_slack().await
+        } else {
+            None
+        };
+        let Some(message) = rest else {
+            store_mergeable_state(&payload, &db).await;
+            recent::record(Delivery::new(&payload, Outcome::Posted));
+            return StatusCode::OK;
+        };
+        message
+    };
+
+    let response = message
+        .post(channels.first().map(String::as_str), &ts)
+        .await;
+    let outcome = match &response {
+        Ok(_) => Outcome::Posted,
+        Err(x) => Outcome::Failed(x.to_string()),
+    };
+    recent::record(Delivery::new(&payload, outcome).with_message(&message));
+    if response.is_err() {
+        email::escalate(&payload).await;
+    }
+    if let Ok(response) = &response {
+        store_mergeable_state(&payload, &db).await;
+        record_review_request_pings(&payload, &db).await;
+        add_reaction(&payload, response).await;
+        let reviewers = message
+            .slack_user
+            .iter()
+            .map(|user| user.id.clone())
+            .collect::<Vec<_>>();
+        track_review_request(&payload, &db, response, &reviewers).await;
+        message.post_to_feeds().await;
+    }
+    for channel in channels.iter().skip(1) {
+        /* Failures are logged by the post itself */
+        let _ = slack::post_message_in(Some(channel), message.render_template(), &None).await;
+    }
+
+    /* Later replies go wherever the thread is, which may be the fallback channel */
+    let thread = match &response {
+        Ok(response) => Some(db::Thread {
+            ts: ts.clone().unwrap_or(response.ts.clone()),
+            channel: Some(response.channel.clone()),
+        }),
+        Err(_) => ts.clone().map(|ts| db::Thread {
+            ts,
+            channel: channels.first().cloned().map(SlackChannelId::new),
+        }),
     };
 
-    let response = payload.post_slack_message(&ts).await;
     if ts.is_none() {
         if let Ok(response) = response {
-            let resp = sqlx::query("INSERT INTO threads VALUES ($1, $2)")
-                .bind(payload.pull_request.url.as_str())
-                .bind(&response.0)
-                .execute(&*db)
-                .await;
-
-            if let Err(x) = resp {
-                tracing::error!(
-                    "Error attempting to add a new timestamp to the DB: \"{}\"",
-                    x
-                )
-            } else {
-                tracing::info!("Top level Slack Thread created");
+            store_thread(&payload, &db, &response).await;
+        }
+    } else if response.is_ok() && payload.escalates() {
+        /* Failures are logged by the escalation itself */
+        let _ = payload.post_review_escalation(channels.first()).await;
+    }
+
+    ready::announce(&payload, thread.as_ref()).await;
+
+    StatusCode::OK
+}
+
+/// The channels a PR's notification goes to when its repo routes by path or templates its
+/// channel, the first holding its thread, or none for the usual channel
+async fn routed_channels(payload: &Webhook, thread: Option<&db::Thread>) -> Vec<String> {
+    /* Replies follow the thread to wherever it was routed, or fell back to, when it started */
+    if let Some(channel) = thread.and_then(|thread| thread.channel.as_ref()) {
+        return vec![channel.0.clone()];
+    }
+
+    let repo_config = config::config().repo(&payload.repository);
+    if repo_config.path_channels.is_empty() && repo_config.channel_template.template.is_none() {
+        return Vec::new();
+    }
+
+    let channels = paths::route(payload).await;
+    if !channels.is_empty() {
+        return channels;
+    }
+
+    channels::templated(payload).await.into_iter().collect()
+}
+
+/// Brings the status summary at the root of the PR's thread up to date, posting it as the root
+/// if there's no thread yet. Returns the root's timestamp for the event to reply to.
+async fn update_live_status(
+    payload: &Webhook,
+    db: &PgPool,
+    thread: Option<db::Thread>,
+) -> Option<SlackTs> {
+    let url = payload.pull_request.url.as_str();
+
+    let mut status = match db::fetch_status(db, url).await {
+        Ok(status) => status.unwrap_or_default(),
+        Err(x) => {
+            tracing::error!("Error attempting to retrieve PR status from DB: \"{}\"", x);
+            Default::default()
+        }
+    };
+
+    status.apply(payload);
+    /* The reviews applied from the event itself still stand */
+    if let Err(x) = status.refresh_reviews(payload).await {
+        tracing::warn!("Unable to refresh reviews from Gitea: \"{}\"", x);
+    }
+
+    if let Err(x) = db::store_status(db, url, &status).await {
+        tracing::error!("Error attempting to store PR status in DB: \"{}\"", x);
+    }
+
+    match thread {
+        Some(db::Thread {
+            ts,
+            channel: Some(channel),
+        }) => {
+            /* Failures are logged by the update itself, and the event can still be threaded */
+            match slack::update_message(&channel, &ts, status.render()).await {
+                Err(x) if slack::is_too_old_to_update(&x) => {
+                    /* A long-lived PR would otherwise silently stop getting status updates */
+                    tracing::info!("Status message is too old to update, starting a new thread");
+                    let response = slack::post_message(status.render(), &None).await.ok()?;
+                    if let Err(x) =
+                        db::replace_thread(db, url, &response.ts, &response.channel).await
+                    {
+                        tracing::error!("Error attempting to replace thread in DB: \"{}\"", x);
+                    }
+                    Some(response.ts)
+                }
+                _ => Some(ts),
+            }
+        }
+        Some(db::Thread { ts, channel: None }) => {
+            tracing::warn!("Unable to update the status of a thread with no recorded channel");
+            Some(ts)
+        }
+        None => {
+            let response = slack::post_message(status.render(), &None).await.ok()?;
+            store_thread(payload, db, &response).await;
+            Some(response.ts)
+        }
+    }
+}
+
+async fn add_reaction(payload: &Webhook, response: &SlackApiChatPostMessageResponse) {
+    let reactions = &config::config().repo(&payload.repository).reactions;
+
+    if let Some(name) = payload.reaction(reactions) {
+        /* Failures are logged by the reaction itself, and it's purely cosmetic anyway */
+        let _ = slack::add_reaction(&response.channel, &response.ts, name).await;
+    }
+}
+
+async fn store_thread(payload: &Webhook, db: &PgPool, response: &SlackApiChatPostMessageResponse) {
+    let resp = db::insert_thread(
+        db,
+        payload.pull_request.url.as_str(),
+        &response.ts,
+        &response.channel,
+    )
+    .await;
+
+    if let Err(x) = resp {
+        tracing::error!(
+            "Error attempting to add a new timestamp to the DB: \"{}\"",
+            x
+        )
+    } else {
+        tracing::info!("Top level Slack Thread created");
+    }
+}
+
+/// Takes reviewers pinged about the PR within the repo's cooldown off a review request, noting
+/// them in the thread instead if configured to. Returns whether anyone is left to ping, leaving
+/// the request as it was if not.
+async fn review_request_cooldown(payload: &mut Webhook, db: &PgPool) -> bool {
+    let repo_config = config::config().repo(&payload.repository);
+    let cooldown_secs = repo_config.review_request_cooldown_secs;
+    if cooldown_secs == 0 || payload.is_draft() {
+        return true;
+    }
+
+    let url = payload.pull_request.url.to_string();
+    let Action::ReviewRequested {
+        requested_reviewers,
+        ..
+    } = &mut payload.action
+    else {
+        return true;
+    };
+
+    let usernames = requested_reviewers
+        .iter()
+        .map(|reviewer| reviewer.username.clone())
+        .collect::<Vec<_>>();
+    let pinged = match db::fetch_review_request_pings(db, &url, &usernames, cooldown_secs).await {
+        Ok(pinged) => pinged,
+        Err(x) => {
+            /* Better to ping again than not at all */
+            tracing::error!(
+                "Error attempting to retrieve review request pings: \"{}\"",
+                x
+            );
+            Vec::new()
+        }
+    };
+    let (cooling_down, to_ping): (Vec<_>, Vec<_>) = requested_reviewers
+        .drain(..)
+        .partition(|reviewer| pinged.contains(&reviewer.username));
+
+    if !cooling_down.is_empty()
+        && repo_config.rerequest_within_cooldown == config::RerequestWithinCooldown::Note
+    {
+        match db::fetch_thread(db, &url).await {
+            Ok(Some(thread)) => {
+                let message = gitea_webhooks::render_quiet_rerequest(&cooling_down);
+                /* Failures are logged by the post itself */
+                let _ = slack::post_reply(message, Some(&thread)).await;
+            }
+            Ok(None) => {}
+            Err(x) => tracing::error!(
+                "Error attempting to retrieve possible timestamp from DB: \"{}\"",
+                x
+            ),
+        }
+    }
+
+    if to_ping.is_empty() {
+        *requested_reviewers = cooling_down;
+        return false;
+    }
+    *requested_reviewers = to_ping;
+
+    true
+}
+
+/// Starts the cooldown for the reviewers a review request was delivered to
+async fn record_review_request_pings(payload: &Webhook, db: &PgPool) {
+    if let Action::ReviewRequested {
+        requested_reviewers,
+        ..
+    } = &payload.action
+    {
+        record_pings(payload, requested_reviewers, db).await;
+    }
+}
+
+async fn record_pings(payload: &Webhook, reviewers: &[User], db: &PgPool) {
+    let cooldown_secs = config::config()
+        .repo(&payload.repository)
+        .review_request_cooldown_secs;
+    if cooldown_secs == 0 || payload.is_draft() || reviewers.is_empty() {
+        return;
+    }
+
+    let usernames = reviewers
+        .iter()
+        .map(|reviewer| reviewer.username.clone())
+        .collect::<Vec<_>>();
+    let url = payload.pull_request.url.as_str();
+    if let Err(x) = db::record_review_request_pings(db, url, &usernames).await {
+        tracing::error!("Error attempting to record review request pings: \"{}\"", x);
+    }
+}
+
+/// Takes the reviewers a review request was sent to as DMs out of it, leaving the rest to post
+/// to the channel for
+fn split_off_reviewers(
+    payload: &mut Webhook,
+    sent: &[(String, SlackUserId, SlackApiChatPostMessageResponse)],
+) -> Vec<User> {
+    let Action::ReviewRequested {
+        requested_reviewers,
+        ..
+    } = &mut payload.action
+    else {
+        return Vec::new();
+    };
+
+    let (delivered, rest) = std::mem::take(requested_reviewers)
+        .into_iter()
+        .partition(|reviewer| sent.iter().any(|(x, ..)| *x == reviewer.username));
+    *requested_reviewers = rest;
+    delivered
+}
+
+/// Remembers where a review request was posted, where the `reviewers` it asked can
+/// acknowledge it
+async fn track_review_request(
+    payload: &Webhook,
+    db: &PgPool,
+    response: &SlackApiChatPostMessageResponse,
+    reviewers: &[SlackUserId],
+) {
+    if !matches!(payload.action, Action::ReviewRequested { .. })
+        || config::config()
+            .repo(&payload.repository)
+            .acknowledge_reaction
+            .is_none()
+    {
+        return;
+    }
+
+    let resp = db::insert_review_request_message(
+        db,
+        payload.pull_request.url.as_str(),
+        &response.ts,
+        &response.channel,
+        reviewers,
+    )
+    .await;
+
+    if let Err(x) = resp {
+        tracing::error!(
+            "Error attempting to add a review request message to the DB: \"{}\"",
+            x
+        )
+    }
+}
+
+/// As the Slack path of [`post_repo_payload`], once it's been decided whether to notify
+async fn notify_teams(
+    payload: &Webhook,
+    notify: bool,
+    skipped: Option<&'static str>,
+    db: &PgPool,
+) -> StatusCode {
+    let notification = if notify {
+        teams::to_notification(payload).await
+    } else {
+        None
+    };
+
+    if let Some(notification) = &notification {
+        let unresolved = teams::unresolved(notification);
+        if payload.is_critical() && !unresolved.is_empty() {
+            tracing::warn!(
+                unresolved = unresolved.len(),
+                "Unable to resolve every recipient to a Teams user, failing webhook for redelivery"
+            );
+            let outcome = Outcome::Failed("unresolved, redelivery".to_string());
+            recent::record(Delivery::new(payload, outcome));
+            return StatusCode::INTERNAL_SERVER_ERROR;
+        }
+    }
+
+    track_progress(payload, db).await;
+
+    match notification {
+        Some(notification) => match teams::notify(&notification).await {
+            Ok(()) => {
+                record_review_request_pings(payload, db).await;
+                recent::record(Delivery::new(payload, Outcome::Posted));
+            }
+            Err(x) => {
+                recent::record(Delivery::new(payload, Outcome::Failed(x.to_string())));
+                email::escalate(payload).await;
+                return StatusCode::OK;
+            }
+        },
+        None => {
+            let reason = match skipped {
+                Some(reason) => reason,
+                None if notify => "nobody to notify",
+                None => "not worth notifying",
+            };
+            recent::record(Delivery::new(payload, Outcome::Skipped(reason)));
+        }
+    }
+
+    store_mergeable_state(payload, db).await;
+    StatusCode::OK
+}
+
+/// Keeps up with the PR whether or not anyone's notified: its head branch, reminders for its
+/// reviewers and, once merged, the PRs it may have left conflicting
+async fn track_progress(payload: &Webhook, db: &PgPool) {
+    track_head(payload, db).await;
+    reminders::track(payload, db).await;
+    if payload.is_merged() {
+        conflicts::check_after_merge(payload, db);
+    }
+}
+
+/// Remembers the branch each PR is from, where branch deletions are announced, as the delete
+/// event only has the branch to go on
+async fn track_head(payload: &Webhook, db: &PgPool) {
+    if !config::config()
+        .repo(&payload.repository)
+        .announce_branch_deletions
+    {
+        return;
+    }
+    let Some(head) = &payload.pull_request.head else {
+        return;
+    };
+    /* A fork's branches are deleted in the fork, which sends the repository itself nothing */
+    if payload.fork().is_some() {
+        return;
+    }
+
+    let resp = db::store_head(
+        db,
+        payload.repository.url.as_str(),
+        payload.pull_request.url.as_str(),
+        &head.name,
+    )
+    .await;
+    if let Err(x) = resp {
+        tracing::error!("Error attempting to store PR head in DB: \"{}\"", x);
+    }
+}
+
+/// Replies in the thread of the PR a deleted branch was from, if there is one
+async fn announce_branch_deletion(event: &DeleteEvent, db: &PgPool) -> Outcome {
+    if event.ref_type != "branch"
+        || !config::config()
+            .repo(&event.repository)
+            .announce_branch_deletions
+    {
+        return Outcome::Skipped("not announced");
+    }
+
+    let url =
+        match db::fetch_pull_request_url_by_head(db, event.repository.url.as_str(), &event.name)
+            .await
+        {
+            Ok(Some(url)) => url,
+            Ok(None) => return Outcome::Skipped("no PR for the branch"),
+            Err(x) => {
+                tracing::error!("Error attempting to find the PR for a branch: \"{}\"", x);
+                return Outcome::Failed(x.to_string());
             }
+        };
+
+    let thread = match db::fetch_thread(db, &url).await {
+        Ok(Some(thread)) => thread,
+        Ok(None) => return Outcome::Skipped("no thread to reply in"),
+        Err(x) => {
+            tracing::error!(
+                "Error attempting to retrieve possible timestamp from DB: \"{}\"",
+                x
+            );
+            return Outcome::Failed(x.to_string());
+        }
+    };
+
+    let message = gitea_webhooks::render_branch_deleted(&event.name, &event.sender.username);
+    match slack::post_reply(message, Some(&thread)).await {
+        Ok(_) => {
+            tracing::info!("Branch deletion announced");
+            Outcome::Posted
+        }
+        Err(x) => Outcome::Failed(x.to_string()),
+    }
+}
+
+async fn announce_package(event: &PackageEvent) -> Outcome {
+    let package = &event.package;
+    let repo_config = match &package.repository {
+        Some(repository) => config::config().repo(repository),
+        None => config::config().defaults(),
+    };
+    /* Deleting a version isn't news to anyone */
+    if !repo_config.packages.enabled || event.action != "created" {
+        return Outcome::Skipped("not announced");
+    }
+
+    let message = gitea_webhooks::render_package_published(package);
+    match slack::post_message_in(repo_config.packages.channel.as_deref(), message, &None).await {
+        Ok(_) => {
+            tracing::info!(package = package.name, "Package publish announced");
+            Outcome::Posted
+        }
+        Err(x) => {
+            tracing::error!("Error attempting to announce package: \"{}\"", x);
+            Outcome::Failed(x.to_string())
+        }
+    }
+}
+
+/// Records the PR's current labels and milestone, returning how they changed since last time
+async fn update_metadata(payload: &Webhook, db: &PgPool) -> Option<MetadataChanges> {
+    let metadata = Metadata::of(&payload.pull_request);
+
+    match db::swap_metadata(db, payload.pull_request.url.as_str(), &metadata).await {
+        /* Nothing recorded yet is as good as nothing set, to avoid a missed change */
+        Ok(previous) => metadata.changes_since(&previous.unwrap_or_default()),
+        Err(x) => {
+            tracing::error!("Error attempting to update PR metadata in DB: \"{}\"", x);
+            None
         }
     }
 }
 
+/// Whether the PR's `mergeable` state flipped from false to true since it was last stored
+async fn became_mergeable(payload: &Webhook, db: &PgPool) -> bool {
+    if payload.pull_request.mergeable != Some(true) {
+        return false;
+    }
+
+    match db::fetch_mergeable(db, payload.pull_request.url.as_str()).await {
+        Ok(previous) => previous == Some(false),
+        Err(x) => {
+            tracing::error!(
+                "Error attempting to retrieve mergeable state from DB: \"{}\"",
+                x
+            );
+            false
+        }
+    }
+}
+
+/// Records the PR's current `mergeable` state, once the event's been dealt with so a failed
+/// delivery still finds the PR becoming mergeable on redelivery
+async fn store_mergeable_state(payload: &Webhook, db: &PgPool) {
+    let Some(mergeable) = payload.pull_request.mergeable else {
+        return;
+    };
+
+    if let Err(x) = db::swap_mergeable(db, payload.pull_request.url.as_str(), mergeable).await {
+        tracing::error!(
+            "Error attempting to update mergeable state in DB: \"{}\"",
+            x
+        );
+    }
+}
+
 fn construct_db_connection_string() -> String {
-    let pg_password = std::env::var("POSTGRES_PASSWORD").expect("This is a required env var");
+    let pg_password = secret::read("POSTGRES_PASSWORD").expect("This is a required env var");
+    let pg_password = pg_password.expose();
     let pg_db = std::env::var("POSTGRES_DB").expect("This is a required env var");
 
     format!("postgres://postgres:{pg_password}@db/{pg_db}")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn webhooks_are_turned_away_until_ready() {
+        let app = Router::new()
+            .route("/webhook", post(|| async { StatusCode::OK }))
+            .route_layer(middleware::from_fn(require_ready));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/webhook", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let client = reqwest::Client::new();
+        let status = || async { client.post(&url).send().await.unwrap().status() };
+        assert_eq!(status().await, StatusCode::SERVICE_UNAVAILABLE);
+
+        READY.store(true, Ordering::Release);
+        assert_eq!(status().await, StatusCode::OK);
+    }
+}
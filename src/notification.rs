@@ -0,0 +1,68 @@
+use crate::gitea_webhooks::{excerpt_len, plain_excerpt, Action, Review, User, Webhook};
+use url::Url;
+
+/// What a notification says, independently of the chat platform rendering it. Slack builds its
+/// own richer messages, this is for backends that only need the gist.
+#[derive(Debug)]
+pub struct Notification {
+    /// The repository and PR title
+    pub title: String,
+    /// What happened, worded as in compact Slack messages, e.g. "✅ Approved by bob"
+    pub text: String,
+    /// Plain text quoted from the PR, comment or review, if there's anything worth quoting
+    pub excerpt: Option<String>,
+    pub link: Url,
+    /// Who to ping, in order, before any platform-specific resolution
+    pub mentions: Vec<User>,
+}
+
+impl Webhook {
+    /// `None` under the same circumstances as [`Webhook::to_my_slack`] minus the Slack-specific
+    /// ones, e.g. for drafts
+    pub async fn to_notification(&self) -> Option<Notification> {
+        let mentions = self.recipients().await?;
+        Some(self.notification(mentions))
+    }
+
+    pub fn notification(&self, mentions: Vec<User>) -> Notification {
+        let sender = &self.sender.username;
+        let max_len = excerpt_len(&self.repository);
+        let excerpt = |body: &str| Some(plain_excerpt(body, max_len)).filter(|x| !x.is_empty());
+
+        let (emoji, what) = self.summary();
+        let text = format!("{} {} by {}", emoji, capitalise(&what), sender);
+
+        let excerpt = match &self.action {
+            Action::Opened | Action::Edited { .. } => excerpt(&self.pull_request.body),
+            Action::Reviewed {
+                review: Review::Rejected { content },
+            } => excerpt(content),
+            Action::Created { comment } => excerpt(&comment.body),
+            _ => None,
+        };
+
+        let link = match &self.action {
+            Action::Created { comment } => comment.url.clone(),
+            _ => None,
+        };
+
+        Notification {
+            title: format!(
+                "{} | {}",
+                self.repository.full_name, self.pull_request.title
+            ),
+            text,
+            excerpt,
+            link: link.unwrap_or_else(|| self.pull_request.url.clone()),
+            mentions,
+        }
+    }
+}
+
+fn capitalise(text: &str) -> String {
+    let mut chars = text.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
@@ -0,0 +1,233 @@
+use crate::config::{config, PathRouting};
+use crate::gitea_api;
+use crate::gitea_webhooks::{Action, Webhook};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, OnceLock};
+
+/// Changed files are cached per head commit, and dropped wholesale once there are this many
+const MAX_CACHED_COMMITS: usize = 256;
+
+static CHANGED_FILES: OnceLock<Mutex<HashMap<String, Arc<Vec<String>>>>> = OnceLock::new();
+
+/// Whether an opened or now-mergeable notification should be dropped because the PR only
+/// changes files matching the repo's ignored paths. Anything that can't be checked is notified.
+pub async fn only_touches_ignored(webhook: &Webhook) -> bool {
+    if !matches!(webhook.action, Action::Opened | Action::Synchronized) {
+        return false;
+    }
+
+    let ignore_paths = &config().repo(&webhook.repository).ignore_paths;
+    if ignore_paths.is_empty() {
+        return false;
+    }
+
+    let ignored = match glob_set(ignore_paths) {
+        Ok(ignored) => ignored,
+        Err(x) => {
+            tracing::error!("Invalid ignore_paths glob: \"{}\"", x);
+            return false;
+        }
+    };
+
+    let Some(files) = changed_files(webhook).await else {
+        return false;
+    };
+
+    all_ignored(&files, &ignored)
+}
+
+/// Whether there are files, and every one of them is ignored
+fn all_ignored(files: &[String], ignored: &GlobSet) -> bool {
+    !files.is_empty() && files.iter().all(|file| ignored.is_match(file))
+}
+
+/// The channels a PR is routed to by the files it changes, empty if it should go to the
+/// usual channel, including when the files can't be fetched
+pub async fn route(webhook: &Webhook) -> Vec<String> {
+    let repo_config = config().repo(&webhook.repository);
+    if repo_config.path_channels.is_empty() {
+        return Vec::new();
+    }
+
+    let Some(files) = changed_files(webhook).await else {
+        return Vec::new();
+    };
+
+    match pick_channels(&files, &repo_config.path_channels, repo_config.path_routing) {
+        Ok(channels) => channels,
+        Err(x) => {
+            tracing::error!("Invalid path_channels glob: \"{}\"", x);
+            Vec::new()
+        }
+    }
+}
+
+fn pick_channels(
+    files: &[String],
+    path_channels: &HashMap<String, String>,
+    routing: PathRouting,
+) -> Result<Vec<String>, globset::Error> {
+    let mut globs = path_channels.iter().collect::<Vec<_>>();
+    globs.sort();
+    let globs = globs
+        .into_iter()
+        .map(|(glob, channel)| Ok((Glob::new(glob)?.compile_matcher(), channel)))
+        .collect::<Result<Vec<_>, globset::Error>>()?;
+
+    /* A file only counts once towards each channel, however many of its globs it matches */
+    let mut counts = HashMap::<&String, usize>::new();
+    for file in files {
+        let matched = globs
+            .iter()
+            .filter(|(glob, _)| glob.is_match(file))
+            .map(|(_, channel)| *channel)
+            .collect::<HashSet<_>>();
+        for channel in matched {
+            *counts.entry(channel).or_default() += 1;
+        }
+    }
+
+    let mut counts = counts.into_iter().collect::<Vec<_>>();
+    /* Most files first, then by name so ties always go the same way */
+    counts.sort_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then(a.cmp(b)));
+
+    let channels = counts.into_iter().map(|(channel, _)| channel.clone());
+    Ok(match routing {
+        PathRouting::Dominant => channels.take(1).collect(),
+        PathRouting::All => {
+            let mut channels = channels.collect::<Vec<_>>();
+            channels.sort();
+            channels
+        }
+    })
+}
+
+pub fn glob_set(globs: &[String]) -> Result<GlobSet, globset::Error> {
+    let mut builder = GlobSetBuilder::new();
+    for glob in globs {
+        builder.add(Glob::new(glob)?);
+    }
+    builder.build()
+}
+
+/// The files the PR changes as of its head commit, `None` if they can't be fetched
+pub async fn changed_files(webhook: &Webhook) -> Option<Arc<Vec<String>>> {
+    let head = webhook.pull_request.head.as_ref()?;
+    let key = format!("{}@{}", webhook.pull_request.url, head.sha);
+
+    let cache = CHANGED_FILES.get_or_init(Default::default);
+    if let Some(files) = cache.lock().unwrap().get(&key) {
+        return Some(files.clone());
+    }
+
+    let files = gitea_api::fetch_changed_files(
+        &webhook.repository.url,
+        &webhook.repository.full_name,
+        webhook.pull_request.number,
+    )
+    .await
+    .ok()?;
+    let files = Arc::new(files);
+
+    let mut cache = cache.lock().unwrap();
+    if cache.len() >= MAX_CACHED_COMMITS {
+        cache.clear();
+    }
+    cache.insert(key, files.clone());
+
+    Some(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{routing::get, Json, Router};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn only_changes_wholly_within_ignored_paths_are_ignored() {
+        let ignored = glob_set(&["docs/**".to_string(), "*.md".to_string()]).unwrap();
+        let files = |files: &[&str]| files.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+
+        assert!(all_ignored(
+            &files(&["docs/setup.md", "README.md"]),
+            &ignored
+        ));
+        assert!(!all_ignored(
+            &files(&["docs/setup.md", "src/main.rs"]),
+            &ignored
+        ));
+        assert!(!all_ignored(&[], &ignored));
+    }
+
+    #[tokio::test]
+    async fn changed_files_are_fetched_once_per_head() {
+        static REQUESTS: AtomicUsize = AtomicUsize::new(0);
+        let router = Router::new().route(
+            "/api/v1/repos/acme/widgets/pulls/42/files",
+            get(|| async {
+                REQUESTS.fetch_add(1, Ordering::SeqCst);
+                Json(serde_json::json!([{ "filename": "docs/setup.md" }]))
+            }),
+        );
+
+        let mut webhook: Webhook =
+            serde_json::from_str(include_str!("../tests/fixtures/opened.json")).unwrap();
+        let url = crate::fake_gitea::serve(router).await;
+        webhook.repository.url = url.clone();
+        webhook.pull_request.url = url;
+
+        for _ in 0..2 {
+            let files = changed_files(&webhook).await.unwrap();
+            assert_eq!(files.as_slice(), ["docs/setup.md".to_string()]);
+        }
+        assert_eq!(REQUESTS.load(Ordering::SeqCst), 1);
+
+        webhook.pull_request.head.as_mut().unwrap().sha = "a new head".to_string();
+        changed_files(&webhook).await.unwrap();
+        assert_eq!(REQUESTS.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn routed_to_the_dominant_or_every_channel() {
+        let path_channels = HashMap::from([
+            ("services/billing/**".to_string(), "#billing".to_string()),
+            ("services/search/**".to_string(), "#search".to_string()),
+            ("services/*/Cargo.toml".to_string(), "#search".to_string()),
+        ]);
+        let files = [
+            "services/billing/src/invoice.rs",
+            "services/billing/Cargo.toml",
+            "services/search/src/index.rs",
+            "README.md",
+        ]
+        .map(String::from);
+
+        let pick = |routing| pick_channels(&files, &path_channels, routing).unwrap();
+        assert_eq!(pick(PathRouting::Dominant), vec!["#billing"]);
+        assert_eq!(pick(PathRouting::All), vec!["#billing", "#search"]);
+
+        let unmatched = ["docs/index.md".to_string()];
+        assert!(pick_channels(&unmatched, &path_channels, PathRouting::All)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn files_count_once_per_channel_however_its_globs_sort() {
+        let path_channels = HashMap::from([
+            ("services/**".to_string(), "#platform".to_string()),
+            ("services/billing/**".to_string(), "#billing".to_string()),
+            ("services/billing/*.rs".to_string(), "#platform".to_string()),
+        ]);
+        let files =
+            ["services/billing/invoice.rs", "services/billing/Cargo.toml"].map(String::from);
+
+        /* Two files each, so the tie goes to the first by name */
+        assert_eq!(
+            pick_channels(&files, &path_channels, PathRouting::Dominant).unwrap(),
+            vec!["#billing"]
+        );
+    }
+}
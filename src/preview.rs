@@ -0,0 +1,87 @@
+use crate::config::config;
+use crate::gitea_webhooks::Webhook;
+use crate::resend::is_admin;
+use crate::{ci, paths, quiet_hours, slack};
+use axum::extract::Json;
+use axum::http::{HeaderMap, StatusCode};
+use serde::Serialize;
+use tracing::instrument;
+
+/// Who a webhook would notify, and why anyone else wouldn't be
+#[derive(Serialize, Debug)]
+pub struct Preview {
+    /// Whether anything would be posted at all
+    pub would_post: bool,
+    /// Set if the event is dropped before recipients are even considered, e.g. for a draft
+    pub suppressed: bool,
+    /// Why it would be held back to be posted later, e.g. until CI passes
+    pub held: Option<&'static str>,
+    pub recipients: Vec<Recipient>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Recipient {
+    pub username: String,
+    /// After de-anonymising, which is what Slack is searched for
+    pub email: String,
+    pub slack_user_id: Option<String>,
+    /// Why each lookup that was tried failed, in the order they were tried
+    pub failures: Vec<String>,
+}
+
+/// Works out who a webhook payload would notify without posting anything, for diagnosing
+/// missing notifications. Requires `ADMIN_TOKEN` as a bearer token.
+#[instrument(skip_all)]
+pub async fn preview_handler(
+    headers: HeaderMap,
+    Json(payload): Json<serde_json::Value>,
+) -> Result<Json<Preview>, StatusCode> {
+    if !is_admin(&headers) {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    let webhook = crate::decode::<Webhook>(&payload).ok_or(StatusCode::BAD_REQUEST)?;
+    let webhook = webhook.try_deanonymise_emails().await;
+
+    let Some(recipients) = webhook.recipients().await else {
+        return Ok(Json(Preview {
+            would_post: false,
+            suppressed: true,
+            held: None,
+            recipients: Vec::new(),
+        }));
+    };
+
+    let resolve_by_name = config().repo(&webhook.repository).resolve_by_name;
+
+    let mut resolved = Vec::new();
+    let mut previews = Vec::new();
+    for recipient in recipients {
+        let (slack_user, failures) = slack::explain_resolution(&recipient, resolve_by_name).await;
+        previews.push(Recipient {
+            username: recipient.username.clone(),
+            email: recipient.email.clone(),
+            slack_user_id: slack_user.as_ref().map(|user| user.id.to_string()),
+            failures,
+        });
+        resolved.push((recipient, slack_user));
+    }
+
+    /* The same gates a received webhook goes through before it's posted */
+    let held = if ci::waits_for_ci(&webhook).await {
+        Some("waiting on CI")
+    } else if quiet_hours::would_hold(&webhook) {
+        Some("quiet hours")
+    } else {
+        None
+    };
+    let would_post = !paths::only_touches_ignored(&webhook).await
+        && webhook.to_my_slack_resolved(resolved).await.is_some();
+
+    Ok(Json(Preview {
+        would_post,
+        suppressed: false,
+        held,
+        recipients: previews,
+    }))
+}
@@ -0,0 +1,290 @@
+use crate::config::config;
+use crate::recent::{self, Received};
+use sqlx::postgres::PgPool;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex as SyncMutex, OnceLock};
+use tokio::sync::{mpsc, Mutex};
+use tracing::{Instrument, Span};
+
+struct Job {
+    event: String,
+    payload: serde_json::Value,
+    /// The repository's full name, if the payload has one, to share the workers out by
+    repository: Option<String>,
+    /// The PR's url, if the payload is about one, whose jobs are handled one at a time
+    pull_request: Option<String>,
+    received: Received,
+    /* Keeps the delivery id on everything logged while the job is handled */
+    span: Span,
+}
+
+static SENDER: OnceLock<mpsc::Sender<Job>> = OnceLock::new();
+static REPOSITORIES: OnceLock<SyncMutex<Repositories<Job>>> = OnceLock::new();
+/// Jobs set aside until their repository has a worker free, or their PR's earlier job is done,
+/// which count towards the capacity
+static PARKED: AtomicUsize = AtomicUsize::new(0);
+
+/// Jobs set aside, in arrival order, with the PR each is about
+type Parked<T> = VecDeque<(Option<String>, T)>;
+
+/// Per repository, how many of its jobs are being handled and those set aside until one of
+/// them finishes, along with the PRs that have a job being handled. A PR's jobs are only ever
+/// handled one at a time, in arrival order, so no worker sits waiting on another's PR.
+struct Repositories<T> {
+    running: HashMap<String, (usize, Parked<T>)>,
+    busy: HashSet<String>,
+}
+
+impl<T> Default for Repositories<T> {
+    fn default() -> Self {
+        Self {
+            running: HashMap::new(),
+            busy: HashSet::new(),
+        }
+    }
+}
+
+impl<T> Repositories<T> {
+    /// Returns the job if it can be handled now, otherwise sets it aside
+    fn admit(
+        &mut self,
+        repository: &str,
+        pull_request: Option<&str>,
+        job: T,
+        cap: usize,
+    ) -> Option<T> {
+        let (running, parked) = self.running.entry(repository.to_string()).or_default();
+        let busy = pull_request.is_some_and(|x| self.busy.contains(x));
+        if *running < cap && !busy {
+            *running += 1;
+            if let Some(pull_request) = pull_request {
+                self.busy.insert(pull_request.to_string());
+            }
+            Some(job)
+        } else {
+            parked.push_back((pull_request.map(str::to_string), job));
+            None
+        }
+    }
+
+    /// Hands the finished job's worker the repository's next job set aside whose PR isn't
+    /// already being handled, if there is one
+    fn release(&mut self, repository: &str, pull_request: Option<&str>) -> Option<T> {
+        if let Some(pull_request) = pull_request {
+            self.busy.remove(pull_request);
+        }
+
+        let (running, parked) = self.running.get_mut(repository)?;
+        let next = parked.iter().position(|(pull_request, _)| {
+            !pull_request.as_ref().is_some_and(|x| self.busy.contains(x))
+        });
+        if let Some((pull_request, job)) = next.and_then(|next| parked.remove(next)) {
+            if let Some(pull_request) = pull_request {
+                self.busy.insert(pull_request);
+            }
+            return Some(job);
+        }
+
+        *running -= 1;
+        if *running == 0 {
+            self.running.remove(repository);
+        }
+        None
+    }
+}
+
+fn repositories() -> &'static SyncMutex<Repositories<Job>> {
+    REPOSITORIES.get_or_init(Default::default)
+}
+
+/// Starts the workers handling queued webhooks, so receiving one only has to queue it
+pub fn spawn_workers(db: PgPool) {
+    let queue = &config().queue;
+    let (sender, receiver) = mpsc::channel(queue.capacity.max(1));
+    if SENDER.set(sender).is_err() {
+        return;
+    }
+
+    let receiver = Arc::new(Mutex::new(receiver));
+    for _ in 0..queue.workers.max(1) {
+        let receiver = receiver.clone();
+        let db = db.clone();
+        tokio::spawn(async move {
+            loop {
+                /* Only held while waiting, so the other workers can pick up the next job */
+                let Some(job) = receiver.lock().await.recv().await else {
+                    return;
+                };
+
+                let Some(repository) = job.repository.clone() else {
+                    handle(job, &db).await;
+                    continue;
+                };
+
+                let pull_request = job.pull_request.clone();
+                let admitted = repositories().lock().unwrap().admit(
+                    &repository,
+                    pull_request.as_deref(),
+                    job,
+                    config().queue.per_repository(),
+                );
+                let Some(mut job) = admitted else {
+                    PARKED.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                };
+
+                /* Working through the repository's jobs set aside meanwhile keeps them in order */
+                loop {
+                    let pull_request = job.pull_request.clone();
+                    handle(job, &db).await;
+
+                    let next = repositories()
+                        .lock()
+                        .unwrap()
+                        .release(&repository, pull_request.as_deref());
+                    match next {
+                        Some(next) => {
+                            PARKED.fetch_sub(1, Ordering::Relaxed);
+                            job = next;
+                        }
+                        None => break,
+                    }
+                }
+            }
+        });
+    }
+}
+
+async fn handle(job: Job, db: &PgPool) {
+    let handling = crate::handle_event(&job.event, job.payload, db);
+    recent::scope(job.received, handling.instrument(job.span)).await;
+}
+
+/// Queues a webhook to be handled, returning false if the queue is full or there are no
+/// workers to handle it
+pub fn enqueue(event: &str, payload: serde_json::Value, received: Received) -> bool {
+    let Some(sender) = SENDER.get() else {
+        return false;
+    };
+
+    offer(
+        sender,
+        PARKED.load(Ordering::Relaxed),
+        config().queue.capacity.max(1),
+        job(event, payload, received),
+    )
+}
+
+/// Whether the job fits in the queue, counting those set aside towards its capacity
+fn offer(sender: &mpsc::Sender<Job>, parked: usize, capacity: usize, job: Job) -> bool {
+    parked < capacity && sender.try_send(job).is_ok()
+}
+
+/// The webhook as a job, along with the repository and PR it's shared out by
+fn job(event: &str, payload: serde_json::Value, received: Received) -> Job {
+    let repository = payload
+        .pointer("/repository/full_name")
+        .and_then(|x| x.as_str())
+        .map(str::to_string);
+    /* Comments are about the PR, which the payload calls an issue */
+    let pull_request = ["/pull_request/html_url", "/issue/html_url"]
+        .iter()
+        .find_map(|pointer| payload.pointer(pointer).and_then(|x| x.as_str()))
+        .map(str::to_string);
+
+    Job {
+        event: event.to_string(),
+        payload,
+        repository,
+        pull_request,
+        received,
+        span: Span::current(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    fn received() -> Received {
+        Received {
+            delivery: None,
+            at: Instant::now(),
+            time: chrono::Utc::now(),
+        }
+    }
+
+    #[test]
+    fn jobs_are_shared_out_by_repository_and_pr() {
+        let job = |payload| job("pull_request", payload, received());
+
+        let pull_request =
+            job(serde_json::from_str(include_str!("../tests/fixtures/opened.json")).unwrap());
+        assert_eq!(pull_request.repository.as_deref(), Some("acme/widgets"));
+        assert_eq!(
+            pull_request.pull_request.as_deref(),
+            Some("https://gitea.example.com/acme/widgets/pulls/42")
+        );
+
+        let comment = job(serde_json::json!({
+            "repository": { "full_name": "acme/widgets" },
+            "issue": { "html_url": "https://gitea.example.com/acme/widgets/pulls/7" },
+        }));
+        assert_eq!(
+            comment.pull_request.as_deref(),
+            Some("https://gitea.example.com/acme/widgets/pulls/7")
+        );
+
+        let package = job(serde_json::json!({ "package": { "name": "widgets" } }));
+        assert_eq!(package.repository, None);
+        assert_eq!(package.pull_request, None);
+    }
+
+    #[test]
+    fn full_queues_turn_jobs_away() {
+        let job = || job("push", serde_json::json!({}), received());
+        let (sender, _receiver) = mpsc::channel(1);
+
+        /* Jobs set aside take up room just as queued ones do */
+        assert!(!offer(&sender, 2, 2, job()));
+        assert!(offer(&sender, 1, 2, job()));
+        assert!(!offer(&sender, 0, 2, job()));
+    }
+
+    #[test]
+    fn busy_repositories_wait_their_turn() {
+        let mut repositories = Repositories::default();
+
+        assert_eq!(repositories.admit("acme/busy", None, 1, 2), Some(1));
+        assert_eq!(repositories.admit("acme/busy", None, 2, 2), Some(2));
+        assert_eq!(repositories.admit("acme/busy", None, 3, 2), None);
+        assert_eq!(repositories.admit("acme/busy", None, 4, 2), None);
+        assert_eq!(repositories.admit("acme/quiet", None, 5, 2), Some(5));
+
+        assert_eq!(repositories.release("acme/busy", None), Some(3));
+        assert_eq!(repositories.release("acme/busy", None), Some(4));
+        assert_eq!(repositories.release("acme/busy", None), None);
+        assert_eq!(repositories.release("acme/busy", None), None);
+        assert!(!repositories.running.contains_key("acme/busy"));
+    }
+
+    #[test]
+    fn a_prs_jobs_are_handled_one_at_a_time_in_order() {
+        let mut repositories = Repositories::default();
+        let (first, second) = (Some("acme/widgets/pulls/1"), Some("acme/widgets/pulls/2"));
+
+        assert_eq!(repositories.admit("acme/widgets", first, 1, 4), Some(1));
+        assert_eq!(repositories.admit("acme/widgets", first, 2, 4), None);
+        assert_eq!(repositories.admit("acme/widgets", first, 3, 4), None);
+        /* Other PRs still get a worker while the first's jobs wait */
+        assert_eq!(repositories.admit("acme/widgets", second, 4, 4), Some(4));
+
+        assert_eq!(repositories.release("acme/widgets", second), None);
+        assert_eq!(repositories.release("acme/widgets", first), Some(2));
+        assert_eq!(repositories.release("acme/widgets", first), Some(3));
+        assert_eq!(repositories.release("acme/widgets", first), None);
+        assert!(repositories.running.is_empty() && repositories.busy.is_empty());
+    }
+}
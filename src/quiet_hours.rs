@@ -0,0 +1,143 @@
+use crate::config::{config, QuietHoursConfig};
+use crate::db;
+use crate::gitea_webhooks::Webhook;
+use axum::Extension;
+use chrono::{DateTime, Datelike, Utc};
+use sqlx::postgres::PgPool;
+use std::time::Duration;
+use tracing::Instrument;
+
+const DEFERRED_REASON: &str = "quiet_hours";
+
+/// How often held notifications are checked for whether their quiet hours have ended
+const FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Holds back non-urgent notifications for repos in their quiet hours, persisting them so they
+/// survive a restart. Returns whether the webhook was held.
+pub async fn hold(webhook: &Webhook, payload: &str, db: &PgPool) -> bool {
+    if !would_hold(webhook) {
+        return false;
+    }
+
+    let url = webhook.pull_request.url.as_str();
+    let key = &webhook.repository.full_name;
+    match db::insert_deferred_webhook(db, DEFERRED_REASON, key, url, payload).await {
+        Ok(()) => {
+            tracing::info!("Held notification until quiet hours end");
+            true
+        }
+        Err(x) => {
+            tracing::error!("Error attempting to hold webhook: \"{}\"", x);
+            false
+        }
+    }
+}
+
+/// Whether [`hold`] would hold the webhook back right now
+pub fn would_hold(webhook: &Webhook) -> bool {
+    let quiet_hours = &config().repo(&webhook.repository).quiet_hours;
+    quiet_hours.enabled && is_quiet(quiet_hours, Utc::now()) && !is_urgent(webhook, quiet_hours)
+}
+
+/// Periodically sends the notifications whose quiet hours have ended, starting with any left
+/// over from before a restart
+pub fn spawn_flusher(db: PgPool) {
+    tokio::spawn(
+        async move {
+            let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+            loop {
+                interval.tick().await;
+                flush(&db).await;
+            }
+        }
+        .in_current_span(),
+    );
+}
+
+async fn flush(db: &PgPool) {
+    let held = match db::fetch_deferred_webhooks(db, DEFERRED_REASON).await {
+        Ok(held) => held,
+        Err(x) => {
+            tracing::error!("Error attempting to fetch held webhooks: \"{}\"", x);
+            return;
+        }
+    };
+
+    for held in held {
+        let Some(webhook) = held.decode() else {
+            continue;
+        };
+
+        /* Quiet hours may have been disabled or moved since the webhook was held */
+        let quiet_hours = &config().repo(&webhook.repository).quiet_hours;
+        if quiet_hours.enabled && is_quiet(quiet_hours, Utc::now()) {
+            continue;
+        }
+
+        match db::delete_deferred_webhook(db, held.id).await {
+            Ok(true) => {
+                crate::post_repo_payload(webhook, Extension(db.clone())).await;
+            }
+            Ok(false) => {}
+            Err(x) => tracing::error!("Error attempting to release held webhook: \"{}\"", x),
+        }
+    }
+}
+
+fn is_urgent(webhook: &Webhook, quiet_hours: &QuietHoursConfig) -> bool {
+    webhook.pull_request.labels.iter().any(|label| {
+        quiet_hours
+            .urgent_labels
+            .iter()
+            .any(|urgent| urgent.eq_ignore_ascii_case(&label.name))
+    })
+}
+
+fn is_quiet(quiet_hours: &QuietHoursConfig, now: DateTime<Utc>) -> bool {
+    let local = now.with_timezone(&quiet_hours.timezone);
+    let time = local.time();
+    let (start, end) = (quiet_hours.start, quiet_hours.end);
+
+    /* A window past midnight belongs to the day it started on */
+    let started_on = if start <= end {
+        (start <= time && time < end).then_some(local.weekday())
+    } else if time >= start {
+        Some(local.weekday())
+    } else if time < end {
+        Some(local.weekday().pred())
+    } else {
+        None
+    };
+
+    started_on.is_some_and(|day| quiet_hours.days.is_empty() || quiet_hours.days.contains(&day))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{NaiveTime, TimeZone, Weekday};
+
+    fn quiet_hours(start: (u32, u32), end: (u32, u32), days: Vec<Weekday>) -> QuietHoursConfig {
+        QuietHoursConfig {
+            enabled: true,
+            start: NaiveTime::from_hms_opt(start.0, start.1, 0).unwrap(),
+            end: NaiveTime::from_hms_opt(end.0, end.1, 0).unwrap(),
+            timezone: chrono_tz::Europe::London,
+            days,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn quiet_hours_past_midnight_belong_to_the_starting_day() {
+        /* Friday evening into Saturday morning, in British Summer Time */
+        let quiet_hours = quiet_hours((19, 0), (8, 0), vec![Weekday::Fri]);
+        let at = |d, h| Utc.with_ymd_and_hms(2026, 7, d, h, 0, 0).unwrap();
+
+        assert!(!is_quiet(&quiet_hours, at(10, 17)));
+        assert!(is_quiet(&quiet_hours, at(10, 18)));
+        assert!(is_quiet(&quiet_hours, at(11, 6)));
+        assert!(!is_quiet(&quiet_hours, at(11, 7)));
+        assert!(!is_quiet(&quiet_hours, at(11, 18)));
+    }
+}
@@ -0,0 +1,155 @@
+use crate::config::config;
+use crate::gitea_api::{self, PullReview};
+use crate::gitea_webhooks::{self, Action, Review, Webhook};
+use crate::{db, slack};
+use std::collections::HashMap;
+
+/// Tells the author when an approval leaves their PR with every approval it needs and no
+/// changes still requested, in the PR's thread if it has one
+pub async fn announce(webhook: &Webhook, thread: Option<&db::Thread>) {
+    let settings = &config().repo(&webhook.repository).ready_to_merge;
+    if !settings.enabled
+        || !matches!(
+            webhook.action,
+            Action::Reviewed {
+                review: Review::Approved { .. }
+            }
+        )
+    {
+        return;
+    }
+
+    let repository = &webhook.repository;
+    let pull_request = &webhook.pull_request;
+    let Some(base) = &pull_request.base else {
+        return;
+    };
+
+    /* Failures are logged by the API calls themselves */
+    let required = match gitea_api::fetch_required_approvals(
+        &repository.url,
+        &repository.full_name,
+        &base.name,
+    )
+    .await
+    {
+        Ok(Some(required)) if required > 0 => required,
+        Ok(_) => settings.unprotected_approvals,
+        Err(_) => return,
+    };
+
+    let Ok(reviews) =
+        gitea_api::fetch_reviews(&repository.url, &repository.full_name, pull_request.number).await
+    else {
+        return;
+    };
+
+    if !became_ready(&reviews, &webhook.sender.username, required) {
+        return;
+    }
+
+    let author = slack::mention_gitea_user(&repository.url, &pull_request.user).await;
+    let message = gitea_webhooks::render_ready_to_merge(&author, pull_request);
+    if slack::post_reply(message, thread).await.is_ok() {
+        tracing::info!("Author told their PR is ready to merge");
+    }
+}
+
+/// Whether `reviewer`'s latest review is the approval that left the PR with `required`
+/// approvals and no changes requested, so authors are only told the once
+fn became_ready(reviews: &[PullReview], reviewer: &str, required: usize) -> bool {
+    let Some(latest) = reviews.iter().rposition(|review| {
+        review
+            .user
+            .as_ref()
+            .is_some_and(|user| user.username == reviewer)
+    }) else {
+        return false;
+    };
+
+    if reviews[latest].state != "APPROVED" || reviews[latest].dismissed {
+        return false;
+    }
+
+    let before = reviews
+        .iter()
+        .enumerate()
+        .filter(|(index, _)| *index != latest)
+        .map(|(_, review)| review);
+    let ready =
+        |(approvals, changes_requested): (usize, bool)| approvals >= required && !changes_requested;
+
+    !ready(verdicts(before)) && ready(verdicts(reviews.iter()))
+}
+
+/// How many reviewers currently approve, and whether any are still requesting changes
+fn verdicts<'a>(reviews: impl Iterator<Item = &'a PullReview>) -> (usize, bool) {
+    let mut verdicts = HashMap::new();
+    for review in reviews {
+        let Some(user) = &review.user else {
+            continue;
+        };
+
+        /* Comments leave an earlier verdict standing */
+        match review.state.as_str() {
+            "APPROVED" | "REQUEST_CHANGES" if !review.dismissed => {
+                verdicts.insert(&user.username, review.state.as_str());
+            }
+            "APPROVED" | "REQUEST_CHANGES" => {
+                verdicts.remove(&user.username);
+            }
+            _ => {}
+        }
+    }
+
+    (
+        verdicts.values().filter(|x| **x == "APPROVED").count(),
+        verdicts.values().any(|x| *x == "REQUEST_CHANGES"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gitea_webhooks::User;
+
+    fn review(username: &str, state: &str) -> PullReview {
+        PullReview {
+            user: Some(User {
+                email: String::new(),
+                username: username.to_string(),
+                full_name: String::new(),
+            }),
+            state: state.to_string(),
+            dismissed: false,
+        }
+    }
+
+    #[test]
+    fn only_the_satisfying_approval_is_ready() {
+        let reviews = [review("bob", "APPROVED"), review("carol", "APPROVED")];
+        assert!(became_ready(&reviews, "carol", 2));
+        /* Already had enough before carol's */
+        assert!(!became_ready(&reviews, "carol", 1));
+        assert!(!became_ready(&reviews[..1], "bob", 2));
+
+        /* Approving again doesn't add an approval */
+        let reviews = [
+            review("bob", "APPROVED"),
+            review("bob", "COMMENT"),
+            review("bob", "APPROVED"),
+        ];
+        assert!(!became_ready(&reviews, "bob", 1));
+
+        let reviews = [review("dave", "REQUEST_CHANGES"), review("bob", "APPROVED")];
+        assert!(!became_ready(&reviews, "bob", 1));
+
+        /* Already had enough approvals, but dave's was what cleared his requested changes */
+        let reviews = [
+            review("dave", "REQUEST_CHANGES"),
+            review("bob", "APPROVED"),
+            review("dave", "APPROVED"),
+        ];
+        assert!(became_ready(&reviews, "dave", 1));
+    }
+}
@@ -0,0 +1,343 @@
+use crate::gitea_webhooks::{MySlackMessage, Webhook};
+use crate::resend::is_admin;
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{Html, IntoResponse, Response};
+use chrono::{DateTime, Utc};
+use slack_morphism::prelude::*;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use url::Url;
+
+/// How many of the latest deliveries are kept, oldest dropped first
+const MAX_RECENT_DELIVERIES: usize = 100;
+
+static RECENT: OnceLock<Mutex<VecDeque<Delivery>>> = OnceLock::new();
+
+/// The webhook request being handled, for the outcome recorded for it
+#[derive(Debug, Clone)]
+pub struct Received {
+    /// From `X-Gitea-Delivery`, matching the delivery in Gitea's webhook history
+    pub delivery: Option<String>,
+    pub at: Instant,
+    /// The same moment as `at`, for showing when it was received
+    pub time: DateTime<Utc>,
+}
+
+tokio::task_local! {
+    static RECEIVED: Received;
+}
+
+/// Runs the handling of a request, attributing every outcome recorded during it to the request.
+/// Webhooks handled later, e.g. once quiet hours end, are recorded without one.
+pub async fn scope<F: Future>(received: Received, handling: F) -> F::Output {
+    RECEIVED.scope(received, handling).await
+}
+
+#[derive(Debug, Clone)]
+pub enum Outcome {
+    Posted,
+    Failed(String),
+    /// Not sent, and never will be, e.g. because nobody needs to know
+    Skipped(&'static str),
+    /// Waiting to be sent later, e.g. until CI passes
+    Held(&'static str),
+    /// Let through this many notifications that were being held, e.g. once CI passed
+    Released(usize),
+}
+
+impl Outcome {
+    /// A short name for the outcome, and whatever detail it has
+    fn describe(&self) -> (&'static str, String) {
+        match self {
+            Outcome::Posted => ("posted", String::new()),
+            Outcome::Failed(error) => ("failed", error.clone()),
+            Outcome::Skipped(reason) => ("skipped", reason.to_string()),
+            Outcome::Held(reason) => ("held", reason.to_string()),
+            Outcome::Released(count) => ("released", format!("{} held notifications", count)),
+        }
+    }
+}
+
+/// What became of a webhook, for operators to inspect without digging through logs
+#[derive(Debug, Clone)]
+pub struct Delivery {
+    pub received_at: DateTime<Utc>,
+    pub delivery: Option<String>,
+    /// From the request being received to this outcome, if it came of a request
+    pub latency: Option<Duration>,
+    pub repository: String,
+    pub action: String,
+    pub url: String,
+    /// Slack mentions of who was resolved, and usernames of who wasn't
+    pub recipients: Vec<String>,
+    pub unresolved: Vec<String>,
+    /// The Block Kit JSON sent, if it got that far
+    pub rendered: Option<String>,
+    pub outcome: Outcome,
+}
+
+impl Delivery {
+    pub fn new(webhook: &Webhook, outcome: Outcome) -> Self {
+        Self::of(
+            webhook.repository.full_name.clone(),
+            webhook.action.to_string(),
+            webhook.pull_request.url.to_string(),
+            outcome,
+        )
+    }
+
+    /// For events other than a PR's, or that couldn't be decoded, taking what it can from the
+    /// raw payload, e.g. `package created` for the action
+    pub fn of_event(event: &str, payload: &serde_json::Value, outcome: Outcome) -> Self {
+        let field = |pointers: &[&str]| {
+            pointers
+                .iter()
+                .find_map(|x| payload.pointer(x).and_then(|x| x.as_str()))
+                .unwrap_or_default()
+                .to_string()
+        };
+
+        let action = match payload.get("action").and_then(|x| x.as_str()) {
+            Some(action) => format!("{} {}", event, action),
+            None => event.to_string(),
+        };
+
+        Self::of(
+            field(&["/repository/full_name", "/package/repository/full_name"]),
+            action,
+            field(&[
+                "/pull_request/html_url",
+                "/package/html_url",
+                "/repository/html_url",
+            ]),
+            outcome,
+        )
+    }
+
+    fn of(repository: String, action: String, url: String, outcome: Outcome) -> Self {
+        let received = RECEIVED.try_with(Received::clone).ok();
+
+        Self {
+            received_at: received.as_ref().map_or_else(Utc::now, |x| x.time),
+            delivery: received.as_ref().and_then(|x| x.delivery.clone()),
+            latency: received.map(|x| x.at.elapsed()),
+            repository,
+            action,
+            url,
+            recipients: Vec::new(),
+            unresolved: Vec::new(),
+            rendered: None,
+            outcome,
+        }
+    }
+
+    pub fn with_message(self, message: &MySlackMessage) -> Self {
+        Self {
+            recipients: message
+                .slack_user
+                .iter()
+                .map(|user| user.id.to_slack_format())
+                .collect(),
+            unresolved: message
+                .unresolved
+                .iter()
+                .map(|user| user.username.clone())
+                .collect(),
+            rendered: serde_json::to_string_pretty(&message.render_template()).ok(),
+            ..self
+        }
+    }
+}
+
+/// Logs the outcome as a single event, with recipients only counted to keep them out of the
+/// logs, and keeps it for the recent deliveries page
+pub fn record(delivery: Delivery) {
+    let (outcome, detail) = delivery.outcome.describe();
+    tracing::info!(
+        delivery = delivery.delivery.as_deref(),
+        repository = %delivery.repository,
+        action = %delivery.action,
+        url = %delivery.url,
+        recipients = delivery.recipients.len(),
+        unresolved = delivery.unresolved.len(),
+        outcome,
+        detail,
+        latency_ms = delivery.latency.map(|x| x.as_millis() as u64),
+        "Webhook outcome"
+    );
+
+    let mut recent = RECENT
+        .get_or_init(Default::default)
+        .lock()
+        .expect("Recent deliveries should not be poisoned");
+
+    if recent.len() >= MAX_RECENT_DELIVERIES {
+        recent.pop_front();
+    }
+    recent.push_back(delivery);
+}
+
+/// A page listing the latest deliveries, newest first. Requires `ADMIN_TOKEN`, either as a
+/// bearer token or as the password of HTTP basic auth so it can be opened in a browser.
+pub async fn recent_handler(headers: HeaderMap) -> Response {
+    if !is_admin(&headers) {
+        return (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, "Basic realm=\"gitea-notif\"")],
+        )
+            .into_response();
+    }
+
+    let recent = RECENT
+        .get_or_init(Default::default)
+        .lock()
+        .expect("Recent deliveries should not be poisoned")
+        .clone();
+
+    Html(render_page(&recent)).into_response()
+}
+
+fn render_page(recent: &VecDeque<Delivery>) -> String {
+    let rows = recent
+        .iter()
+        .rev()
+        .map(|delivery| {
+            let (outcome, detail) = delivery.outcome.describe();
+            let latency = match delivery.latency {
+                Some(latency) => format!("{} ms", latency.as_millis()),
+                None => String::new(),
+            };
+
+            let rendered = match &delivery.rendered {
+                Some(rendered) => format!(
+                    "<details><summary>Block Kit</summary><pre>{}</pre></details>",
+                    escape_html(rendered)
+                ),
+                None => String::new(),
+            };
+
+            format!(
+                "<tr class=\"{outcome}\"><td>{}</td><td>{}</td><td>{}</td><td>{}</td>\
+                 <td>{}</td><td>{}</td><td>{}</td><td>{outcome} {}</td>\
+                 <td>{}</td><td>{}</td></tr>",
+                delivery.received_at.format("%Y-%m-%d %H:%M:%S"),
+                escape_html(delivery.delivery.as_deref().unwrap_or_default()),
+                escape_html(&delivery.repository),
+                escape_html(&delivery.action),
+                link(&delivery.url),
+                escape_html(&delivery.recipients.join(", ")),
+                escape_html(&delivery.unresolved.join(", ")),
+                escape_html(&detail),
+                latency,
+                rendered,
+            )
+        })
+        .collect::<String>();
+
+    format!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>Recent notifications</title>\
+         <style>body{{font-family:sans-serif}}td{{padding:4px 8px;vertical-align:top}}\
+         .failed{{background:#fdd}}.held{{background:#ffd}}.skipped{{color:#888}}</style>\
+         </head><body><h1>Recent notifications</h1><table><tr><th>Received (UTC)</th>\
+         <th>Delivery</th><th>Repository</th><th>Action</th><th>PR</th><th>Recipients</th><th>Unresolved</th>\
+         <th>Outcome</th><th>Latency</th><th>Rendered</th></tr>{}</table></body></html>",
+        rows
+    )
+}
+
+/// Links the PR, unless its url isn't a web page, e.g. `javascript:` from a forged payload
+fn link(url: &str) -> String {
+    let is_web_page = url
+        .parse::<Url>()
+        .is_ok_and(|x| matches!(x.scheme(), "http" | "https"));
+
+    if is_web_page {
+        format!("<a href=\"{}\">{}</a>", escape_html(url), escape_html(url))
+    } else {
+        escape_html(url)
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn delivery(url: &str, repository: &str) -> Delivery {
+        Delivery {
+            received_at: Utc::now(),
+            delivery: Some("7f3c".to_string()),
+            latency: Some(Duration::from_millis(42)),
+            repository: repository.to_string(),
+            action: "opened".to_string(),
+            url: url.to_string(),
+            recipients: vec!["<@U0BOB>".to_string()],
+            unresolved: Vec::new(),
+            rendered: Some("{\"blocks\": []}".to_string()),
+            outcome: Outcome::Failed("<oops>".to_string()),
+        }
+    }
+
+    #[test]
+    fn page_escapes_deliveries_and_only_links_web_pages() {
+        let recent = VecDeque::from([
+            delivery(
+                "https://gitea.example.com/acme/widgets/pulls/42",
+                "acme/widgets",
+            ),
+            delivery("javascript:alert(1)", "<script>acme</script>"),
+        ]);
+        let page = render_page(&recent);
+
+        assert!(page.contains(
+            "<a href=\"https://gitea.example.com/acme/widgets/pulls/42\">\
+             https://gitea.example.com/acme/widgets/pulls/42</a>"
+        ));
+        assert!(!page.contains("href=\"javascript:"));
+        assert!(page.contains("<td>javascript:alert(1)</td>"));
+        assert!(page.contains("&lt;script&gt;acme&lt;/script&gt;"));
+        assert!(page.contains("failed &lt;oops&gt;"));
+        assert!(page.contains("42 ms"));
+
+        /* Newest first */
+        assert!(page.find("javascript:").unwrap() < page.find("widgets/pulls/42").unwrap());
+    }
+
+    #[tokio::test]
+    async fn events_are_described_from_the_payload_as_received() {
+        let received = Received {
+            delivery: Some("9b1e".to_string()),
+            at: Instant::now(),
+            time: Utc::now() - chrono::TimeDelta::minutes(5),
+        };
+        let payload = serde_json::json!({
+            "action": "created",
+            "package": {
+                "html_url": "https://gitea.example.com/acme/-/packages/npm/widgets/1.2.0",
+                "repository": { "full_name": "acme/widgets" },
+            },
+        });
+
+        let delivery = scope(received.clone(), async {
+            Delivery::of_event("package", &payload, Outcome::Posted)
+        })
+        .await;
+
+        assert_eq!(delivery.received_at, received.time);
+        assert_eq!(delivery.delivery.as_deref(), Some("9b1e"));
+        assert_eq!(delivery.repository, "acme/widgets");
+        assert_eq!(delivery.action, "package created");
+        assert_eq!(
+            delivery.url,
+            "https://gitea.example.com/acme/-/packages/npm/widgets/1.2.0"
+        );
+    }
+}
@@ -0,0 +1,300 @@
+use crate::config::{config, Escalation, ReviewReminderConfig};
+use crate::db::PendingEscalation;
+use crate::gitea_webhooks::{self, Action, Webhook};
+use crate::{db, slack};
+use slack_morphism::prelude::*;
+use sqlx::postgres::PgPool;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+use tokio::task::AbortHandle;
+use tracing::Instrument;
+
+/// Pending reminders are keyed on the PR url and the requested reviewer's username
+type ReminderKey = (String, String);
+
+static PENDING: OnceLock<Mutex<HashMap<ReminderKey, (u64, AbortHandle)>>> = OnceLock::new();
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+const ESCALATION_INTERVAL: Duration = Duration::from_secs(60);
+/* Long enough for a step to be sent, short enough that one lost to a restart mid-step isn't
+ * held up for long */
+const ESCALATION_LEASE_SECS: u64 = 10 * 60;
+
+fn pending() -> &'static Mutex<HashMap<ReminderKey, (u64, AbortHandle)>> {
+    PENDING.get_or_init(Default::default)
+}
+
+/// Schedules a reminder, or the escalation ladder, for new review requests and cancels any
+/// outstanding ones once they're finished with, as [`cancel_finished`]. Nobody is chased for a
+/// draft PR.
+pub async fn track(webhook: &Webhook, db: &PgPool) {
+    let url = webhook.pull_request.url.to_string();
+
+    match &webhook.action {
+        Action::ReviewRequested { .. } if webhook.is_draft() => {}
+        Action::ReviewRequested {
+            requested_reviewers,
+            ..
+        } => {
+            let reminder_config = &config().repo(&webhook.repository).review_reminder;
+
+            if let Some(first) = reminder_config
+                .ladder
+                .first()
+                .filter(|_| reminder_config.enabled)
+            {
+                for requested_reviewer in
+                    requested_reviewers.iter().filter(|x| !webhook.is_author(x))
+                {
+                    let escalation = PendingEscalation {
+                        url: url.clone(),
+                        reviewer: requested_reviewer.username.clone(),
+                        reviewer_email: requested_reviewer.email.clone(),
+                        repository: webhook.repository.full_name.clone(),
+                        pull_request_link: gitea_webhooks::format_pull_request_url(
+                            &webhook.pull_request,
+                        ),
+                        step: 0,
+                    };
+                    if let Err(x) = db::schedule_escalation(db, &escalation, first.after_secs).await
+                    {
+                        tracing::error!(
+                            "Error attempting to schedule review escalation: \"{}\"",
+                            x
+                        );
+                    }
+                }
+            } else if reminder_config.enabled && reminder_config.max_reminders > 0 {
+                for requested_reviewer in
+                    requested_reviewers.iter().filter(|x| !webhook.is_author(x))
+                {
+                    schedule(
+                        (url.clone(), requested_reviewer.username.clone()),
+                        requested_reviewer.email.clone(),
+                        gitea_webhooks::format_pull_request_url(&webhook.pull_request),
+                        Duration::from_secs(reminder_config.delay_secs),
+                        reminder_config.max_reminders,
+                        db.clone(),
+                    );
+                }
+            }
+        }
+        _ => cancel_finished(webhook, db).await,
+    }
+}
+
+/// Cancels outstanding reminders and escalations once the reviewer has reviewed or been taken
+/// off the PR, or the PR is no longer open or has been marked as a draft. Also for events that aren't otherwise handled, e.g.
+/// reviews by an ignored sender, so they're still never chased for.
+pub async fn cancel_finished(webhook: &Webhook, db: &PgPool) {
+    let url = webhook.pull_request.url.to_string();
+
+    match &webhook.action {
+        Action::Reviewed { review: _ } => {
+            cancel_escalations(db, &url, Some(&webhook.sender.username)).await;
+            cancel(&(url, webhook.sender.username.clone()));
+        }
+        Action::ReviewRequestRemoved {
+            requested_reviewers,
+        } => {
+            for reviewer in requested_reviewers {
+                cancel_escalations(db, &url, Some(&reviewer.username)).await;
+                cancel(&(url.clone(), reviewer.username.clone()));
+            }
+        }
+        Action::Closed | Action::Merged => {
+            cancel_escalations(db, &url, None).await;
+            cancel_pull_request(&url);
+        }
+        Action::Edited { .. } if webhook.is_draft() => {
+            cancel_escalations(db, &url, None).await;
+            cancel_pull_request(&url);
+        }
+        _ => {}
+    }
+}
+
+/// Regardless of the repo's config, as it may have changed since the ladder was started
+async fn cancel_escalations(db: &PgPool, url: &str, reviewer: Option<&str>) {
+    if let Err(x) = db::cancel_escalations(db, url, reviewer).await {
+        tracing::error!("Error attempting to cancel review escalations: \"{}\"", x);
+    }
+}
+
+/// Takes the next step of any escalation ladders that are due, once a minute
+pub fn spawn_escalations(db: PgPool) {
+    tokio::spawn(
+        async move {
+            let mut interval = tokio::time::interval(ESCALATION_INTERVAL);
+            loop {
+                interval.tick().await;
+                escalate(&db).await;
+            }
+        }
+        .in_current_span(),
+    );
+}
+
+async fn escalate(db: &PgPool) {
+    let due = match db::claim_due_escalations(db, ESCALATION_LEASE_SECS).await {
+        Ok(due) => due,
+        Err(x) => {
+            tracing::error!(
+                "Error attempting to fetch due review escalations: \"{}\"",
+                x
+            );
+            return;
+        }
+    };
+
+    for escalation in due {
+        let reminder_config = &config().repo_named(&escalation.repository).review_reminder;
+        /* The ladder may have been shortened or switched off since it was started */
+        let step = reminder_config
+            .ladder
+            .get(escalation.step)
+            .filter(|_| reminder_config.enabled);
+
+        if let Some(step) = step {
+            send_escalation(&escalation, step.action, reminder_config, db).await;
+        }
+
+        let next = step
+            .filter(|step| step.action != Escalation::Stale)
+            .and_then(|_| reminder_config.ladder.get(escalation.step + 1))
+            .map(|next| next.after_secs);
+        if let Err(x) = db::advance_escalation(db, &escalation, next).await {
+            tracing::error!("Error attempting to advance review escalation: \"{}\"", x);
+        }
+    }
+}
+
+async fn send_escalation(
+    escalation: &PendingEscalation,
+    action: Escalation,
+    reminder_config: &ReviewReminderConfig,
+    db: &PgPool,
+) {
+    let reviewer = slack::fetch_user_from_email(&escalation.reviewer_email)
+        .await
+        .map(|user| user.id.to_slack_format())
+        .unwrap_or(escalation.reviewer.clone());
+    let link = &escalation.pull_request_link;
+
+    let message = match action {
+        Escalation::Remind => gitea_webhooks::render_review_reminder(&reviewer, link),
+        Escalation::Lead => {
+            /* Someone in the channel can chase it when there's nobody in particular to */
+            let lead = match reminder_config.leads.get(&escalation.reviewer) {
+                Some(email) => slack::fetch_user_from_email(email)
+                    .await
+                    .map(|user| user.id.to_slack_format())
+                    .ok(),
+                None => None,
+            };
+            gitea_webhooks::render_review_chase(
+                lead.as_deref().unwrap_or("<!here>"),
+                &reviewer,
+                link,
+            )
+        }
+        Escalation::Channel => gitea_webhooks::render_review_chase("<!here>", &reviewer, link),
+        Escalation::Stale => gitea_webhooks::render_review_stale(&reviewer, link),
+    };
+
+    let thread = thread(&escalation.url, db).await;
+    match slack::post_reply(message, thread.as_ref()).await {
+        Ok(_) => tracing::info!(step = escalation.step, ?action, "Review escalated"),
+        Err(x) => tracing::error!("Error attempting to escalate review: \"{}\"", x),
+    }
+}
+
+async fn thread(url: &str, db: &PgPool) -> Option<db::Thread> {
+    match db::fetch_thread(db, url).await {
+        Ok(thread) => thread,
+        Err(x) => {
+            tracing::error!(
+                "Error attempting to retrieve possible timestamp from DB: \"{}\"",
+                x
+            );
+            None
+        }
+    }
+}
+
+fn schedule(
+    key: ReminderKey,
+    reviewer_email: String,
+    pull_request_link: String,
+    delay: Duration,
+    max_reminders: u32,
+    db: PgPool,
+) {
+    let generation = GENERATION.fetch_add(1, Ordering::Relaxed);
+    let task_key = key.clone();
+
+    let task = tokio::spawn(
+        async move {
+            for _ in 0..max_reminders {
+                tokio::time::sleep(delay).await;
+                send_reminder(&task_key, &reviewer_email, &pull_request_link, &db).await;
+            }
+
+            let mut pending = pending().lock().unwrap();
+            /* A re-request may have replaced this reminder in the meantime */
+            if pending.get(&task_key).map(|x| x.0) == Some(generation) {
+                pending.remove(&task_key);
+            }
+        }
+        .in_current_span(),
+    );
+
+    if let Some((_, previous)) = pending()
+        .lock()
+        .unwrap()
+        .insert(key, (generation, task.abort_handle()))
+    {
+        previous.abort();
+    }
+}
+
+fn cancel(key: &ReminderKey) {
+    if let Some((_, reminder)) = pending().lock().unwrap().remove(key) {
+        tracing::debug!("Cancelled review reminder");
+        reminder.abort();
+    }
+}
+
+fn cancel_pull_request(url: &str) {
+    pending().lock().unwrap().retain(|key, (_, reminder)| {
+        if key.0 == url {
+            reminder.abort();
+            false
+        } else {
+            true
+        }
+    });
+}
+
+async fn send_reminder(
+    (url, username): &ReminderKey,
+    reviewer_email: &str,
+    pull_request_link: &str,
+    db: &PgPool,
+) {
+    let thread = thread(url, db).await;
+
+    let reviewer = match slack::fetch_user_from_email(reviewer_email).await {
+        Ok(user) => user.id.to_slack_format(),
+        Err(_) => username.to_string(),
+    };
+
+    let message = gitea_webhooks::render_review_reminder(&reviewer, pull_request_link);
+
+    match slack::post_reply(message, thread.as_ref()).await {
+        Ok(_) => tracing::info!("Review reminder sent"),
+        Err(x) => tracing::error!("Error attempting to send review reminder: \"{}\"", x),
+    }
+}
@@ -0,0 +1,100 @@
+use crate::config::config_env_var;
+use crate::gitea_api;
+use crate::gitea_webhooks::{Action, Webhook};
+use crate::secret;
+use axum::extract::Path;
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::Extension;
+use base64::prelude::*;
+use sqlx::postgres::PgPool;
+use subtle::ConstantTimeEq;
+use tracing::instrument;
+use url::Url;
+
+/// Rebuilds and reposts the review-request notification for a PR from its current state in
+/// Gitea, for when a ping was missed or forgotten. Requires `ADMIN_TOKEN` as a bearer token.
+#[instrument(skip(db, headers))]
+pub async fn resend_handler(
+    db: Extension<PgPool>,
+    headers: HeaderMap,
+    Path((owner, name, number)): Path<(String, String, u64)>,
+) -> StatusCode {
+    if !is_admin(&headers) {
+        return StatusCode::UNAUTHORIZED;
+    }
+
+    let webhook = match review_request(&format!("{}/{}", owner, name), number).await {
+        Ok(Some(webhook)) => webhook,
+        Ok(None) => return StatusCode::NO_CONTENT,
+        Err(x) => {
+            tracing::error!("Error attempting to rebuild review request: \"{}\"", x);
+            return StatusCode::BAD_GATEWAY;
+        }
+    };
+
+    crate::post_repo_payload(webhook, db).await
+}
+
+/// Whether the request carries the admin token, which must be configured at all for the
+/// admin endpoints to be usable. Taken as a bearer token, or as the password of basic auth for
+/// pages opened in a browser.
+pub fn is_admin(headers: &HeaderMap) -> bool {
+    let Ok(admin_token) = secret::read("ADMIN_TOKEN") else {
+        return false;
+    };
+    let admin_token = admin_token.expose();
+
+    let Some(authorization) = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|x| x.to_str().ok())
+    else {
+        return false;
+    };
+
+    let token = if let Some(token) = authorization.strip_prefix("Bearer ") {
+        token.to_string()
+    } else if let Some(credentials) = authorization.strip_prefix("Basic ") {
+        let Some(credentials) = BASE64_STANDARD
+            .decode(credentials)
+            .ok()
+            .and_then(|x| String::from_utf8(x).ok())
+        else {
+            return false;
+        };
+        match credentials.split_once(':') {
+            Some((_, password)) => password.to_string(),
+            None => return false,
+        }
+    } else {
+        return false;
+    };
+
+    /* Compared in constant time so the time taken doesn't give away how much of it matched */
+    !admin_token.is_empty() && bool::from(token.as_bytes().ct_eq(admin_token.as_bytes()))
+}
+
+/// A review-request webhook for the PR as though the author had just requested its
+/// outstanding reviewers, or `None` if there aren't any
+async fn review_request(repository: &str, number: u64) -> Result<Option<Webhook>, anyhow::Error> {
+    let url: Url = config_env_var("GITEA_URL")?.parse()?;
+
+    let repository = gitea_api::fetch_repository(&url, repository).await?;
+    let (pull_request, requested_reviewers) =
+        gitea_api::fetch_pull_request(&url, &repository.full_name, number).await?;
+
+    if requested_reviewers.is_empty() {
+        return Ok(None);
+    }
+
+    Ok(Some(Webhook {
+        action: Action::ReviewRequested {
+            requested_reviewers,
+            review_reason: None,
+            review_round: None,
+        },
+        sender: pull_request.user.clone(),
+        pull_request,
+        repository,
+        sender_mention: None,
+    }))
+}
@@ -0,0 +1,56 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+use tokio::sync::{Mutex as AsyncMutex, OwnedMutexGuard};
+
+type Locks = Mutex<HashMap<String, Weak<AsyncMutex<()>>>>;
+
+/// A lock per PR, only held onto while someone is using it
+static LOCKS: OnceLock<Locks> = OnceLock::new();
+
+/// Waits for every earlier event for the same key, e.g. a PR's url, to finish being handled.
+/// Waiters are let through in the order they arrived, and different keys never wait on each
+/// other.
+pub async fn lock(key: &str) -> OwnedMutexGuard<()> {
+    let lock = {
+        let mut locks = LOCKS
+            .get_or_init(Default::default)
+            .lock()
+            .expect("Sequencing locks should not be poisoned");
+
+        if let Some(lock) = locks.get(key).and_then(Weak::upgrade) {
+            lock
+        } else {
+            /* Nobody else holds these anymore, so nobody can be waiting on them either */
+            locks.retain(|_, lock| lock.strong_count() > 0);
+
+            let lock = Arc::new(AsyncMutex::new(()));
+            locks.insert(key.to_string(), Arc::downgrade(&lock));
+            lock
+        }
+    };
+
+    lock.lock_owned().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn only_the_same_key_waits() {
+        let held = lock("acme/widgets/pulls/1").await;
+
+        /* Would hang if unrelated PRs were serialised */
+        let other = lock("acme/widgets/pulls/2").await;
+        drop(other);
+
+        let waiting = tokio::spawn(lock("acme/widgets/pulls/1"));
+        tokio::task::yield_now().await;
+        assert!(!waiting.is_finished());
+
+        drop(held);
+        waiting
+            .await
+            .expect("Lock should be acquired once released");
+    }
+}
@@ -0,0 +1,947 @@
+use crate::config::{config, config_env_var, MessageNotes, SlackTokenType};
+use crate::gitea_webhooks::{self, User};
+use crate::{aliases, block_kit, db, http, secret};
+use anyhow::Context;
+use chrono::{NaiveDate, Utc};
+use slack_morphism::errors::SlackClientError;
+use slack_morphism::prelude::*;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tracing::instrument;
+use url::Url;
+
+static CLIENT: OnceLock<SlackHyperClient> = OnceLock::new();
+
+/// How long a fetched `users.list` is trusted before being fetched again
+const USERS_CACHE_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// When the workspace's members were last listed, and who they were
+type UsersCache = Option<(Instant, Arc<Vec<SlackUser>>)>;
+
+static USERS: OnceLock<Mutex<UsersCache>> = OnceLock::new();
+
+/// Slack mentions of senders by Gitea username, or that they couldn't be resolved, as of when
+/// they were looked up
+type MentionsCache = HashMap<String, (Instant, Option<String>)>;
+
+static MENTIONS: OnceLock<Mutex<MentionsCache>> = OnceLock::new();
+
+/// Marks the root messages of daily threads, so they can be found again after a restart
+const DAILY_THREAD_EVENT_TYPE: &str = "gitea_notif_daily_thread";
+
+/// Per channel, the day the latest daily thread is for and its root message
+static DAILY_THREADS: OnceLock<Mutex<HashMap<String, (NaiveDate, SlackTs)>>> = OnceLock::new();
+
+/// Ids of the channels found or created for templated channel names, by name
+static CHANNELS: OnceLock<Mutex<HashMap<String, SlackChannelId>>> = OnceLock::new();
+
+fn client() -> Result<&'static SlackHyperClient, anyhow::Error> {
+    if let Some(client) = CLIENT.get() {
+        return Ok(client);
+    }
+
+    let client = SlackClient::new(SlackClientHyperConnector::new()?);
+    Ok(CLIENT.get_or_init(|| client))
+}
+
+fn token() -> Result<SlackApiToken, anyhow::Error> {
+    let token = secret::read("SLACK_API_TOKEN")?;
+    Ok(SlackApiToken::new(SlackApiTokenValue::new(
+        token.expose().clone(),
+    )))
+}
+
+const AUTH_TEST_URL: &str = "https://slack.com/api/auth.test";
+
+#[derive(serde::Deserialize)]
+pub struct AuthTest {
+    ok: bool,
+    error: Option<String>,
+    bot_id: Option<String>,
+    /* Slack lists a token's scopes in a header rather than in the body */
+    #[serde(skip)]
+    scopes: Vec<String>,
+}
+
+/// Calls `auth.test` with the configured token
+pub async fn auth_test() -> Result<AuthTest, anyhow::Error> {
+    let token = secret::read("SLACK_API_TOKEN")?;
+
+    let response = http::client()
+        .post(AUTH_TEST_URL)
+        .bearer_auth(token.expose())
+        .send()
+        .await?;
+
+    let scopes = response
+        .headers()
+        .get("x-oauth-scopes")
+        .and_then(|x| x.to_str().ok())
+        .unwrap_or_default()
+        .split(',')
+        .map(|x| x.trim().to_string())
+        .collect();
+
+    Ok(AuthTest {
+        scopes,
+        ..response.json().await?
+    })
+}
+
+/// Checks the token is the configured type and has every scope the enabled features call
+/// APIs with, so a missing scope is caught at startup rather than as a `missing_scope` later
+pub fn check_token(auth: &AuthTest) -> Result<SlackTokenType, anyhow::Error> {
+    if !auth.ok {
+        anyhow::bail!(
+            "Slack rejected SLACK_API_TOKEN: {}",
+            auth.error.as_deref().unwrap_or_default()
+        );
+    }
+
+    let token_type = if auth.bot_id.is_some() {
+        SlackTokenType::Bot
+    } else {
+        SlackTokenType::User
+    };
+    if let Some(expected) = config().slack_token_type {
+        if expected != token_type {
+            anyhow::bail!(
+                "SLACK_API_TOKEN is a {} token, but slack_token_type is configured as {}",
+                token_type,
+                expected
+            );
+        }
+    }
+
+    /* User tokens always post as the user themselves */
+    if config().bot_identity.is_set() && token_type != SlackTokenType::Bot {
+        anyhow::bail!("bot_identity can only be used with a bot token");
+    }
+
+    let missing = required_scopes()
+        .into_iter()
+        .filter(|scope| !auth.scopes.iter().any(|x| x == scope))
+        .collect::<Vec<_>>();
+    if !missing.is_empty() {
+        anyhow::bail!(
+            "SLACK_API_TOKEN is missing the scopes {}",
+            missing.join(", ")
+        );
+    }
+
+    Ok(token_type)
+}
+
+/// Errors if the token is definitely unusable, but only warns if Slack can't be reached
+pub async fn validate_token() -> Result<(), anyhow::Error> {
+    match auth_test().await {
+        Ok(auth) => {
+            let token_type = check_token(&auth)?;
+            tracing::info!("Using a Slack {} token", token_type);
+        }
+        Err(x) => tracing::warn!("Unable to reach Slack to validate the token: \"{}\"", x),
+    }
+
+    Ok(())
+}
+
+/// Checks the channel in `SLACK_CHANNEL` exists and can be posted in, returning its name
+pub async fn check_channel() -> Result<String, anyhow::Error> {
+    let token = token()?;
+    let session = client()?.open_session(&token);
+
+    let channel = channel()?;
+    let request = SlackApiConversationsInfoRequest::new(channel.clone().into());
+    let info = session
+        .conversations_info(&request)
+        .await
+        .with_context(|| format!("Unable to find channel {}", channel))?
+        .channel;
+
+    let flags = &info.flags;
+    if flags.is_archived.unwrap_or(false) {
+        anyhow::bail!("Channel {} is archived", channel);
+    }
+
+    /* Private channels have to be joined by invitation */
+    if !flags.is_member.unwrap_or(false) && flags.is_private.unwrap_or(false) {
+        anyhow::bail!(
+            "Channel {} is private and the token isn't a member",
+            channel
+        );
+    }
+
+    Ok(info.name.unwrap_or(channel))
+}
+
+fn required_scopes() -> Vec<&'static str> {
+    /* `users.lookupByEmail` needs both of the users scopes */
+    let mut scopes = vec!["chat:write", "users:read", "users:read.email"];
+
+    if config().any_repo(|repo| !repo.reactions.is_empty()) {
+        scopes.push("reactions:write");
+    }
+
+    if config().any_repo(|repo| repo.acknowledge_reaction.is_some()) {
+        scopes.push("reactions:read");
+    }
+
+    if config().any_repo(|repo| repo.dm_review_requests) {
+        scopes.push("im:write");
+    }
+
+    if config().bot_identity.is_set() {
+        scopes.push("chat:write.customize");
+    }
+
+    if config().daily_thread.enabled {
+        scopes.push("channels:history");
+    }
+
+    if config().any_repo(|repo| repo.channel_template.auto_create) {
+        scopes.extend(["channels:read", "channels:join", "channels:manage"]);
+    } else if config().channel_fallback.auto_join {
+        scopes.push("channels:join");
+    }
+
+    scopes
+}
+
+#[instrument(err)]
+pub async fn fetch_user(id: &SlackUserId) -> Result<SlackUser, anyhow::Error> {
+    let token = token()?;
+    let session = client()?.open_session(&token);
+
+    let request = SlackApiUsersInfoRequest::new(id.clone());
+    Ok(session.users_info(&request).await?.user)
+}
+
+#[instrument(err, skip(email))]
+pub async fn fetch_user_from_email(email: &str) -> Result<SlackUser, anyhow::Error> {
+    /* An alias is someone's other address, which Slack won't know them by */
+    if let Some(id) = aliases::slack_user_id(email) {
+        return fetch_user(&id.into()).await;
+    }
+
+    let token = token()?;
+    let session = client()?.open_session(&token);
+
+    let request = SlackApiUsersLookupByEmailRequest::new(EmailAddress(email.to_string()));
+    let slack_user = session.users_lookup_by_email(&request).await?;
+
+    Ok(slack_user.user)
+}
+
+/// Lists the workspace's members ahead of the first lookup by name, if any repo resolves users
+/// by name, so the first notification isn't held up by it
+pub async fn warm_users_cache() {
+    if !config().any_repo(|repo| repo.resolve_by_name) {
+        return;
+    }
+
+    if let Err(x) = list_users().await {
+        tracing::warn!("Unable to warm the Slack users cache: \"{}\"", x);
+    }
+}
+
+/// Every member of the workspace, cached for [`USERS_CACHE_TTL`] as listing them is expensive
+async fn list_users() -> Result<Arc<Vec<SlackUser>>, anyhow::Error> {
+    /* Holding the lock across the fetch means concurrent lookups share a single listing */
+    let mut cached = USERS.get_or_init(|| Mutex::new(None)).lock().await;
+    if let Some((fetched, users)) = cached.as_ref() {
+        if fetched.elapsed() < USERS_CACHE_TTL {
+            return Ok(users.clone());
+        }
+    }
+
+    let token = token()?;
+    let session = client()?.open_session(&token);
+
+    let mut users = Vec::new();
+    let mut cursor = None;
+    loop {
+        let request = SlackApiUsersListRequest::new()
+            .with_limit(200)
+            .opt_cursor(cursor);
+        let response = session.users_list(&request).await?;
+        users.extend(response.members);
+
+        cursor = response
+            .response_metadata
+            .and_then(|metadata| metadata.next_cursor)
+            .filter(|cursor| !cursor.0.is_empty());
+        if cursor.is_none() {
+            break;
+        }
+    }
+
+    let users = Arc::new(users);
+    *cached = Some((Instant::now(), users.clone()));
+    Ok(users)
+}
+
+/// Whether a Slack user's display or real name matches `full_name`, ignoring case
+pub fn has_name(user: &SlackUser, full_name: &str) -> bool {
+    let full_name = full_name.trim();
+    if full_name.is_empty() {
+        return false;
+    }
+
+    let profile = user.profile.as_ref();
+    [
+        profile.and_then(|profile| profile.display_name.as_ref()),
+        profile.and_then(|profile| profile.real_name.as_ref()),
+        user.real_name.as_ref(),
+    ]
+    .into_iter()
+    .flatten()
+    .any(|name| name.trim().eq_ignore_ascii_case(full_name))
+}
+
+/// Best-effort lookup of a Slack user by their display or real name, for when exact methods
+/// fail. Ambiguous names resolve to nobody rather than risk pinging the wrong person.
+#[instrument(err)]
+pub async fn fetch_user_from_name(full_name: &str) -> Result<SlackUser, anyhow::Error> {
+    user_named(&list_users().await?, full_name)
+}
+
+fn user_named(users: &[SlackUser], full_name: &str) -> Result<SlackUser, anyhow::Error> {
+    let mut matches = users
+        .iter()
+        .filter(|user| !user.deleted.unwrap_or(false) && !user.flags.is_bot.unwrap_or(false))
+        .filter(|user| has_name(user, full_name));
+
+    match (matches.next(), matches.next()) {
+        (Some(user), None) => Ok(user.clone()),
+        (Some(_), Some(_)) => anyhow::bail!("Several Slack users are named {}", full_name),
+        (None, _) => anyhow::bail!("No Slack user is named {}", full_name),
+    }
+}
+
+/// Resolves a Gitea user to a Slack user by their email, optionally falling back to matching
+/// their full name against Slack names
+pub async fn resolve_user(user: &User, name_fallback: bool) -> Option<SlackUser> {
+    explain_resolution(user, name_fallback).await.0
+}
+
+/// As [`resolve_user`], also noting why each lookup that was tried failed, in the order they
+/// were tried
+pub async fn explain_resolution(
+    user: &User,
+    name_fallback: bool,
+) -> (Option<SlackUser>, Vec<String>) {
+    let mut failures = Vec::new();
+
+    match fetch_user_from_email(&user.email).await {
+        Ok(slack_user) => return (Some(slack_user), failures),
+        Err(x) => failures.push(match aliases::slack_user_id(&user.email) {
+            Some(id) => format!("No Slack user {} found for the email's alias: {}", id, x),
+            None => format!("No Slack user found by email: {}", x),
+        }),
+    }
+
+    if !name_fallback {
+        return (None, failures);
+    }
+
+    if user.full_name.trim().is_empty() {
+        failures.push("No full name in Gitea to match Slack names against".to_string());
+        return (None, failures);
+    }
+
+    match fetch_user_from_name(&user.full_name).await {
+        Ok(slack_user) => (Some(slack_user), failures),
+        Err(x) => {
+            failures.push(x.to_string());
+            (None, failures)
+        }
+    }
+}
+
+/// As [`resolve_user`] but as a mention, cached for [`USERS_CACHE_TTL`] since the same few
+/// people send most webhooks
+pub async fn cached_mention(user: &User, name_fallback: bool) -> Option<String> {
+    cached(&user.username, || async {
+        let slack_user = lookup_user(user, name_fallback).await?;
+        Ok(slack_user.map(|slack_user| slack_user.id.to_slack_format()))
+    })
+    .await
+}
+
+/// The mention cached for `username`, or else looked up. Only someone found or definitely not
+/// in Slack is cached, a lookup that failed is tried again next time.
+async fn cached<F, Fut>(username: &str, lookup: F) -> Option<String>
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = Result<Option<String>, anyhow::Error>>,
+{
+    let mentions = MENTIONS.get_or_init(Default::default);
+    if let Some((resolved, mention)) = mentions.lock().await.get(username) {
+        if resolved.elapsed() < USERS_CACHE_TTL {
+            return mention.clone();
+        }
+    }
+
+    match lookup().await {
+        Ok(mention) => {
+            mentions
+                .lock()
+                .await
+                .insert(username.to_string(), (Instant::now(), mention.clone()));
+            mention
+        }
+        Err(x) => {
+            tracing::warn!("Unable to look up a Slack user: \"{}\"", x);
+            None
+        }
+    }
+}
+
+/// As [`resolve_user`], but `Ok(None)` only when the user is definitely not in Slack, rather
+/// than the lookup failing
+async fn lookup_user(user: &User, name_fallback: bool) -> Result<Option<SlackUser>, anyhow::Error> {
+    let by_email = match fetch_user_from_email(&user.email).await {
+        Ok(slack_user) => return Ok(Some(slack_user)),
+        Err(x) if is_user_not_found(&x) => Ok(None),
+        Err(x) => Err(x),
+    };
+
+    if !name_fallback || user.full_name.trim().is_empty() {
+        return by_email;
+    }
+
+    /* Nobody by that name is as definite as nobody with that email */
+    match user_named(&list_users().await?, &user.full_name) {
+        Ok(slack_user) => Ok(Some(slack_user)),
+        Err(_) => by_email,
+    }
+}
+
+/* By email, or by id for an alias */
+const USER_NOT_FOUND: [&str; 2] = ["users_not_found", "user_not_found"];
+
+/// Whether a lookup by email failed because nobody in Slack has it
+fn is_user_not_found(error: &anyhow::Error) -> bool {
+    matches!(
+        error.downcast_ref::<SlackClientError>(),
+        Some(SlackClientError::ApiError(x)) if USER_NOT_FOUND.contains(&x.code.as_str())
+    )
+}
+
+/// Resolves a Gitea user, as found outside of a deanonymised webhook, to a Slack mention,
+/// falling back to their username
+pub async fn mention_gitea_user(url: &Url, user: &User) -> String {
+    let email = gitea_webhooks::deanonymised_email(url, user)
+        .await
+        .unwrap_or(user.email.clone());
+
+    match fetch_user_from_email(&email).await {
+        Ok(slack_user) => slack_user.id.to_slack_format(),
+        Err(_) => user.username.clone(),
+    }
+}
+
+/// The deployment environment from `ENVIRONMENT`, if it isn't production
+fn environment() -> Option<String> {
+    non_production(config_env_var("ENVIRONMENT").ok())
+}
+
+fn non_production(environment: Option<String>) -> Option<String> {
+    environment.filter(|env| {
+        !env.is_empty() && !matches!(env.to_lowercase().as_str(), "prod" | "production")
+    })
+}
+
+/// Prefixes a message with a visible tag, e.g. `[STAGING]`, outside of production so test
+/// notifications can't be mistaken for real ones
+fn tag_environment(message: SlackMessageContent, environment: Option<&str>) -> SlackMessageContent {
+    let Some(environment) = environment else {
+        return message;
+    };
+    let tag = format!("[{}]", environment.to_uppercase());
+
+    let mut blocks = vec![SlackContextBlock::new(vec![md!("*{}*", tag)]).into()];
+    blocks.extend(message.blocks.unwrap_or_default());
+
+    SlackMessageContent {
+        text: Some(format!("{} {}", tag, message.text.unwrap_or_default())),
+        blocks: Some(blocks),
+        ..message
+    }
+}
+
+/// Wraps a message in the configured prefix and suffix, e.g. a compliance footer, as context
+/// blocks so they're kept apart from the message itself and its mentions
+fn with_notes(message: SlackMessageContent, notes: &MessageNotes) -> SlackMessageContent {
+    if notes.prefix.is_empty() && notes.suffix.is_empty() {
+        return message;
+    }
+
+    let mut blocks = Vec::new();
+    if !notes.prefix.is_empty() {
+        blocks.push(SlackContextBlock::new(vec![md!("{}", notes.prefix)]).into());
+    }
+    blocks.extend(message.blocks.unwrap_or_default());
+    if !notes.suffix.is_empty() {
+        blocks.push(SlackContextBlock::new(vec![md!("{}", notes.suffix)]).into());
+    }
+
+    SlackMessageContent {
+        blocks: Some(blocks),
+        ..message
+    }
+}
+
+/// The channel to post to, preferring `ENVIRONMENT_SLACK_CHANNEL` outside of production
+pub fn channel() -> Result<String, anyhow::Error> {
+    let environment_channel = config_env_var("ENVIRONMENT_SLACK_CHANNEL").ok();
+
+    match environment_channel_for(environment().as_deref(), environment_channel) {
+        Some(channel) => Ok(channel),
+        None => config_env_var("SLACK_CHANNEL"),
+    }
+}
+
+/// `ENVIRONMENT_SLACK_CHANNEL`, if set and the environment isn't production
+fn environment_channel_for(
+    environment: Option<&str>,
+    environment_channel: Option<String>,
+) -> Option<String> {
+    environment?;
+    environment_channel.filter(|channel| !channel.is_empty())
+}
+
+pub async fn post_message(
+    message: SlackMessageContent,
+    parent: &Option<SlackTs>,
+) -> Result<SlackApiChatPostMessageResponse, anyhow::Error> {
+    post_message_in(None, message, parent).await
+}
+
+/// As [`post_message`], to `channel` instead of the configured one if given
+pub async fn post_message_in(
+    channel: Option<&str>,
+    message: SlackMessageContent,
+    parent: &Option<SlackTs>,
+) -> Result<SlackApiChatPostMessageResponse, anyhow::Error> {
+    let channel = match channel {
+        Some(channel) => channel.to_string(),
+        None => self::channel()?,
+    };
+
+    /* Slack threads don't nest, so replies to a PR's message land in the daily thread it's in */
+    let daily_thread = &config().daily_thread;
+    let parent = if daily_thread.enabled && (parent.is_none() || daily_thread.flatten) {
+        match daily_thread_root(&channel).await {
+            Ok(root) => Some(root),
+            Err(x) => {
+                tracing::warn!("Unable to find today's thread, posting as is: \"{}\"", x);
+                parent.clone()
+            }
+        }
+    } else {
+        parent.clone()
+    };
+
+    post_message_to(&channel, message, &parent).await
+}
+
+/// Replies in a PR's thread, in whichever channel it was started in, or posts to the configured
+/// channel if there's no thread
+pub async fn post_reply(
+    message: SlackMessageContent,
+    thread: Option<&db::Thread>,
+) -> Result<SlackApiChatPostMessageResponse, anyhow::Error> {
+    let channel = thread
+        .and_then(|thread| thread.channel.as_ref())
+        .map(|channel| channel.0.as_str());
+    let ts = thread.map(|thread| thread.ts.clone());
+    post_message_in(channel, message, &ts).await
+}
+
+/// Today's root message in `channel`, found among the channel's messages since midnight or
+/// posted if there isn't one yet
+async fn daily_thread_root(channel: &str) -> Result<SlackTs, anyhow::Error> {
+    let timezone = config().daily_thread.timezone;
+    let today = Utc::now().with_timezone(&timezone).date_naive();
+
+    /* Holding the lock across posting means concurrent notifications share a single root */
+    let mut roots = DAILY_THREADS.get_or_init(Default::default).lock().await;
+    if let Some((day, root)) = roots.get(channel) {
+        if *day == today {
+            return Ok(root.clone());
+        }
+    }
+
+    let midnight = today
+        .and_hms_opt(0, 0, 0)
+        .and_then(|midnight| midnight.and_local_timezone(timezone).earliest())
+        .context("Midnight doesn't exist in the daily thread's timezone")?;
+
+    let token = token()?;
+    let session = client()?.open_session(&token);
+    let request = SlackApiConversationsHistoryRequest::new()
+        .with_channel(channel.into())
+        .with_oldest(SlackTs::new(format!("{}.000000", midnight.timestamp())))
+        .with_include_all_metadata(true)
+        .with_limit(200);
+    let existing = session
+        .conversations_history(&request)
+        .await?
+        .messages
+        .into_iter()
+        .find(|message| {
+            message
+                .content
+                .metadata
+                .as_ref()
+                .is_some_and(|metadata| metadata.event_type == DAILY_THREAD_EVENT_TYPE)
+        });
+
+    let root = match existing {
+        Some(message) => message.origin.ts,
+        None => {
+            let message = gitea_webhooks::render_daily_thread(today).with_metadata(
+                SlackMessageMetadata::new(DAILY_THREAD_EVENT_TYPE.to_string()),
+            );
+            post_message_to(channel, message, &None).await?.ts
+        }
+    };
+
+    roots.insert(channel.to_string(), (today, root.clone()));
+    Ok(root)
+}
+
+#[instrument(err, skip(message))]
+pub async fn post_message_to(
+    channel: &str,
+    message: SlackMessageContent,
+    parent: &Option<SlackTs>,
+) -> Result<SlackApiChatPostMessageResponse, anyhow::Error> {
+    let token = token()?;
+    let session = client()?.open_session(&token);
+
+    let message = tag_environment(
+        with_notes(message, &config().message_notes),
+        environment().as_deref(),
+    );
+    warn_if_invalid(&message);
+
+    let error = match send_message(&session, channel, message.clone(), parent).await {
+        Ok(response) => return Ok(response),
+        Err(SlackClientError::ApiError(x)) if UNAVAILABLE_CHANNEL.contains(&x.code.as_str()) => x,
+        Err(x) => return Err(x.into()),
+    };
+
+    /* e.g. archived or left since it was found, so it's found or created again next time */
+    forget_channel(channel).await;
+
+    let fallback = &config().channel_fallback;
+    if fallback.auto_join && error.code == "not_in_channel" {
+        let request = SlackApiConversationsJoinRequest::new(channel.into());
+        match session.conversations_join(&request).await {
+            Ok(_) => {
+                tracing::info!("Joined {} to post there", channel);
+                return Ok(send_message(&session, channel, message, parent).await?);
+            }
+            Err(x) => tracing::warn!("Unable to join {}: \"{}\"", channel, x),
+        }
+    }
+
+    let Some(fallback_channel) = fallback.channel.as_deref().filter(|x| *x != channel) else {
+        return Err(SlackClientError::ApiError(error).into());
+    };
+
+    tracing::warn!(
+        code = error.code,
+        "Unable to post to {}, posting to {} instead",
+        channel,
+        fallback_channel
+    );
+
+    /* The thread, if any, is in the channel that couldn't be posted to */
+    let note = format!(
+        "⚠️ Meant for `{}`, which couldn't be posted to (`{}`)",
+        channel, error.code
+    );
+    let message = SlackMessageContent {
+        blocks: Some(
+            std::iter::once(SlackContextBlock::new(vec![md!("{}", note)]).into())
+                .chain(message.blocks.unwrap_or_default())
+                .collect(),
+        ),
+        ..message
+    };
+    Ok(send_message(&session, fallback_channel, message, &None).await?)
+}
+
+/// Error codes Slack gives when a channel can't be posted to however many times it's retried
+const UNAVAILABLE_CHANNEL: [&str; 3] = ["channel_not_found", "not_in_channel", "is_archived"];
+
+async fn send_message(
+    session: &SlackClientSession<'_, SlackClientHyperHttpsConnector>,
+    channel: &str,
+    message: SlackMessageContent,
+    parent: &Option<SlackTs>,
+) -> Result<SlackApiChatPostMessageResponse, SlackClientError> {
+    let identity = &config().bot_identity;
+    let post_chat_req = SlackApiChatPostMessageRequest::new(channel.into(), message)
+        .opt_thread_ts(parent.clone())
+        .opt_username(identity.username.clone())
+        .opt_icon_emoji(identity.icon_emoji.clone())
+        .opt_icon_url(identity.icon_url.clone());
+
+    session.chat_post_message(&post_chat_req).await
+}
+
+/// Logs anything Slack is likely to reject the message for, which it would otherwise only
+/// report as `invalid_blocks`. Still sent, in case the checks are stricter than Slack is.
+fn warn_if_invalid(message: &SlackMessageContent) {
+    let problems = block_kit::validate(message);
+    if !problems.is_empty() {
+        tracing::warn!(problems = ?problems, "Message looks like invalid Block Kit");
+    }
+}
+
+/// Error codes Slack gives when a message is past the workspace's edit window
+const TOO_OLD_TO_UPDATE: [&str; 2] = ["edit_window_closed", "cant_update_message"];
+
+/// Whether an update failed because the message is too old, rather than something transient
+pub fn is_too_old_to_update(error: &anyhow::Error) -> bool {
+    matches!(
+        error.downcast_ref::<SlackClientError>(),
+        Some(SlackClientError::ApiError(x)) if TOO_OLD_TO_UPDATE.contains(&x.code.as_str())
+    )
+}
+
+/// The public channel named `name`, joined if the bot isn't in it and created if there isn't
+/// one. Cached once found, as finding it means listing every channel.
+#[instrument(err)]
+pub async fn find_or_create_channel(name: &str) -> Result<SlackChannelId, anyhow::Error> {
+    /* Holding the lock across the lookup means concurrent posts can't both create it */
+    let mut channels = CHANNELS.get_or_init(Default::default).lock().await;
+    if let Some(id) = channels.get(name) {
+        return Ok(id.clone());
+    }
+
+    let token = token()?;
+    let session = client()?.open_session(&token);
+
+    let mut cursor = None;
+    let found = loop {
+        let request = SlackApiConversationsListRequest::new()
+            .with_limit(200)
+            .with_exclude_archived(true)
+            .with_types(vec![SlackConversationType::Public])
+            .opt_cursor(cursor);
+        let response = session.conversations_list(&request).await?;
+        let found = response
+            .channels
+            .into_iter()
+            .find(|channel| channel.name.as_deref() == Some(name));
+        if found.is_some() {
+            break found;
+        }
+
+        cursor = response
+            .response_metadata
+            .and_then(|metadata| metadata.next_cursor)
+            .filter(|cursor| !cursor.0.is_empty());
+        if cursor.is_none() {
+            break None;
+        }
+    };
+
+    let id = match found {
+        Some(channel) if channel.flags.is_member.unwrap_or(false) => channel.id,
+        Some(channel) => {
+            let request = SlackApiConversationsJoinRequest::new(channel.id.clone());
+            session.conversations_join(&request).await?;
+            tracing::info!("Joined {} to post there", name);
+            channel.id
+        }
+        None => {
+            /* An archived channel by the same name makes this fail with `name_taken` */
+            let request = SlackApiConversationsCreateRequest::new(name.to_string());
+            let created = session.conversations_create(&request).await?;
+            tracing::info!("Created {} to post there", name);
+            created.channel.id
+        }
+    };
+
+    channels.insert(name.to_string(), id.clone());
+    Ok(id)
+}
+
+/// Stops using a found or created channel, by id, for its name
+async fn forget_channel(id: &str) {
+    if let Some(channels) = CHANNELS.get() {
+        channels.lock().await.retain(|_, x| x.0 != id);
+    }
+}
+
+/// Opens (or reopens) a DM with a user, returning its channel to post in
+#[instrument(err)]
+pub async fn open_dm(user: &SlackUserId) -> Result<SlackChannelId, anyhow::Error> {
+    let token = token()?;
+    let session = client()?.open_session(&token);
+
+    let request = SlackApiConversationsOpenRequest::new().with_users(vec![user.clone()]);
+    Ok(session.conversations_open(&request).await?.channel.id)
+}
+
+#[instrument(err, skip(message))]
+pub async fn update_message(
+    channel: &SlackChannelId,
+    ts: &SlackTs,
+    message: SlackMessageContent,
+) -> Result<(), anyhow::Error> {
+    let token = token()?;
+    let session = client()?.open_session(&token);
+
+    let message = tag_environment(
+        with_notes(message, &config().message_notes),
+        environment().as_deref(),
+    );
+    warn_if_invalid(&message);
+
+    let request = SlackApiChatUpdateRequest::new(channel.clone(), message, ts.clone());
+    session.chat_update(&request).await?;
+
+    Ok(())
+}
+
+#[instrument(err)]
+pub async fn add_reaction(
+    channel: &SlackChannelId,
+    ts: &SlackTs,
+    name: &str,
+) -> Result<(), anyhow::Error> {
+    let token = token()?;
+    let session = client()?.open_session(&token);
+
+    let request = SlackApiReactionsAddRequest::new(
+        channel.clone(),
+        SlackReactionName(name.to_string()),
+        ts.clone(),
+    );
+    session.reactions_add(&request).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use slack_morphism::errors::SlackClientApiError;
+
+    #[test]
+    fn only_non_production_environments_are_tagged() {
+        for production in [
+            None,
+            Some(""),
+            Some("prod"),
+            Some("Production"),
+            Some("PROD"),
+        ] {
+            assert_eq!(non_production(production.map(str::to_string)), None);
+        }
+        assert_eq!(
+            non_production(Some("staging".to_string())).as_deref(),
+            Some("staging")
+        );
+
+        let message = SlackMessageContent::new()
+            .with_text("Opened".to_string())
+            .with_blocks(vec![SlackDividerBlock::new().into()]);
+        let tagged = tag_environment(message.clone(), Some("staging"));
+        assert_eq!(tagged.text.as_deref(), Some("[STAGING] Opened"));
+        assert_eq!(
+            tagged.blocks,
+            Some(vec![
+                SlackContextBlock::new(vec![md!("*[STAGING]*")]).into(),
+                SlackDividerBlock::new().into(),
+            ])
+        );
+        assert_eq!(tag_environment(message.clone(), None), message);
+    }
+
+    #[test]
+    fn the_environment_channel_is_only_used_outside_production() {
+        let channel = || Some("C0STAGING".to_string());
+        assert_eq!(
+            environment_channel_for(Some("staging"), channel()).as_deref(),
+            Some("C0STAGING")
+        );
+        assert_eq!(environment_channel_for(None, channel()), None);
+        assert_eq!(
+            environment_channel_for(Some("staging"), Some(String::new())),
+            None
+        );
+        assert_eq!(environment_channel_for(Some("staging"), None), None);
+    }
+
+    #[test]
+    fn edit_window_closed_is_too_old_to_update() {
+        let error = |code: &str| {
+            anyhow::Error::from(SlackClientError::ApiError(SlackClientApiError::new(
+                code.to_string(),
+            )))
+        };
+
+        assert!(is_too_old_to_update(&error("edit_window_closed")));
+        assert!(!is_too_old_to_update(&error("ratelimited")));
+        assert!(!is_too_old_to_update(&anyhow::anyhow!(
+            "edit_window_closed"
+        )));
+    }
+
+    #[tokio::test]
+    async fn only_definite_mentions_are_cached() {
+        let failed = || async { Err(anyhow::anyhow!("ratelimited")) };
+        let found = || async { Ok(Some("<@U0ALICE>".to_string())) };
+        let not_found = || async { Ok(None) };
+
+        assert_eq!(cached("alice-cached", failed).await, None);
+        assert_eq!(
+            cached("alice-cached", found).await.as_deref(),
+            Some("<@U0ALICE>")
+        );
+        assert_eq!(
+            cached("alice-cached", failed).await.as_deref(),
+            Some("<@U0ALICE>")
+        );
+
+        assert_eq!(cached("mallory-cached", not_found).await, None);
+        assert_eq!(cached("mallory-cached", found).await, None);
+    }
+
+    #[test]
+    fn only_a_missing_email_is_not_found() {
+        let error = |code: &str| {
+            anyhow::Error::from(SlackClientError::ApiError(SlackClientApiError::new(
+                code.to_string(),
+            )))
+        };
+
+        assert!(is_user_not_found(&error("users_not_found")));
+        assert!(!is_user_not_found(&error("ratelimited")));
+        assert!(!is_user_not_found(&anyhow::anyhow!("users_not_found")));
+    }
+
+    #[test]
+    fn notes_surround_the_message() {
+        let notes = MessageNotes {
+            prefix: String::new(),
+            suffix: "Automated notification, do not reply".to_string(),
+        };
+        let message = SlackMessageContent::new().with_blocks(slack_blocks![some_into(
+            SlackSectionBlock::new().with_text(md!("<@U0ALICE>, your PR was merged"))
+        )]);
+
+        insta::assert_json_snapshot!(with_notes(message, &notes));
+    }
+}
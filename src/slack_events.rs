@@ -0,0 +1,489 @@
+use crate::config::config;
+use crate::gitea_webhooks::MIRRORED_COMMENT_MARKER;
+use crate::{db, gitea_api, secret, sequence, slack};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Extension;
+use regex::{Captures, Regex};
+use slack_morphism::prelude::*;
+use slack_morphism::signature_verifier::SlackEventSignatureVerifier;
+use sqlx::postgres::PgPool;
+use std::collections::HashMap;
+use std::sync::OnceLock;
+use tracing::{instrument, Instrument};
+use url::Url;
+
+static SLACK_REFERENCE: OnceLock<Regex> = OnceLock::new();
+static SLACK_EMPHASIS: OnceLock<Regex> = OnceLock::new();
+
+/// Receives Slack's Events API callbacks, mirroring replies in PR threads back to Gitea and
+/// tracking acknowledgements of review requests
+#[instrument(skip_all)]
+pub async fn events_handler(db: Extension<PgPool>, headers: HeaderMap, body: String) -> Response {
+    if let Err(x) = verify(&headers, &body) {
+        tracing::warn!("Rejected Slack event: \"{}\"", x);
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+
+    let event = match serde_json::from_str::<SlackPushEvent>(&body) {
+        Ok(event) => event,
+        Err(x) => {
+            tracing::error!("Error decoding Slack event \"{}\"", x);
+            return StatusCode::OK.into_response();
+        }
+    };
+
+    match event {
+        SlackPushEvent::UrlVerification(verification) => verification.challenge.into_response(),
+        SlackPushEvent::EventCallback(SlackPushEventCallback {
+            event: SlackEventCallbackBody::Message(message),
+            ..
+        }) => {
+            /* Slack retries anything not acknowledged within a few seconds */
+            tokio::spawn(async move { mirror_reply(message, &db).await }.in_current_span());
+            StatusCode::OK.into_response()
+        }
+        SlackPushEvent::EventCallback(SlackPushEventCallback {
+            event: SlackEventCallbackBody::ReactionAdded(reaction),
+            ..
+        }) => {
+            let (user, name, item) = (reaction.user, reaction.reaction, reaction.item);
+            tokio::spawn(
+                async move { acknowledge(item, user, name, true, &db).await }.in_current_span(),
+            );
+            StatusCode::OK.into_response()
+        }
+        SlackPushEvent::EventCallback(SlackPushEventCallback {
+            event: SlackEventCallbackBody::ReactionRemoved(reaction),
+            ..
+        }) => {
+            let (user, name, item) = (reaction.user, reaction.reaction, reaction.item);
+            tokio::spawn(
+                async move { acknowledge(item, user, name, false, &db).await }.in_current_span(),
+            );
+            StatusCode::OK.into_response()
+        }
+        _ => StatusCode::OK.into_response(),
+    }
+}
+
+fn verify(headers: &HeaderMap, body: &str) -> Result<(), anyhow::Error> {
+    verify_signed(
+        secret::read("SLACK_SIGNING_SECRET")?.expose(),
+        headers,
+        body,
+    )
+}
+
+fn verify_signed(secret: &str, headers: &HeaderMap, body: &str) -> Result<(), anyhow::Error> {
+    let header = |name| {
+        headers
+            .get(name)
+            .and_then(|x| x.to_str().ok())
+            .ok_or_else(|| anyhow::anyhow!("Missing {} header", name))
+    };
+
+    SlackEventSignatureVerifier::new(&SlackSigningSecret::new(secret.to_string())).verify(
+        header(SlackEventSignatureVerifier::SLACK_SIGNED_HASH_HEADER)?,
+        body,
+        header(SlackEventSignatureVerifier::SLACK_SIGNED_TIMESTAMP)?,
+    )?;
+
+    Ok(())
+}
+
+async fn mirror_reply(message: SlackMessageEvent, db: &PgPool) {
+    /* Edits, deletions and anything posted by a bot, including our own notifications, are
+     * never mirrored, which is what stops mirrored comments echoing back and forth */
+    if message.subtype.is_some() || message.sender.bot_id.is_some() {
+        return;
+    }
+
+    let (Some(user), Some(channel), Some(thread_ts)) = (
+        &message.sender.user,
+        &message.origin.channel,
+        &message.origin.thread_ts,
+    ) else {
+        return;
+    };
+    let Some(text) = message.content.and_then(|content| content.text) else {
+        return;
+    };
+
+    let url = match db::fetch_thread_url(db, thread_ts, channel).await {
+        Ok(Some(url)) => url,
+        Ok(None) => return,
+        Err(x) => {
+            tracing::error!("Error attempting to find thread's PR in DB: \"{}\"", x);
+            return;
+        }
+    };
+
+    let Some((url, repository, number)) = parse_pull_request_url(&url) else {
+        tracing::warn!("Unable to mirror reply to unrecognised PR url {}", url);
+        return;
+    };
+
+    if !config().repo_named(&repository).mirror_slack_replies {
+        return;
+    }
+
+    let name = match slack::fetch_user(user).await {
+        Ok(slack_user) => display_name(&slack_user).unwrap_or(user.to_string()),
+        Err(_) => user.to_string(),
+    };
+
+    let body = format!(
+        "{}\n\n— {} via Slack\n{}",
+        to_gitea_markdown(&text, &mentioned_names(&text).await),
+        name,
+        MIRRORED_COMMENT_MARKER
+    );
+    if gitea_api::post_comment(&url, &repository, number, &body)
+        .await
+        .is_ok()
+    {
+        tracing::info!("Mirrored Slack reply to PR");
+    }
+}
+
+/// Marks a reviewer as having seen their review request, or not anymore, when they react to it
+/// with the repo's acknowledgement emoji. Reactions from anyone it didn't ask are ignored.
+async fn acknowledge(
+    item: SlackReactionsItem,
+    user: SlackUserId,
+    reaction: SlackReactionName,
+    acknowledged: bool,
+    db: &PgPool,
+) {
+    let SlackReactionsItem::Message(message) = item else {
+        return;
+    };
+    let Some(channel) = &message.origin.channel else {
+        return;
+    };
+
+    let (url, reviewers) = match db::fetch_review_request(db, &message.origin.ts, channel).await {
+        Ok(Some(review_request)) => review_request,
+        Ok(None) => return,
+        Err(x) => {
+            tracing::error!(
+                "Error attempting to find review request's PR in DB: \"{}\"",
+                x
+            );
+            return;
+        }
+    };
+
+    let Some((pull_request_url, repository, number)) = parse_pull_request_url(&url) else {
+        return;
+    };
+
+    let repo_config = config().repo_named(&repository);
+    if repo_config.acknowledge_reaction.as_deref() != Some(reaction.0.as_str())
+        || !reviewers.contains(&user)
+    {
+        return;
+    }
+
+    if repo_config.live_status {
+        update_acknowledged(db, &url, &user, acknowledged).await;
+    }
+
+    /* A comment can't be taken back, so removing the reaction only updates the status, and
+     * reacting again doesn't comment again */
+    if acknowledged
+        && repo_config.comment_acknowledgements
+        && claim_acknowledgement(db, &message.origin.ts, channel, &user).await
+    {
+        let name = match slack::fetch_user(&user).await {
+            Ok(slack_user) => display_name(&slack_user).unwrap_or(user.to_string()),
+            Err(_) => user.to_string(),
+        };
+
+        let body = format!(
+            "👀 {} acknowledged the review request via Slack\n{}",
+            name, MIRRORED_COMMENT_MARKER
+        );
+        if gitea_api::post_comment(&pull_request_url, &repository, number, &body)
+            .await
+            .is_ok()
+        {
+            tracing::info!("Review request acknowledgement commented on PR");
+        }
+    }
+}
+
+/// Failures count as it having been claimed already, as a missing comment beats a repeated one
+async fn claim_acknowledgement(
+    db: &PgPool,
+    ts: &SlackTs,
+    channel: &SlackChannelId,
+    user: &SlackUserId,
+) -> bool {
+    match db::claim_acknowledgement(db, ts, channel, user).await {
+        Ok(claimed) => claimed,
+        Err(x) => {
+            tracing::error!(
+                "Error attempting to record acknowledgement in DB: \"{}\"",
+                x
+            );
+            false
+        }
+    }
+}
+
+async fn update_acknowledged(db: &PgPool, url: &str, user: &SlackUserId, acknowledged: bool) {
+    /* Webhooks update the same status */
+    let _sequenced = sequence::lock(url).await;
+
+    let mut status = match db::fetch_status(db, url).await {
+        Ok(Some(status)) => status,
+        Ok(None) => return,
+        Err(x) => {
+            tracing::error!("Error attempting to retrieve PR status from DB: \"{}\"", x);
+            return;
+        }
+    };
+
+    /* Only reviewers can acknowledge, and they're only known by who they were resolved as */
+    let Some(reviewer) = status.reviewer_mentioned_as(&user.to_slack_format()) else {
+        return;
+    };
+    if !status.acknowledge(&reviewer, acknowledged) {
+        return;
+    }
+
+    if let Err(x) = db::store_status(db, url, &status).await {
+        tracing::error!("Error attempting to store PR status in DB: \"{}\"", x);
+        return;
+    }
+
+    match db::fetch_thread(db, url).await {
+        Ok(Some(db::Thread {
+            ts,
+            channel: Some(channel),
+        })) => {
+            /* Failures are logged by the update itself */
+            let _ = slack::update_message(&channel, &ts, status.render()).await;
+        }
+        Ok(_) => {}
+        Err(x) => tracing::error!("Error attempting to retrieve thread from DB: \"{}\"", x),
+    }
+}
+
+/// Splits `https://host/owner/name/pulls/42` into its url, repository and number
+fn parse_pull_request_url(url: &str) -> Option<(Url, String, u64)> {
+    let url: Url = url.parse().ok()?;
+    let segments = url.path_segments()?.collect::<Vec<_>>();
+
+    match segments.as_slice() {
+        [.., owner, name, "pulls", number] => {
+            let repository = format!("{}/{}", owner, name);
+            let number = number.parse().ok()?;
+            Some((url.clone(), repository, number))
+        }
+        _ => None,
+    }
+}
+
+/// The display names of the users `text` mentions, by their Slack user id, for naming them on
+/// Gitea where the id means nothing
+async fn mentioned_names(text: &str) -> HashMap<String, String> {
+    let mut names = HashMap::new();
+    for captures in slack_reference().captures_iter(text) {
+        let Some(id) = captures[1].strip_prefix('@') else {
+            continue;
+        };
+        if names.contains_key(id) {
+            continue;
+        }
+
+        if let Ok(slack_user) = slack::fetch_user(&id.into()).await {
+            if let Some(name) = display_name(&slack_user) {
+                names.insert(id.to_string(), name);
+            }
+        }
+    }
+
+    names
+}
+
+fn slack_reference() -> &'static Regex {
+    SLACK_REFERENCE.get_or_init(|| {
+        Regex::new(r"<([^<>|]+)(?:\|([^<>]*))?>").expect("Slack reference regex should be valid")
+    })
+}
+
+/// Converts a message in Slack's mrkdwn to markdown for Gitea: mentions and channels become
+/// `@name` and `#name`, links `[text](url)`, and `*bold*` and `~strike~` are doubled up. Code is
+/// left as it is. Mentioned users are named from `names`, falling back to their id.
+fn to_gitea_markdown(text: &str, names: &HashMap<String, String>) -> String {
+    let slack_emphasis = SLACK_EMPHASIS.get_or_init(|| {
+        Regex::new(r"(^|[\s(])([*~])([^\s*~](?:[^*~\n]*[^\s*~])?)([*~])")
+            .expect("Slack emphasis regex should be valid")
+    });
+
+    let convert = |text: &str| {
+        let text = slack_reference().replace_all(text, |captures: &Captures| {
+            let target = &captures[1];
+            let label = captures
+                .get(2)
+                .map(|x| x.as_str())
+                .filter(|x| !x.is_empty());
+
+            if let Some(id) = target.strip_prefix('@') {
+                let name = names.get(id).map(String::as_str).or(label).unwrap_or(id);
+                format!("@{}", name)
+            } else if let Some(id) = target.strip_prefix('#') {
+                format!("#{}", label.unwrap_or(id))
+            } else if let Some(special) = target.strip_prefix('!') {
+                /* e.g. <!here>, or <!subteam^ID|@team> */
+                label.map_or(format!("@{}", special), str::to_string)
+            } else {
+                match label {
+                    Some(label) => format!("[{}]({})", label, target),
+                    None => target.to_string(),
+                }
+            }
+        });
+
+        slack_emphasis
+            .replace_all(&text, |captures: &Captures| {
+                if captures[2] != captures[4] {
+                    return captures[0].to_string();
+                }
+                let marker = captures[2].repeat(2);
+                format!("{}{}{}{}", &captures[1], marker, &captures[3], marker)
+            })
+            .into_owned()
+    };
+
+    /* Slack only escapes these three, and does so inside code too */
+    let unescape = |text: &str| {
+        text.replace("&lt;", "<")
+            .replace("&gt;", ">")
+            .replace("&amp;", "&")
+    };
+
+    /* Odd parts are inside code blocks, and then inside inline code */
+    text.split("```")
+        .enumerate()
+        .map(|(i, block)| {
+            if i % 2 == 1 {
+                return unescape(block);
+            }
+
+            block
+                .split('`')
+                .enumerate()
+                .map(|(i, span)| {
+                    if i % 2 == 1 {
+                        unescape(span)
+                    } else {
+                        unescape(&convert(span))
+                    }
+                })
+                .collect::<Vec<_>>()
+                .join("`")
+        })
+        .collect::<Vec<_>>()
+        .join("```")
+}
+
+fn display_name(user: &SlackUser) -> Option<String> {
+    let profile = user.profile.as_ref()?;
+    [&profile.display_name, &profile.real_name]
+        .into_iter()
+        .flatten()
+        .find(|name| !name.is_empty())
+        .cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hmac::{Hmac, KeyInit, Mac};
+    use sha2::Sha256;
+
+    #[test]
+    fn parse_pull_request_urls() {
+        let (url, repository, number) =
+            parse_pull_request_url("https://gitea.example.com/acme/widgets/pulls/42").unwrap();
+        assert_eq!(
+            url.as_str(),
+            "https://gitea.example.com/acme/widgets/pulls/42"
+        );
+        assert_eq!(repository, "acme/widgets");
+        assert_eq!(number, 42);
+
+        /* Gitea served from a subpath */
+        let (_, repository, _) =
+            parse_pull_request_url("https://example.com/gitea/acme/widgets/pulls/7").unwrap();
+        assert_eq!(repository, "acme/widgets");
+
+        assert!(
+            parse_pull_request_url("https://gitea.example.com/acme/widgets/issues/42").is_none()
+        );
+        assert!(
+            parse_pull_request_url("https://gitea.example.com/acme/widgets/pulls/new").is_none()
+        );
+        assert!(parse_pull_request_url("https://gitea.example.com/pulls/42").is_none());
+        assert!(parse_pull_request_url("not a url").is_none());
+    }
+
+    fn signed(secret: &str, timestamp: i64, body: &str) -> HeaderMap {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(format!("v0:{}:{}", timestamp, body).as_bytes());
+        let signature = format!("v0={}", hex::encode(mac.finalize().into_bytes()));
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            SlackEventSignatureVerifier::SLACK_SIGNED_HASH_HEADER,
+            signature.parse().unwrap(),
+        );
+        headers.insert(
+            SlackEventSignatureVerifier::SLACK_SIGNED_TIMESTAMP,
+            timestamp.to_string().parse().unwrap(),
+        );
+        headers
+    }
+
+    #[test]
+    fn verify_only_accepts_recent_events_signed_with_the_secret() {
+        let secret = "8f742231b10e8888abcd99yyyzzz85a5";
+        let body = r#"{"type":"url_verification","challenge":"3eZbrw1a"}"#;
+        let now = chrono::Utc::now().timestamp();
+
+        assert!(verify_signed(secret, &signed(secret, now, body), body).is_ok());
+        assert!(verify_signed(secret, &signed("other secret", now, body), body).is_err());
+        assert!(verify_signed(secret, &signed(secret, now, body), "{}").is_err());
+        assert!(verify_signed(secret, &signed(secret, now - 60 * 60, body), body).is_err());
+
+        let mut unsigned = signed(secret, now, body);
+        unsigned.remove(SlackEventSignatureVerifier::SLACK_SIGNED_HASH_HEADER);
+        assert!(verify_signed(secret, &unsigned, body).is_err());
+    }
+
+    #[test]
+    fn replies_are_converted_to_gitea_markdown() {
+        let names = HashMap::from([("U0ALICE".to_string(), "Alice".to_string())]);
+
+        assert_eq!(
+            to_gitea_markdown(
+                "<@U0ALICE> <@U0BOB> see <https://example.com/docs|the docs> in <#C0DEV|dev>, \
+                 *not* ~this~ <!here>",
+                &names
+            ),
+            "@Alice @U0BOB see [the docs](https://example.com/docs) in #dev, **not** ~~this~~ @here"
+        );
+        assert_eq!(
+            to_gitea_markdown("<https://example.com> &lt;3 &amp; 2*3*4", &names),
+            "https://example.com <3 & 2*3*4"
+        );
+        assert_eq!(
+            to_gitea_markdown("`*a* &lt;b&gt;`\n```\n*c*\n```", &names),
+            "`*a* <b>`\n```\n*c*\n```"
+        );
+    }
+}
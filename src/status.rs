@@ -0,0 +1,441 @@
+use crate::config::config;
+use crate::gitea_api::{self, PullReview};
+use crate::gitea_webhooks::{slack_emoji, Action, Review, User, Webhook};
+use crate::slack;
+use crate::timestamps::format_lasting_time;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use slack_morphism::prelude::*;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+/// Reviewers listed in the status before the rest are summarised as a count
+const MAX_LISTED_REVIEWERS: usize = 10;
+
+/// How long reviews refreshed from Gitea are trusted for events that don't change them, so a
+/// busy PR doesn't fetch them for every event
+const REVIEWS_TTL: Duration = Duration::from_secs(60);
+/// Refresh times are dropped wholesale once this many PRs have them
+const MAX_REFRESHED_PULL_REQUESTS: usize = 256;
+
+static REFRESHED: OnceLock<Mutex<HashMap<String, Instant>>> = OnceLock::new();
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Lifecycle {
+    #[default]
+    Open,
+    Merged,
+    Closed,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReviewState {
+    Requested,
+    Approved,
+    ChangesRequested,
+    Commented,
+}
+
+/// Who last did something to a PR, and when
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct LastUpdate {
+    /// As they're named in notifications
+    pub by: String,
+    pub at: DateTime<Utc>,
+}
+
+/// The summary of a PR kept up to date in the root message of its Slack thread
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct PullRequestStatus {
+    pub title: String,
+    pub url: String,
+    pub repository: String,
+    pub lifecycle: Lifecycle,
+    pub mergeable: Option<bool>,
+    pub labels: Vec<String>,
+    /// Latest review state of each reviewer, keyed on username
+    pub reviews: BTreeMap<String, ReviewState>,
+    /// Slack mentions of the reviewers that could be resolved, keyed on username
+    pub mentions: BTreeMap<String, String>,
+    /// Reviewers who've acknowledged their review request by reacting to it, keyed on username
+    pub acknowledged: BTreeSet<String>,
+    pub last_update: Option<LastUpdate>,
+}
+
+impl PullRequestStatus {
+    pub fn apply(&mut self, webhook: &Webhook) {
+        let pull_request = &webhook.pull_request;
+
+        self.title = pull_request.title.clone();
+        self.url = pull_request.url.to_string();
+        self.repository = webhook.repository.full_name.clone();
+        self.labels = pull_request.labels.iter().map(|x| x.name.clone()).collect();
+        if pull_request.mergeable.is_some() {
+            self.mergeable = pull_request.mergeable;
+        }
+        self.last_update = Some(LastUpdate {
+            by: webhook.sender_name().to_string(),
+            at: webhook.happened_at().unwrap_or_else(Utc::now),
+        });
+
+        match &webhook.action {
+            Action::Opened | Action::Reopened => self.lifecycle = Lifecycle::Open,
+            Action::Merged => self.lifecycle = Lifecycle::Merged,
+            Action::Closed if webhook.is_merged() => self.lifecycle = Lifecycle::Merged,
+            Action::Closed => self.lifecycle = Lifecycle::Closed,
+            Action::ReviewRequested {
+                requested_reviewers,
+                ..
+            } => {
+                for reviewer in requested_reviewers.iter().filter(|x| !webhook.is_author(x)) {
+                    self.reviews
+                        .insert(reviewer.username.clone(), ReviewState::Requested);
+                    /* A new request needs acknowledging afresh */
+                    self.acknowledged.remove(&reviewer.username);
+                }
+            }
+            Action::Reviewed { review } => {
+                let reviewer = webhook.sender.username.clone();
+                let state = match review {
+                    Review::Approved { content: _ } => ReviewState::Approved,
+                    Review::Rejected { content: _ } => ReviewState::ChangesRequested,
+                    /* A plain comment doesn't undo an earlier verdict */
+                    Review::Comment { content: _ } => match self.reviews.get(&reviewer) {
+                        Some(state @ (ReviewState::Approved | ReviewState::ChangesRequested)) => {
+                            *state
+                        }
+                        _ => ReviewState::Commented,
+                    },
+                };
+                self.reviews.insert(reviewer, state);
+            }
+            Action::ReviewRequestRemoved {
+                requested_reviewers,
+            } => {
+                for reviewer in requested_reviewers {
+                    if self.reviews.get(&reviewer.username) == Some(&ReviewState::Requested) {
+                        self.reviews.remove(&reviewer.username);
+                    }
+                }
+            }
+            /* Their verdict no longer counts, so their review is outstanding again */
+            Action::ReviewDismissed {
+                reviewer: Some(reviewer),
+            } if !webhook.is_author(reviewer) => {
+                self.reviews
+                    .insert(reviewer.username.clone(), ReviewState::Requested);
+            }
+            _ => {}
+        }
+    }
+
+    /// Replaces the reviews pieced together from events with Gitea's own record of them, which
+    /// also covers anything from before the thread existed, and resolves any new reviewers to
+    /// Slack mentions
+    pub async fn refresh_reviews(&mut self, webhook: &Webhook) -> Result<(), anyhow::Error> {
+        let changes_reviews = matches!(
+            webhook.action,
+            Action::Reviewed { .. }
+                | Action::ReviewRequested { .. }
+                | Action::ReviewRequestRemoved { .. }
+                | Action::ReviewDismissed { .. }
+        );
+        if !changes_reviews && recently_refreshed(webhook.pull_request.url.as_str()) {
+            return Ok(());
+        }
+
+        let (url, repository, number) = (
+            &webhook.repository.url,
+            webhook.repository.full_name.as_str(),
+            webhook.pull_request.number,
+        );
+
+        let (reviews, pull_request) = tokio::join!(
+            gitea_api::fetch_reviews(url, repository, number),
+            gitea_api::fetch_pull_request(url, repository, number),
+        );
+        let (_, requested_reviewers) = pull_request?;
+
+        self.sync_reviews(
+            &reviews?,
+            &requested_reviewers,
+            &webhook.pull_request.user.username,
+        );
+
+        for reviewer in self.reviews.keys() {
+            if self.mentions.contains_key(reviewer) {
+                continue;
+            }
+
+            let user = User {
+                email: String::new(),
+                username: reviewer.clone(),
+                full_name: String::new(),
+            };
+            let mention = slack::mention_gitea_user(&webhook.pull_request.url, &user).await;
+            if mention != *reviewer {
+                self.mentions.insert(reviewer.clone(), mention);
+            }
+        }
+
+        let mut refreshed = REFRESHED.get_or_init(Default::default).lock().unwrap();
+        if refreshed.len() >= MAX_REFRESHED_PULL_REQUESTS {
+            refreshed.clear();
+        }
+        refreshed.insert(webhook.pull_request.url.to_string(), Instant::now());
+
+        Ok(())
+    }
+
+    fn sync_reviews(&mut self, reviews: &[PullReview], requested: &[User], author: &str) {
+        let mut states = BTreeMap::new();
+        for review in reviews {
+            let Some(user) = &review.user else {
+                continue;
+            };
+
+            let state = match review.state.as_str() {
+                "APPROVED" => ReviewState::Approved,
+                "REQUEST_CHANGES" => ReviewState::ChangesRequested,
+                "REQUEST_REVIEW" => ReviewState::Requested,
+                "COMMENT" => match states.get(&user.username) {
+                    Some(state @ (ReviewState::Approved | ReviewState::ChangesRequested)) => *state,
+                    _ => ReviewState::Commented,
+                },
+                /* e.g. an unsubmitted draft review */
+                _ => continue,
+            };
+            states.insert(user.username.clone(), state);
+        }
+
+        /* A re-requested review is outstanding again, whatever came before */
+        for reviewer in requested {
+            states.insert(reviewer.username.clone(), ReviewState::Requested);
+        }
+
+        states.remove(author);
+        self.reviews = states;
+    }
+
+    /// The reviewer resolved to a Slack mention, e.g. `<@U0BOB>`, if any was
+    pub fn reviewer_mentioned_as(&self, mention: &str) -> Option<String> {
+        self.mentions
+            .iter()
+            .find(|(_, x)| *x == mention)
+            .map(|(reviewer, _)| reviewer.clone())
+    }
+
+    /// Returns whether that changed anything
+    pub fn acknowledge(&mut self, reviewer: &str, acknowledged: bool) -> bool {
+        if acknowledged {
+            self.acknowledged.insert(reviewer.to_string())
+        } else {
+            self.acknowledged.remove(reviewer)
+        }
+    }
+
+    /// e.g. "2 approvals, 1 changes requested", or nothing before any verdicts
+    fn verdicts(&self) -> Option<String> {
+        let count = |state| self.reviews.values().filter(|x| **x == state).count();
+        let (approvals, changes_requested) = (
+            count(ReviewState::Approved),
+            count(ReviewState::ChangesRequested),
+        );
+
+        let mut verdicts = Vec::new();
+        match approvals {
+            0 => {}
+            1 => verdicts.push("1 approval".to_string()),
+            n => verdicts.push(format!("{} approvals", n)),
+        }
+        if changes_requested > 0 {
+            verdicts.push(format!("{} changes requested", changes_requested));
+        }
+
+        (!verdicts.is_empty()).then(|| verdicts.join(", "))
+    }
+
+    pub fn render(&self) -> SlackMessageContent {
+        let lifecycle = match self.lifecycle {
+            Lifecycle::Open => "🟢 Open",
+            Lifecycle::Merged => "🟣 Merged",
+            Lifecycle::Closed => "🔴 Closed",
+        };
+
+        let mut summary = vec![lifecycle.to_string()];
+        match self.mergeable {
+            Some(true) => summary.push("✅ Mergeable".to_string()),
+            Some(false) => summary.push("⚠️ Conflicts".to_string()),
+            None => {}
+        }
+        if let Some(verdicts) = self.verdicts() {
+            summary.push(format!("👍 {}", verdicts));
+        }
+        if !self.labels.is_empty() {
+            summary.push(format!("🏷️ {}", self.labels.join(", ")));
+        }
+        let repo_config = config().repo_named(&self.repository);
+        if let Some(last_update) = self
+            .last_update
+            .as_ref()
+            .filter(|_| repo_config.show_last_update)
+        {
+            summary.push(format!(
+                "✏️ Last updated by {} {}",
+                last_update.by,
+                format_lasting_time(last_update.at, &repo_config.timestamps)
+            ));
+        }
+
+        let mut reviews = self
+            .reviews
+            .iter()
+            .take(MAX_LISTED_REVIEWERS)
+            .map(|(reviewer, state)| {
+                let icon = match state {
+                    ReviewState::Requested if self.acknowledged.contains(reviewer) => "👀",
+                    ReviewState::Requested => "⏳",
+                    ReviewState::Approved => "✅",
+                    ReviewState::ChangesRequested => "❌",
+                    ReviewState::Commented => "💬",
+                };
+                let reviewer = self.mentions.get(reviewer).unwrap_or(reviewer);
+                format!("{} {}", icon, reviewer)
+            })
+            .collect::<Vec<String>>()
+            .join("  ");
+
+        if self.reviews.len() > MAX_LISTED_REVIEWERS {
+            reviews += &format!("  and {} more", self.reviews.len() - MAX_LISTED_REVIEWERS);
+        }
+
+        SlackMessageContent::new().with_blocks(slack_blocks![
+            some_into(SlackSectionBlock::new().with_text(md!(
+                "*<{}|{}>* in {}",
+                self.url,
+                slack_emoji(&self.title),
+                self.repository
+            ))),
+            some_into(SlackContextBlock::new(vec![md!(
+                "{}",
+                summary.join(" · ")
+            )])),
+            optionally_into(!reviews.is_empty() => SlackContextBlock::new(vec![md!(
+                "Reviews: {}",
+                reviews
+            )]))
+        ])
+    }
+}
+
+fn recently_refreshed(url: &str) -> bool {
+    REFRESHED
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap()
+        .get(url)
+        .is_some_and(|refreshed| refreshed.elapsed() < REVIEWS_TTL)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn webhook(fixture: &str) -> Webhook {
+        serde_json::from_str(fixture).expect("Fixture should deserialise into a Webhook")
+    }
+
+    #[test]
+    fn render_after_review() {
+        let mut status = PullRequestStatus::default();
+        status.apply(&webhook(include_str!("../tests/fixtures/opened.json")));
+        status.apply(&webhook(include_str!(
+            "../tests/fixtures/review_requested.json"
+        )));
+        status.apply(&webhook(include_str!(
+            "../tests/fixtures/review_approved.json"
+        )));
+
+        assert_eq!(status.reviews.get("bob"), Some(&ReviewState::Approved));
+        assert_eq!(
+            status.last_update.as_ref().map(|x| x.by.as_str()),
+            Some("bob")
+        );
+        insta::assert_json_snapshot!(status.render());
+    }
+
+    #[test]
+    fn unrequested_reviewers_are_dropped_unless_they_reviewed() {
+        let fixture = include_str!("../tests/fixtures/review_requested.json");
+        let mut removed = serde_json::from_str::<serde_json::Value>(fixture).unwrap();
+        removed["action"] = "review_request_removed".into();
+        let removed: Webhook = serde_json::from_value(removed).unwrap();
+
+        let mut status = PullRequestStatus::default();
+        status.apply(&webhook(fixture));
+        status.apply(&removed);
+        assert_eq!(status.reviews.get("bob"), None);
+
+        status.apply(&webhook(include_str!(
+            "../tests/fixtures/review_approved.json"
+        )));
+        status.apply(&removed);
+        assert_eq!(status.reviews.get("bob"), Some(&ReviewState::Approved));
+    }
+
+    #[test]
+    fn acknowledged_until_requested_again() {
+        let requested = webhook(include_str!("../tests/fixtures/review_requested.json"));
+        let mut status = PullRequestStatus::default();
+        status.apply(&requested);
+        status
+            .mentions
+            .insert("bob".to_string(), "<@U0BOB>".to_string());
+
+        let reviewer = status.reviewer_mentioned_as("<@U0BOB>");
+        assert_eq!(reviewer.as_deref(), Some("bob"));
+        assert!(status.acknowledge("bob", true));
+        assert!(!status.acknowledge("bob", true));
+
+        status.apply(&requested);
+        assert!(status.acknowledged.is_empty());
+    }
+
+    #[test]
+    fn sync_reviews_from_gitea() {
+        let user = |username: &str| User {
+            email: String::new(),
+            username: username.to_string(),
+            full_name: String::new(),
+        };
+        let review = |username: &str, state: &str| PullReview {
+            user: Some(user(username)),
+            state: state.to_string(),
+            dismissed: false,
+        };
+
+        let mut status = PullRequestStatus::default();
+        status.sync_reviews(
+            &[
+                review("bob", "APPROVED"),
+                review("bob", "COMMENT"),
+                review("carol", "REQUEST_CHANGES"),
+                review("dave", "APPROVED"),
+                review("erin", "PENDING"),
+            ],
+            &[user("dave"), user("alice")],
+            "alice",
+        );
+
+        assert_eq!(
+            status.reviews.into_iter().collect::<Vec<_>>(),
+            vec![
+                ("bob".to_string(), ReviewState::Approved),
+                ("carol".to_string(), ReviewState::ChangesRequested),
+                ("dave".to_string(), ReviewState::Requested),
+            ]
+        );
+    }
+}
@@ -0,0 +1,194 @@
+use crate::config::config;
+use crate::gitea_webhooks::{Action, User, Webhook};
+use crate::http;
+use crate::notification::Notification;
+use crate::secret;
+use serde_json::json;
+use std::collections::HashMap;
+use tracing::instrument;
+
+const ADAPTIVE_CARD_CONTENT_TYPE: &str = "application/vnd.microsoft.card.adaptive";
+const ADAPTIVE_CARD_VERSION: &str = "1.4";
+
+/// A Teams user that can be mentioned, which needs their Entra ID (formerly AAD) object id
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TeamsUser {
+    pub id: String,
+    pub name: String,
+}
+
+/// Looks a Gitea user up in the configured `teams_users`, by email or else by username, since
+/// Gitea may only give us a noreply address
+pub fn resolve_user(user: &User, teams_users: &HashMap<String, String>) -> Option<TeamsUser> {
+    let id = teams_users
+        .get(&user.email)
+        .or_else(|| teams_users.get(&user.username))?;
+
+    let name = if user.full_name.is_empty() {
+        &user.username
+    } else {
+        &user.full_name
+    };
+
+    Some(TeamsUser {
+        id: id.clone(),
+        name: name.clone(),
+    })
+}
+
+/// The webhook's notification for Teams, `None` if there's nobody to notify
+pub async fn to_notification(webhook: &Webhook) -> Option<Notification> {
+    let notification = webhook.to_notification().await?;
+
+    /* As in Slack, a comment is only worth posting if it pings someone */
+    if let Action::Created { .. } = webhook.action {
+        let post_unresolved = config().repo(&webhook.repository).post_unresolved_comments;
+        let resolved = unresolved(&notification).len() < notification.mentions.len();
+
+        if !resolved && (!post_unresolved || notification.mentions.is_empty()) {
+            tracing::debug!("Nobody to notify");
+            return None;
+        }
+    }
+
+    Some(notification)
+}
+
+/// Whoever the notification mentions that isn't one of the configured `teams_users`
+pub fn unresolved(notification: &Notification) -> Vec<&User> {
+    notification
+        .mentions
+        .iter()
+        .filter(|user| resolve_user(user, &config().teams_users).is_none())
+        .collect()
+}
+
+/// Sends the notification to the Teams channel
+pub async fn notify(notification: &Notification) -> Result<(), anyhow::Error> {
+    post_card(render_card(notification, &config().teams_users)).await
+}
+
+/// Renders a notification as an Adaptive Card, mentioning whoever resolves to a Teams user and
+/// naming everyone else by username
+pub fn render_card(
+    notification: &Notification,
+    teams_users: &HashMap<String, String>,
+) -> serde_json::Value {
+    let mut entities = Vec::new();
+    let mut names = Vec::new();
+    for user in &notification.mentions {
+        match resolve_user(user, teams_users) {
+            Some(teams_user) => {
+                let text = format!("<at>{}</at>", teams_user.name);
+
+                /* Someone mentioned twice should only be pinged once */
+                if !names.contains(&text) {
+                    entities.push(json!({
+                        "type": "mention",
+                        "text": text,
+                        "mentioned": { "id": teams_user.id, "name": teams_user.name },
+                    }));
+                    names.push(text);
+                }
+            }
+            None => names.push(user.username.clone()),
+        }
+    }
+
+    let text = if names.is_empty() {
+        notification.text.clone()
+    } else {
+        format!("{}, {}", names.join(", "), notification.text)
+    };
+
+    let notes = &config().message_notes;
+    let note =
+        |text: &str| json!({ "type": "TextBlock", "text": text, "isSubtle": true, "wrap": true });
+
+    let mut body = Vec::new();
+    if !notes.prefix.is_empty() {
+        body.push(note(&notes.prefix));
+    }
+
+    body.extend([
+        json!({
+            "type": "TextBlock",
+            "text": notification.title,
+            "weight": "Bolder",
+            "wrap": true,
+        }),
+        json!({ "type": "TextBlock", "text": text, "wrap": true }),
+    ]);
+
+    if let Some(excerpt) = &notification.excerpt {
+        body.push(note(excerpt));
+    }
+
+    if !notes.suffix.is_empty() {
+        body.push(note(&notes.suffix));
+    }
+
+    json!({
+        "type": "AdaptiveCard",
+        "$schema": "http://adaptivecards.io/schemas/adaptive-card.json",
+        "version": ADAPTIVE_CARD_VERSION,
+        "body": body,
+        "actions": [{
+            "type": "Action.OpenUrl",
+            "title": "View on Gitea",
+            "url": notification.link,
+        }],
+        "msteams": { "entities": entities },
+    })
+}
+
+/// Posts a card to the incoming webhook in `TEAMS_WEBHOOK_URL`
+#[instrument(err, skip_all)]
+pub async fn post_card(card: serde_json::Value) -> Result<(), anyhow::Error> {
+    /* The URL itself is the credential */
+    let url = secret::read("TEAMS_WEBHOOK_URL")?;
+
+    let message = json!({
+        "type": "message",
+        "attachments": [{
+            "contentType": ADAPTIVE_CARD_CONTENT_TYPE,
+            "content": card,
+        }],
+    });
+
+    http::client()
+        .post(url.expose())
+        .json(&message)
+        .send()
+        .await?
+        .error_for_status()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_review_requested_card() {
+        let webhook: Webhook =
+            serde_json::from_str(include_str!("../tests/fixtures/review_requested.json"))
+                .expect("Fixture should deserialise into a Webhook");
+        let Action::ReviewRequested {
+            requested_reviewers,
+            ..
+        } = &webhook.action
+        else {
+            panic!("Fixture should be a review request");
+        };
+
+        let teams_users = HashMap::from([(
+            requested_reviewers[0].username.clone(),
+            "00000000-0000-0000-0000-00000000a11c".to_string(),
+        )]);
+
+        let notification = webhook.notification(requested_reviewers.clone());
+        insta::assert_json_snapshot!(render_card(&notification, &teams_users));
+    }
+}
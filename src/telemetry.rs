@@ -0,0 +1,56 @@
+use opentelemetry::trace::TracerProvider;
+use opentelemetry_otlp::SpanExporter;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+use std::sync::OnceLock;
+use tracing::Subscriber;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+const SERVICE_NAME: &str = "gitea-notif";
+
+static PROVIDER: OnceLock<SdkTracerProvider> = OnceLock::new();
+
+/// Exports spans over OTLP/HTTP when `OTEL_EXPORTER_OTLP_ENDPOINT` is set. The exporter reads
+/// the endpoint, and any of the other standard `OTEL_` variables, itself.
+pub fn layer<S>() -> Option<impl Layer<S>>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT")
+        .ok()
+        .filter(|endpoint| !endpoint.is_empty())?;
+
+    /* The batch exporter runs on its own thread outside of Tokio, hence the blocking client */
+    let exporter = match SpanExporter::builder().with_http().build() {
+        Ok(exporter) => exporter,
+        Err(x) => {
+            /* Tracing isn't initialised yet, so there's nowhere better to report this */
+            eprintln!(
+                "Failed to build OTLP exporter, traces won't be exported: {}",
+                x
+            );
+            return None;
+        }
+    };
+
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(Resource::builder().with_service_name(SERVICE_NAME).build())
+        .build();
+    let tracer = provider.tracer(SERVICE_NAME);
+    opentelemetry::global::set_tracer_provider(provider.clone());
+    let _ = PROVIDER.set(provider);
+
+    Some(tracing_opentelemetry::layer().with_tracer(tracer))
+}
+
+/// Exports whatever spans are still batched, so the last ones before exiting aren't lost.
+/// Blocks until the exporter finishes.
+pub fn shutdown() {
+    if let Some(provider) = PROVIDER.get() {
+        if let Err(x) = provider.shutdown() {
+            eprintln!("Failed to export the last traces: {}", x);
+        }
+    }
+}
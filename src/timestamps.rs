@@ -0,0 +1,91 @@
+use crate::config::{TimestampConfig, TimestampStyle};
+use chrono::{DateTime, TimeDelta, Utc};
+
+/// Formats when something happened as the repo prefers, e.g. "3 days ago" or
+/// "on 12 Mar 2026 07:12 UTC", to follow on from what happened, e.g. "Opened"
+pub fn format_time(at: DateTime<Utc>, now: DateTime<Utc>, timestamps: &TimestampConfig) -> String {
+    match timestamps.style {
+        TimestampStyle::Relative => format_relative_time(now - at),
+        TimestampStyle::Absolute => format!(
+            "on {}",
+            at.with_timezone(&timestamps.timezone)
+                .format(&timestamps.format)
+        ),
+    }
+}
+
+/// Formats when something happened for a message that's left up, where a relative time would
+/// soon be wrong, so Slack shows it in each reader's own timezone instead, e.g. "today at
+/// 07:12". Absolute times are as [`format_time`].
+pub fn format_lasting_time(at: DateTime<Utc>, timestamps: &TimestampConfig) -> String {
+    match timestamps.style {
+        TimestampStyle::Relative => format!(
+            "<!date^{}^{{date_short_pretty}} at {{time}}|{}>",
+            at.timestamp(),
+            at.format("%-d %b %Y %H:%M UTC")
+        ),
+        TimestampStyle::Absolute => format_time(at, at, timestamps),
+    }
+}
+
+/// Formats how long ago something happened in its largest whole unit, e.g. "3 days ago"
+fn format_relative_time(elapsed: TimeDelta) -> String {
+    let (count, unit) = if elapsed.num_days() > 0 {
+        (elapsed.num_days(), "day")
+    } else if elapsed.num_hours() > 0 {
+        (elapsed.num_hours(), "hour")
+    } else if elapsed.num_minutes() > 0 {
+        (elapsed.num_minutes(), "minute")
+    } else {
+        return "just now".to_string();
+    };
+
+    match count {
+        1 => format!("1 {} ago", unit),
+        _ => format!("{} {}s ago", count, unit),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn times_are_relative_or_absolute_in_the_timezone() {
+        let at = "2026-03-12T07:12:40Z".parse().unwrap();
+        let now = "2026-03-12T10:30:00Z".parse().unwrap();
+
+        let mut timestamps = TimestampConfig::default();
+        assert_eq!(format_time(at, now, &timestamps), "3 hours ago");
+
+        timestamps.style = TimestampStyle::Absolute;
+        assert_eq!(
+            format_time(at, now, &timestamps),
+            "on 12 Mar 2026 07:12 UTC"
+        );
+
+        timestamps.timezone = chrono_tz::America::New_York;
+        timestamps.format = "%m/%d/%Y %-I:%M %p %Z".to_string();
+        assert_eq!(
+            format_time(at, now, &timestamps),
+            "on 03/12/2026 3:12 AM EDT"
+        );
+    }
+
+    #[test]
+    fn lasting_times_are_left_to_slack_unless_absolute() {
+        let at = "2026-03-12T07:12:40Z".parse().unwrap();
+
+        let mut timestamps = TimestampConfig::default();
+        assert_eq!(
+            format_lasting_time(at, &timestamps),
+            "<!date^1773299560^{date_short_pretty} at {time}|12 Mar 2026 07:12 UTC>"
+        );
+
+        timestamps.style = TimestampStyle::Absolute;
+        assert_eq!(
+            format_lasting_time(at, &timestamps),
+            "on 12 Mar 2026 07:12 UTC"
+        );
+    }
+}
@@ -0,0 +1,56 @@
+use crate::config::config;
+use crate::gitea_webhooks::{MySlackMessage, Webhook};
+use slack_morphism::prelude::*;
+
+/// Logs the webhook as it was deserialised, redacted as the raw payload is, when
+/// `verbose_logging` is on
+pub fn log_webhook(webhook: &Webhook) {
+    if !config().verbose_logging {
+        return;
+    }
+
+    match serde_json::to_value(webhook) {
+        Ok(webhook) => tracing::debug!(webhook = %crate::redact(webhook), "Webhook received"),
+        Err(x) => tracing::warn!("Unable to serialise webhook for logging: \"{}\"", x),
+    }
+}
+
+/// Logs who the webhook's recipients were resolved to, when `verbose_logging` is on
+pub fn log_resolution(message: &MySlackMessage) {
+    if !config().verbose_logging {
+        return;
+    }
+
+    let resolved = message
+        .slack_user
+        .iter()
+        .map(|user| user.id.to_slack_format())
+        .collect::<Vec<String>>();
+    let unresolved = message
+        .unresolved
+        .iter()
+        .map(|user| user.username.clone())
+        .collect::<Vec<String>>();
+
+    tracing::debug!(?resolved, ?unresolved, "Recipients resolved");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn logged_webhooks_are_redacted_like_payloads() {
+        let webhook: Webhook =
+            serde_json::from_str(include_str!("../tests/fixtures/review_requested.json"))
+                .expect("Fixture should deserialise into a Webhook");
+        let redacted = crate::redact(serde_json::to_value(&webhook).unwrap());
+
+        assert_eq!(redacted["action"], "review_requested");
+        assert_eq!(redacted["sender"]["username"], "alice");
+        assert_eq!(redacted["sender"]["email"], "***");
+        assert_eq!(redacted["sender"]["full_name"], "***");
+        assert_eq!(redacted["requested_reviewer"][0]["email"], "***");
+        assert_eq!(redacted["repository"]["full_name"], "acme/widgets");
+    }
+}